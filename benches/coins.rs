@@ -0,0 +1,96 @@
+/*
+ * Copyright 2020 BDK Team
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Persisting the coins after a block with a few wallet outputs, in a wallet of thousands of coins:
+//! rewriting all coins against writing only the changes.
+//!
+//! cargo bench --bench coins
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bitcoin::{Address, BitcoinHash, Block, BlockHeader, Network, OutPoint, Transaction, TxIn, TxOut};
+use bitcoin::blockdata::constants::genesis_block;
+use bitcoin::blockdata::script::Builder;
+use bitcoin::util::hash::MerkleRoot;
+use bitcoin_hashes::sha256d;
+use criterion::{Criterion, criterion_group, criterion_main};
+
+use bdk::db::DB;
+use bdk::storage::{Storage, StoredCoins};
+use bdk::wallet::Wallet;
+
+// coins of the wallet before the measured block
+const BLOCKS: u32 = 100;
+const OUTPUTS_PER_BLOCK: u32 = 50;
+
+fn block(prev: &sha256d::Hash, height: u32, to: &Address, outputs: u32) -> Block {
+    let coin_base = Transaction {
+        version: 2,
+        lock_time: 0,
+        input: vec!(TxIn {
+            previous_output: OutPoint { txid: sha256d::Hash::default(), vout: 0 },
+            script_sig: Builder::new().push_int(height as i64).into_script(),
+            sequence: 0xffffffff,
+            witness: Vec::new(),
+        }),
+        output: (0..outputs).map(|i| TxOut { value: 1000 + i as u64, script_pubkey: to.script_pubkey() }).collect(),
+    };
+    let mut block = Block {
+        header: BlockHeader {
+            version: 1,
+            time: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as u32,
+            nonce: 0,
+            bits: 0x1d00ffff,
+            prev_blockhash: *prev,
+            merkle_root: sha256d::Hash::default(),
+        },
+        txdata: vec!(coin_base),
+    };
+    block.header.merkle_root = block.merkle_root();
+    block
+}
+
+fn persist_coins(c: &mut Criterion) {
+    let (_, address, mut wallet) = Wallet::new(Network::Regtest, "benchmark passphrase", None).unwrap();
+    let mut tip = genesis_block(Network::Regtest).bitcoin_hash();
+    for height in 1..=BLOCKS {
+        let next = block(&tip, height, &address, OUTPUTS_PER_BLOCK);
+        wallet.process(&next);
+        tip = next.bitcoin_hash();
+    }
+    let mut db = DB::memory().unwrap();
+    db.migrate().unwrap();
+    bdk::storage::init(&mut db, wallet.coins(), &wallet.master).unwrap();
+
+    let before = StoredCoins::new(wallet.coins());
+    wallet.process(&block(&tip, BLOCKS + 1, &address, 2));
+    let changes = before.changes(wallet.coins());
+
+    // each iteration rolls back so all start from the same database
+    c.bench_function("rewrite all coins", |b| b.iter(|| {
+        let mut tx = db.transaction().unwrap();
+        tx.store_coins(wallet.coins()).unwrap();
+        tx.rollback().unwrap();
+    }));
+    c.bench_function("write changed coins", |b| b.iter(|| {
+        let mut tx = db.transaction().unwrap();
+        tx.update_coins(wallet.coins(), &changes).unwrap();
+        tx.rollback().unwrap();
+    }));
+}
+
+criterion_group!(benches, persist_coins);
+criterion_main!(benches);
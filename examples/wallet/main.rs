@@ -33,7 +33,7 @@ use log::{debug, error, info, warn, LevelFilter};
 use rustyline::Editor;
 use rustyline::error::ReadlineError;
 
-use bdk::api::{balance, deposit_addr, init_config, start, stop, update_config, withdraw};
+use bdk::api::{init_config, start, update_config};
 use bdk::api;
 use bdk::config::Config;
 use bdk::error::Error;
@@ -111,10 +111,8 @@ fn main() -> Result<(), Error> {
         println!("No previous history.");
     }
 
-    let p2p_thread = thread::spawn(move || {
-        println!("starting p2p thread");
-        api::start(work_dir.clone(), network, false);
-    });
+    println!("starting wallet");
+    let wallet = api::start(work_dir.clone(), network, false)?;
 
     loop {
         let readline = rl.readline(">> ");
@@ -131,11 +129,11 @@ fn main() -> Result<(), Error> {
                                 break;
                             }
                             "balance" => {
-                                let balance_amt = api::balance().unwrap();
+                                let balance_amt = wallet.balance().unwrap();
                                 println!("balance: {}, confirmed: {}", balance_amt.balance, balance_amt.confirmed);
                             }
                             "deposit" => {
-                               let deposit_addr = api::deposit_addr();
+                               let deposit_addr = wallet.deposit_addr().unwrap();
                                 println!("deposit address: {}", deposit_addr);
                             }
                             "withdraw" => {
@@ -144,7 +142,7 @@ fn main() -> Result<(), Error> {
                                 let address = Address::from_str(a.value_of("address").unwrap()).unwrap();
                                 let fee = a.value_of("fee").unwrap().parse::<u64>().unwrap();
                                 let amount = Some(a.value_of("amount").unwrap().parse::<u64>().unwrap());
-                                let withdraw_tx = api::withdraw(password, address, fee, amount).unwrap();
+                                let withdraw_tx = wallet.withdraw(password, address, fee, amount).unwrap();
                                 println!("withdraw tx id: {}, fee: {}", withdraw_tx.txid, withdraw_tx.fee);
                             }
                             _ => {
@@ -173,8 +171,7 @@ fn main() -> Result<(), Error> {
     }
     rl.save_history(history_file).unwrap();
     println!("stopping");
-    api::stop();
-    p2p_thread.join().unwrap();
+    wallet.stop();
     println!("stopped");
     Ok(())
 }
@@ -0,0 +1,141 @@
+#ifndef BDK_H
+#define BDK_H
+
+/* generated with cbindgen from src/ffi.rs, do not edit */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/*
+ result of every bdk function
+ */
+typedef enum {
+  BDK_ERROR_CODE_OK = 0,
+  /*
+   error without a more specific code
+   */
+  BDK_ERROR_CODE_ERROR = 1,
+  /*
+   a required pointer argument was null
+   */
+  BDK_ERROR_CODE_NULL_POINTER = 2,
+  /*
+   a string argument is not UTF-8
+   */
+  BDK_ERROR_CODE_INVALID_UTF8 = 3,
+  /*
+   the wallet is not started
+   */
+  BDK_ERROR_CODE_NOT_STARTED = 4,
+  /*
+   a wallet is already configured in the work directory
+   */
+  BDK_ERROR_CODE_ALREADY_EXISTS = 5,
+  BDK_ERROR_CODE_INSUFFICIENT_FUNDS = 6,
+  BDK_ERROR_CODE_BELOW_DUST = 7,
+  BDK_ERROR_CODE_WRONG_PASSPHRASE = 8,
+  BDK_ERROR_CODE_PASSPHRASE_TOO_SHORT = 9,
+  BDK_ERROR_CODE_INVALID_ADDRESS = 10,
+  BDK_ERROR_CODE_NETWORK_MISMATCH = 11,
+  BDK_ERROR_CODE_CORRUPTED = 12,
+  /*
+   the library panicked, the wallet should not be used any further
+   */
+  BDK_ERROR_CODE_PANIC = 13,
+  BDK_ERROR_CODE_FEE_ABOVE_MAXIMUM = 14,
+  /*
+   the database is encrypted, it can not be started without its secret
+   */
+  BDK_ERROR_CODE_DATABASE_LOCKED = 15,
+} BdkErrorCode;
+
+/*
+ a running wallet
+ */
+typedef struct BdkWallet BdkWallet;
+
+/*
+ balances in satoshis
+ */
+typedef struct {
+  uint64_t balance;
+  uint64_t confirmed;
+} BdkBalance;
+
+/*
+ filled by bdk_init_config, free with bdk_init_result_free
+ */
+typedef struct {
+  char *mnemonic_words;
+  char *deposit_address;
+} BdkInitResult;
+
+/*
+ filled by bdk_withdraw, free with bdk_withdraw_tx_free
+ */
+typedef struct {
+  char *txid;
+  uint64_t fee;
+} BdkWithdrawTx;
+
+BdkErrorCode bdk_balance(const BdkWallet *wallet, BdkBalance *out);
+
+/*
+ on success *out is a new deposit address, free with bdk_string_free
+ */
+BdkErrorCode bdk_deposit_addr(const BdkWallet *wallet, char **out);
+
+/*
+ create a new wallet in work_dir, pd_passphrase may be null.
+ Network is 0 for bitcoin, 1 for testnet and 2 for regtest.
+ */
+BdkErrorCode bdk_init_config(const char *work_dir,
+                             int32_t network,
+                             const char *passphrase,
+                             const char *pd_passphrase,
+                             BdkInitResult *out);
+
+/*
+ free the strings of an init result, the struct itself is owned by the caller
+ */
+void bdk_init_result_free(BdkInitResult *init_result);
+
+/*
+ message of the last error on this thread, null if there was none.
+ The string is owned by the library and valid until the next call on this thread.
+ */
+const char *bdk_last_error(void);
+
+/*
+ start the wallet configured in work_dir, on success *out is the wallet to pass to bdk_stop
+ */
+BdkErrorCode bdk_start(const char *work_dir, int32_t network, bool rescan, BdkWallet **out);
+
+/*
+ stop and free the wallet, it must not be used afterwards. Null is ignored.
+ */
+BdkErrorCode bdk_stop(BdkWallet *wallet);
+
+/*
+ free a string returned by the library. Null is ignored.
+ */
+void bdk_string_free(char *string);
+
+/*
+ send amount satoshis to address, amount 0 sends everything
+ */
+BdkErrorCode bdk_withdraw(const BdkWallet *wallet,
+                          const char *passphrase,
+                          const char *address,
+                          uint64_t fee_per_vbyte,
+                          uint64_t amount,
+                          BdkWithdrawTx *out);
+
+/*
+ free the strings of a withdraw result, the struct itself is owned by the caller
+ */
+void bdk_withdraw_tx_free(BdkWithdrawTx *withdraw_tx);
+
+#endif /* BDK_H */
@@ -0,0 +1,13 @@
+#!/bin/bash
+# build the library with the java feature, compile the java classes and run the JNI tests on the JVM
+
+set -e
+cd "$(dirname "$0")/.."
+
+cargo build --features "java jni-test"
+rm -rf target/java
+mkdir -p target/java
+javac -d target/java $(find java/src -name "*.java")
+rm -rf ./test_jni
+java -Djava.library.path=target/debug -cp target/java org.bdk.jni.BdkLibTest
+rm -rf ./test_jni
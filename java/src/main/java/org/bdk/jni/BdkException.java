@@ -0,0 +1,31 @@
+/*
+ * Copyright 2020 BDK Team
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+package org.bdk.jni;
+
+/** base of all exceptions thrown by the native library, kind is the name of the rust error variant */
+public class BdkException extends RuntimeException {
+    private final String kind;
+
+    public BdkException(String kind, String message) {
+        super(message);
+        this.kind = kind;
+    }
+
+    public String getKind() {
+        return kind;
+    }
+}
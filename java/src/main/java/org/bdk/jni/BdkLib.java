@@ -0,0 +1,65 @@
+/*
+ * Copyright 2020 BDK Team
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+package org.bdk.jni;
+
+import java.util.Optional;
+
+/** native functions of src/jni.rs, errors are thrown as BdkException or one of its subclasses */
+public class BdkLib {
+    static {
+        System.loadLibrary("bdk");
+    }
+
+    public native void initLogger();
+
+    public native Optional<Config> loadConfig(String workDir, int network);
+
+    public native Optional<Config> removeConfig(String workDir, int network);
+
+    public native Optional<Config> updateConfig(String workDir, int network, String[] bitcoinPeers, int bitcoinConnections, boolean bitcoinDiscovery);
+
+    /** update is a JSON object of the config fields to change, e.g. {"gap_limit": 100} */
+    public native Optional<Config> updateConfigJson(String workDir, int network, String update);
+
+    public native Optional<InitResult> initConfig(String workDir, int network, String passphrase, String pdPassphrase);
+
+    /** empty if a wallet is already configured, birth is the unix time the mnemonic was created */
+    public native Optional<Address> restoreConfig(String workDir, int network, String mnemonicWords, long birth, String passphrase, String pdPassphrase);
+
+    /** returns the wallet to pass to the other functions until stop */
+    public native long start(String workDir, int network, boolean rescan);
+
+    public native void stop(long wallet);
+
+    public native Optional<BalanceAmt> balance(long wallet);
+
+    public native Address depositAddress(long wallet);
+
+    public native WithdrawTx withdraw(long wallet, String passphrase, String address, long feePerVbyte, long amount);
+
+    /** fee withdraw would pay, without signing */
+    public native long estimateFee(long wallet, String address, long feePerVbyte, long amount);
+
+    public native HistoryEntry[] history(long wallet);
+
+    public native SyncStatus syncStatus(long wallet);
+
+    /** the connected peers */
+    public native Peer[] peers(long wallet);
+
+    public native void subscribe(long wallet, WalletListener listener);
+}
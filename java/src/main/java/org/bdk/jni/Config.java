@@ -0,0 +1,31 @@
+/*
+ * Copyright 2020 BDK Team
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+package org.bdk.jni;
+
+public class Config {
+    public final Network network;
+    public final String[] bitcoinPeers;
+    public final int bitcoinConnections;
+    public final boolean bitcoinDiscovery;
+
+    public Config(int networkEnumOrdinal, String[] bitcoinPeers, int bitcoinConnections, boolean bitcoinDiscovery) {
+        this.network = Network.values()[networkEnumOrdinal];
+        this.bitcoinPeers = bitcoinPeers;
+        this.bitcoinConnections = bitcoinConnections;
+        this.bitcoinDiscovery = bitcoinDiscovery;
+    }
+}
@@ -0,0 +1,42 @@
+/*
+ * Copyright 2020 BDK Team
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+package org.bdk.jni;
+
+import java.util.Optional;
+
+/** a wallet transaction, amounts in satoshis */
+public class HistoryEntry {
+    public final String txid;
+    /** block, height and time are empty until the transaction is confirmed */
+    public final Optional<String> blockHash;
+    public final Optional<Long> height;
+    public final Optional<Long> time;
+    public final long received;
+    public final long sent;
+    /** known only if the wallet funded the transaction */
+    public final Optional<Long> fee;
+
+    public HistoryEntry(String txid, Optional<String> blockHash, Optional<Long> height, Optional<Long> time, long received, long sent, Optional<Long> fee) {
+        this.txid = txid;
+        this.blockHash = blockHash;
+        this.height = height;
+        this.time = time;
+        this.received = received;
+        this.sent = sent;
+        this.fee = fee;
+    }
+}
@@ -0,0 +1,38 @@
+/*
+ * Copyright 2020 BDK Team
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+package org.bdk.jni;
+
+/** called on a background thread of the native library until the wallet is stopped */
+public interface WalletListener {
+    void onNewTip(String blockHash, long height);
+
+    void onBlockConnected(String blockHash, long height);
+
+    void onBlockDisconnected(String blockHash);
+
+    void onIncomingTx(String txid, long amount);
+
+    void onOutgoingTxConfirmed(String txid, long height);
+
+    void onBalanceChanged(long balance, long confirmed);
+
+    void onPeerConnected(String address);
+
+    void onPeerDisconnected(String address);
+
+    void onSyncFinished(long height);
+}
@@ -0,0 +1,154 @@
+/*
+ * Copyright 2020 BDK Team
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+package org.bdk.jni;
+
+import java.util.Optional;
+
+/** exercises the JNI functions on an offline regtest wallet, run with java/run-tests.sh */
+public class BdkLibTest {
+    private static final String WORK_DIR = "./test_jni";
+    private static final int REGTEST = Network.REGTEST.ordinal();
+    private static final String PASSPHRASE = "correct horse battery staple";
+    // p2wsh of OP_VERIFY
+    private static final String BURN = "bcrt1qme73kus6rcrr9d70qnkl2qev3m8l48u6ppyjz54eymc6tfl8vhtsy3gtc7";
+
+    private static int failures = 0;
+
+    interface Call {
+        void run();
+    }
+
+    private static void expectThrows(String what, Class<? extends BdkException> expected, String kind, Call call) {
+        try {
+            call.run();
+            fail(what + ": nothing thrown");
+        } catch (BdkException e) {
+            if (expected.isInstance(e) && e.getKind().equals(kind) && e.getMessage() != null) {
+                System.out.println("ok " + what);
+            } else {
+                fail(what + ": got " + e.getClass().getSimpleName() + " " + e.getKind() + " " + e.getMessage());
+            }
+        }
+    }
+
+    private static void expect(String what, boolean condition) {
+        if (condition) {
+            System.out.println("ok " + what);
+        } else {
+            fail(what);
+        }
+    }
+
+    private static void fail(String what) {
+        System.err.println("FAIL " + what);
+        failures++;
+    }
+
+    // counts new tips, throws at every hundredth
+    static class CountingListener implements WalletListener {
+        int tips = 0;
+
+        public void onNewTip(String blockHash, long height) {
+            tips++;
+            if (height % 100 == 99) {
+                throw new IllegalStateException("listener failure at " + height);
+            }
+        }
+
+        public void onBlockConnected(String blockHash, long height) {}
+
+        public void onBlockDisconnected(String blockHash) {}
+
+        public void onIncomingTx(String txid, long amount) {}
+
+        public void onOutgoingTxConfirmed(String txid, long height) {}
+
+        public void onBalanceChanged(long balance, long confirmed) {}
+
+        public void onPeerConnected(String address) {}
+
+        public void onPeerDisconnected(String address) {}
+
+        public void onSyncFinished(long height) {}
+    }
+
+    public static void main(String[] args) {
+        BdkLib lib = new BdkLib();
+        lib.initLogger();
+        expectThrows("second logger init", BdkException.class, "Unsupported", lib::initLogger);
+
+        // the JVM survives a panic, the calls below still run
+        expectThrows("panic", BdkException.class, "Panic", () -> new PanicTest().panic("test panic"));
+
+        // more events than local references fit a frame, exceptions of the listener do not stop delivery
+        CountingListener listener = new CountingListener();
+        new ListenerTest().replay(listener, 5000);
+        expect("listener called for every event", listener.tips == 5000);
+
+        expectThrows("short passphrase", PassphraseTooShortException.class, "PassphraseTooShort",
+                () -> lib.initConfig(WORK_DIR, REGTEST, "short", null));
+        expectThrows("invalid network", BdkException.class, "Unsupported",
+                () -> lib.initConfig(WORK_DIR, 7, PASSPHRASE, null));
+        expectThrows("null work dir", BdkException.class, "Unsupported",
+                () -> lib.initConfig(null, REGTEST, PASSPHRASE, null));
+        expectThrows("balance before start", NotStartedException.class, "NotStarted", () -> lib.balance(0));
+        expectThrows("bad peer address", BdkException.class, "AddrParse",
+                () -> lib.updateConfig(WORK_DIR, REGTEST, new String[]{"not a peer"}, 1, false));
+
+        Optional<InitResult> init = lib.initConfig(WORK_DIR, REGTEST, PASSPHRASE, null);
+        expect("init", init.isPresent());
+        expect("init again is empty", !lib.initConfig(WORK_DIR, REGTEST, PASSPHRASE, null).isPresent());
+        expect("load config", lib.loadConfig(WORK_DIR, REGTEST).get().network == Network.REGTEST);
+        expect("update config", lib.updateConfigJson(WORK_DIR, REGTEST, "{\"gap_limit\": 100}").isPresent());
+        expectThrows("refused sync mode", BdkException.class, "Unsupported",
+                () -> lib.updateConfigJson(WORK_DIR, REGTEST, "{\"sync_mode\": \"headers\"}"));
+        expectThrows("refused proxy", BdkException.class, "Unsupported",
+                () -> lib.updateConfigJson(WORK_DIR, REGTEST, "{\"proxy\": \"127.0.0.1:9050\"}"));
+
+        long wallet = lib.start(WORK_DIR, REGTEST, false);
+        expect("started", wallet != 0);
+        expect("empty balance", lib.balance(wallet).get().balance == 0);
+        expect("deposit address", lib.depositAddress(wallet).network == Network.REGTEST);
+        expectThrows("withdraw from empty wallet", InsufficientFundsException.class, "InsufficientFunds",
+                () -> lib.withdraw(wallet, PASSPHRASE, BURN, 1, 10000));
+        expectThrows("withdraw to bad address", InvalidAddressException.class, "InvalidAddress",
+                () -> lib.withdraw(wallet, PASSPHRASE, "not an address", 1, 10000));
+        expectThrows("negative amount", BdkException.class, "Unsupported",
+                () -> lib.withdraw(wallet, PASSPHRASE, BURN, 1, -1));
+        expectThrows("estimate fee of empty wallet", InsufficientFundsException.class, "InsufficientFunds",
+                () -> lib.estimateFee(wallet, BURN, 1, 10000));
+        expect("empty history", lib.history(wallet).length == 0);
+        expect("sync status", lib.syncStatus(wallet).tipHeight >= 0);
+        expect("no peers offline", lib.peers(wallet).length == 0);
+        lib.stop(wallet);
+        expectThrows("balance after stop", NotStartedException.class, "NotStarted", () -> lib.balance(wallet));
+        expectThrows("stop again", NotStartedException.class, "NotStarted", () -> lib.stop(wallet));
+
+        expect("remove config", lib.removeConfig(WORK_DIR, REGTEST).isPresent());
+
+        expectThrows("restore invalid mnemonic", BdkException.class, "Wallet",
+                () -> lib.restoreConfig(WORK_DIR, REGTEST, "not a mnemonic", 0, PASSPHRASE, null));
+        Optional<Address> restored = lib.restoreConfig(WORK_DIR, REGTEST, init.get().mnemonicWords, 0, PASSPHRASE, null);
+        expect("restore", restored.isPresent() && restored.get().address.equals(init.get().depositAddress.address));
+        expect("restore again is empty",
+                !lib.restoreConfig(WORK_DIR, REGTEST, init.get().mnemonicWords, 0, PASSPHRASE, null).isPresent());
+        expect("remove restored config", lib.removeConfig(WORK_DIR, REGTEST).isPresent());
+
+        System.out.println(failures + " failures");
+        System.exit(failures == 0 ? 0 : 1);
+    }
+}
@@ -0,0 +1,27 @@
+/*
+ * Copyright 2020 BDK Team
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+package org.bdk.jni;
+
+/** native function that calls a listener like a subscribed wallet, built with the jni-test feature */
+public class ListenerTest {
+    static {
+        System.loadLibrary("bdk");
+    }
+
+    /** calls onNewTip count times on a native thread, returns once all calls are done */
+    public native void replay(WalletListener listener, int count);
+}
@@ -15,36 +15,41 @@
  * limitations under the License.
  */
 
-use std::{fs, time};
+use std::fs;
+use std::io::{BufRead, Write};
 use std::net::SocketAddr;
 use std::path::{Path, PathBuf};
-use std::sync::{Arc, Mutex, RwLock};
+use std::sync::{Arc, mpsc, Mutex, RwLock};
 
 use bitcoin::{Address, BitcoinHash, Network};
 use bitcoin::hashes::core::str::FromStr;
 use bitcoin::util::bip32::ExtendedPubKey;
 use bitcoin_hashes::sha256d;
 use bitcoin_wallet::account::MasterAccount;
-use futures::{executor::ThreadPoolBuilder};
-use futures_timer::Delay;
-use log::{info, warn};
-use log::{debug, error};
+use bitcoin_wallet::mnemonic::Mnemonic;
+use futures::executor::{ThreadPool, ThreadPoolBuilder};
+use log::{error, info, warn};
 use murmel::chaindb::ChainDB;
-use once_cell::sync::Lazy;
 
 use crate::{config, db};
-use crate::config::Config;
+use crate::backup::Backup;
+use crate::config::{Backend, Config, ConfigUpdate, FeePolicy, SyncMode};
+use crate::crypt::DbSecret;
 use crate::db::DB;
 use crate::error::Error;
+use crate::event::WalletEvent;
+use crate::export;
+use crate::export::ExportFormat;
+use crate::labels;
+use crate::labels::LabelKind;
 use crate::p2p_bitcoin::{ChainDBTrunk, P2PBitcoin};
-use crate::store::{ContentStore, SharedContentStore};
+use crate::storage::{IssuedAddress, SharedStorage};
+use crate::store::{CoinEntry, ContentStore, HistoryEntry, SharedContentStore, VerifyReport};
 use crate::trunk::Trunk;
 use crate::wallet::{KEY_LOOK_AHEAD, Wallet};
 
 const CONFIG_FILE_NAME: &str = "bdk.cfg";
 
-static CONTENT_STORE: Lazy<Arc<RwLock<Option<SharedContentStore>>>> = Lazy::new(|| Arc::new(RwLock::new(None::<SharedContentStore>)));
-
 // load config
 
 pub fn load_config(work_dir: PathBuf, network: Network) -> Result<Config, Error> {
@@ -70,15 +75,15 @@ pub fn remove_config(work_dir: PathBuf, network: Network) -> Result<Config, Erro
 
 // update config
 
-pub fn update_config(work_dir: PathBuf, network: Network, bitcoin_peers: Vec<SocketAddr>,
-                     bitcoin_connections: usize, bitcoin_discovery: bool) -> Result<Config, Error> {
+/// change the fields set in update, returns the saved config
+pub fn update_config(work_dir: PathBuf, network: Network, update: ConfigUpdate) -> Result<Config, Error> {
     let mut config_path = PathBuf::from(work_dir);
     config_path.push(network.to_string());
     let mut file_path = config_path.clone();
     file_path.push(CONFIG_FILE_NAME);
 
     let config = config::load(&file_path)?;
-    let updated_config = config.update(bitcoin_peers, bitcoin_connections, bitcoin_discovery);
+    let updated_config = config.update(update)?;
     config::save(&config_path, &file_path, &updated_config)?;
     Ok(updated_config)
 }
@@ -100,158 +105,188 @@ impl InitResult {
 }
 
 pub fn init_config(work_dir: PathBuf, network: Network, passphrase: &str, pd_passphrase: Option<&str>) -> Result<Option<InitResult>, Error> {
-    let mut config_path = PathBuf::from(work_dir);
-    config_path.push(network.to_string());
-    fs::create_dir_all(&config_path).expect(format!("unable to create config_path: {}", &config_path.to_str().unwrap()).as_str());
-
-    let mut file_path = config_path.clone();
-    file_path.push(CONFIG_FILE_NAME);
+    let (config_path, file_path) = wallet_paths(work_dir, network)?;
 
     if let Ok(_config) = config::load(&file_path) {
         // do not init if a config already exists, return none
         Ok(Option::None)
     } else {
         // create new wallet
-        let (mnemonic_words, deposit_address, wallet) = Wallet::new(network, passphrase, pd_passphrase);
+        let (mnemonic_words, deposit_address, wallet) = Wallet::new(network, passphrase, pd_passphrase)?;
         let mnemonic_words = mnemonic_words.to_string();
-        let deposit_address = deposit_address;
+        save_wallet(&config_path, &file_path, network, &wallet)?;
 
-        let encryptedwalletkey = hex::encode(wallet.encrypted().as_slice());
-        let keyroot = wallet.master_public().to_string();
-        let lookahead = KEY_LOOK_AHEAD;
-        let birth = wallet.birth();
+        Ok(Option::from(InitResult::new(mnemonic_words, deposit_address)))
+    }
+}
 
-        // init database
-        db::init(&config_path, &wallet.coins, &wallet.master);
+/// restore a wallet from its mnemonic words, blocks after birth (unix time) are scanned for its coins.
+/// Returns the first deposit address, or None if a config already exists.
+pub fn restore_config(work_dir: PathBuf, network: Network, mnemonic_words: &str, birth: u64, passphrase: &str, pd_passphrase: Option<&str>) -> Result<Option<Address>, Error> {
+    let (config_path, file_path) = wallet_paths(work_dir, network)?;
 
-        // save config
-        let config = Config::new(encryptedwalletkey.as_str(),
-                                 keyroot.as_str(), lookahead, birth, network);
-        config::save(&config_path, &file_path, &config)?;
+    if let Ok(_config) = config::load(&file_path) {
+        Ok(Option::None)
+    } else {
+        let mnemonic = Mnemonic::from_str(mnemonic_words)?;
+        let (deposit_address, wallet) = Wallet::restore(network, &mnemonic, birth, passphrase, pd_passphrase)?;
+        save_wallet(&config_path, &file_path, network, &wallet)?;
 
-        Ok(Option::from(InitResult::new(mnemonic_words, deposit_address)))
+        Ok(Option::from(deposit_address))
     }
 }
 
-pub fn start(work_dir: PathBuf, network: Network, rescan: bool) -> Result<(), Error> {
-    let p2p_bitcoin;
-    let content_store;
+// create the config directory, return it and the config file path
+fn wallet_paths(work_dir: PathBuf, network: Network) -> Result<(PathBuf, PathBuf), Error> {
+    let mut config_path = PathBuf::from(work_dir);
+    config_path.push(network.to_string());
+    fs::create_dir_all(&config_path)?;
 
-    match CONTENT_STORE.write() {
-        Err(e) => {
-            error!("{:?}", e);
-            return Ok(());
-        }
-        Ok(mut cs) => {
-            if cs.is_some() {
-                debug!("content store exists");
-                return Ok(());
-            } else {
-                debug!("content store not initialized");
-
-                let mut config_path = PathBuf::from(work_dir);
-                config_path.push(network.to_string());
-
-                let mut config_file_path = config_path.clone();
-                config_file_path.push(CONFIG_FILE_NAME);
-
-                info!("config file path: {}", &config_file_path.to_str().unwrap());
-                let config = config::load(&config_file_path).expect("can not open config file");
-
-                let mut chain_file_path = config_path.clone();
-                chain_file_path.push("bdk.chain");
-
-                let mut chain_db = ChainDB::new(chain_file_path.as_path(), network).expect("can not open chain db");
-                chain_db.init().expect("can not initialize db");
-                let chain_db = Arc::new(RwLock::new(chain_db));
-
-                let db = open_db(&config_path);
-                let db = Arc::new(Mutex::new(db));
-
-                // get master account
-                let mut bitcoin_wallet;
-                let mut master_account = MasterAccount::from_encrypted(
-                    hex::decode(config.encryptedwalletkey).expect("encryptedwalletkey is not hex").as_slice(),
-                    ExtendedPubKey::from_str(config.keyroot.as_str()).expect("keyroot is malformed"),
-                    config.birth,
-                );
-
-                // load wallet from master account
-                {
-                    let mut db = db.lock().unwrap();
-                    let mut tx = db.transaction();
-                    let account = tx.read_account(0, 0, network, config.lookahead).expect("can not read account 0/0");
-                    master_account.add_account(account);
-                    let account = tx.read_account(0, 1, network, config.lookahead).expect("can not read account 0/1");
-                    master_account.add_account(account);
-                    let account = tx.read_account(1, 0, network, 0).expect("can not read account 1/0");
-                    master_account.add_account(account);
-                    let coins = tx.read_coins(&mut master_account).expect("can not read coins");
-                    bitcoin_wallet = Wallet::from_storage(coins, master_account);
-                }
-
-                // rescan chain if requested
-                if rescan {
-                    let chain_db = chain_db.read().unwrap();
-                    let mut after = None;
-                    for cached_header in chain_db.iter_trunk_rev(None) {
-                        if (cached_header.stored.header.time as u64) < config.birth {
-                            after = Some(cached_header.bitcoin_hash());
-                            break;
-                        }
-                    }
-                    if let Some(after) = after {
-                        info!("Re-scanning after block {}", &after);
-                        let mut db = db.lock().unwrap();
-                        let mut tx = db.transaction();
-                        tx.rescan(&after).expect("can not re-scan");
-                        tx.commit();
-                        bitcoin_wallet.rescan();
-                    }
-                }
-
-                let trunk = Arc::new(ChainDBTrunk { chaindb: chain_db.clone() });
-                info!("Wallet balance: {} satoshis {} available", bitcoin_wallet.balance(), bitcoin_wallet.available_balance(trunk.len(), |h| trunk.get_height(h)));
-
-                content_store =
-                    Arc::new(RwLock::new(
-                        ContentStore::new(db.clone(), trunk, bitcoin_wallet).expect("can not initialize content store")));
-
-                *cs = Option::Some(content_store.clone());
-
-                p2p_bitcoin = P2PBitcoin::new(config.network, config.bitcoin_connections, config.bitcoin_peers, config.bitcoin_discovery, chain_db.clone(), db.clone(),
-                                              content_store.clone(), config.birth);
-            }
+    let mut file_path = config_path.clone();
+    file_path.push(CONFIG_FILE_NAME);
+    Ok((config_path, file_path))
+}
+
+fn save_wallet(config_path: &Path, file_path: &Path, network: Network, wallet: &Wallet) -> Result<(), Error> {
+    let encryptedwalletkey = hex::encode(wallet.encrypted().as_slice());
+    let keyroot = wallet.master_public().to_string();
+    let lookahead = KEY_LOOK_AHEAD;
+    let birth = wallet.birth();
+
+    // init database
+    db::init(config_path, &wallet.coins, &wallet.master)?;
+
+    // save config
+    let config = Config::new(encryptedwalletkey.as_str(),
+                             keyroot.as_str(), lookahead, birth, network);
+    config::save(config_path, file_path, &config)
+}
+
+/// a running wallet, returned by start
+pub struct WalletHandle {
+    content_store: SharedContentStore,
+    db: SharedStorage,
+    // directory of the config file
+    config_path: PathBuf,
+    p2p_bitcoin: P2PBitcoin,
+    fee_policy: FeePolicy,
+    // keeps the p2p tasks running
+    thread_pool: ThreadPool,
+}
+
+/// start the wallet in background threads, returns without waiting for connections
+pub fn start(work_dir: PathBuf, network: Network, rescan: bool) -> Result<WalletHandle, Error> {
+    start_with_db_secret(work_dir, network, rescan, None)
+}
+
+/// start a wallet with an encrypted database, see encrypt_db
+pub fn start_encrypted(work_dir: PathBuf, network: Network, rescan: bool, db_secret: &DbSecret) -> Result<WalletHandle, Error> {
+    start_with_db_secret(work_dir, network, rescan, Some(db_secret))
+}
+
+fn start_with_db_secret(work_dir: PathBuf, network: Network, rescan: bool, db_secret: Option<&DbSecret>) -> Result<WalletHandle, Error> {
+    let mut config_path = PathBuf::from(&work_dir);
+    config_path.push(network.to_string());
+
+    let mut db = open_db(&config_path)?;
+    db.migrate()?;
+    match db_secret {
+        Some(db_secret) => db.unlock(db_secret)?,
+        None => if db.is_encrypted()? {
+            return Err(Error::DatabaseLocked);
         }
     }
+    start_with_storage(work_dir, network, rescan, Arc::new(Mutex::new(db)))
+}
+
+/// start a wallet that persists to the given storage instead of the database in the work dir,
+/// the storage must hold the accounts and coins of the wallet, see storage::init
+pub fn start_with_storage(work_dir: PathBuf, network: Network, rescan: bool, db: SharedStorage) -> Result<WalletHandle, Error> {
+    let mut config_path = PathBuf::from(work_dir);
+    config_path.push(network.to_string());
+
+    let mut config_file_path = config_path.clone();
+    config_file_path.push(CONFIG_FILE_NAME);
+
+    info!("config file path: {}", config_file_path.display());
+    let config = config::load(&config_file_path)?;
+    match config.backend {
+        // the only backend yet, it is started below
+        Backend::P2P => {}
+    }
+    if config.sync_mode != SyncMode::Full {
+        return Err(Error::Unsupported("only full sync mode is supported"));
+    }
+    if config.proxy.is_some() {
+        // refuse rather than connect without the proxy the user asked for
+        return Err(Error::Unsupported("proxy is not supported"));
+    }
+    let lookahead = config.gap_limit.unwrap_or(config.lookahead);
+
+    let mut chain_file_path = config_path.clone();
+    chain_file_path.push("bdk.chain");
+
+    let mut chain_db = ChainDB::new(chain_file_path.as_path(), network)?;
+    chain_db.init()?;
+    let chain_db = Arc::new(RwLock::new(chain_db));
 
-    let mut thread_pool = ThreadPoolBuilder::new().name_prefix("futures ").create().expect("can not start thread pool");
-    p2p_bitcoin.start(&mut thread_pool);
-    thread_pool.run(check_stopped(content_store));
+    // get master account
+    let mut bitcoin_wallet;
+    let mut master_account = MasterAccount::from_encrypted(
+        hex::decode(config.encryptedwalletkey).map_err(|_| Error::Corrupted("encryptedwalletkey is not hex"))?.as_slice(),
+        ExtendedPubKey::from_str(config.keyroot.as_str()).map_err(|_| Error::Corrupted("keyroot is malformed"))?,
+        config.birth,
+    );
 
+    // load wallet from master account
     {
-        let mut cs = CONTENT_STORE.write().unwrap();
-        *cs = Option::None;
-        debug!("content store set to None");
-        p2p_bitcoin.shutdown()
+        let mut db = db.lock().unwrap();
+        let mut tx = db.transaction()?;
+        let account = tx.read_account(0, 0, network, lookahead)?;
+        master_account.add_account(account);
+        let account = tx.read_account(0, 1, network, lookahead)?;
+        master_account.add_account(account);
+        let account = tx.read_account(1, 0, network, 0)?;
+        master_account.add_account(account);
+        let coins = tx.read_coins(&mut master_account)?;
+        bitcoin_wallet = Wallet::from_storage(coins, master_account)?;
     }
-    Ok(())
-}
 
-async fn check_stopped(store: Arc<RwLock<ContentStore>>) -> () {
-    info!("start check_stopped");
-    let mut stopped = false;
-    while !stopped {
-        Delay::new(time::Duration::from_millis(100)).await.unwrap();
-        stopped = store.read().unwrap().get_stopped();
+    // rescan chain if requested
+    if rescan {
+        let chain_db = chain_db.read().unwrap();
+        let mut after = None;
+        for cached_header in chain_db.iter_trunk_rev(None) {
+            if (cached_header.stored.header.time as u64) < config.birth {
+                after = Some(cached_header.bitcoin_hash());
+                break;
+            }
+        }
+        if let Some(after) = after {
+            info!("Re-scanning after block {}", &after);
+            let mut db = db.lock().unwrap();
+            let mut tx = db.transaction()?;
+            tx.rescan(&after)?;
+            tx.commit()?;
+            bitcoin_wallet.rescan();
+        }
     }
-    warn!("stopped");
-}
 
-pub fn stop() -> () {
-    info!("stopping");
-    let store = CONTENT_STORE.read().unwrap().as_ref().unwrap().clone();
-    store.write().unwrap().set_stopped(true);
+    let trunk = Arc::new(ChainDBTrunk { chaindb: chain_db.clone() });
+    info!("Wallet balance: {} satoshis {} available", bitcoin_wallet.balance(), bitcoin_wallet.available_balance(trunk.len(), |h| trunk.get_height(h)));
+
+    let content_store =
+        Arc::new(RwLock::new(
+            ContentStore::new(db.clone(), trunk, bitcoin_wallet)?));
+
+    let mut p2p_bitcoin = P2PBitcoin::new(config.network, config.bitcoin_connections, config.bitcoin_peers, config.bitcoin_discovery, chain_db.clone(), db.clone(),
+                                      content_store.clone(), config.birth);
+    p2p_bitcoin.set_user_agent(config.user_agent);
+
+    let mut thread_pool = ThreadPoolBuilder::new().name_prefix("futures ").create()?;
+    p2p_bitcoin.start(&mut thread_pool)?;
+
+    Ok(WalletHandle { content_store, db, config_path, p2p_bitcoin, fee_policy: config.fee_policy, thread_pool })
 }
 
 #[derive(Debug, Clone)]
@@ -263,18 +298,6 @@ impl BalanceAmt {
     }
 }
 
-pub fn balance() -> Result<BalanceAmt, Error> {
-    let store = CONTENT_STORE.read().unwrap().as_ref().unwrap().clone();
-    let bal_vec = store.read().unwrap().balance();
-    Ok(BalanceAmt::new(bal_vec[0], bal_vec[1]))
-}
-
-pub fn deposit_addr() -> Address {
-    let store = CONTENT_STORE.read().unwrap().as_ref().unwrap().clone();
-    let addr = store.write().unwrap().deposit_address();
-    addr
-}
-
 #[derive(Debug, Clone)]
 pub struct WithdrawTx { pub txid: sha256d::Hash, pub fee: u64 }
 
@@ -284,23 +307,339 @@ impl WithdrawTx {
     }
 }
 
-pub fn withdraw(passphrase: String, address: Address, fee_per_vbyte: u64, amount: Option<u64>) -> Result<WithdrawTx, Error> {
-    let store = CONTENT_STORE.read().unwrap().as_ref().unwrap().clone();
-    let withdraw = store.write().unwrap().withdraw(passphrase, address, fee_per_vbyte, amount);
-    match withdraw {
-        Ok((t, f)) => {
-            Ok(WithdrawTx::new(t.txid(), f))
+#[derive(Debug, Clone)]
+pub struct SyncStatus { pub tip_height: u32, pub processed_height: Option<u32> }
+
+impl SyncStatus {
+    /// true if the wallet processed all blocks of the header chain
+    pub fn synced(&self) -> bool {
+        self.processed_height == Some(self.tip_height)
+    }
+}
+
+impl WalletHandle {
+    /// stop all components and flush databases, returns once everything stopped
+    pub fn stop(mut self) {
+        info!("stopping");
+        self.p2p_bitcoin.stop();
+        drop(self.thread_pool);
+        if let Err(e) = self.db.lock().unwrap().flush() {
+            error!("can not flush db: {}", e);
+        }
+        warn!("stopped");
+    }
+
+    /// receive wallet and chain events until the receiver is dropped or the wallet stopped
+    pub fn subscribe(&self) -> mpsc::Receiver<WalletEvent> {
+        self.content_store.write().unwrap().subscribe()
+    }
+
+    pub fn balance(&self) -> Result<BalanceAmt, Error> {
+        let bal_vec = self.content_store.read().unwrap().balance();
+        Ok(BalanceAmt::new(bal_vec[0], bal_vec[1]))
+    }
+
+    /// the current deposit address, a new one once it received funds
+    pub fn deposit_addr(&self) -> Result<Address, Error> {
+        self.content_store.write().unwrap().deposit_address()
+    }
+
+    /// a fresh deposit address, even if the current one received nothing yet
+    pub fn new_addr(&self) -> Result<Address, Error> {
+        self.content_store.write().unwrap().new_address()
+    }
+
+    /// addresses handed out to receive payments, in the order they were issued
+    pub fn issued_addresses(&self) -> Result<Vec<IssuedAddress>, Error> {
+        self.content_store.read().unwrap().issued_addresses()
+    }
+
+    pub fn withdraw(&self, passphrase: String, address: Address, fee_per_vbyte: u64, amount: Option<u64>) -> Result<WithdrawTx, Error> {
+        if let Some(max_fee_per_vbyte) = self.fee_policy.max_fee_per_vbyte {
+            if fee_per_vbyte > max_fee_per_vbyte {
+                return Err(Error::FeeAboveMaximum { fee_per_vbyte, max_fee_per_vbyte });
+            }
         }
+        let (t, f) = self.content_store.write().unwrap().withdraw(passphrase, address, fee_per_vbyte, amount)?;
+        Ok(WithdrawTx::new(t.txid(), f))
+    }
+
+    /// fee rates of the config the wallet was started with
+    pub fn fee_policy(&self) -> &FeePolicy {
+        &self.fee_policy
+    }
+
+    /// fee withdraw would pay with the same arguments, no passphrase needed
+    pub fn estimate_fee(&self, address: &Address, fee_per_vbyte: u64, amount: Option<u64>) -> Result<u64, Error> {
+        self.content_store.read().unwrap().estimate_fee(address, fee_per_vbyte, amount)
+    }
+
+    /// wallet transactions, confirmed in order of height, then unconfirmed
+    pub fn history(&self) -> Result<Vec<HistoryEntry>, Error> {
+        self.content_store.read().unwrap().history()
+    }
+
+    /// write the history with block time in [from, until), unix seconds, for accounting
+    pub fn export_history<W: Write>(&self, writer: &mut W, format: ExportFormat, from: Option<u64>, until: Option<u64>) -> Result<(), Error> {
+        let history = self.history()?;
+        export::write(writer, format, &export::rows(&history, from, until))
+    }
+
+    /// confirmed coins with their labels
+    pub fn coins(&self) -> Result<Vec<CoinEntry>, Error> {
+        self.content_store.read().unwrap().coins()
+    }
+
+    /// label a transaction, address, output or xpub, an empty label removes it
+    pub fn set_label(&self, kind: LabelKind, reference: &str, label: &str) -> Result<(), Error> {
+        self.content_store.write().unwrap().set_label(kind, reference, label)
+    }
+
+    /// import BIP329 labels, replacing those of the same references. Returns the number imported.
+    pub fn import_labels<R: BufRead>(&self, reader: R) -> Result<usize, Error> {
+        let labels = labels::read(reader)?;
+        self.content_store.write().unwrap().import_labels(&labels)
+    }
+
+    /// write all labels as BIP329
+    pub fn export_labels<W: Write>(&self, writer: &mut W) -> Result<(), Error> {
+        let labels = self.content_store.read().unwrap().labels()?;
+        labels::write(writer, &labels)
+    }
+
+    /// check stored proofs, coins and confirmations against the trunk, repair differences if asked
+    pub fn verify(&self, repair: bool) -> Result<VerifyReport, Error> {
+        self.content_store.write().unwrap().verify(repair)
+    }
+
+    pub fn sync_status(&self) -> Result<SyncStatus, Error> {
+        let content_store = self.content_store.read().unwrap();
+        Ok(SyncStatus { tip_height: content_store.tip_height(), processed_height: content_store.processed_height()? })
+    }
+
+    /// addresses of connected bitcoin peers
+    pub fn peers(&self) -> Vec<SocketAddr> {
+        self.p2p_bitcoin.connected_peers().into_iter().filter_map(|a| a).collect()
+    }
+
+    /// config and database of the wallet but peers, encrypted with the passphrase, see import_backup.
+    /// The passphrase protects the backup only, it may differ from the wallet passphrase.
+    pub fn export_backup(&self, passphrase: &str) -> Result<Vec<u8>, Error> {
+        let mut file_path = self.config_path.clone();
+        file_path.push(CONFIG_FILE_NAME);
+        let config = fs::read_to_string(&file_path)?;
+        let mut db = self.db.lock().unwrap();
+        let mut tx = db.transaction()?;
+        let snapshot = tx.read_snapshot()?;
+        tx.rollback()?;
+        Backup { config, snapshot }.seal(passphrase)
+    }
+}
+
+/// create the wallet of a backup made with export_backup, returns its config or None if a config already exists.
+/// The database is created plain, see encrypt_db.
+pub fn import_backup(work_dir: PathBuf, network: Network, passphrase: &str, backup: &[u8]) -> Result<Option<Config>, Error> {
+    let (config_path, file_path) = wallet_paths(work_dir, network)?;
+
+    if let Ok(_config) = config::load(&file_path) {
+        return Ok(Option::None);
+    }
+    let backup = Backup::open(passphrase, backup)?;
+    let config: Config = toml::from_str(backup.config.as_str())?;
+    if config.network != network {
+        return Err(Error::NetworkMismatch { expected: network, found: config.network });
+    }
+
+    // leave nothing behind that was created before a failure
+    let db_path = db_path(&config_path);
+    let created_db = !db_path.exists();
+    let created_config = !file_path.exists();
+    let restored = restore_db(&db_path, &backup).and_then(|_| {
+        fs::write(&file_path, backup.config.as_bytes())?;
+        // migrated if the backup is of an older version
+        config::load(&file_path)
+    });
+    match restored {
+        Ok(config) => Ok(Option::from(config)),
         Err(e) => {
+            if created_db {
+                fs::remove_file(&db_path).ok();
+            }
+            if created_config {
+                fs::remove_file(&file_path).ok();
+            }
             Err(e)
         }
     }
 }
 
-fn open_db(config_path: &Path) -> DB {
-    let mut db_path = PathBuf::from(config_path);
+fn restore_db(db_path: &Path, backup: &Backup) -> Result<(), Error> {
+    let mut db = DB::new(db_path)?;
+    db.migrate()?;
+    if db.is_encrypted()? {
+        return Err(Error::Unsupported("an encrypted database without config exists"));
+    }
+    let mut tx = db.transaction()?;
+    tx.store_snapshot(&backup.snapshot)?;
+    tx.commit()
+}
+
+/// encrypt coins, transactions and accounts in the database of a stopped wallet.
+/// The secret may be the wallet passphrase or a key the app keeps.
+pub fn encrypt_db(work_dir: PathBuf, network: Network, db_secret: &DbSecret) -> Result<(), Error> {
+    let mut config_path = PathBuf::from(work_dir);
+    config_path.push(network.to_string());
+    let mut db = open_db(&config_path)?;
+    db.migrate()?;
+    db.encrypt(db_secret)
+}
+
+/// change the secret of an encrypted database of a stopped wallet
+pub fn change_db_secret(work_dir: PathBuf, network: Network, old: &DbSecret, new: &DbSecret) -> Result<(), Error> {
+    let mut config_path = PathBuf::from(work_dir);
+    config_path.push(network.to_string());
+    let mut db = open_db(&config_path)?;
+    db.migrate()?;
+    db.change_secret(old, new)
+}
+
+fn open_db(config_path: &Path) -> Result<DB, Error> {
+    DB::new(db_path(config_path).as_path())
+}
+
+fn db_path(config_path: &Path) -> PathBuf {
     const DB_FILE_NAME: &str = "bdk.db";
+    let mut db_path = PathBuf::from(config_path);
     db_path.push(DB_FILE_NAME);
-    let db = DB::new(db_path.as_path()).expect(format!("Can't open DB {}", db_path.to_str().expect("can't get db_path")).as_str());
-    db
-}
\ No newline at end of file
+    db_path
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+    use std::thread;
+    use std::time::Duration;
+
+    use bitcoin::{Address, Network};
+    use bitcoin::blockdata::opcodes::all;
+    use bitcoin::blockdata::script::Builder;
+
+    use crate::api::{encrypt_db, import_backup, init_config, remove_config, start, start_encrypted, update_config};
+    use crate::backup::Backup;
+    use crate::config::{Config, ConfigUpdate, FeePolicy, SyncMode};
+    use crate::crypt::DbSecret;
+    use crate::error::Error;
+    use crate::mocknode::MockNode;
+    use crate::storage::Snapshot;
+
+    const NEW_COINS: u64 = 5000000000;
+    const PASSPHRASE: &str = "correct horse battery staple";
+    const COINBASE_MATURITY: usize = 100;
+
+    fn wait_for<F>(what: &str, condition: F) where F: Fn() -> bool {
+        for _ in 0..600 {
+            if condition() {
+                return;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        panic!("timeout waiting for {}", what);
+    }
+
+    #[test]
+    fn sync_withdraw_reorg() {
+        let network = Network::Regtest;
+        let node = MockNode::start(network);
+        let work_dir = PathBuf::from("./test_api");
+
+        let init = init_config(work_dir.clone(), network, PASSPHRASE, None).unwrap().unwrap();
+        update_config(work_dir.clone(), network, ConfigUpdate {
+            bitcoin_peers: Some(vec!(node.address())),
+            bitcoin_connections: Some(1),
+            bitcoin_discovery: Some(false),
+            fee_policy: Some(FeePolicy { fee_per_vbyte: 1, max_fee_per_vbyte: Some(10) }),
+            ..Default::default()
+        }).unwrap();
+        // refused rather than saved, the wallet would not start with it
+        assert!(update_config(work_dir.clone(), network, ConfigUpdate { sync_mode: Some(SyncMode::Headers), ..Default::default() }).is_err());
+
+        let burn = Address::p2wsh(&Builder::new().push_opcode(all::OP_VERIFY).into_script(), network);
+        node.mine(&init.deposit_address);
+        for _ in 0..COINBASE_MATURITY {
+            node.mine(&burn);
+        }
+
+        let wallet = start(work_dir.clone(), network, false).unwrap();
+        wait_for("initial sync", || wallet.balance().unwrap().balance == NEW_COINS);
+
+        match wallet.withdraw(PASSPHRASE.to_string(), burn.clone(), 11, Some(NEW_COINS / 2)) {
+            Err(Error::FeeAboveMaximum { fee_per_vbyte: 11, max_fee_per_vbyte: 10 }) => {}
+            other => panic!("expected fee above maximum, got {:?}", other.map(|t| t.txid))
+        }
+
+        // withdraw is broadcast to the node
+        let withdraw_tx = wallet.withdraw(PASSPHRASE.to_string(), burn.clone(), 1, Some(NEW_COINS / 2)).unwrap();
+        wait_for("broadcast", || node.received().iter().any(|t| t.txid() == withdraw_tx.txid));
+        assert_eq!(wallet.balance().unwrap().balance, NEW_COINS / 2);
+
+        // new blocks are announced with headers, processed without asking for headers again
+        wait_for("sendheaders", || node.peers_send_headers());
+        let get_headers = node.get_headers_received();
+        node.mine(&init.deposit_address);
+        wait_for("confirmation", || wallet.balance().unwrap().balance == NEW_COINS + NEW_COINS / 2);
+        assert_eq!(node.get_headers_received(), get_headers);
+
+        // the block that confirmed the withdrawal and paid us is re-orged out
+        node.reorg(1, &burn);
+        wait_for("re-org", || wallet.balance().unwrap().balance < NEW_COINS + NEW_COINS / 2);
+
+        wallet.stop();
+        node.disconnect();
+        remove_config(work_dir.clone(), network).unwrap();
+        std::fs::remove_dir_all(work_dir).unwrap();
+    }
+
+    #[test]
+    fn import_backup_leaves_nothing_behind() {
+        let work_dir = PathBuf::from("./test_import_backup");
+        let backup = |network| Backup {
+            config: toml::to_string(&Config::new("00", "tpub", 10, 0, network)).unwrap(),
+            snapshot: Snapshot::default(),
+        }.seal(PASSPHRASE).unwrap();
+
+        match import_backup(work_dir.clone(), Network::Regtest, PASSPHRASE, backup(Network::Testnet).as_slice()) {
+            Err(Error::NetworkMismatch { expected: Network::Regtest, found: Network::Testnet }) => {}
+            other => panic!("expected network mismatch, got {:?}", other)
+        }
+
+        // the config can not be written, the database created for the import is removed
+        let mut wallet_dir = work_dir.clone();
+        wallet_dir.push(Network::Regtest.to_string());
+        let mut config_file = wallet_dir.clone();
+        config_file.push("bdk.cfg");
+        std::fs::create_dir_all(&config_file).unwrap();
+        assert!(import_backup(work_dir.clone(), Network::Regtest, PASSPHRASE, backup(Network::Regtest).as_slice()).is_err());
+        let mut db_file = wallet_dir.clone();
+        db_file.push("bdk.db");
+        assert!(!db_file.exists());
+        std::fs::remove_dir_all(work_dir).unwrap();
+    }
+
+    #[test]
+    fn encrypted_database_needs_its_secret() {
+        let network = Network::Regtest;
+        let work_dir = PathBuf::from("./test_db_locked");
+        init_config(work_dir.clone(), network, PASSPHRASE, None).unwrap().unwrap();
+        encrypt_db(work_dir.clone(), network, &DbSecret::Key([7u8; 32])).unwrap();
+
+        match start(work_dir.clone(), network, false) {
+            Err(Error::DatabaseLocked) => {}
+            other => panic!("expected database locked, got {:?}", other.err())
+        }
+        match start_encrypted(work_dir.clone(), network, false, &DbSecret::Key([8u8; 32])) {
+            Err(Error::WrongPassphrase) => {}
+            other => panic!("expected wrong passphrase, got {:?}", other.err())
+        }
+        std::fs::remove_dir_all(work_dir).unwrap();
+    }
+}
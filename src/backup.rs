@@ -0,0 +1,168 @@
+/*
+ * Copyright 2020 BDK Team
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Encrypted wallet backup.
+//!
+//! A backup file is a header of magic bytes, format version and salt, followed by the sealed CBOR
+//! of the config file and a storage snapshot. The key is stretched from the backup passphrase,
+//! the header is authenticated together with the content.
+
+use crate::crypt::{Cipher, random_salt, SALT_LEN};
+use crate::error::Error;
+use crate::storage::Snapshot;
+
+const MAGIC: &[u8] = b"bdk backup";
+const BACKUP_VERSION: u8 = 1;
+const HEADER_LEN: usize = MAGIC.len() + 1 + SALT_LEN;
+
+/// what a backup restores
+#[derive(Serialize, Deserialize)]
+pub struct Backup {
+    /// content of the config file
+    pub config: String,
+    /// everything in the database but peers
+    pub snapshot: Snapshot,
+}
+
+impl Backup {
+    /// the backup file
+    pub fn seal(&self, passphrase: &str) -> Result<Vec<u8>, Error> {
+        if passphrase.len() < 8 {
+            return Err(Error::PassphraseTooShort);
+        }
+        let salt = random_salt();
+        let mut file = MAGIC.to_vec();
+        file.push(BACKUP_VERSION);
+        file.extend_from_slice(&salt);
+        let sealed = Cipher::from_passphrase(passphrase, &salt).seal_with(serde_cbor::ser::to_vec(self)?.as_slice(), file.as_slice())?;
+        file.extend(sealed);
+        Ok(file)
+    }
+
+    /// read a backup file, fails with WrongPassphrase if the passphrase does not fit or the file was modified
+    pub fn open(passphrase: &str, file: &[u8]) -> Result<Backup, Error> {
+        if file.len() < HEADER_LEN || &file[..MAGIC.len()] != MAGIC {
+            return Err(Error::Corrupted("not a backup file"));
+        }
+        if file[MAGIC.len()] > BACKUP_VERSION {
+            return Err(Error::Unsupported("backup is of a newer version"));
+        }
+        let (header, sealed) = file.split_at(HEADER_LEN);
+        let plain = Cipher::from_passphrase(passphrase, &header[MAGIC.len() + 1..]).open_with(sealed, header)
+            .map_err(|_| Error::WrongPassphrase)?;
+        Ok(serde_cbor::from_slice(plain.as_slice())?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+    use std::net::SocketAddr;
+    use std::str::FromStr;
+    use std::sync::{Arc, Mutex};
+
+    use bitcoin::{OutPoint, Script, Transaction, TxIn, TxOut};
+    use bitcoin_hashes::sha256d;
+    use bitcoin_hashes::hex::FromHex;
+
+    use crate::db::DB;
+    use crate::error::Error;
+    use crate::storage::{Storage, WalletTransaction};
+
+    use super::Backup;
+
+    const PASSPHRASE: &str = "backup passphrase";
+
+    #[test]
+    fn backup_round_trip() {
+        let tx = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec!(TxIn { previous_output: OutPoint::default(), script_sig: Script::new(), sequence: 0xffffffff, witness: vec!() }),
+            output: vec!(TxOut { value: 4242, script_pubkey: Script::new() }),
+        };
+        let genesis = sha256d::Hash::from_hex("0f9188f13cb7b2c71f2a335e3a4fc328bf5beb436012afca590b1a11466e2206").unwrap();
+        let peer = SocketAddr::from_str("127.0.0.1:18444").unwrap();
+
+        let mut db = DB::memory().unwrap();
+        db.migrate().unwrap();
+        let mut dbtx = db.transaction().unwrap();
+        dbtx.store_txout(&tx, None).unwrap();
+        dbtx.store_processed(&genesis).unwrap();
+        dbtx.store_address("regtest", &peer, 1, 2, 0).unwrap();
+        let file = Backup { config: "network = \"regtest\"\n".to_string(), snapshot: dbtx.read_snapshot().unwrap() }.seal(PASSPHRASE).unwrap();
+        dbtx.commit().unwrap();
+
+        match Backup::open("wrong passphrase", file.as_slice()) {
+            Err(Error::WrongPassphrase) => {}
+            other => panic!("expected wrong passphrase, got {:?}", other.map(|b| b.config))
+        }
+        let mut modified = file.clone();
+        modified[10] = 0;
+        assert!(Backup::open(PASSPHRASE, modified.as_slice()).is_err());
+
+        let backup = Backup::open(PASSPHRASE, file.as_slice()).unwrap();
+        assert_eq!(backup.config, "network = \"regtest\"\n");
+        let mut restored = DB::memory().unwrap();
+        restored.migrate().unwrap();
+        let mut dbtx = restored.transaction().unwrap();
+        dbtx.store_snapshot(&backup.snapshot).unwrap();
+        assert_eq!(dbtx.read_unconfirmed().unwrap()[0].0, tx);
+        assert_eq!(dbtx.read_processed().unwrap(), Some(genesis));
+        // peers are not backed up
+        assert_eq!(dbtx.get_an_address("regtest", Arc::new(Mutex::new(HashSet::new()))).unwrap(), None);
+    }
+
+    #[test]
+    fn spent_withdrawal_keeps_its_block() {
+        let withdrawal = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec!(TxIn { previous_output: OutPoint::default(), script_sig: Script::new(), sequence: 0xffffffff, witness: vec!() }),
+            output: vec!(TxOut { value: 4242, script_pubkey: Script::new() }),
+        };
+        let confirming = sha256d::Hash::from_hex("11".repeat(32).as_str()).unwrap();
+        let tip = sha256d::Hash::from_hex("22".repeat(32).as_str()).unwrap();
+
+        // confirmed below the processed tip and no coin left to prove it
+        let mut db = DB::memory().unwrap();
+        db.migrate().unwrap();
+        let mut dbtx = db.transaction().unwrap();
+        dbtx.store_txout(&withdrawal, None).unwrap();
+        dbtx.confirm(&withdrawal.txid(), Some(&confirming)).unwrap();
+        dbtx.store_transaction(&WalletTransaction {
+            block_hash: Some(confirming),
+            height: Some(1),
+            position: Some(1),
+            ..WalletTransaction::unconfirmed(withdrawal.clone())
+        }).unwrap();
+        dbtx.store_processed(&tip).unwrap();
+        let file = Backup { config: String::new(), snapshot: dbtx.read_snapshot().unwrap() }.seal(PASSPHRASE).unwrap();
+        dbtx.commit().unwrap();
+
+        let backup = Backup::open(PASSPHRASE, file.as_slice()).unwrap();
+        let mut restored = DB::memory().unwrap();
+        restored.migrate().unwrap();
+        let mut dbtx = restored.transaction().unwrap();
+        dbtx.store_snapshot(&backup.snapshot).unwrap();
+        assert!(dbtx.read_unconfirmed().unwrap().is_empty());
+        // a re-org of the tip does not touch it, a re-org of its block does
+        assert_eq!(dbtx.unconfirm(&tip).unwrap(), 0);
+        assert!(dbtx.read_unconfirmed().unwrap().is_empty());
+        assert_eq!(dbtx.unconfirm(&confirming).unwrap(), 1);
+        assert_eq!(dbtx.read_unconfirmed().unwrap()[0].0, withdrawal);
+    }
+}
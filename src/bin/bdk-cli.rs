@@ -0,0 +1,465 @@
+/*
+ * Copyright 2020 BDK Team
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! bdk-cli, one-shot wallet commands for scripts.
+//! Passphrases are read from the first line of stdin or from a file given with --passphrase-file.
+//! Results go to stdout, errors to stderr, and the exit code tells what went wrong.
+
+use std::fs;
+use std::io::{self, BufRead};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::process;
+use std::str::FromStr;
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+use bitcoin::{Address, Network};
+use clap::{App, AppSettings, Arg, ArgMatches, ErrorKind, SubCommand};
+
+use bdk::api;
+use bdk::api::WalletHandle;
+use bdk::config::{ConfigUpdate, FeePolicy, SyncMode};
+use bdk::error::Error;
+use bdk::event::WalletEvent;
+use bdk::export;
+use bdk::export::ExportFormat;
+use bdk::labels::LabelKind;
+
+// exit codes
+const EXIT_ERROR: i32 = 1;
+const EXIT_USAGE: i32 = 2;
+const EXIT_NO_WALLET: i32 = 3;
+const EXIT_WRONG_PASSPHRASE: i32 = 4;
+const EXIT_INSUFFICIENT_FUNDS: i32 = 5;
+const EXIT_INVALID_ADDRESS: i32 = 6;
+const EXIT_DATABASE_LOCKED: i32 = 7;
+
+// how long the wallet has to stay at the tip before sync --until-tip returns
+const SYNC_SETTLE: Duration = Duration::from_secs(5);
+
+fn cli<'a, 'b>() -> App<'a, 'b> {
+    let passphrase_file = Arg::with_name("passphrase-file")
+        .long("passphrase-file")
+        .value_name("FILE")
+        .help("read the passphrase from the first line of FILE instead of stdin")
+        .takes_value(true);
+
+    App::new("bdk-cli")
+        .about("bdk wallet command line")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .arg(Arg::with_name("data")
+            .short("d")
+            .long("data")
+            .value_name("DIRECTORY")
+            .help("data directory")
+            .takes_value(true)
+            .global(true)
+            .default_value("."))
+        .arg(Arg::with_name("network")
+            .short("n")
+            .long("net")
+            .value_name("NETWORK")
+            .help("bitcoin network")
+            .takes_value(true)
+            .global(true)
+            .default_value("testnet")
+            .possible_values(&["bitcoin", "regtest", "testnet"]))
+        .subcommand(SubCommand::with_name("init")
+            .about("create a new wallet, prints the mnemonic words and the first deposit address")
+            .arg(passphrase_file.clone()))
+        .subcommand(SubCommand::with_name("restore")
+            .about("re-create a wallet from its mnemonic words, prints the first deposit address")
+            .arg(passphrase_file.clone())
+            .arg(Arg::with_name("birth")
+                .long("birth")
+                .value_name("UNIXTIME")
+                .help("time the wallet was created, blocks after it are scanned")
+                .takes_value(true)
+                .default_value("0"))
+            .arg(Arg::with_name("words")
+                .help("mnemonic words")
+                .required(true)
+                .multiple(true)))
+        .subcommand(SubCommand::with_name("sync")
+            .about("download blocks, prints wallet events")
+            .arg(Arg::with_name("until-tip")
+                .long("until-tip")
+                .help("exit once all blocks up to the tip of the header chain are processed")))
+        .subcommand(SubCommand::with_name("balance")
+            .about("prints balance and confirmed balance in satoshis"))
+        .subcommand(SubCommand::with_name("receive")
+            .about("prints the deposit address, a new one once it received funds")
+            .arg(Arg::with_name("new")
+                .long("new")
+                .help("issue a new address even if the current one received nothing yet")))
+        .subcommand(SubCommand::with_name("addresses")
+            .about("prints issued deposit addresses with derivation path, issue time and height first funded"))
+        .subcommand(SubCommand::with_name("send")
+            .about("withdraw to an address, prints txid and fee")
+            .arg(passphrase_file.clone())
+            .arg(Arg::with_name("fee")
+                .long("fee")
+                .value_name("SATS_PER_VBYTE")
+                .help("fee rate, the configured fee rate if omitted")
+                .takes_value(true))
+            .arg(Arg::with_name("address")
+                .help("destination address")
+                .required(true))
+            .arg(Arg::with_name("amount")
+                .help("amount in satoshis, everything if omitted")))
+        .subcommand(SubCommand::with_name("history")
+            .about("prints wallet transactions")
+            .arg(Arg::with_name("json")
+                .long("json")
+                .help("print as JSON")))
+        .subcommand(SubCommand::with_name("export")
+            .about("prints wallet transactions with date, running balance and labels for accounting")
+            .arg(Arg::with_name("format")
+                .long("format")
+                .possible_values(&["csv", "json"])
+                .default_value("csv"))
+            .arg(Arg::with_name("from")
+                .long("from")
+                .value_name("YYYY-MM-DD")
+                .help("first day, UTC")
+                .takes_value(true))
+            .arg(Arg::with_name("until")
+                .long("until")
+                .value_name("YYYY-MM-DD")
+                .help("day after the last, UTC. Unconfirmed transactions are left out if given")
+                .takes_value(true)))
+        .subcommand(SubCommand::with_name("coins")
+            .about("prints confirmed coins with value, address and label"))
+        .subcommand(SubCommand::with_name("label")
+            .about("label a transaction, address, output or xpub")
+            .arg(Arg::with_name("type")
+                .help("what is labeled")
+                .possible_values(&["tx", "addr", "output", "xpub"])
+                .required(true))
+            .arg(Arg::with_name("ref")
+                .help("txid, address, txid:vout or xpub")
+                .required(true))
+            .arg(Arg::with_name("label")
+                .help("the label, removed if omitted")))
+        .subcommand(SubCommand::with_name("labels")
+            .about("prints all labels as BIP329")
+            .arg(Arg::with_name("import")
+                .long("import")
+                .value_name("FILE")
+                .help("import BIP329 labels from FILE instead, - for stdin")
+                .takes_value(true)))
+        .subcommand(SubCommand::with_name("verify")
+            .about("checks stored proofs, coins and confirmations against the chain, prints the differences")
+            .arg(Arg::with_name("repair")
+                .long("repair")
+                .help("unwind blocks off the chain and rewrite coins and confirmations")))
+        .subcommand(SubCommand::with_name("config")
+            .about("change the wallet configuration")
+            .setting(AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(SubCommand::with_name("set")
+                .about("set a configuration value")
+                .arg(Arg::with_name("key")
+                    .required(true)
+                    .possible_values(&["peers", "connections", "discovery", "proxy", "sync-mode", "user-agent",
+                        "gap-limit", "fee-rate", "max-fee-rate"]))
+                .arg(Arg::with_name("value")
+                    .help("comma separated addresses for peers, a number for connections, on or off for discovery, \
+                           an address or off for proxy, full or headers for sync-mode, a number or default for gap-limit, \
+                           a number for fee-rate, a number or off for max-fee-rate")
+                    .required(true))))
+}
+
+fn main() {
+    let matches = match cli().get_matches_safe() {
+        Ok(matches) => matches,
+        Err(e) => match e.kind {
+            ErrorKind::HelpDisplayed | ErrorKind::VersionDisplayed => {
+                println!("{}", e.message);
+                process::exit(0);
+            }
+            _ => {
+                eprintln!("{}", e.message);
+                process::exit(EXIT_USAGE);
+            }
+        }
+    };
+    if let Err(e) = run(&matches) {
+        eprintln!("error: {}", e);
+        process::exit(exit_code(&e));
+    }
+}
+
+fn exit_code(err: &Error) -> i32 {
+    match err {
+        Error::IO(ref e) if e.kind() == io::ErrorKind::NotFound => EXIT_NO_WALLET,
+        Error::Unsupported(_) | Error::AddrParse(_) | Error::FeeAboveMaximum { .. } => EXIT_USAGE,
+        Error::WrongPassphrase | Error::PassphraseTooShort => EXIT_WRONG_PASSPHRASE,
+        Error::InsufficientFunds { .. } | Error::BelowDust { .. } => EXIT_INSUFFICIENT_FUNDS,
+        Error::InvalidAddress(_) | Error::NetworkMismatch { .. } => EXIT_INVALID_ADDRESS,
+        Error::DatabaseLocked => EXIT_DATABASE_LOCKED,
+        _ => EXIT_ERROR
+    }
+}
+
+fn run(matches: &ArgMatches) -> Result<(), Error> {
+    let work_dir = PathBuf::from(matches.value_of("data").unwrap_or("."));
+    let network = matches.value_of("network").unwrap_or("testnet").parse::<Network>()
+        .map_err(|_| Error::Unsupported("unknown network"))?;
+
+    match matches.subcommand() {
+        ("init", Some(args)) => {
+            let passphrase = read_passphrase(args)?;
+            match api::init_config(work_dir, network, passphrase.as_str(), None)? {
+                Some(init) => {
+                    println!("{}", init.mnemonic_words);
+                    println!("{}", init.deposit_address);
+                    Ok(())
+                }
+                None => Err(Error::Unsupported("wallet already exists"))
+            }
+        }
+        ("restore", Some(args)) => {
+            let words = args.values_of("words").map(|w| w.collect::<Vec<_>>().join(" ")).unwrap_or_default();
+            let birth = parse_arg::<u64>(args, "birth")?.unwrap_or(0);
+            let passphrase = read_passphrase(args)?;
+            match api::restore_config(work_dir, network, words.as_str(), birth, passphrase.as_str(), None)? {
+                Some(deposit_address) => {
+                    println!("{}", deposit_address);
+                    Ok(())
+                }
+                None => Err(Error::Unsupported("wallet already exists"))
+            }
+        }
+        ("sync", Some(args)) => {
+            let wallet = api::start(work_dir, network, false)?;
+            let result = sync(&wallet, args.is_present("until-tip"));
+            wallet.stop();
+            result
+        }
+        ("balance", Some(_)) => {
+            with_wallet(work_dir, network, |wallet| {
+                let balance = wallet.balance()?;
+                println!("{} {}", balance.balance, balance.confirmed);
+                Ok(())
+            })
+        }
+        ("receive", Some(args)) => {
+            with_wallet(work_dir, network, |wallet| {
+                if args.is_present("new") {
+                    println!("{}", wallet.new_addr()?);
+                } else {
+                    println!("{}", wallet.deposit_addr()?);
+                }
+                Ok(())
+            })
+        }
+        ("addresses", Some(_)) => {
+            with_wallet(work_dir, network, |wallet| {
+                for issued in wallet.issued_addresses()? {
+                    println!("{} {} {} {}", issued.address, issued.path(),
+                             export::format_date(issued.issued),
+                             issued.funded.map_or("unused".to_string(), |h| h.to_string()));
+                }
+                Ok(())
+            })
+        }
+        ("send", Some(args)) => {
+            let address = Address::from_str(args.value_of("address").unwrap_or_default())?;
+            let fee = parse_arg::<u64>(args, "fee")?;
+            let amount = parse_arg::<u64>(args, "amount")?;
+            let passphrase = read_passphrase(args)?;
+            with_wallet(work_dir, network, |wallet| {
+                let fee = fee.unwrap_or(wallet.fee_policy().fee_per_vbyte);
+                let withdraw_tx = wallet.withdraw(passphrase, address, fee, amount)?;
+                println!("{} {}", withdraw_tx.txid, withdraw_tx.fee);
+                Ok(())
+            })
+        }
+        ("history", Some(args)) => {
+            with_wallet(work_dir, network, |wallet| {
+                let history = wallet.history()?;
+                if args.is_present("json") {
+                    println!("{}", serde_json::to_string_pretty(&history).map_err(|_| Error::Unsupported("can not format history"))?);
+                } else {
+                    for entry in history {
+                        println!("{} {} {} {} {} {}", entry.txid,
+                                 entry.height.map_or("unconfirmed".to_string(), |h| h.to_string()),
+                                 entry.received, entry.sent,
+                                 entry.fee.map_or("-".to_string(), |f| f.to_string()),
+                                 entry.label.unwrap_or_default());
+                    }
+                }
+                Ok(())
+            })
+        }
+        ("export", Some(args)) => {
+            let format = args.value_of("format").unwrap_or("csv").parse::<ExportFormat>()?;
+            let from = args.value_of("from").map(export::parse_date).transpose()?;
+            let until = args.value_of("until").map(export::parse_date).transpose()?;
+            with_wallet(work_dir, network, |wallet| {
+                wallet.export_history(&mut io::stdout().lock(), format, from, until)
+            })
+        }
+        ("coins", Some(_)) => {
+            with_wallet(work_dir, network, |wallet| {
+                for coin in wallet.coins()? {
+                    println!("{}:{} {} {} {}", coin.outpoint.txid, coin.outpoint.vout, coin.value,
+                             coin.address.map_or("-".to_string(), |a| a.to_string()),
+                             coin.label.unwrap_or_default());
+                }
+                Ok(())
+            })
+        }
+        ("label", Some(args)) => {
+            let kind = args.value_of("type").unwrap_or_default().parse::<LabelKind>()?;
+            with_wallet(work_dir, network, |wallet| {
+                wallet.set_label(kind, args.value_of("ref").unwrap_or_default(), args.value_of("label").unwrap_or_default())
+            })
+        }
+        ("labels", Some(args)) => {
+            with_wallet(work_dir, network, |wallet| {
+                match args.value_of("import") {
+                    Some("-") => println!("{}", wallet.import_labels(io::stdin().lock())?),
+                    Some(file) => println!("{}", wallet.import_labels(io::BufReader::new(fs::File::open(file)?))?),
+                    None => wallet.export_labels(&mut io::stdout().lock())?
+                }
+                Ok(())
+            })
+        }
+        ("verify", Some(args)) => {
+            with_wallet(work_dir, network, |wallet| {
+                let report = wallet.verify(args.is_present("repair"))?;
+                println!("{}", serde_json::to_string_pretty(&report).map_err(|_| Error::Unsupported("can not format report"))?);
+                Ok(())
+            })
+        }
+        ("config", Some(args)) => {
+            match args.subcommand() {
+                ("set", Some(args)) => config_set(work_dir, network, args.value_of("key").unwrap_or_default(), args.value_of("value").unwrap_or_default()),
+                _ => Err(Error::Unsupported("unknown config command"))
+            }
+        }
+        _ => Err(Error::Unsupported("unknown command"))
+    }
+}
+
+/// start the wallet, run f and stop the wallet even if f failed
+fn with_wallet<F>(work_dir: PathBuf, network: Network, f: F) -> Result<(), Error> where F: FnOnce(&WalletHandle) -> Result<(), Error> {
+    let wallet = api::start(work_dir, network, false)?;
+    let result = f(&wallet);
+    wallet.stop();
+    result
+}
+
+/// print events, return once the wallet stayed at the tip for a while if until_tip
+fn sync(wallet: &WalletHandle, until_tip: bool) -> Result<(), Error> {
+    let events = wallet.subscribe();
+    let mut connected = false;
+    let mut at_tip_since: Option<Instant> = None;
+    loop {
+        match events.recv_timeout(Duration::from_secs(1)) {
+            Ok(event) => {
+                if let WalletEvent::PeerConnected { .. } = event {
+                    connected = true;
+                }
+                println!("{:?}", event);
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => {}
+            Err(mpsc::RecvTimeoutError::Disconnected) => return Err(Error::NotStarted)
+        }
+        if until_tip && connected {
+            if wallet.sync_status()?.synced() {
+                let since = *at_tip_since.get_or_insert_with(Instant::now);
+                if since.elapsed() >= SYNC_SETTLE {
+                    return Ok(());
+                }
+            } else {
+                at_tip_since = None;
+            }
+        }
+    }
+}
+
+fn config_set(work_dir: PathBuf, network: Network, key: &str, value: &str) -> Result<(), Error> {
+    let config = api::load_config(work_dir.clone(), network)?;
+    let mut update = ConfigUpdate::default();
+    match key {
+        "peers" => {
+            update.bitcoin_peers = Some(value.split(',').filter(|p| !p.is_empty())
+                .map(|p| SocketAddr::from_str(p.trim()))
+                .collect::<Result<Vec<_>, _>>()?);
+        }
+        "connections" => {
+            update.bitcoin_connections = Some(value.parse::<usize>().map_err(|_| Error::Unsupported("connections should be a number"))?);
+        }
+        "discovery" => {
+            update.bitcoin_discovery = Some(match value {
+                "on" => true,
+                "off" => false,
+                _ => return Err(Error::Unsupported("discovery should be on or off"))
+            });
+        }
+        "proxy" => {
+            update.proxy = Some(if value == "off" { None } else { Some(SocketAddr::from_str(value)?) });
+        }
+        "sync-mode" => {
+            update.sync_mode = Some(match value {
+                "full" => SyncMode::Full,
+                "headers" => SyncMode::Headers,
+                _ => return Err(Error::Unsupported("sync mode should be full or headers"))
+            });
+        }
+        "user-agent" => {
+            update.user_agent = Some(value.to_string());
+        }
+        "gap-limit" => {
+            update.gap_limit = Some(if value == "default" { None } else {
+                Some(value.parse::<u32>().map_err(|_| Error::Unsupported("gap limit should be a number"))?)
+            });
+        }
+        "fee-rate" => {
+            let fee_per_vbyte = value.parse::<u64>().map_err(|_| Error::Unsupported("fee rate should be a number"))?;
+            update.fee_policy = Some(FeePolicy { fee_per_vbyte, ..config.fee_policy });
+        }
+        "max-fee-rate" => {
+            let max_fee_per_vbyte = if value == "off" { None } else {
+                Some(value.parse::<u64>().map_err(|_| Error::Unsupported("max fee rate should be a number"))?)
+            };
+            update.fee_policy = Some(FeePolicy { max_fee_per_vbyte, ..config.fee_policy });
+        }
+        _ => return Err(Error::Unsupported("unknown config key"))
+    }
+    api::update_config(work_dir, network, update)?;
+    Ok(())
+}
+
+fn parse_arg<T: FromStr>(args: &ArgMatches, name: &str) -> Result<Option<T>, Error> {
+    args.value_of(name).map(|v| v.parse::<T>().map_err(|_| Error::Unsupported("argument should be a number"))).transpose()
+}
+
+/// first line of the passphrase file or of stdin
+fn read_passphrase(args: &ArgMatches) -> Result<String, Error> {
+    let line = match args.value_of("passphrase-file") {
+        Some(file) => fs::read_to_string(file)?.lines().next().unwrap_or_default().to_string(),
+        None => {
+            let mut line = String::new();
+            io::stdin().lock().read_line(&mut line)?;
+            line
+        }
+    };
+    Ok(line.trim_end_matches(|c| c == '\n' || c == '\r').to_string())
+}
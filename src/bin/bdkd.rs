@@ -0,0 +1,486 @@
+/*
+ * Copyright 2020 BDK Team
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! bdkd, a wallet daemon serving JSON-RPC on a local address.
+//! Clients authenticate like with bitcoind's cookie, the user and password
+//! are in the .cookie file of the network directory while the daemon runs.
+
+#[macro_use]
+extern crate serde_derive;
+
+use std::fs;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use bitcoin::{Address, Network};
+use clap::{App, Arg};
+use log::{error, info, LevelFilter};
+use rand::{RngCore, thread_rng};
+use serde_json::{json, Value};
+use simplelog::WriteLogger;
+use tiny_http::{Header, Method, Response, Server};
+
+use bdk::api;
+use bdk::api::WalletHandle;
+use bdk::error::Error;
+
+const COOKIE_FILE_NAME: &str = ".cookie";
+const COOKIE_USER: &str = "__cookie__";
+/// how often serve checks if it was asked to stop by a signal
+const STOP_POLL: Duration = Duration::from_millis(500);
+
+// error codes of bitcoind where there is one with the same meaning
+const RPC_MISC_ERROR: i32 = -1;
+const RPC_WALLET_ERROR: i32 = -4;
+const RPC_INVALID_ADDRESS_OR_KEY: i32 = -5;
+const RPC_WALLET_INSUFFICIENT_FUNDS: i32 = -6;
+const RPC_INVALID_PARAMETER: i32 = -8;
+const RPC_WALLET_UNLOCK_NEEDED: i32 = -13;
+const RPC_WALLET_PASSPHRASE_INCORRECT: i32 = -14;
+const RPC_INVALID_REQUEST: i32 = -32600;
+const RPC_METHOD_NOT_FOUND: i32 = -32601;
+const RPC_PARSE_ERROR: i32 = -32700;
+
+#[derive(Deserialize)]
+struct RpcRequest {
+    #[serde(default)]
+    id: Value,
+    method: String,
+    #[serde(default)]
+    params: Vec<Value>,
+}
+
+#[derive(Serialize)]
+struct RpcResponse {
+    result: Value,
+    error: Option<RpcError>,
+    id: Value,
+}
+
+#[derive(Serialize, Debug)]
+struct RpcError {
+    code: i32,
+    message: String,
+}
+
+impl RpcError {
+    fn new(code: i32, message: String) -> RpcError {
+        RpcError { code, message }
+    }
+}
+
+impl From<Error> for RpcError {
+    fn from(err: Error) -> RpcError {
+        let code = match err {
+            Error::InsufficientFunds { .. } | Error::BelowDust { .. } => RPC_WALLET_INSUFFICIENT_FUNDS,
+            Error::WrongPassphrase => RPC_WALLET_PASSPHRASE_INCORRECT,
+            Error::DatabaseLocked => RPC_WALLET_UNLOCK_NEEDED,
+            Error::FeeAboveMaximum { .. } => RPC_INVALID_PARAMETER,
+            Error::InvalidAddress(_) | Error::NetworkMismatch { .. } => RPC_INVALID_ADDRESS_OR_KEY,
+            Error::SigningFailed | Error::Wallet(_) => RPC_WALLET_ERROR,
+            _ => RPC_MISC_ERROR
+        };
+        RpcError::new(code, err.to_string())
+    }
+}
+
+fn main() -> Result<(), Error> {
+    let cli = App::new("bdkd")
+        .about("bdk wallet daemon serving JSON-RPC")
+        .arg(Arg::with_name("data")
+            .short("d")
+            .long("data")
+            .value_name("DIRECTORY")
+            .help("data directory of a wallet initialized earlier")
+            .takes_value(true)
+            .default_value("."))
+        .arg(Arg::with_name("network")
+            .short("n")
+            .long("net")
+            .value_name("NETWORK")
+            .help("bitcoin network")
+            .takes_value(true)
+            .default_value("testnet")
+            .possible_values(&["bitcoin", "regtest", "testnet"]))
+        .arg(Arg::with_name("rpcbind")
+            .short("r")
+            .long("rpcbind")
+            .value_name("ADDRESS")
+            .help("address to serve JSON-RPC on, defaults to localhost and a port of the network")
+            .takes_value(true))
+        .arg(Arg::with_name("rescan")
+            .long("rescan")
+            .help("re-scan the chain from the wallet's birth"))
+        .arg(Arg::with_name("logging")
+            .short("l")
+            .long("log")
+            .value_name("LEVEL")
+            .help("logging level")
+            .takes_value(true)
+            .default_value("info")
+            .possible_values(&["debug", "info", "warn", "error"]))
+        .get_matches();
+
+    let work_dir = PathBuf::from(cli.value_of("data").unwrap_or("."));
+    let network = cli.value_of("network").unwrap_or("testnet").parse::<Network>()
+        .map_err(|_| Error::Unsupported("unknown network"))?;
+    let rpcbind = match cli.value_of("rpcbind") {
+        Some(rpcbind) => SocketAddr::from_str(rpcbind)?,
+        None => SocketAddr::from(([127, 0, 0, 1], default_rpc_port(network)))
+    };
+    let log_level = LevelFilter::from_str(cli.value_of("logging").unwrap_or("info"))
+        .map_err(|_| Error::Unsupported("unknown logging level"))?;
+
+    let mut network_dir = work_dir.clone();
+    network_dir.push(network.to_string());
+    fs::create_dir_all(&network_dir)?;
+
+    let mut log_file = network_dir.clone();
+    log_file.push("bdkd.log");
+    WriteLogger::init(log_level, simplelog::Config::default(), File::create(log_file)?)
+        .map_err(|_| Error::Unsupported("logger is already set"))?;
+
+    let server = Server::http(rpcbind)
+        .map_err(|e| Error::IO(io::Error::new(io::ErrorKind::Other, e.to_string())))?;
+
+    let wallet = api::start(work_dir, network, cli.is_present("rescan"))?;
+
+    let mut cookie_file = network_dir.clone();
+    cookie_file.push(COOKIE_FILE_NAME);
+    let cookie = write_cookie(&cookie_file)?;
+
+    // SIGINT and SIGTERM stop like the stop method, so the cookie is removed and the wallet flushed
+    let stop = Arc::new(AtomicBool::new(false));
+    let signalled = stop.clone();
+    if let Err(e) = ctrlc::set_handler(move || signalled.store(true, Ordering::SeqCst)) {
+        error!("can not handle signals: {}", e);
+    }
+    info!("serving JSON-RPC on {}", rpcbind);
+
+    serve(&server, &wallet, &cookie, &stop);
+
+    if let Err(e) = fs::remove_file(&cookie_file) {
+        error!("can not remove cookie file: {}", e);
+    }
+    wallet.stop();
+    Ok(())
+}
+
+fn default_rpc_port(network: Network) -> u16 {
+    match network {
+        Network::Bitcoin => 8350,
+        Network::Testnet => 18350,
+        Network::Regtest => 18450,
+    }
+}
+
+/// write a new random cookie, readable only by the user running the daemon
+fn write_cookie(path: &Path) -> Result<String, Error> {
+    let mut secret = [0u8; 32];
+    thread_rng().fill_bytes(&mut secret);
+    let cookie = format!("{}:{}", COOKIE_USER, hex::encode(secret));
+
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(path)?;
+    file.write_all(cookie.as_bytes())?;
+    file.sync_all()?;
+    Ok(cookie)
+}
+
+/// true if the Authorization header carries the cookie as basic auth
+fn authorized(authorization: Option<&str>, cookie: &str) -> bool {
+    match authorization {
+        Some(authorization) => constant_time_eq(authorization.as_bytes(), format!("Basic {}", base64::encode(cookie)).as_bytes()),
+        None => false
+    }
+}
+
+// compare without leaking the length of the common prefix through timing
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b.iter()).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// answer requests until the stop method is called or stop is set
+fn serve(server: &Server, wallet: &WalletHandle, cookie: &str, stop: &AtomicBool) {
+    while !stop.load(Ordering::SeqCst) {
+        let mut request = match server.recv_timeout(STOP_POLL) {
+            Ok(Some(request)) => request,
+            Ok(None) => continue,
+            Err(e) => {
+                error!("can not receive request: {}", e);
+                break;
+            }
+        };
+        let authorization = request.headers().iter()
+            .find(|h| h.field.equiv("Authorization"))
+            .map(|h| h.value.as_str().to_string());
+        if !authorized(authorization.as_ref().map(|a| a.as_str()), cookie) {
+            let header = Header::from_bytes(&b"WWW-Authenticate"[..], &b"Basic realm=\"bdkd\""[..]).unwrap();
+            request.respond(Response::empty(401).with_header(header)).ok();
+            continue;
+        }
+        if *request.method() != Method::Post {
+            request.respond(Response::empty(405)).ok();
+            continue;
+        }
+
+        let mut body = String::new();
+        let (response, stopping) = match request.as_reader().read_to_string(&mut body) {
+            Ok(_) => handle(wallet, body.as_str()),
+            Err(e) => (error_response(Value::Null, RpcError::new(RPC_INVALID_REQUEST, e.to_string())), false)
+        };
+        let status = status_code(&response);
+        let header = Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..]).unwrap();
+        let body = serde_json::to_string(&response).unwrap_or_default();
+        if let Err(e) = request.respond(Response::from_string(body).with_status_code(status).with_header(header)) {
+            error!("can not send response: {}", e);
+        }
+        if stopping {
+            break;
+        }
+    }
+}
+
+/// answer a request and tell if the daemon should stop
+fn handle(wallet: &WalletHandle, body: &str) -> (RpcResponse, bool) {
+    let request = match serde_json::from_str::<RpcRequest>(body) {
+        Ok(request) => request,
+        Err(e) => return (error_response(Value::Null, RpcError::new(RPC_PARSE_ERROR, e.to_string())), false)
+    };
+    let stop = request.method == "stop";
+    let response = match dispatch(wallet, request.method.as_str(), &request.params) {
+        Ok(result) => RpcResponse { result, error: None, id: request.id },
+        Err(e) => error_response(request.id, e)
+    };
+    (response, stop)
+}
+
+fn error_response(id: Value, error: RpcError) -> RpcResponse {
+    RpcResponse { result: Value::Null, error: Some(error), id }
+}
+
+/// HTTP status of a response, as bitcoind answers: 4xx for requests that are not understood, 500 if the call failed
+fn status_code(response: &RpcResponse) -> u16 {
+    match response.error {
+        None => 200,
+        Some(ref error) => match error.code {
+            RPC_PARSE_ERROR | RPC_INVALID_REQUEST | RPC_INVALID_PARAMETER | RPC_INVALID_ADDRESS_OR_KEY => 400,
+            RPC_METHOD_NOT_FOUND => 404,
+            _ => 500
+        }
+    }
+}
+
+fn dispatch(wallet: &WalletHandle, method: &str, params: &[Value]) -> Result<Value, RpcError> {
+    match method {
+        "getbalance" => {
+            let balance = wallet.balance()?;
+            Ok(json!({ "balance": balance.balance, "confirmed": balance.confirmed }))
+        }
+        "getnewaddress" => {
+            Ok(json!(wallet.new_addr()?.to_string()))
+        }
+        "getdepositaddress" => {
+            Ok(json!(wallet.deposit_addr()?.to_string()))
+        }
+        "listaddresses" => {
+            serde_json::to_value(wallet.issued_addresses()?).map_err(|e| RpcError::new(RPC_MISC_ERROR, e.to_string()))
+        }
+        "withdraw" => {
+            // passphrase, address, fee_per_vbyte, amount or everything if omitted
+            let passphrase = string_param(params, 0, "passphrase")?;
+            let address = Address::from_str(string_param(params, 1, "address")?.as_str()).map_err(Error::from)?;
+            let fee_per_vbyte = u64_param(params, 2, "fee_per_vbyte")?
+                .ok_or(RpcError::new(RPC_INVALID_PARAMETER, "missing fee_per_vbyte".to_string()))?;
+            let amount = u64_param(params, 3, "amount")?;
+            let withdraw_tx = wallet.withdraw(passphrase, address, fee_per_vbyte, amount)?;
+            Ok(json!({ "txid": withdraw_tx.txid.to_string(), "fee": withdraw_tx.fee }))
+        }
+        "listtransactions" => {
+            serde_json::to_value(wallet.history()?).map_err(|e| RpcError::new(RPC_MISC_ERROR, e.to_string()))
+        }
+        "getsyncstatus" => {
+            let status = wallet.sync_status()?;
+            Ok(json!({ "tip_height": status.tip_height, "processed_height": status.processed_height, "synced": status.synced() }))
+        }
+        "getpeerinfo" => {
+            Ok(Value::Array(wallet.peers().iter().map(|a| json!({ "address": a.to_string() })).collect()))
+        }
+        "stop" => {
+            Ok(json!("bdkd stopping"))
+        }
+        _ => Err(RpcError::new(RPC_METHOD_NOT_FOUND, format!("method {} not found", method)))
+    }
+}
+
+fn string_param(params: &[Value], index: usize, name: &str) -> Result<String, RpcError> {
+    params.get(index).and_then(|p| p.as_str()).map(|p| p.to_string())
+        .ok_or(RpcError::new(RPC_INVALID_PARAMETER, format!("{} should be a string", name)))
+}
+
+fn u64_param(params: &[Value], index: usize, name: &str) -> Result<Option<u64>, RpcError> {
+    match params.get(index) {
+        None | Some(Value::Null) => Ok(None),
+        Some(p) => p.as_u64().map(Some)
+            .ok_or(RpcError::new(RPC_INVALID_PARAMETER, format!("{} should be a positive integer", name)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::path::PathBuf;
+    use std::sync::atomic::AtomicBool;
+    use std::thread;
+
+    use bitcoin::Network;
+    use serde_json::Value;
+    use tiny_http::Server;
+
+    use bdk::api;
+    use bdk::api::WalletHandle;
+    use bdk::config::ConfigUpdate;
+
+    use super::{authorized, COOKIE_USER, handle, RPC_INVALID_PARAMETER, RPC_METHOD_NOT_FOUND, RPC_PARSE_ERROR, serve, status_code};
+
+    const PASSPHRASE: &str = "correct horse battery staple";
+
+    // a wallet without peers, enough to answer calls
+    fn start_wallet(work_dir: &PathBuf) -> WalletHandle {
+        api::init_config(work_dir.clone(), Network::Regtest, PASSPHRASE, None).unwrap().unwrap();
+        api::update_config(work_dir.clone(), Network::Regtest, ConfigUpdate {
+            bitcoin_connections: Some(0),
+            bitcoin_discovery: Some(false),
+            ..Default::default()
+        }).unwrap();
+        api::start(work_dir.clone(), Network::Regtest, false).unwrap()
+    }
+
+    // status code and body of a POST
+    fn post(address: &str, authorization: Option<&str>, body: &str) -> (u16, String) {
+        let mut stream = TcpStream::connect(address).unwrap();
+        let mut request = format!("POST / HTTP/1.1\r\nHost: {}\r\nConnection: close\r\nContent-Length: {}\r\n", address, body.len());
+        if let Some(authorization) = authorization {
+            request.push_str(format!("Authorization: {}\r\n", authorization).as_str());
+        }
+        request.push_str("\r\n");
+        request.push_str(body);
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let status = response.split(' ').nth(1).unwrap().parse().unwrap();
+        let body = response.splitn(2, "\r\n\r\n").nth(1).unwrap_or_default().to_string();
+        (status, body)
+    }
+
+    #[test]
+    fn cookie_authorization() {
+        let cookie = format!("{}:{}", COOKIE_USER, "00ff");
+        let header = format!("Basic {}", base64::encode(cookie.as_str()));
+        assert!(authorized(Some(header.as_str()), cookie.as_str()));
+        assert!(!authorized(Some("Basic X19jb29raWVfXzpvdGhlcg=="), cookie.as_str()));
+        assert!(!authorized(Some(&header[..header.len() - 1]), cookie.as_str()));
+        assert!(!authorized(None, cookie.as_str()));
+    }
+
+    #[test]
+    fn dispatch_requests() {
+        let work_dir = PathBuf::from("./test_bdkd_dispatch");
+        let wallet = start_wallet(&work_dir);
+
+        let (response, stop) = handle(&wallet, r#"{"id": 1, "method": "getbalance"}"#);
+        assert!(!stop && response.error.is_none());
+        assert_eq!(response.result["balance"], 0);
+        assert_eq!(response.id, Value::from(1));
+        assert_eq!(status_code(&response), 200);
+
+        let (response, _) = handle(&wallet, r#"{"id": 2, "method": "getdepositaddress"}"#);
+        let deposit = response.result.as_str().unwrap().to_string();
+        let (response, _) = handle(&wallet, r#"{"id": 3, "method": "listaddresses"}"#);
+        assert_eq!(response.result[0]["address"], Value::from(deposit.as_str()));
+
+        let (response, stop) = handle(&wallet, r#"{"id": 4, "method": "getwalletinfo"}"#);
+        assert!(!stop);
+        assert_eq!(response.error.as_ref().unwrap().code, RPC_METHOD_NOT_FOUND);
+        assert_eq!(response.id, Value::from(4));
+        assert_eq!(status_code(&response), 404);
+
+        let (response, _) = handle(&wallet, r#"{"id": 5, "method": "withdraw", "params": ["passphrase", "not an address", 1]}"#);
+        assert_eq!(status_code(&response), 400);
+        let (response, _) = handle(&wallet, r#"{"id": 6, "method": "withdraw", "params": [1]}"#);
+        assert_eq!(response.error.as_ref().unwrap().code, RPC_INVALID_PARAMETER);
+        assert_eq!(status_code(&response), 400);
+        let (response, _) = handle(&wallet, format!(r#"{{"id": 7, "method": "withdraw", "params": ["passphrase", "{}", -1]}}"#, deposit).as_str());
+        assert_eq!(response.error.as_ref().unwrap().code, RPC_INVALID_PARAMETER);
+
+        let (response, stop) = handle(&wallet, "{\"method\": ");
+        assert!(!stop);
+        assert_eq!(response.error.as_ref().unwrap().code, RPC_PARSE_ERROR);
+        assert_eq!(response.id, Value::Null);
+        assert_eq!(status_code(&response), 400);
+
+        let (response, stop) = handle(&wallet, r#"{"id": 8, "method": "stop"}"#);
+        assert!(stop && response.error.is_none());
+
+        wallet.stop();
+        api::remove_config(work_dir.clone(), Network::Regtest).unwrap();
+        std::fs::remove_dir_all(work_dir).unwrap();
+    }
+
+    #[test]
+    fn serve_until_stop() {
+        let work_dir = PathBuf::from("./test_bdkd_serve");
+        let wallet = start_wallet(&work_dir);
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let address = server.server_addr().to_string();
+        let cookie = format!("{}:{}", COOKIE_USER, "00ff");
+        let authorization = format!("Basic {}", base64::encode(cookie.as_str()));
+
+        let client = thread::spawn(move || {
+            assert_eq!(post(address.as_str(), None, r#"{"method": "getbalance"}"#).0, 401);
+            assert_eq!(post(address.as_str(), Some("Basic X19jb29raWVfXzpvdGhlcg=="), r#"{"method": "getbalance"}"#).0, 401);
+            assert_eq!(post(address.as_str(), Some(authorization.as_str()), "not json").0, 400);
+            assert_eq!(post(address.as_str(), Some(authorization.as_str()), r#"{"method": "nomethod"}"#).0, 404);
+            let (status, body) = post(address.as_str(), Some(authorization.as_str()), r#"{"id": 1, "method": "getbalance"}"#);
+            assert_eq!(status, 200);
+            assert_eq!(serde_json::from_str::<Value>(body.as_str()).unwrap()["result"]["balance"], 0);
+            assert_eq!(post(address.as_str(), Some(authorization.as_str()), r#"{"id": 2, "method": "stop"}"#).0, 200);
+        });
+        // returns once stop was called
+        serve(&server, &wallet, cookie.as_str(), &AtomicBool::new(false));
+        client.join().unwrap();
+
+        // a signal stops it without a request
+        serve(&server, &wallet, cookie.as_str(), &AtomicBool::new(true));
+
+        wallet.stop();
+        api::remove_config(work_dir.clone(), Network::Regtest).unwrap();
+        std::fs::remove_dir_all(work_dir).unwrap();
+    }
+}
@@ -17,7 +17,6 @@
 use std::{
     collections::VecDeque,
     sync::mpsc,
-    thread,
     time::Duration,
 };
 
@@ -28,64 +27,139 @@ use bitcoin::{BitcoinHash, Block, blockdata::{
     message_blockdata::{GetHeadersMessage, Inventory, InvType},
 }};
 use bitcoin_hashes::sha256d;
-use log::{debug, info, trace};
-use murmel::chaindb::SharedChainDB;
+use log::{debug, error, info, trace};
+use murmel::chaindb::{ChainDB, SharedChainDB};
 use murmel::downstream::SharedDownstream;
-use murmel::error::Error;
+use murmel::error::Error as MurmelError;
 use murmel::p2p::{P2PControl, P2PControlSender, PeerId, PeerMessage, PeerMessageReceiver, PeerMessageSender, SERVICE_BLOCKS};
 use murmel::timeout::{ExpectedReply, SharedTimeout};
 
+use crate::component::{Component, stopped};
+use crate::error::Error;
+use crate::storage::{DownloadProgress, SharedStorage};
+
+/// protocol version that introduced the sendheaders message (BIP130)
+pub const SENDHEADERS_VERSION: u32 = 70012;
+/// a headers message with less than this many headers is the last in a batch
+const MAX_HEADERS_PER_MESSAGE: usize = 2000;
+/// number of blocks a block time might lag behind its predecessors
+const MEDIAN_TIME_SPAN: u32 = 11;
+
+/// number of blocks queued ahead of the download
+const QUEUE_LENGTH: usize = 2000;
+
 pub struct BlockDownload {
     p2p: P2PControlSender<NetworkMessage>,
     chaindb: SharedChainDB,
+    db: SharedStorage,
     timeout: SharedTimeout<NetworkMessage, ExpectedReply>,
     downstream: SharedDownstream,
     blocks_wanted: VecDeque<(sha256d::Hash, u32)>,
     blocks_asked: VecDeque<(sha256d::Hash, u32)>,
+    // height of the next trunk block to queue
+    next_wanted: u32,
     block_download_peer: Option<PeerId>,
     birth: u64
 }
 
 impl BlockDownload {
-    pub fn new(chaindb: SharedChainDB, p2p: P2PControlSender<NetworkMessage>, timeout: SharedTimeout<NetworkMessage, ExpectedReply>, downstream: SharedDownstream, processed_block: Option<sha256d::Hash>, birth: u64) -> PeerMessageSender<NetworkMessage> {
+    pub fn new(chaindb: SharedChainDB, db: SharedStorage, p2p: P2PControlSender<NetworkMessage>, timeout: SharedTimeout<NetworkMessage, ExpectedReply>, downstream: SharedDownstream, processed_block: Option<sha256d::Hash>, birth: u64) -> Result<(PeerMessageSender<NetworkMessage>, Component), Error> {
         let (sender, receiver) = mpsc::sync_channel(p2p.back_pressure);
 
+        let progress = {
+            let mut db = db.lock().unwrap();
+            let mut tx = db.transaction()?;
+            tx.read_download_progress()?
+        };
+        let (blocks_wanted, next_wanted) = Self::resume(&chaindb.read().unwrap(), progress, processed_block, birth);
+        if let Some(progress) = progress {
+            info!("resume block download at height {}, last queued {}", next_wanted, progress.queued.0);
+        }
+
+        let mut headerdownload = BlockDownload { chaindb, db, p2p, timeout, downstream: downstream,
+            blocks_wanted, blocks_asked: VecDeque::new(), next_wanted, block_download_peer: None, birth };
+        headerdownload.queue_blocks();
+
+        let component = Component::spawn("header download", move |stop| { headerdownload.run(receiver, stop) })?;
+
+        Ok((PeerMessageSender::new(sender), component))
+    }
+
+    /// blocks to download again and the height to continue queuing at after a restart.
+    /// Blocks that were queued before the restart are queued again, up to the last queued that is still on the trunk.
+    fn resume(chaindb: &ChainDB, progress: Option<DownloadProgress>, processed_block: Option<sha256d::Hash>, birth: u64) -> (VecDeque<(sha256d::Hash, u32)>, u32) {
+        // persisted progress is only valid if its blocks are still on the trunk
+        let progress = progress.filter(|p| chaindb.pos_on_trunk(&p.verified.1) == Some(p.verified.0));
+        let processed = processed_block.and_then(|p| chaindb.pos_on_trunk(&p));
+        let mut next_wanted = match (progress, processed) {
+            (Some(progress), Some(processed)) => std::cmp::min(progress.verified.0, processed) + 1,
+            (None, Some(processed)) => processed + 1,
+            (_, None) => Self::first_after_birth(chaindb, birth)
+        };
         let mut blocks_wanted = VecDeque::new();
-        {
-            let chaindb = chaindb.read().unwrap();
-            if let Some(mut h) = chaindb.header_tip() {
-                if (h.stored.header.time as u64) > birth {
-                    let stop_at = processed_block.unwrap_or_default();
-                    let mut block_hash = h.bitcoin_hash();
-                    while block_hash != stop_at {
-                        blocks_wanted.push_front((block_hash, h.stored.height));
-                        block_hash = h.stored.header.prev_blockhash.clone();
-                        if block_hash != sha256d::Hash::default() {
-                            h = chaindb.get_header(&block_hash).expect("inconsistent header cache");
-                            if (h.stored.header.time as u64) < birth {
-                                break;
-                            }
-                        }
-                    }
+        if let (Some(progress), Some(_)) = (progress, processed) {
+            Self::queue_until(chaindb, &mut blocks_wanted, &mut next_wanted, birth, progress.queued.0);
+        }
+        (blocks_wanted, next_wanted)
+    }
+
+    /// queue trunk blocks from next_wanted up to height last, skipping those before birth,
+    /// until QUEUE_LENGTH blocks are queued
+    fn queue_until(chaindb: &ChainDB, blocks_wanted: &mut VecDeque<(sha256d::Hash, u32)>, next_wanted: &mut u32, birth: u64, last: u32) {
+        while blocks_wanted.len() < QUEUE_LENGTH && *next_wanted <= last {
+            if let Some(header) = chaindb.get_header_for_height(*next_wanted) {
+                if (header.stored.header.time as u64) > birth {
+                    blocks_wanted.push_back((header.bitcoin_hash(), *next_wanted));
                 }
+                *next_wanted += 1;
+            } else {
+                break;
             }
         }
+    }
 
-        let mut headerdownload = BlockDownload { chaindb, p2p, timeout, downstream: downstream,
-            blocks_wanted, blocks_asked: VecDeque::new(), block_download_peer: None, birth };
+    /// height of the first trunk block that might be of interest for a wallet born at birth
+    fn first_after_birth(chaindb: &ChainDB, birth: u64) -> u32 {
+        let mut low = 0;
+        let mut high = if let Some(tip) = chaindb.header_tip() { tip.stored.height + 1 } else { return 0; };
+        while low < high {
+            let mid = (low + high) / 2;
+            match chaindb.get_header_for_height(mid) {
+                Some(header) if (header.stored.header.time as u64) <= birth => low = mid + 1,
+                _ => high = mid
+            }
+        }
+        // block times are not strictly increasing, the time filter while queuing takes care of the rest
+        low.saturating_sub(MEDIAN_TIME_SPAN)
+    }
 
-        thread::Builder::new().name("header download".to_string()).spawn(move || { headerdownload.run(receiver) }).unwrap();
+    /// queue trunk blocks from next_wanted on, skipping those before birth
+    fn queue_blocks(&mut self) {
+        let chaindb = self.chaindb.read().unwrap();
+        Self::queue_until(&chaindb, &mut self.blocks_wanted, &mut self.next_wanted, self.birth, u32::max_value());
+    }
 
-        PeerMessageSender::new(sender)
+    /// persist how far download got, so it can resume after restart
+    fn store_progress(&self, verified: (u32, sha256d::Hash)) -> Result<(), Error> {
+        let queued = if let Some((hash, height)) = self.blocks_wanted.back().or(self.blocks_asked.back()) {
+            (*height, *hash)
+        } else {
+            verified
+        };
+        let mut db = self.db.lock().unwrap();
+        let mut tx = db.transaction()?;
+        tx.store_download_progress(&DownloadProgress { verified, queued })?;
+        tx.commit()
     }
 
-    fn run(&mut self, receiver: PeerMessageReceiver<NetworkMessage>) {
-        loop {
+    fn run(&mut self, receiver: PeerMessageReceiver<NetworkMessage>, stop: mpsc::Receiver<()>) {
+        while !stopped(&stop) {
             while let Ok(msg) = receiver.recv_timeout(Duration::from_millis(1000)) {
                 match msg {
                     PeerMessage::Connected(pid,_) => {
                         if self.is_serving_blocks(pid) {
                             trace!("serving blocks peer={}", pid);
+                            self.send_headers(pid);
                             self.get_headers(pid);
                             if self.block_download_peer.is_none() {
                                 debug!("new block download peer={}", pid);
@@ -106,7 +180,12 @@ impl BlockDownload {
                     }
                     PeerMessage::Incoming(pid, msg) => {
                         match msg {
-                            NetworkMessage::Headers(ref headers) => if self.is_serving_blocks(pid) { self.headers(headers, pid); },
+                            NetworkMessage::Headers(ref headers) => if self.is_serving_blocks(pid) {
+                                if let Err(e) = self.headers(headers, pid) {
+                                    error!("can not process headers, header download stops: {}", e);
+                                    return;
+                                }
+                            },
                             NetworkMessage::Inv(ref inv) => if self.is_serving_blocks(pid) { self.inv(inv, pid); },
                             NetworkMessage::Block(ref block) => self.block(block, pid),
                             _ => {}
@@ -126,6 +205,7 @@ impl BlockDownload {
     }
 
     fn ask_blocks (&mut self, pid: PeerId) {
+        self.queue_blocks();
         let mut timeout = self.timeout.lock().unwrap();
         if !timeout.is_busy_with(pid, ExpectedReply::Block) {
             let mut n_entries = 0;
@@ -163,14 +243,26 @@ impl BlockDownload {
                         self.timeout.lock().unwrap().received(pid, 1, ExpectedReply::Block);
 
                         self.blocks_asked.pop_front();
-                        let mut downstream = self.downstream.lock().unwrap();
-                        downstream.block_connected(block, height);
+                        self.downstream.lock().unwrap().block_connected(block, height);
+                        if let Err(e) = self.store_progress((height, block.header.bitcoin_hash())) {
+                            error!("can not store download progress: {}", e);
+                        }
                     }
                 }
             }
         }
     }
 
+    /// ask peer to announce new blocks with headers instead of inv (BIP130)
+    fn send_headers(&self, peer: PeerId) {
+        if let Some(peer_version) = self.p2p.peer_version(peer) {
+            if peer_version.version >= SENDHEADERS_VERSION {
+                debug!("ask for headers announcement peer={}", peer);
+                self.p2p.send_network(peer, NetworkMessage::SendHeaders);
+            }
+        }
+    }
+
     fn is_serving_blocks(&self, peer: PeerId) -> bool {
         if let Some(peer_version) = self.p2p.peer_version(peer) {
             return peer_version.services & SERVICE_BLOCKS != 0;
@@ -204,18 +296,13 @@ impl BlockDownload {
         }
         let chaindb = self.chaindb.read().unwrap();
         let locator = chaindb.header_locators();
-        if locator.len() > 0 {
-            let first = if locator.len() > 0 {
-                *locator.first().unwrap()
-            } else {
-                sha256d::Hash::default()
-            };
+        if let Some(first) = locator.first().cloned() {
             self.timeout.lock().unwrap().expect(peer, 1, ExpectedReply::Headers);
             self.p2p.send_network(peer, NetworkMessage::GetHeaders(GetHeadersMessage::new(locator, first)));
         }
     }
 
-    fn headers(&mut self, headers: &Vec<BlockHeader>, peer: PeerId) {
+    fn headers(&mut self, headers: &Vec<BlockHeader>, peer: PeerId) -> Result<(), Error> {
         self.timeout.lock().unwrap().received(peer, 1, ExpectedReply::Headers);
 
         if headers.len() > 0 {
@@ -223,6 +310,8 @@ impl BlockDownload {
             let mut height;
             // some received headers were not yet known
             let mut some_new = false;
+            // some received headers did not connect to our chain
+            let mut unconnected = false;
             let mut moved_tip = None;
             {
                 let chaindb = self.chaindb.read().unwrap();
@@ -230,7 +319,7 @@ impl BlockDownload {
                 if let Some(tip) = chaindb.header_tip() {
                     height = tip.stored.height;
                 } else {
-                    return;
+                    return Ok(());
                 }
             }
 
@@ -249,48 +338,59 @@ impl BlockDownload {
                                 // POW is ok, stored top chaindb
                                 some_new = true;
 
-                                if let Some(forwards) = forwards {
-                                    moved_tip = Some(forwards.last().unwrap().clone());
+                                if let Some(tip) = forwards.and_then(|f| f.last().cloned()) {
+                                    moved_tip = Some(tip);
                                 }
                                 height = stored.height;
 
                                 if let Some(unwinds) = unwinds {
-                                    disconnected_headers.extend(unwinds.iter()
-                                        .map(|h| chaindb.get_header(h).unwrap().stored.header));
+                                    for h in unwinds.iter() {
+                                        let cached = chaindb.get_header(h).ok_or(Error::Corrupted("unwound header is not in the chain db"))?;
+                                        disconnected_headers.push((cached.stored.height, cached.stored.header));
+                                    }
                                     break;
                                 }
                             }
                             Ok(None) => {}
-                            Err(Error::SpvBadProofOfWork) => {
+                            Err(MurmelError::SpvBadProofOfWork) => {
                                 info!("Incorrect POW, banning peer={}", peer);
                                 self.p2p.ban(peer, 100);
                             }
                             Err(e) => {
                                 debug!("error {} processing header {} ", e, header.bitcoin_hash());
+                                unconnected = true;
                             }
                         }
                     }
-                    chaindb.batch().unwrap();
+                    chaindb.batch()?;
                 }
 
                 // call downstream outside of chaindb lock
                 let mut downstream = self.downstream.lock().unwrap();
-                for header in &disconnected_headers {
-                    if (header.time as u64) > self.birth {
-                        self.blocks_wanted.pop_back();
+                for (height, header) in &disconnected_headers {
+                    let block_hash = header.bitcoin_hash();
+                    if self.blocks_wanted.iter().chain(self.blocks_asked.iter()).any(|(h, _)| *h == block_hash) {
+                        // not yet processed, just forget it
+                        self.blocks_wanted.retain(|(h, _)| *h != block_hash);
+                        self.blocks_asked.retain(|(h, _)| *h != block_hash);
+                    } else if *height < self.next_wanted && (header.time as u64) > self.birth {
                         downstream.block_disconnected(header);
                     }
+                    // queue the blocks of the new trunk at the heights of those disconnected
+                    self.next_wanted = std::cmp::min(self.next_wanted, *height);
+                }
+                if !disconnected_headers.is_empty() {
+                    info!("re-org of depth {} peer={}", disconnected_headers.len(), peer);
                 }
                 for (height, header) in &connected_headers {
                     if (header.time as u64) > self.birth {
-                        self.blocks_wanted.push_back((header.bitcoin_hash(), *height));
                         downstream.header_connected(header, *height);
                     }
                 }
             }
 
-            if some_new {
-                // ask if peer knows even more
+            if (some_new && headers.len() >= MAX_HEADERS_PER_MESSAGE) || unconnected {
+                // ask if peer knows even more, or for the missing link to an announced header
                 self.get_headers(peer);
             }
 
@@ -301,5 +401,68 @@ impl BlockDownload {
                 debug!("received {} known or orphan headers [{} .. {}] from peer={}", headers.len(), headers[0].bitcoin_hash(), headers[headers.len()-1].bitcoin_hash(), peer);
             }
         }
+        Ok(())
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::VecDeque;
+    use std::path::PathBuf;
+
+    use bitcoin::{BitcoinHash, Network};
+    use bitcoin::blockdata::block::BlockHeader;
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin_hashes::sha256d;
+    use murmel::chaindb::ChainDB;
+
+    use crate::storage::DownloadProgress;
+
+    use super::BlockDownload;
+
+    #[test]
+    fn resume_queued_blocks() {
+        let path = PathBuf::from("./test_resume.chain");
+        std::fs::remove_file(&path).ok();
+        let mut chaindb = ChainDB::new(path.as_path(), Network::Regtest).unwrap();
+        chaindb.init().unwrap();
+        let mut hashes = vec!(genesis_block(Network::Regtest).bitcoin_hash());
+        let mut prev = genesis_block(Network::Regtest).header;
+        for _ in 0..10 {
+            let mut header = BlockHeader { version: 1, prev_blockhash: prev.bitcoin_hash(), merkle_root: sha256d::Hash::default(),
+                time: prev.time + 600, bits: prev.bits, nonce: 0 };
+            while header.validate_pow(&header.target()).is_err() {
+                header.nonce += 1;
+            }
+            chaindb.add_header(&header).unwrap();
+            hashes.push(header.bitcoin_hash());
+            prev = header;
+        }
+        chaindb.batch().unwrap();
+
+        // blocks verified up to 3 and queued up to 7 are queued again
+        let progress = DownloadProgress { verified: (3, hashes[3]), queued: (7, hashes[7]) };
+        let (wanted, next_wanted) = BlockDownload::resume(&chaindb, Some(progress), Some(hashes[5]), 0);
+        assert_eq!(wanted, (4..=7u32).map(|h| (hashes[h as usize], h)).collect::<VecDeque<_>>());
+        assert_eq!(next_wanted, 8);
+
+        // the processed block is behind, download restarts there
+        let (wanted, next_wanted) = BlockDownload::resume(&chaindb, Some(progress), Some(hashes[1]), 0);
+        assert_eq!(wanted.front(), Some(&(hashes[2], 2)));
+        assert_eq!(wanted.back(), Some(&(hashes[7], 7)));
+        assert_eq!(next_wanted, 8);
+
+        // progress on a fork is ignored
+        let forked = DownloadProgress { verified: (3, hashes[4]), queued: (7, hashes[7]) };
+        let (wanted, next_wanted) = BlockDownload::resume(&chaindb, Some(forked), Some(hashes[5]), 0);
+        assert!(wanted.is_empty());
+        assert_eq!(next_wanted, 6);
+
+        // blocks before birth are not queued
+        let (wanted, next_wanted) = BlockDownload::resume(&chaindb, Some(progress), Some(hashes[5]), (prev.time - 5 * 600) as u64);
+        assert_eq!(wanted, (6..=7u32).map(|h| (hashes[h as usize], h)).collect::<VecDeque<_>>());
+        assert_eq!(next_wanted, 8);
+
+        std::fs::remove_file(&path).ok();
+    }
+}
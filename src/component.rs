@@ -0,0 +1,98 @@
+/*
+ * Copyright 2020 BDK Team
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! threads of the wallet that run until told to stop
+
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use log::{debug, error};
+
+use crate::error::Error;
+
+// how long a component waits for a message before it looks for a stop signal
+const STOP_CHECK: Duration = Duration::from_millis(500);
+
+/// a named thread with a channel to tell it to stop
+pub struct Component {
+    name: String,
+    stop: mpsc::Sender<()>,
+    thread: thread::JoinHandle<()>
+}
+
+impl Component {
+    /// run in a new thread, run should return soon after it receives on the stop channel
+    pub fn spawn<F>(name: &str, run: F) -> Result<Component, Error> where F: FnOnce(mpsc::Receiver<()>) + Send + 'static {
+        let (stop, stopped) = mpsc::channel();
+        let thread = thread::Builder::new().name(name.to_string()).spawn(move || run(stopped))?;
+        Ok(Component { name: name.to_string(), stop, thread })
+    }
+
+    /// tell the thread to stop and wait until it did
+    pub fn stop(self) {
+        self.stop.send(()).ok();
+        match self.thread.join() {
+            Ok(()) => debug!("stopped {}", self.name),
+            Err(_) => error!("{} thread panicked", self.name)
+        }
+    }
+}
+
+/// true if a stop was signalled or the component was dropped
+pub fn stopped(stop: &mpsc::Receiver<()>) -> bool {
+    match stop.try_recv() {
+        Err(mpsc::TryRecvError::Empty) => false,
+        _ => true
+    }
+}
+
+/// wait for the next message. Returns None once stop was signalled or the sender is gone.
+pub fn next<T>(receiver: &mpsc::Receiver<T>, stop: &mpsc::Receiver<()>) -> Option<T> {
+    loop {
+        if stopped(stop) {
+            return None;
+        }
+        match receiver.recv_timeout(STOP_CHECK) {
+            Ok(msg) => return Some(msg),
+            Err(mpsc::RecvTimeoutError::Timeout) => continue,
+            Err(mpsc::RecvTimeoutError::Disconnected) => return None
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::mpsc;
+
+    use super::{Component, next};
+
+    #[test]
+    fn stop_joins_thread() {
+        let (sender, receiver) = mpsc::channel();
+        let (seen_sender, seen) = mpsc::channel();
+        let component = Component::spawn("test", move |stop| {
+            while let Some(n) = next(&receiver, &stop) {
+                seen_sender.send(n).unwrap();
+            }
+        }).unwrap();
+        sender.send(1).unwrap();
+        assert_eq!(seen.recv().unwrap(), 1);
+        component.stop();
+        // the thread dropped its end
+        assert!(seen.recv().is_err());
+    }
+}
@@ -24,8 +24,17 @@ use crate::error::Error;
 
 use bitcoin::Network;
 
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
+/// version of the config file written by this library
+pub const CONFIG_VERSION: u32 = 1;
+pub const DEFAULT_USER_AGENT: &str = "bdk 0.1.0";
+
+// Sections are optional and take their default if missing, so files written by older versions still load.
+// Plain values must precede sections (tables) for toml serialization.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 pub struct Config {
+    /// files written before versioning have none, version 0
+    #[serde(default)]
+    pub version: u32,
     pub encryptedwalletkey: String,
     pub keyroot: String,
     pub lookahead: u32,
@@ -34,11 +43,111 @@ pub struct Config {
     pub bitcoin_peers: Vec<SocketAddr>,
     pub bitcoin_connections: usize,
     pub bitcoin_discovery: bool,
+    /// SOCKS5 proxy for peer connections
+    #[serde(default)]
+    pub proxy: Option<SocketAddr>,
+    #[serde(default)]
+    pub sync_mode: SyncMode,
+    #[serde(default = "default_user_agent")]
+    pub user_agent: String,
+    /// unused addresses watched after the last used one, lookahead if not set
+    #[serde(default)]
+    pub gap_limit: Option<u32>,
+    #[serde(default)]
+    pub backend: Backend,
+    #[serde(default)]
+    pub fee_policy: FeePolicy,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum SyncMode {
+    /// download blocks after birth and scan them for wallet transactions
+    Full,
+    /// follow the header chain only
+    Headers,
+}
+
+impl Default for SyncMode {
+    fn default() -> Self {
+        SyncMode::Full
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    /// bitcoin p2p network
+    P2P,
+}
+
+impl Default for Backend {
+    fn default() -> Self {
+        Backend::P2P
+    }
+}
+
+/// fee rates in satoshi per vbyte
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct FeePolicy {
+    /// used if the caller does not give a fee rate
+    #[serde(default = "default_fee_per_vbyte")]
+    pub fee_per_vbyte: u64,
+    /// withdrawals above this rate are refused
+    #[serde(default)]
+    pub max_fee_per_vbyte: Option<u64>,
+}
+
+impl Default for FeePolicy {
+    fn default() -> Self {
+        FeePolicy { fee_per_vbyte: default_fee_per_vbyte(), max_fee_per_vbyte: None }
+    }
+}
+
+fn default_user_agent() -> String {
+    DEFAULT_USER_AGENT.to_string()
+}
+
+fn default_fee_per_vbyte() -> u64 {
+    1
+}
+
+/// fields to change in a config, None leaves the field as is
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[serde(default)]
+pub struct ConfigUpdate {
+    pub bitcoin_peers: Option<Vec<SocketAddr>>,
+    pub bitcoin_connections: Option<usize>,
+    pub bitcoin_discovery: Option<bool>,
+    /// Some(None) removes the proxy
+    pub proxy: Option<Option<SocketAddr>>,
+    pub sync_mode: Option<SyncMode>,
+    pub user_agent: Option<String>,
+    /// Some(None) falls back to lookahead
+    pub gap_limit: Option<Option<u32>>,
+    pub backend: Option<Backend>,
+    pub fee_policy: Option<FeePolicy>,
+}
+
+impl ConfigUpdate {
+    /// refuse values the wallet can not start with yet, so they are never saved
+    pub fn validate(&self) -> Result<(), Error> {
+        if let Some(Some(_)) = self.proxy {
+            return Err(Error::Unsupported("proxy is not supported"));
+        }
+        if let Some(sync_mode) = self.sync_mode {
+            if sync_mode != SyncMode::Full {
+                return Err(Error::Unsupported("only full sync mode is supported"));
+            }
+        }
+        Ok(())
+    }
 }
 
 impl Config {
     pub fn new(encryptedwalletkey: &str, keyroot: &str, lookahead: u32, birth: u64, network: Network) -> Config {
         Config {
+            version: CONFIG_VERSION,
             encryptedwalletkey: String::from(encryptedwalletkey),
             keyroot: String::from(keyroot),
             lookahead,
@@ -47,20 +156,56 @@ impl Config {
             bitcoin_peers: vec![],
             bitcoin_connections: 0,
             bitcoin_discovery: false,
+            proxy: None,
+            sync_mode: SyncMode::default(),
+            user_agent: default_user_agent(),
+            gap_limit: None,
+            backend: Backend::default(),
+            fee_policy: FeePolicy::default(),
         }
     }
 
-    pub fn update(&self, bitcoin_peers: Vec<SocketAddr>, bitcoin_connections: usize, bitcoin_discovery: bool) -> Config {
-        Config {
-            encryptedwalletkey: self.encryptedwalletkey.clone(),
-            keyroot: self.keyroot.clone(),
-            lookahead: self.lookahead,
-            birth: self.birth,
-            network: self.network,
-            bitcoin_peers,
-            bitcoin_connections,
-            bitcoin_discovery,
+    /// a copy with the fields of the update applied, fails if the update does not validate
+    pub fn update(&self, update: ConfigUpdate) -> Result<Config, Error> {
+        update.validate()?;
+        let mut config = self.clone();
+        if let Some(bitcoin_peers) = update.bitcoin_peers {
+            config.bitcoin_peers = bitcoin_peers;
+        }
+        if let Some(bitcoin_connections) = update.bitcoin_connections {
+            config.bitcoin_connections = bitcoin_connections;
+        }
+        if let Some(bitcoin_discovery) = update.bitcoin_discovery {
+            config.bitcoin_discovery = bitcoin_discovery;
         }
+        if let Some(proxy) = update.proxy {
+            config.proxy = proxy;
+        }
+        if let Some(sync_mode) = update.sync_mode {
+            config.sync_mode = sync_mode;
+        }
+        if let Some(user_agent) = update.user_agent {
+            config.user_agent = user_agent;
+        }
+        if let Some(gap_limit) = update.gap_limit {
+            config.gap_limit = gap_limit;
+        }
+        if let Some(backend) = update.backend {
+            config.backend = backend;
+        }
+        if let Some(fee_policy) = update.fee_policy {
+            config.fee_policy = fee_policy;
+        }
+        Ok(config)
+    }
+
+    // bring a config read from an older file up to CONFIG_VERSION
+    fn migrate(mut self) -> Config {
+        // version 0 had no sections, serde filled them with defaults
+        if self.version == 0 {
+            self.version = 1;
+        }
+        self
     }
 }
 
@@ -74,15 +219,24 @@ pub fn save(config_path: &Path, file_path: &Path, config: &Config) -> Result<(),
     Ok(())
 }
 
+/// load the config, a file of an older version is migrated and saved
 pub fn load(file_path: &Path) -> Result<Config, Error> {
     // get config (if any)
     let mut file = File::open(file_path)?;
     let mut config_string = String::new();
     file.read_to_string(&mut config_string)?;
-    match toml::from_str(config_string.as_str()) {
-        Ok(c) => Ok(c),
-        Err(e) => Err(e.into())
+    let config: Config = toml::from_str(config_string.as_str())?;
+    if config.version > CONFIG_VERSION {
+        return Err(Error::Unsupported("config file is of a newer version"));
+    }
+    if config.version < CONFIG_VERSION {
+        let config = config.migrate();
+        if let Some(config_path) = file_path.parent() {
+            save(config_path, file_path, &config)?;
+        }
+        return Ok(config);
     }
+    Ok(config)
 }
 
 pub fn remove(config_path: &Path) -> Result<(), Error> {
@@ -96,13 +250,14 @@ pub fn remove(config_path: &Path) -> Result<(), Error> {
 mod test {
     use std::{fs, io};
     use std::error::Error;
+    use std::net::SocketAddr;
     use std::path::PathBuf;
     use std::str::FromStr;
 
     use bitcoin::Network;
 
     use crate::config;
-    use crate::config::Config;
+    use crate::config::{Backend, Config, CONFIG_VERSION, ConfigUpdate, FeePolicy, SyncMode};
 
     #[test]
     fn save_load_delete() {
@@ -145,7 +300,11 @@ mod test {
         assert_eq!(loaded, test_config);
 
         let bitcoin_peers = vec! {"127.0.0.1:8080".parse().unwrap(), "127.0.0.1:8081".parse().unwrap(), "127.0.0.1:8082".parse().unwrap()};
-        let updated = loaded.update(bitcoin_peers, 10, false);
+        let updated = loaded.update(ConfigUpdate {
+            bitcoin_peers: Some(bitcoin_peers),
+            bitcoin_connections: Some(10),
+            ..Default::default()
+        }).unwrap();
         let saved_updated = config::save(&config_path, &file_path, &updated);
         assert_eq!(saved_updated.is_ok(), true);
 
@@ -162,5 +321,79 @@ mod test {
         let loaded_updated = config::load(&file_path);
         assert_eq!(loaded_updated.is_ok(), false);
     }
+
+    #[test]
+    fn update_sections() {
+        let test_config = Config::new("encryptedwalletkey", "keyroot", 0, 0, Network::Testnet);
+        let updated = test_config.update(ConfigUpdate {
+            sync_mode: Some(SyncMode::Full),
+            gap_limit: Some(Some(100)),
+            fee_policy: Some(FeePolicy { fee_per_vbyte: 5, max_fee_per_vbyte: Some(50) }),
+            ..Default::default()
+        }).unwrap();
+        assert_eq!(updated.bitcoin_peers, test_config.bitcoin_peers);
+        assert_eq!(updated.sync_mode, SyncMode::Full);
+        assert_eq!(updated.gap_limit, Some(100));
+        assert_eq!(updated.fee_policy.max_fee_per_vbyte, Some(50));
+
+        let toml = toml::to_string(&updated).unwrap();
+        assert_eq!(toml::from_str::<Config>(toml.as_str()).unwrap(), updated);
+
+        let removed = updated.update(ConfigUpdate { proxy: Some(None), ..Default::default() }).unwrap();
+        assert_eq!(removed.proxy, None);
+
+        // not supported yet, the wallet would not start
+        assert!(updated.update(ConfigUpdate { proxy: Some(Some("127.0.0.1:9050".parse().unwrap())), ..Default::default() }).is_err());
+        assert!(updated.update(ConfigUpdate { sync_mode: Some(SyncMode::Headers), ..Default::default() }).is_err());
+    }
+
+    #[derive(Serialize)]
+    struct ConfigV0 {
+        encryptedwalletkey: String,
+        keyroot: String,
+        lookahead: u32,
+        birth: u64,
+        network: Network,
+        bitcoin_peers: Vec<SocketAddr>,
+        bitcoin_connections: usize,
+        bitcoin_discovery: bool,
+    }
+
+    #[test]
+    fn migrate_version_0() {
+        let config_path = PathBuf::from("./test_config_v0");
+        let mut file_path = config_path.clone();
+        file_path.push("bdk.cfg");
+        fs::create_dir_all(&config_path).unwrap();
+        // written before the config had a version and sections
+        let v0 = ConfigV0 {
+            encryptedwalletkey: "00".to_string(),
+            keyroot: "keyroot".to_string(),
+            lookahead: 10,
+            birth: 1,
+            network: Network::Regtest,
+            bitcoin_peers: vec!("127.0.0.1:18444".parse().unwrap()),
+            bitcoin_connections: 1,
+            bitcoin_discovery: false,
+        };
+        fs::write(&file_path, toml::to_string(&v0).unwrap()).unwrap();
+
+        let loaded = config::load(&file_path).unwrap();
+        assert_eq!(loaded.version, CONFIG_VERSION);
+        assert_eq!(loaded.bitcoin_connections, 1);
+        assert_eq!(loaded.sync_mode, SyncMode::Full);
+        assert_eq!(loaded.backend, Backend::P2P);
+        assert_eq!(loaded.user_agent, config::DEFAULT_USER_AGENT);
+        assert_eq!(loaded.fee_policy, FeePolicy::default());
+
+        // migrated file was saved
+        let saved = fs::read_to_string(&file_path).unwrap();
+        assert!(saved.contains("version = 1"));
+
+        fs::write(&file_path, saved.replace("version = 1", "version = 99")).unwrap();
+        assert!(config::load(&file_path).is_err());
+
+        config::remove(&config_path).unwrap();
+    }
 }
 
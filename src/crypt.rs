@@ -0,0 +1,185 @@
+/*
+ * Copyright 2020 BDK Team
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Encryption of database rows.
+//!
+//! Rows are sealed with a random data key. The data key is stored wrapped by a key derived from
+//! the secret the app provides, so changing the secret re-wraps the data key only.
+
+use bitcoin_hashes::{Hash, HashEngine, sha256};
+use bitcoin_hashes::hmac::{Hmac, HmacEngine};
+use chacha20poly1305::ChaCha20Poly1305;
+use chacha20poly1305::aead::{Aead, NewAead, Payload};
+use chacha20poly1305::aead::generic_array::GenericArray;
+use rand::{RngCore, thread_rng};
+use sha2::Sha256;
+
+use crate::error::Error;
+
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+pub const SALT_LEN: usize = 16;
+const KDF_ROUNDS: u32 = 100_000;
+
+/// unlocks an encrypted database
+pub enum DbSecret<'a> {
+    /// stretched with PBKDF2, e.g. the wallet passphrase
+    Passphrase(&'a str),
+    /// a key managed by the app, e.g. in a hardware keystore
+    Key([u8; KEY_LEN]),
+}
+
+impl<'a> DbSecret<'a> {
+    // the key wrapping the data key
+    fn wrapping_cipher(&self, salt: &[u8]) -> Cipher {
+        match self {
+            DbSecret::Passphrase(passphrase) => Cipher::new(&pbkdf2_sha256(passphrase.as_bytes(), salt, KDF_ROUNDS)),
+            DbSecret::Key(key) => Cipher::new(key)
+        }
+    }
+}
+
+/// seals rows and computes keys to look them up
+pub struct Cipher {
+    aead: ChaCha20Poly1305,
+    index_key: [u8; KEY_LEN],
+}
+
+impl Cipher {
+    fn new(key: &[u8]) -> Cipher {
+        Cipher {
+            aead: ChaCha20Poly1305::new(GenericArray::clone_from_slice(&hmac(key, b"bdk seal"))),
+            index_key: hmac(key, b"bdk index"),
+        }
+    }
+
+    /// a key stretched from the passphrase, for data kept outside the database
+    pub fn from_passphrase(passphrase: &str, salt: &[u8]) -> Cipher {
+        DbSecret::Passphrase(passphrase).wrapping_cipher(salt)
+    }
+
+    /// a new random data key, returns its cipher and the data key wrapped by the secret
+    pub fn generate(secret: &DbSecret, salt: &[u8]) -> Result<(Cipher, Vec<u8>), Error> {
+        let mut data_key = [0u8; KEY_LEN];
+        thread_rng().fill_bytes(&mut data_key);
+        Ok((Cipher::new(&data_key), secret.wrapping_cipher(salt).seal(&data_key)?))
+    }
+
+    /// unwrap the data key
+    pub fn unlock(secret: &DbSecret, salt: &[u8], wrapped: &[u8]) -> Result<Cipher, Error> {
+        let data_key = secret.wrapping_cipher(salt).open(wrapped).map_err(|_| Error::WrongPassphrase)?;
+        Ok(Cipher::new(&data_key))
+    }
+
+    /// the data key of the cipher unwrapped with old wrapped by new
+    pub fn rewrap(old: &DbSecret, old_salt: &[u8], wrapped: &[u8], new: &DbSecret, new_salt: &[u8]) -> Result<Vec<u8>, Error> {
+        let data_key = old.wrapping_cipher(old_salt).open(wrapped).map_err(|_| Error::WrongPassphrase)?;
+        new.wrapping_cipher(new_salt).seal(&data_key)
+    }
+
+    /// random nonce followed by the cipher text
+    pub fn seal(&self, plain: &[u8]) -> Result<Vec<u8>, Error> {
+        self.seal_with(plain, &[])
+    }
+
+    pub fn open(&self, sealed: &[u8]) -> Result<Vec<u8>, Error> {
+        self.open_with(sealed, &[])
+    }
+
+    /// seal, also authenticating associated data kept in the clear
+    pub fn seal_with(&self, plain: &[u8], associated: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut nonce = [0u8; NONCE_LEN];
+        thread_rng().fill_bytes(&mut nonce);
+        let mut sealed = nonce.to_vec();
+        sealed.extend(self.aead.encrypt(GenericArray::from_slice(&nonce), Payload { msg: plain, aad: associated })
+            .map_err(|_| Error::Unsupported("can not encrypt"))?);
+        Ok(sealed)
+    }
+
+    pub fn open_with(&self, sealed: &[u8], associated: &[u8]) -> Result<Vec<u8>, Error> {
+        if sealed.len() < NONCE_LEN {
+            return Err(Error::Corrupted("sealed data is too short"));
+        }
+        self.aead.decrypt(GenericArray::from_slice(&sealed[..NONCE_LEN]), Payload { msg: &sealed[NONCE_LEN..], aad: associated })
+            .map_err(|_| Error::Corrupted("can not decrypt"))
+    }
+
+    /// a keyed hash of id, equal ids have equal indexes
+    pub fn index(&self, id: &str) -> String {
+        hex::encode(hmac(&self.index_key, id.as_bytes()))
+    }
+}
+
+pub fn random_salt() -> [u8; SALT_LEN] {
+    let mut salt = [0u8; SALT_LEN];
+    thread_rng().fill_bytes(&mut salt);
+    salt
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> [u8; KEY_LEN] {
+    let mut engine = HmacEngine::<sha256::Hash>::new(key);
+    engine.input(data);
+    Hmac::<sha256::Hash>::from_engine(engine).into_inner()
+}
+
+// PBKDF2 with HMAC-SHA256
+fn pbkdf2_sha256(passphrase: &[u8], salt: &[u8], rounds: u32) -> [u8; KEY_LEN] {
+    let mut key = [0u8; KEY_LEN];
+    pbkdf2::pbkdf2::<::hmac::Hmac<Sha256>>(passphrase, salt, rounds as usize, &mut key);
+    key
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Cipher, DbSecret, pbkdf2_sha256, random_salt};
+    use crate::error::Error;
+
+    #[test]
+    fn pbkdf2_vector() {
+        // RFC 7914 section 11, first 32 bytes
+        assert_eq!(hex::encode(pbkdf2_sha256(b"passwd", b"salt", 1)),
+                   "55ac046e56e3089fec1691c22544b605f94185216dde0465e68b9d57c20dacbc");
+        // RFC 6070 inputs with HMAC-SHA256, first 32 bytes
+        assert_eq!(hex::encode(pbkdf2_sha256(b"password", b"salt", 2)),
+                   "ae4d0c95af6b46d32d0adff928f06dd02a303f8ef3c251dfd6e2d85a95474c43");
+        assert_eq!(hex::encode(pbkdf2_sha256(b"password", b"salt", 4096)),
+                   "c5e478d59288c841aa530db6845c4c8d962893a001ce4e11a4963873aa98134a");
+        assert_eq!(hex::encode(pbkdf2_sha256(b"passwordPASSWORDpassword", b"saltSALTsaltSALTsaltSALTsaltSALTsalt", 4096)),
+                   "348c89dbcbd32b2f32d814b8116e84cf2b17347ebc1800181c4e2a1fb8dd53e1");
+    }
+
+    #[test]
+    fn seal_open_rewrap() {
+        let salt = random_salt();
+        let (cipher, wrapped) = Cipher::generate(&DbSecret::Key([1u8; 32]), &salt).unwrap();
+        let sealed = cipher.seal(b"coins").unwrap();
+        assert_eq!(cipher.open(sealed.as_slice()).unwrap(), b"coins");
+        assert_ne!(cipher.seal(b"coins").unwrap(), sealed);
+        assert_eq!(cipher.index("txid"), cipher.index("txid"));
+
+        match Cipher::unlock(&DbSecret::Key([2u8; 32]), &salt, wrapped.as_slice()) {
+            Err(Error::WrongPassphrase) => {}
+            _ => panic!("unwrapped with the wrong key")
+        }
+
+        let new_salt = random_salt();
+        let rewrapped = Cipher::rewrap(&DbSecret::Key([1u8; 32]), &salt, wrapped.as_slice(),
+                                       &DbSecret::Passphrase("new passphrase"), &new_salt).unwrap();
+        let unwrapped = Cipher::unlock(&DbSecret::Passphrase("new passphrase"), &new_salt, rewrapped.as_slice()).unwrap();
+        assert_eq!(unwrapped.open(sealed.as_slice()).unwrap(), b"coins");
+        assert_eq!(unwrapped.index("txid"), cipher.index("txid"));
+    }
+}
@@ -24,115 +24,308 @@ use std::str::FromStr;
 use std::sync::{Arc, Mutex};
 use std::time::SystemTime;
 
-use bitcoin::{Network, OutPoint, PublicKey, Script, TxOut};
-use bitcoin::consensus::{deserialize, serialize};
-use bitcoin::util::bip32::ExtendedPubKey;
+use bitcoin::{Network, PublicKey};
 use bitcoin_hashes::{sha256, sha256d};
 use bitcoin_hashes::hex::FromHex;
-use bitcoin_wallet::account::{Account, AccountAddressType, KeyDerivation, MasterAccount};
-use bitcoin_wallet::coins::{Coin, Coins};
-use bitcoin_wallet::proved::ProvedTransaction;
+use bitcoin_wallet::account::{Account, MasterAccount};
+use bitcoin_wallet::coins::Coins;
 use byteorder::{ByteOrder, LittleEndian};
 use log::debug;
 use rand::{Rng, RngCore, thread_rng};
 use rand_distr::Poisson;
 use rusqlite::{Connection, NO_PARAMS, OptionalExtension, ToSql, Transaction};
-use rusqlite::types::{Null, ValueRef};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
 use siphasher::sip::SipHasher;
 
+use crate::crypt::{Cipher, DbSecret, random_salt};
 use crate::error::Error;
-
-pub type SharedDB = Arc<Mutex<DB>>;
+use crate::labels::{Label, LabelKind};
+use crate::storage;
+use crate::storage::{AccountRow, BAN_TIME, CoinChanges, CoinRow, DownloadProgress, IssuedAddress, IssuedAddressRow, Snapshot, Storage, StorageTransaction, TransactionRow, TxoutRow, Unconfirmed, WalletTransaction};
 
 const ADDRESS_SLOTS: u64 = 10000;
 
+// Schema migrations, the one at index i brings the schema from version i to i + 1.
+// Databases created before versioning are at version 0 with some of the tables of version 1 or 2,
+// so these two create only what is missing. Append new migrations, never change released ones.
+const MIGRATIONS: &[&str] = &[
+    // 1: tables of the first release
+    r#"
+        create table if not exists seed (
+            k0 number,
+            k1 number
+        );
+
+        create table if not exists address (
+            network text,
+            slot number,
+            ip text,
+            connected number,
+            last_seen number,
+            banned number,
+            primary key(network, slot)
+        ) without rowid;
+
+        create table if not exists account (
+            account number,
+            sub number,
+            address_type number,
+            master text,
+            instantiated blob,
+            primary key(account, sub)
+        ) without rowid;
+
+        create table if not exists coins (
+            txid text,
+            vout number,
+            value number,
+            script blob,
+            account number,
+            sub number,
+            kix number,
+            tweak text,
+            csv number,
+            proof blob,
+            primary key(txid, vout)
+        ) without rowid;
+
+        create table if not exists processed (
+            block text
+        );
+
+        create table if not exists txout (
+            txid text primary key,
+            tx blob,
+            confirmed text,
+            publisher blob,
+            id text,
+            term number
+        ) without rowid;
+    "#,
+    // 2: block download progress
+    r#"
+        create table if not exists download (
+            verified_height number,
+            verified_block text,
+            queued_height number,
+            queued_block text
+        );
+    "#,
+    // 3: encryption, the wrapped data key and sealed rows
+    r#"
+        create table if not exists crypt (
+            salt blob,
+            wrapped blob
+        );
+
+        alter table coins add column sealed blob;
+        alter table txout add column sealed blob;
+        alter table account add column sealed blob;
+    "#,
+    // 4: all transactions of the wallet
+    r#"
+        create table if not exists transactions (
+            txid text primary key,
+            tx blob,
+            block text,
+            height number,
+            position number,
+            first_seen number,
+            sealed blob
+        ) without rowid;
+    "#,
+    // 5: addresses handed out to receive payments
+    r#"
+        create table if not exists issued (
+            address text primary key,
+            account number,
+            sub number,
+            kix number,
+            issued number,
+            funded number,
+            label text,
+            sealed blob
+        ) without rowid;
+    "#,
+    // 6: labels of transactions, addresses, outputs and xpubs
+    r#"
+        create table if not exists labels (
+            kind text,
+            reference text,
+            label text,
+            origin text,
+            sealed blob,
+            primary key (kind, reference)
+        ) without rowid;
+    "#,
+];
+
 pub struct DB {
-    connection: Connection
+    connection: Connection,
+    // set once an encrypted database is unlocked
+    cipher: Option<Cipher>,
 }
 
 impl DB {
     pub fn memory() -> Result<DB, Error> {
-        Ok(DB { connection: Connection::open_in_memory()? })
+        Ok(DB { connection: Connection::open_in_memory()?, cipher: None })
     }
 
     pub fn new(path: &std::path::Path) -> Result<DB, Error> {
-        Ok(DB { connection: Connection::open(path)? })
+        Ok(DB { connection: Connection::open(path)?, cipher: None })
     }
 
-    pub fn transaction(&mut self) -> TX {
-        TX { tx: self.connection.transaction().expect("can not start db transaction") }
+    /// true if coins, transactions, accounts and issued addresses are stored encrypted
+    pub fn is_encrypted(&self) -> Result<bool, Error> {
+        Ok(self.read_crypt()?.is_some())
     }
-}
 
-pub struct TX<'db> {
-    tx: Transaction<'db>
-}
+    /// unlock an encrypted database, fails with WrongPassphrase if the secret does not fit
+    pub fn unlock(&mut self, secret: &DbSecret) -> Result<(), Error> {
+        let (salt, wrapped) = self.read_crypt()?.ok_or(Error::Unsupported("database is not encrypted"))?;
+        self.cipher = Some(Cipher::unlock(secret, salt.as_slice(), wrapped.as_slice())?);
+        Ok(())
+    }
 
-impl<'db> TX<'db> {
-    pub fn commit(self) {
-        self.tx.commit().expect("failed to commit db transaction");
+    /// encrypt the coins, transactions, accounts and issued addresses of a plain database, the database stays unlocked
+    pub fn encrypt(&mut self, secret: &DbSecret) -> Result<(), Error> {
+        if self.is_encrypted()? {
+            return Err(Error::Unsupported("database is already encrypted"));
+        }
+        let salt = random_salt();
+        let (cipher, wrapped) = Cipher::generate(secret, &salt)?;
+        {
+            let mut tx = TX { tx: self.connection.transaction()?, cipher: None };
+            let coins = tx.read_coin_rows()?;
+            let txouts = tx.read_txout_rows(false)?;
+            let accounts = tx.read_account_rows()?;
+            let transactions = tx.read_transaction_rows(None)?;
+            let issued = tx.read_issued_rows()?;
+            let labels = tx.read_labels()?;
+            tx.tx.execute_batch(r#"
+                delete from coins;
+                delete from txout;
+                delete from account;
+                delete from transactions;
+                delete from issued;
+                delete from labels;
+            "#)?;
+
+            tx.cipher = Some(&cipher);
+            for row in &coins {
+                tx.insert_coin_row(row)?;
+            }
+            for (row, confirmed) in &txouts {
+                tx.insert_txout_row(row, confirmed.as_ref().map(|c| cipher.index(c.as_str())))?;
+            }
+            for row in &accounts {
+                tx.insert_account_row(row)?;
+            }
+            for row in &transactions {
+                tx.insert_transaction_row(row)?;
+            }
+            for row in &issued {
+                tx.insert_issued_row(row)?;
+            }
+            for label in &labels {
+                tx.store_label(label)?;
+            }
+            tx.tx.execute(r#"
+                insert into crypt (salt, wrapped) values (?1, ?2)
+            "#, &[&salt.to_vec() as &dyn ToSql, &wrapped])?;
+            tx.tx.commit()?;
+        }
+        // plain rows might remain in free pages otherwise
+        self.connection.execute_batch("VACUUM")?;
+        self.cipher = Some(cipher);
+        Ok(())
     }
 
-    pub fn rollback(self) {
-        self.tx.rollback().expect("failed to roll back db transaction");
+    /// change the secret of an encrypted database, only the wrapped data key is rewritten
+    pub fn change_secret(&mut self, old: &DbSecret, new: &DbSecret) -> Result<(), Error> {
+        let (salt, wrapped) = self.read_crypt()?.ok_or(Error::Unsupported("database is not encrypted"))?;
+        let new_salt = random_salt();
+        let rewrapped = Cipher::rewrap(old, salt.as_slice(), wrapped.as_slice(), new, &new_salt)?;
+        self.connection.execute(r#"
+            update crypt set salt = ?1, wrapped = ?2
+        "#, &[&new_salt.to_vec() as &dyn ToSql, &rewrapped])?;
+        Ok(())
     }
 
-    pub fn create_tables(&mut self) {
-        self.tx.execute_batch(r#"
-            create table if not exists seed (
-                k0 number,
-                k1 number
-            );
+    fn read_crypt(&self) -> Result<Option<(Vec<u8>, Vec<u8>)>, Error> {
+        Ok(self.connection.query_row(r#"
+            select salt, wrapped from crypt
+        "#, NO_PARAMS, |r| Ok((r.get::<usize, Vec<u8>>(0)?, r.get::<usize, Vec<u8>>(1)?))).optional()?)
+    }
 
-            create table if not exists address (
-                network text,
-                slot number,
-                ip text,
-                connected number,
-                last_seen number,
-                banned number,
-                primary key(network, slot)
-            ) without rowid;
-
-            create table if not exists account (
-                account number,
-                sub number,
-                address_type number,
-                master text,
-                instantiated blob,
-                primary key(account, sub)
-            ) without rowid;
-
-            create table if not exists coins (
-                txid text,
-                vout number,
-                value number,
-                script blob,
-                account number,
-                sub number,
-                kix number,
-                tweak text,
-                csv number,
-                proof blob,
-                primary key(txid, vout)
-            ) without rowid;
-
-            create table if not exists processed (
-                block text
+    /// bring the schema up to the latest version, each migration runs in its own transaction.
+    /// Returns the schema version.
+    pub fn migrate(&mut self) -> Result<u32, Error> {
+        self.migrate_with(MIGRATIONS)
+    }
+
+    fn migrate_with(&mut self, migrations: &[&str]) -> Result<u32, Error> {
+        self.connection.execute_batch(r#"
+            create table if not exists schema_version (
+                version number
             );
+        "#)?;
+        let mut version = self.schema_version()?;
+        if version as usize > migrations.len() {
+            return Err(Error::Unsupported("database is of a newer version"));
+        }
+        for migration in &migrations[version as usize..] {
+            // rolled back if dropped before commit
+            let tx = self.connection.transaction()?;
+            tx.execute_batch(migration)?;
+            version += 1;
+            tx.execute(r#"
+                insert or replace into schema_version (rowid, version) values (1, ?1)
+            "#, &[&version as &dyn ToSql])?;
+            tx.commit()?;
+            debug!("migrated database to schema version {}", version);
+        }
+        Ok(version)
+    }
+
+    /// 0 for databases created before versioning
+    pub fn schema_version(&self) -> Result<u32, Error> {
+        Ok(self.connection.query_row(r#"
+            select version from schema_version where rowid = 1
+        "#, NO_PARAMS, |r| r.get::<usize, u32>(0)).optional()?.unwrap_or(0))
+    }
+}
+
+impl Storage for DB {
+    fn transaction<'a>(&'a mut self) -> Result<Box<dyn StorageTransaction + 'a>, Error> {
+        Ok(Box::new(TX { tx: self.connection.transaction()?, cipher: self.cipher.as_ref() }))
+    }
+
+    /// move the write ahead log, if any, into the database file
+    fn flush(&mut self) -> Result<(), Error> {
+        Ok(self.connection.execute_batch("PRAGMA wal_checkpoint(TRUNCATE)")?)
+    }
+}
 
-            create table if not exists txout (
-                txid text primary key,
-                tx blob,
-                confirmed text,
-                publisher blob,
-                id text,
-                term number
-            ) without rowid;
-        "#).expect("failed to create db tables");
+/// a transaction of the SQLite storage.
+/// An encrypted database keeps coins, transactions, accounts and issued addresses CBOR encoded and sealed in the
+/// sealed column, with keyed hashes in the columns used to look them up.
+pub struct TX<'db> {
+    tx: Transaction<'db>,
+    cipher: Option<&'db Cipher>,
+}
+
+impl<'db> StorageTransaction for TX<'db> {
+    fn commit(self: Box<Self>) -> Result<(), Error> {
+        Ok(self.tx.commit()?)
     }
 
-    pub fn rescan(&mut self, after: &sha256d::Hash) -> Result<(), Error> {
+    fn rollback(self: Box<Self>) -> Result<(), Error> {
+        Ok(self.tx.rollback()?)
+    }
+
+    fn rescan(&mut self, after: &sha256d::Hash) -> Result<(), Error> {
         self.tx.execute(r#"
             update processed set block = ?1
         "#, &[&after.to_string() as &dyn ToSql])?;
@@ -142,189 +335,193 @@ impl<'db> TX<'db> {
         self.tx.execute(r#"
             delete from coins
         "#, NO_PARAMS)?;
+        self.tx.execute(r#"
+            delete from download
+        "#, NO_PARAMS)?;
+        self.tx.execute(r#"
+            delete from transactions
+        "#, NO_PARAMS)?;
+        // sealed rows hold the funding height too
+        for mut row in self.read_issued_rows()? {
+            row.funded = None;
+            self.insert_issued_row(&row)?;
+        }
         Ok(())
     }
 
-    pub fn store_txout(&mut self, tx: &bitcoin::Transaction, funding: Option<(&PublicKey, &sha256::Hash, u16)>) -> Result<(), Error> {
-        if let Some((publisher, id, term)) = funding {
-            self.tx.execute(r#"
-            insert or replace into txout (txid, tx, publisher, id, term) values (?1, ?2, ?3, ?4, ?5)
-        "#, &[&tx.txid().to_string() as &dyn ToSql,
-                &serialize(tx),
-                &publisher.to_bytes(), &id.to_string(), &term])?;
+    fn store_txout(&mut self, tx: &bitcoin::Transaction, funding: Option<(&PublicKey, &sha256::Hash, u16)>) -> Result<(), Error> {
+        self.insert_txout_row(&TxoutRow::new(tx, funding), None)
+    }
+
+    fn read_unconfirmed(&self) -> Result<Vec<Unconfirmed>, Error> {
+        self.read_txout_rows(true)?.into_iter().map(|(row, _)| row.unconfirmed()).collect()
+    }
+
+    fn read_processed(&mut self) -> Result<Option<sha256d::Hash>, Error> {
+        Ok(self.tx.query_row(r#"
+            select block from processed where rowid = 1
+        "#, NO_PARAMS, |r| r.get::<usize, String>(0)).optional()?
+            .map(|block| sha256d::Hash::from_hex(block.as_str())).transpose()?)
+    }
+
+    fn store_processed(&mut self, block_id: &sha256d::Hash) -> Result<(), Error> {
+        self.tx.execute(r#"
+            insert or replace into processed (rowid, block) values (1, ?1)
+        "#, &[&block_id.to_string() as &dyn ToSql])?;
+        Ok(())
+    }
+
+    fn read_download_progress(&mut self) -> Result<Option<DownloadProgress>, Error> {
+        if let Some((verified_height, verified_block, queued_height, queued_block)) = self.tx.query_row(r#"
+            select verified_height, verified_block, queued_height, queued_block from download where rowid = 1
+        "#, NO_PARAMS, |r| Ok((r.get::<usize, u32>(0)?, r.get::<usize, String>(1)?, r.get::<usize, u32>(2)?, r.get::<usize, String>(3)?))).optional()? {
+            Ok(Some(DownloadProgress {
+                verified: (verified_height, sha256d::Hash::from_hex(verified_block.as_str())?),
+                queued: (queued_height, sha256d::Hash::from_hex(queued_block.as_str())?),
+            }))
         } else {
-            self.tx.execute(r#"
-            insert or replace into txout (txid, tx) values (?1, ?2)
-        "#, &[&tx.txid().to_string() as &dyn ToSql,
-                &serialize(tx)])?;
+            Ok(None)
         }
+    }
+
+    fn store_download_progress(&mut self, progress: &DownloadProgress) -> Result<(), Error> {
+        self.tx.execute(r#"
+            insert or replace into download (rowid, verified_height, verified_block, queued_height, queued_block) values (1, ?1, ?2, ?3, ?4)
+        "#, &[&progress.verified.0 as &dyn ToSql, &progress.verified.1.to_string(),
+            &progress.queued.0, &progress.queued.1.to_string()])?;
         Ok(())
     }
 
-    pub fn read_unconfirmed(&self) -> Result<Vec<(bitcoin::Transaction, Option<(PublicKey, sha256::Hash, u16)>)>, Error> {
-        let mut result = Vec::new();
-        // remove unconfirmed spend
-        let mut query = self.tx.prepare(r#"
-            select tx, publisher, id, term from txout where confirmed is null
-        "#)?;
-        for r in query.query_map(NO_PARAMS, |r| {
-            Ok((r.get_unwrap::<usize, Vec<u8>>(0),
-                match r.get_raw(1) {
-                    ValueRef::Null => None,
-                    ValueRef::Blob(publisher) => Some(publisher.to_vec()),
-                    _ => panic!("unexpected tweak type")
-                },
-                match r.get_raw(2) {
-                    ValueRef::Null => None,
-                    ValueRef::Text(id) => Some(id.to_vec()),
-                    _ => panic!("unexpected tweak type")
-                },
-                match r.get_raw(3) {
-                    ValueRef::Null => None,
-                    ValueRef::Integer(n) => Some(n as u16),
-                    _ => panic!("unexpected tweak type")
-                }))
-        })? {
-            let (tx, publisher, id, term) = r?;
-            result.push(
-                (deserialize::<bitcoin::Transaction>(tx.as_slice()).expect("can not deserialize stored transaction"),
-                 if let Some(publisher) = publisher {
-                     Some((PublicKey::from_slice(publisher.as_slice()).expect("stored publisher in txout not a pubkey"),
-                           sha256::Hash::from_hex(std::str::from_utf8(id.unwrap().as_slice()).unwrap()).expect("stored id in txout not hex"),
-                           term.unwrap()))
-                 } else { None },
-                ));
+    fn delete_processed(&mut self) -> Result<(), Error> {
+        self.tx.execute(r#"
+            delete from processed
+        "#, NO_PARAMS)?;
+        Ok(())
+    }
+
+    fn unconfirm(&mut self, block_id: &sha256d::Hash) -> Result<usize, Error> {
+        // rewritten as sealed rows hold the block too
+        for mut row in self.read_transaction_rows(Some(self.index(block_id.to_string())))? {
+            row.unconfirm();
+            self.insert_transaction_row(&row)?;
         }
-        Ok(result)
+        Ok(self.tx.execute(r#"
+            update txout set confirmed = null where confirmed = ?1
+        "#, &[&self.index(block_id.to_string()) as &dyn ToSql])?)
     }
 
-    pub fn read_seed(&mut self) -> Result<(u64, u64), Error> {
-        if let Some(seed) = self.tx.query_row(r#"
-            select k0, k1 from seed where rowid = 1
-        "#, NO_PARAMS, |r| Ok(
-            (r.get_unwrap::<usize, i64>(0) as u64,
-             r.get_unwrap::<usize, i64>(1) as u64))).optional()? {
-            return Ok(seed);
+    fn confirm(&mut self, txid: &sha256d::Hash, block_id: Option<&sha256d::Hash>) -> Result<usize, Error> {
+        Ok(self.tx.execute(r#"
+            update txout set confirmed = ?1 where txid = ?2
+        "#, &[&block_id.map(|b| self.index(b.to_string())) as &dyn ToSql, &self.index(txid.to_string())])?)
+    }
+
+    fn store_transaction(&mut self, transaction: &WalletTransaction) -> Result<(), Error> {
+        let mut row = TransactionRow::new(transaction);
+        if let Some(stored) = self.read_transaction_row(row.txid.as_str())? {
+            row.first_seen = row.first_seen.min(stored.first_seen);
+        }
+        self.insert_transaction_row(&row)
+    }
+
+    fn read_transactions(&self) -> Result<Vec<WalletTransaction>, Error> {
+        self.read_transaction_rows(None)?.iter().map(|row| row.to_transaction()).collect()
+    }
+
+    fn store_issued_address(&mut self, address: &IssuedAddress) -> Result<(), Error> {
+        self.insert_issued_row(&IssuedAddressRow::new(address))
+    }
+
+    fn read_issued_addresses(&self) -> Result<Vec<IssuedAddress>, Error> {
+        IssuedAddressRow::to_issued_addresses(self.read_issued_rows()?)
+    }
+
+    fn store_label(&mut self, label: &Label) -> Result<(), Error> {
+        if let Some(cipher) = self.cipher {
+            let reference = cipher.index(label.reference.as_str());
+            self.tx.execute(r#"
+                insert or replace into labels (kind, reference, sealed) values (?1, ?2, ?3)
+            "#, &[&label.kind.as_str() as &dyn ToSql, &reference, &seal(cipher, label, &[label.kind.as_str(), reference.as_str()])?])?;
         } else {
-            let k0 = thread_rng().next_u64();
-            let k1 = thread_rng().next_u64();
             self.tx.execute(r#"
-                insert or replace into seed (rowid, k0, k1) values (1, ?1, ?2)
-            "#, &[&(k0 as i64) as &dyn ToSql, &(k1 as i64)])?;
-            return Ok((k0, k1));
+                insert or replace into labels (kind, reference, label, origin) values (?1, ?2, ?3, ?4)
+            "#, &[&label.kind.as_str() as &dyn ToSql, &label.reference, &label.label, &label.origin])?;
         }
+        Ok(())
     }
 
-    pub fn read_processed(&mut self) -> Result<Option<sha256d::Hash>, Error> {
-        Ok(self.tx.query_row(r#"
-            select block from processed where rowid = 1
-        "#, NO_PARAMS, |r| Ok(sha256d::Hash::from_hex(r.get_unwrap::<usize, String>(0).as_str())
-            .expect("stored block not hex"))).optional()?)
+    fn delete_label(&mut self, kind: LabelKind, reference: &str) -> Result<usize, Error> {
+        Ok(self.tx.execute(r#"
+            delete from labels where kind = ?1 and reference = ?2
+        "#, &[&kind.as_str() as &dyn ToSql, &self.index(reference.to_string())])?)
     }
 
-    pub fn store_processed(&mut self, block_id: &sha256d::Hash) -> Result<(), Error> {
-        self.tx.execute(r#"
-            insert or replace into processed (rowid, block) values (1, ?1)
-        "#, &[&block_id.to_string() as &dyn ToSql])?;
-        Ok(())
+    fn read_labels(&self) -> Result<Vec<Label>, Error> {
+        let mut result = Vec::new();
+        if let Some(cipher) = self.cipher {
+            let mut query = self.tx.prepare(r#"
+                select kind, reference, sealed from labels
+            "#)?;
+            for r in query.query_map(NO_PARAMS, |r| Ok((r.get::<usize, String>(0)?, r.get::<usize, String>(1)?, r.get::<usize, Vec<u8>>(2)?)))? {
+                let (kind, reference, sealed) = r?;
+                result.push(unseal(cipher, sealed, &[kind.as_str(), reference.as_str()])?);
+            }
+        } else {
+            let mut query = self.tx.prepare(r#"
+                select kind, reference, label, origin from labels
+            "#)?;
+            for r in query.query_map(NO_PARAMS, |r| Ok((r.get::<usize, String>(0)?, r.get::<usize, String>(1)?, r.get::<usize, String>(2)?, r.get::<usize, Option<String>>(3)?)))? {
+                let (kind, reference, label, origin) = r?;
+                result.push(Label { kind: LabelKind::from_str(kind.as_str())?, reference, label, origin });
+            }
+        }
+        Ok(result)
     }
 
-    pub fn store_coins(&mut self, coins: &Coins) -> Result<(), Error> {
+    fn store_coins(&mut self, coins: &Coins) -> Result<(), Error> {
         self.tx.execute(r#"
             delete from coins;
         "#, NO_PARAMS)?;
-        let mut statement = self.tx.prepare(r#"
-            insert into coins (txid, vout, value, script, account, sub, kix, tweak, csv, proof)
-            values (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)
-        "#)?;
-        let proofs = coins.proofs();
-        for (outpoint, coin) in coins.confirmed() {
-            let proof = proofs.get(&outpoint.txid).expect("inconsistent wallet, missing proof");
-            let tweak = if let Some(ref tweak) = coin.derivation.tweak { hex::encode(tweak) } else { "".to_string() };
-
-            statement.execute(&[
-                &outpoint.txid.to_string() as &dyn ToSql, &outpoint.vout,
-                &(coin.output.value as i64), &coin.output.script_pubkey.to_bytes(),
-                &coin.derivation.account, &coin.derivation.sub, &coin.derivation.kix,
-                if tweak == "".to_string() {
-                    &Null
-                } else {
-                    &tweak as &dyn ToSql
-                },
-                if let Some(ref csv) = coin.derivation.csv {
-                    csv as &dyn ToSql
-                } else {
-                    &Null
-                },
-                &serde_cbor::ser::to_vec(&proof).expect("can not serialize proof")
-            ])?;
+        for row in CoinRow::from_coins(coins)? {
+            self.insert_coin_row(&row)?;
         }
 
+        let proofs = coins.proofs();
         for (unconfirmed, _) in self.read_unconfirmed()? {
             if let Some(proof) = proofs.values().find(|p| p.get_transaction().txid() == unconfirmed.txid()) {
                 self.tx.execute(r#"
                     update txout set confirmed = ?1 where txid = ?2
-                "#, &[&proof.get_block_hash().to_string() as &dyn ToSql, &proof.get_transaction().txid().to_string()])?;
+                "#, &[&self.index(proof.get_block_hash().to_string()) as &dyn ToSql, &self.index(unconfirmed.txid().to_string())])?;
             }
         }
 
         Ok(())
     }
 
-    pub fn read_coins(&mut self, master_account: &mut MasterAccount) -> Result<Coins, Error> {
-        // read confirmed
-        let mut query = self.tx.prepare(r#"
-            select txid, vout, value, script, account, sub, kix, tweak, csv, proof from coins
-        "#)?;
-        let mut coins = Coins::new();
-        for r in query.query_map::<(OutPoint, Coin, ProvedTransaction), &[&dyn ToSql], _>(NO_PARAMS, |r| {
-            Ok((
-                OutPoint {
-                    txid: sha256d::Hash::from_hex(r.get_unwrap::<usize, String>(0).as_str()).expect("transaction id not hex"),
-                    vout: r.get_unwrap::<usize, u32>(1),
-                },
-                Coin {
-                    output: TxOut {
-                        script_pubkey: Script::from(r.get_unwrap::<usize, Vec<u8>>(3)),
-                        value: r.get_unwrap::<usize, i64>(2) as u64,
-                    },
-                    derivation: KeyDerivation {
-                        account: r.get_unwrap::<usize, u32>(4),
-                        sub: r.get_unwrap::<usize, u32>(5),
-                        kix: r.get_unwrap::<usize, u32>(6),
-                        tweak: match r.get_raw(7) {
-                            ValueRef::Null => None,
-                            ValueRef::Text(tweak) => Some(hex::decode(tweak).expect("tweak not hex")),
-                            _ => panic!("unexpected tweak type")
-                        },
-                        csv: match r.get_raw(8) {
-                            ValueRef::Null => None,
-                            ValueRef::Integer(i) => Some(i as u16),
-                            _ => panic!("unexpected csv type")
-                        },
-                    },
-                },
-                serde_cbor::from_slice(r.get_unwrap::<usize, Vec<u8>>(9).as_slice()).expect("can not deserialize stored proof")
-            ))
-        })? {
-            let (point, coin, proof) = r?;
-            coins.add_confirmed(point, coin, proof);
-        }
-
-        // remove unconfirmed spend
-        let mut query = self.tx.prepare(r#"
-            select tx from txout where confirmed is null
-        "#)?;
-        for r in query.query_map(NO_PARAMS, |r| {
-            Ok(r.get_unwrap::<usize, Vec<u8>>(0))
-        })? {
-            let tx = deserialize::<bitcoin::Transaction>(r?.as_slice()).expect("can not deserialize stored transaction");
-            coins.process_unconfirmed_transaction(master_account, &tx);
+    fn update_coins(&mut self, coins: &Coins, changes: &CoinChanges) -> Result<(), Error> {
+        for outpoint in &changes.spent {
+            self.tx.execute(r#"
+                delete from coins where txid = ?1 and vout = ?2
+            "#, &[&self.index(outpoint.txid.to_string()) as &dyn ToSql, &outpoint.vout])?;
         }
-        Ok(coins)
+        for row in CoinRow::added(coins, changes)? {
+            self.insert_coin_row(&row)?;
+        }
+        for txid in &changes.proved {
+            if let Some(proof) = coins.proofs().get(txid) {
+                self.tx.execute(r#"
+                    update txout set confirmed = ?1 where txid = ?2
+                "#, &[&self.index(proof.get_block_hash().to_string()) as &dyn ToSql, &self.index(txid.to_string())])?;
+            }
+        }
+        Ok(())
+    }
+
+    fn read_coins(&mut self, master_account: &mut MasterAccount) -> Result<Coins, Error> {
+        CoinRow::to_coins(self.read_coin_rows()?, self.read_unconfirmed()?, master_account)
     }
 
-    pub fn store_master(&mut self, master: &MasterAccount) -> Result<usize, Error> {
+    fn store_master(&mut self, master: &MasterAccount) -> Result<usize, Error> {
         debug!("store master account");
         self.tx.execute(r#"
             delete from account;
@@ -336,36 +533,20 @@ impl<'db> TX<'db> {
         Ok(inserted)
     }
 
-    pub fn store_account(&mut self, account: &Account) -> Result<usize, Error> {
+    fn store_account(&mut self, account: &Account) -> Result<usize, Error> {
         debug!("store account {}/{}", account.account_number(), account.sub_account_number());
-        Ok(self.tx.execute(r#"
-            insert or replace into account (account, address_type, sub, master, instantiated)
-            values (?1, ?2, ?3, ?4, ?5)
-        "#, &[&account.account_number() as &dyn ToSql,
-            &account.address_type().as_u32(), &account.sub_account_number(), &account.master_public().to_string(),
-            &serde_cbor::ser::to_vec(&account.instantiated())?],
-        )?)
+        self.insert_account_row(&AccountRow::new(account)?)
     }
 
-    pub fn read_account(&mut self, account_number: u32, sub: u32, network: Network, look_ahead: u32) -> Result<Account, Error> {
+    fn read_account(&mut self, account_number: u32, sub: u32, network: Network, look_ahead: u32) -> Result<Account, Error> {
         debug!("read account {}/{}", account_number, sub);
-        Ok(self.tx.query_row(r#"
-            select address_type, master, instantiated from account where account = ?1 and sub = ?2
-        "#, &[&account_number as &dyn ToSql, &sub], |r| {
-            Ok(Account::new_from_storage(
-                AccountAddressType::from_u32(r.get_unwrap::<usize, u32>(0)),
-                account_number,
-                sub,
-                ExtendedPubKey::from_str(r.get_unwrap::<usize, String>(1).as_str()).expect("malformed master public stored"),
-                serde_cbor::from_slice(r.get_unwrap::<usize, Vec<u8>>(2).as_slice()).expect("malformed instantiated keys stored"),
-                0,
-                look_ahead,
-                network,
-            ))
-        })?)
-    }
-
-    pub fn store_address(&mut self, network: &str, address: &SocketAddr, mut connected: u64, mut last_seen: u64, mut banned: u64) -> Result<usize, Error> {
+        self.read_account_rows()?.iter()
+            .find(|row| row.account == account_number && row.sub == sub)
+            .ok_or(Error::Corrupted("missing account"))?
+            .to_account(network, look_ahead)
+    }
+
+    fn store_address(&mut self, network: &str, address: &SocketAddr, mut connected: u64, mut last_seen: u64, mut banned: u64) -> Result<usize, Error> {
         let (k0, k1) = self.read_seed()?;
         let mut siphasher = SipHasher::new_with_keys(k0, k1);
         siphasher.write(network.as_bytes());
@@ -379,10 +560,11 @@ impl<'db> TX<'db> {
                 select ip, connected, last_seen, banned from address where network = ?1 and slot = ?2
             "#, &[&network.to_string() as &dyn ToSql, &slot],
                                                                           |r| Ok(
-                                                                              (SocketAddr::from_str(r.get_unwrap::<usize, String>(0).as_str()).expect("address stored in db should be parsable"),
-                                                                               r.get_unwrap::<usize, i64>(1) as u64,
-                                                                               r.get_unwrap::<usize, i64>(2) as u64,
-                                                                               r.get_unwrap::<usize, i64>(3) as u64))) {
+                                                                              (r.get::<usize, String>(0)?,
+                                                                               r.get::<usize, i64>(1)? as u64,
+                                                                               r.get::<usize, i64>(2)? as u64,
+                                                                               r.get::<usize, i64>(3)? as u64))) {
+            let oldip = SocketAddr::from_str(oldip.as_str())?;
             // do not reduce last_seen or banned fields
             if oldip != *address {
                 let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
@@ -418,26 +600,19 @@ impl<'db> TX<'db> {
     // get an address not banned during the last day
     // the probability to be selected is exponentially higher for those with higher last_seen time
     // TODO mark tried connections, build slots instead of storing all. Replace only if not tried for long or banned
-    pub fn get_an_address(&self, network: &str, other_than: Arc<Mutex<HashSet<SocketAddr>>>) -> Result<Option<SocketAddr>, Error> {
-        const BAN_TIME: u64 = 60 * 60 * 24; // a day
-
+    fn get_an_address(&self, network: &str, other_than: Arc<Mutex<HashSet<SocketAddr>>>) -> Result<Option<SocketAddr>, Error> {
         let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
         let mut statement = self.tx.prepare(r#"
             select ip from address where network = ?2 and banned < ?1 order by last_seen desc
         "#)?;
         let other_than = other_than.lock().unwrap();
-        let eligible = statement.query_map::<SocketAddr, _, _>(
+        let eligible = statement.query_map::<String, _, _>(
             &[&((now - BAN_TIME) as i64) as &dyn ToSql, &network.to_string()],
-            |row| {
-                let s = row.get_unwrap::<usize, String>(0);
-                let addr = SocketAddr::from_str(s.as_str()).expect("address stored in db should be parsable");
-                Ok(addr)
-            })?
-            .filter_map(|socket|
-                match socket {
-                    Ok(a) => if !other_than.contains(&a) { Some(a) } else { None },
-                    Err(_) => None
-                }).collect::<Vec<_>>();
+            |row| row.get::<usize, String>(0))?
+            // skip what can not be read or parsed
+            .filter_map(|s| s.ok().and_then(|s| SocketAddr::from_str(s.as_str()).ok()))
+            .filter(|a| !other_than.contains(a))
+            .collect::<Vec<_>>();
         let len = eligible.len();
         if len == 0 {
             return Ok(None);
@@ -447,28 +622,327 @@ impl<'db> TX<'db> {
                 std::cmp::min(len - 1, thread_rng().sample::<f64, _>(
                     Poisson::new(len as f64 / 4.0).unwrap()) as usize)]))
     }
+
+    fn read_snapshot(&mut self) -> Result<Snapshot, Error> {
+        Ok(Snapshot::new(
+            self.read_coin_rows()?,
+            self.read_txout_rows(false)?.into_iter().map(|(row, confirmed)| (row, confirmed.is_some())).collect(),
+            self.read_account_rows()?,
+            self.read_processed()?,
+            self.read_download_progress()?,
+            self.read_transaction_rows(None)?,
+            self.read_issued_rows()?,
+            self.read_labels()?))
+    }
+
+    fn store_snapshot(&mut self, snapshot: &Snapshot) -> Result<(), Error> {
+        self.tx.execute_batch(r#"
+            delete from coins;
+            delete from txout;
+            delete from account;
+            delete from processed;
+            delete from download;
+            delete from transactions;
+            delete from issued;
+            delete from labels;
+        "#)?;
+        for row in snapshot.coins() {
+            self.insert_coin_row(row)?;
+        }
+        for (row, confirmed) in snapshot.txouts()? {
+            self.insert_txout_row(&row, confirmed.map(|c| self.index(c.to_string())))?;
+        }
+        for row in snapshot.accounts() {
+            self.insert_account_row(row)?;
+        }
+        if let Some(processed) = snapshot.processed() {
+            self.store_processed(&processed)?;
+        }
+        if let Some(download) = snapshot.download() {
+            self.store_download_progress(&download)?;
+        }
+        for row in snapshot.transactions() {
+            self.insert_transaction_row(row)?;
+        }
+        for row in snapshot.issued() {
+            self.insert_issued_row(row)?;
+        }
+        for label in snapshot.labels() {
+            self.store_label(label)?;
+        }
+        Ok(())
+    }
 }
 
+impl<'db> TX<'db> {
+    fn read_seed(&mut self) -> Result<(u64, u64), Error> {
+        if let Some(seed) = self.tx.query_row(r#"
+            select k0, k1 from seed where rowid = 1
+        "#, NO_PARAMS, |r| Ok(
+            (r.get_unwrap::<usize, i64>(0) as u64,
+             r.get_unwrap::<usize, i64>(1) as u64))).optional()? {
+            return Ok(seed);
+        } else {
+            let k0 = thread_rng().next_u64();
+            let k1 = thread_rng().next_u64();
+            self.tx.execute(r#"
+                insert or replace into seed (rowid, k0, k1) values (1, ?1, ?2)
+            "#, &[&(k0 as i64) as &dyn ToSql, &(k1 as i64)])?;
+            return Ok((k0, k1));
+        }
+    }
 
-pub fn init(config_path: &Path, coins: &Coins, master: &MasterAccount) {
-    let mut db = new(&config_path);
-    {
-        let mut tx = db.transaction();
-        tx.create_tables();
-        tx.commit();
+    // the stored form of an id that rows are looked up by
+    fn index(&self, id: String) -> String {
+        match self.cipher {
+            Some(cipher) => cipher.index(id.as_str()),
+            None => id
+        }
     }
-    {
-        let mut tx = db.transaction();
-        tx.store_coins(coins).expect("can not store new wallet's coins");
-        tx.store_master(master).expect("can not store new master account");
-        tx.commit();
+
+    // insert or replace
+    fn insert_coin_row(&self, row: &CoinRow) -> Result<(), Error> {
+        if let Some(cipher) = self.cipher {
+            let txid = cipher.index(row.txid.as_str());
+            self.tx.execute(r#"
+                insert or replace into coins (txid, vout, sealed) values (?1, ?2, ?3)
+            "#, &[&txid as &dyn ToSql, &row.vout, &seal(cipher, row, &[txid.as_str(), row.vout.to_string().as_str()])?])?;
+        } else {
+            self.tx.execute(r#"
+                insert or replace into coins (txid, vout, value, script, account, sub, kix, tweak, csv, proof)
+                values (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)
+            "#, &[&row.txid as &dyn ToSql, &row.vout, &row.value, &row.script,
+                &row.account, &row.sub, &row.kix, &row.tweak, &row.csv, &row.proof])?;
+        }
+        Ok(())
+    }
+
+    fn read_coin_rows(&self) -> Result<Vec<CoinRow>, Error> {
+        let mut result = Vec::new();
+        if let Some(cipher) = self.cipher {
+            let mut query = self.tx.prepare(r#"
+                select txid, vout, sealed from coins
+            "#)?;
+            for r in query.query_map(NO_PARAMS, |r| Ok((r.get::<usize, String>(0)?, r.get::<usize, u32>(1)?, r.get::<usize, Vec<u8>>(2)?)))? {
+                let (txid, vout, sealed) = r?;
+                result.push(unseal(cipher, sealed, &[txid.as_str(), vout.to_string().as_str()])?);
+            }
+        } else {
+            let mut query = self.tx.prepare(r#"
+                select txid, vout, value, script, account, sub, kix, tweak, csv, proof from coins
+            "#)?;
+            for r in query.query_map(NO_PARAMS, |r| {
+                Ok(CoinRow {
+                    txid: r.get(0)?, vout: r.get(1)?, value: r.get(2)?, script: r.get(3)?,
+                    account: r.get(4)?, sub: r.get(5)?, kix: r.get(6)?,
+                    tweak: r.get(7)?, csv: r.get(8)?, proof: r.get(9)?,
+                })
+            })? {
+                result.push(r?);
+            }
+        }
+        Ok(result)
     }
+
+    // insert or replace, confirmed is the stored form of the block hash
+    fn insert_txout_row(&self, row: &TxoutRow, confirmed: Option<String>) -> Result<(), Error> {
+        if let Some(cipher) = self.cipher {
+            let txid = cipher.index(row.txid.as_str());
+            self.tx.execute(r#"
+                insert or replace into txout (txid, confirmed, sealed) values (?1, ?2, ?3)
+            "#, &[&txid as &dyn ToSql, &confirmed, &seal(cipher, row, &[txid.as_str()])?])?;
+        } else {
+            self.tx.execute(r#"
+                insert or replace into txout (txid, tx, confirmed, publisher, id, term) values (?1, ?2, ?3, ?4, ?5, ?6)
+            "#, &[&row.txid as &dyn ToSql, &row.tx, &confirmed, &row.publisher, &row.id, &row.term])?;
+        }
+        Ok(())
+    }
+
+    // rows with the stored form of their confirming block hash
+    fn read_txout_rows(&self, unconfirmed_only: bool) -> Result<Vec<(TxoutRow, Option<String>)>, Error> {
+        let filter = if unconfirmed_only { "where confirmed is null" } else { "" };
+        let mut result = Vec::new();
+        if let Some(cipher) = self.cipher {
+            let mut query = self.tx.prepare(format!(r#"
+                select txid, sealed, confirmed from txout {}
+            "#, filter).as_str())?;
+            for r in query.query_map(NO_PARAMS, |r| Ok((r.get::<usize, String>(0)?, r.get::<usize, Vec<u8>>(1)?, r.get::<usize, Option<String>>(2)?)))? {
+                let (txid, sealed, confirmed) = r?;
+                result.push((unseal(cipher, sealed, &[txid.as_str()])?, confirmed));
+            }
+        } else {
+            let mut query = self.tx.prepare(format!(r#"
+                select txid, tx, publisher, id, term, confirmed from txout {}
+            "#, filter).as_str())?;
+            for r in query.query_map(NO_PARAMS, |r| {
+                Ok((TxoutRow { txid: r.get(0)?, tx: r.get(1)?, publisher: r.get(2)?, id: r.get(3)?, term: r.get(4)? },
+                    r.get::<usize, Option<String>>(5)?))
+            })? {
+                result.push(r?);
+            }
+        }
+        Ok(result)
+    }
+
+    fn insert_account_row(&self, row: &AccountRow) -> Result<usize, Error> {
+        if let Some(cipher) = self.cipher {
+            Ok(self.tx.execute(r#"
+                insert or replace into account (account, sub, sealed) values (?1, ?2, ?3)
+            "#, &[&row.account as &dyn ToSql, &row.sub,
+                &seal(cipher, row, &[row.account.to_string().as_str(), row.sub.to_string().as_str()])?])?)
+        } else {
+            Ok(self.tx.execute(r#"
+                insert or replace into account (account, address_type, sub, master, instantiated)
+                values (?1, ?2, ?3, ?4, ?5)
+            "#, &[&row.account as &dyn ToSql, &row.address_type, &row.sub, &row.master, &row.instantiated])?)
+        }
+    }
+
+    fn read_account_rows(&self) -> Result<Vec<AccountRow>, Error> {
+        let mut result = Vec::new();
+        if let Some(cipher) = self.cipher {
+            let mut query = self.tx.prepare(r#"
+                select account, sub, sealed from account
+            "#)?;
+            for r in query.query_map(NO_PARAMS, |r| Ok((r.get::<usize, u32>(0)?, r.get::<usize, u32>(1)?, r.get::<usize, Vec<u8>>(2)?)))? {
+                let (account, sub, sealed) = r?;
+                result.push(unseal(cipher, sealed, &[account.to_string().as_str(), sub.to_string().as_str()])?);
+            }
+        } else {
+            let mut query = self.tx.prepare(r#"
+                select account, sub, address_type, master, instantiated from account
+            "#)?;
+            for r in query.query_map(NO_PARAMS, |r| {
+                Ok(AccountRow { account: r.get(0)?, sub: r.get(1)?, address_type: r.get(2)?, master: r.get(3)?, instantiated: r.get(4)? })
+            })? {
+                result.push(r?);
+            }
+        }
+        Ok(result)
+    }
+
+    // insert or replace
+    fn insert_transaction_row(&self, row: &TransactionRow) -> Result<(), Error> {
+        if let Some(cipher) = self.cipher {
+            let txid = cipher.index(row.txid.as_str());
+            self.tx.execute(r#"
+                insert or replace into transactions (txid, block, sealed) values (?1, ?2, ?3)
+            "#, &[&txid as &dyn ToSql, &row.block.as_ref().map(|b| cipher.index(b.as_str())),
+                &seal(cipher, row, &[txid.as_str()])?])?;
+        } else {
+            self.tx.execute(r#"
+                insert or replace into transactions (txid, tx, block, height, position, first_seen) values (?1, ?2, ?3, ?4, ?5, ?6)
+            "#, &[&row.txid as &dyn ToSql, &row.tx, &row.block, &row.height, &row.position, &row.first_seen])?;
+        }
+        Ok(())
+    }
+
+    fn read_transaction_row(&self, txid: &str) -> Result<Option<TransactionRow>, Error> {
+        if let Some(cipher) = self.cipher {
+            let txid = cipher.index(txid);
+            match self.tx.query_row(r#"
+                select sealed from transactions where txid = ?1
+            "#, &[&txid as &dyn ToSql], |r| r.get::<usize, Vec<u8>>(0)).optional()? {
+                Some(sealed) => Ok(Some(unseal(cipher, sealed, &[txid.as_str()])?)),
+                None => Ok(None)
+            }
+        } else {
+            Ok(self.tx.query_row(r#"
+                select txid, tx, block, height, position, first_seen from transactions where txid = ?1
+            "#, &[&txid as &dyn ToSql], |r| Ok(TransactionRow {
+                txid: r.get(0)?, tx: r.get(1)?, block: r.get(2)?, height: r.get(3)?, position: r.get(4)?, first_seen: r.get(5)?,
+            })).optional()?)
+        }
+    }
+
+    // all rows or those of a block, given in its stored form
+    fn read_transaction_rows(&self, block: Option<String>) -> Result<Vec<TransactionRow>, Error> {
+        let filter = if block.is_some() { "where block = ?1" } else { "" };
+        let params: Vec<&dyn ToSql> = block.iter().map(|b| b as &dyn ToSql).collect();
+        let mut result = Vec::new();
+        if let Some(cipher) = self.cipher {
+            let mut query = self.tx.prepare(format!(r#"
+                select txid, sealed from transactions {}
+            "#, filter).as_str())?;
+            for r in query.query_map(params.as_slice(), |r| Ok((r.get::<usize, String>(0)?, r.get::<usize, Vec<u8>>(1)?)))? {
+                let (txid, sealed) = r?;
+                result.push(unseal(cipher, sealed, &[txid.as_str()])?);
+            }
+        } else {
+            let mut query = self.tx.prepare(format!(r#"
+                select txid, tx, block, height, position, first_seen from transactions {}
+            "#, filter).as_str())?;
+            for r in query.query_map(params.as_slice(), |r| {
+                Ok(TransactionRow { txid: r.get(0)?, tx: r.get(1)?, block: r.get(2)?, height: r.get(3)?, position: r.get(4)?, first_seen: r.get(5)? })
+            })? {
+                result.push(r?);
+            }
+        }
+        Ok(result)
+    }
+
+    // insert or replace
+    fn insert_issued_row(&self, row: &IssuedAddressRow) -> Result<(), Error> {
+        if let Some(cipher) = self.cipher {
+            let address = cipher.index(row.address.as_str());
+            self.tx.execute(r#"
+                insert or replace into issued (address, sealed) values (?1, ?2)
+            "#, &[&address as &dyn ToSql, &seal(cipher, row, &[address.as_str()])?])?;
+        } else {
+            self.tx.execute(r#"
+                insert or replace into issued (address, account, sub, kix, issued, funded, label) values (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+            "#, &[&row.address as &dyn ToSql, &row.account, &row.sub, &row.kix, &row.issued, &row.funded, &row.label])?;
+        }
+        Ok(())
+    }
+
+    fn read_issued_rows(&self) -> Result<Vec<IssuedAddressRow>, Error> {
+        let mut result = Vec::new();
+        if let Some(cipher) = self.cipher {
+            let mut query = self.tx.prepare(r#"
+                select address, sealed from issued
+            "#)?;
+            for r in query.query_map(NO_PARAMS, |r| Ok((r.get::<usize, String>(0)?, r.get::<usize, Vec<u8>>(1)?)))? {
+                let (address, sealed) = r?;
+                result.push(unseal(cipher, sealed, &[address.as_str()])?);
+            }
+        } else {
+            let mut query = self.tx.prepare(r#"
+                select address, account, sub, kix, issued, funded, label from issued
+            "#)?;
+            for r in query.query_map(NO_PARAMS, |r| {
+                Ok(IssuedAddressRow {
+                    address: r.get(0)?, account: r.get(1)?, sub: r.get(2)?, kix: r.get(3)?,
+                    issued: r.get(4)?, funded: r.get(5)?, label: r.get(6)?,
+                })
+            })? {
+                result.push(r?);
+            }
+        }
+        Ok(result)
+    }
+}
+
+// the key columns of a row are authenticated with it, so a sealed row can not be moved to an other key
+fn seal<T: Serialize>(cipher: &Cipher, row: &T, key: &[&str]) -> Result<Vec<u8>, Error> {
+    cipher.seal_with(serde_cbor::ser::to_vec(row)?.as_slice(), key.join(":").as_bytes())
+}
+
+fn unseal<T: DeserializeOwned>(cipher: &Cipher, sealed: Vec<u8>, key: &[&str]) -> Result<T, Error> {
+    Ok(serde_cbor::from_slice(cipher.open_with(sealed.as_slice(), key.join(":").as_bytes())?.as_slice())?)
 }
 
-pub fn new(config_path: &Path) -> DB {
+pub fn init(config_path: &Path, coins: &Coins, master: &MasterAccount) -> Result<(), Error> {
+    let mut db = new(&config_path)?;
+    db.migrate()?;
+    storage::init(&mut db, coins, master)
+}
+
+pub fn new(config_path: &Path) -> Result<DB, Error> {
     let mut db_path = PathBuf::from(config_path);
     db_path.push("bdk.db");
-    DB::new(db_path.as_path()).expect("can not open database")
+    DB::new(db_path.as_path())
 }
 
 #[derive(Clone, Copy, Serialize, Deserialize, Hash, Default, Eq, PartialEq, Debug)]
@@ -519,4 +993,125 @@ impl NetAddress {
         };
         Ok(NetAddress { address, port })
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::path::PathBuf;
+
+    use bitcoin::{OutPoint, Script, Transaction, TxIn, TxOut};
+    use bitcoin_hashes::sha256d;
+    use bitcoin_hashes::hex::FromHex;
+
+    use crate::crypt::DbSecret;
+    use crate::error::Error;
+    use crate::labels::{Label, LabelKind};
+    use crate::storage::{DownloadProgress, Storage, StorageTransaction};
+
+    use super::{DB, MIGRATIONS, TX};
+
+    // schema of the first release, before versioning
+    const FIXTURE_V0: &str = include_str!("../tests/fixtures/bdk_db_v0.sql");
+
+    #[test]
+    fn migrate_new() {
+        let mut db = DB::memory().unwrap();
+        assert_eq!(db.schema_version().unwrap(), 0);
+        assert_eq!(db.migrate().unwrap(), MIGRATIONS.len() as u32);
+        // nothing to do the second time
+        assert_eq!(db.migrate().unwrap(), MIGRATIONS.len() as u32);
+    }
+
+    #[test]
+    fn migrate_unversioned() {
+        let mut db = DB::memory().unwrap();
+        db.connection.execute_batch(FIXTURE_V0).unwrap();
+        assert_eq!(db.migrate().unwrap(), MIGRATIONS.len() as u32);
+
+        let genesis = sha256d::Hash::from_hex("0f9188f13cb7b2c71f2a335e3a4fc328bf5beb436012afca590b1a11466e2206").unwrap();
+        let mut tx = TX { tx: db.connection.transaction().unwrap(), cipher: None };
+        // data of the fixture survived
+        assert_eq!(tx.read_processed().unwrap(), Some(genesis));
+        assert_eq!(tx.read_seed().unwrap(), (1, 2));
+        // table of a later version is usable
+        let progress = DownloadProgress { verified: (0, genesis), queued: (0, genesis) };
+        tx.store_download_progress(&progress).unwrap();
+        assert_eq!(tx.read_download_progress().unwrap(), Some(progress));
+        tx.tx.commit().unwrap();
+    }
+
+    #[test]
+    fn failed_migration_rolls_back() {
+        let mut db = DB::memory().unwrap();
+        let migrations = [MIGRATIONS[0], "create table broken (a number); insert into missing values (1);"];
+        assert!(db.migrate_with(&migrations).is_err());
+        assert_eq!(db.schema_version().unwrap(), 1);
+        assert!(db.connection.prepare("select * from broken").is_err());
+    }
+
+    #[test]
+    fn newer_database_is_refused() {
+        let mut db = DB::memory().unwrap();
+        db.migrate().unwrap();
+        assert!(db.migrate_with(&MIGRATIONS[..1]).is_err());
+    }
+
+    #[test]
+    fn encrypt_and_change_secret() {
+        let dir = PathBuf::from("./test_db_crypt");
+        fs::create_dir_all(&dir).unwrap();
+        let mut path = dir.clone();
+        path.push("bdk.db");
+
+        let tx = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec!(TxIn { previous_output: OutPoint::default(), script_sig: Script::new(), sequence: 0xffffffff, witness: vec!() }),
+            // OP_RETURN "bdk!"
+            output: vec!(TxOut { value: 4242, script_pubkey: Script::from(vec!(0x6a, 0x04, b'b', b'd', b'k', b'!')) }),
+        };
+        let label = Label::new(LabelKind::Tx, tx.txid().to_string().as_str(), "paid bdk!".to_string()).unwrap();
+        {
+            let mut db = DB::new(&path).unwrap();
+            db.migrate().unwrap();
+            let mut dbtx = db.transaction().unwrap();
+            dbtx.store_txout(&tx, None).unwrap();
+            dbtx.store_label(&label).unwrap();
+            dbtx.commit().unwrap();
+            assert!(!db.is_encrypted().unwrap());
+            db.encrypt(&DbSecret::Key([7u8; 32])).unwrap();
+            // unlocked after encryption
+            assert_eq!(db.transaction().unwrap().read_unconfirmed().unwrap()[0].0, tx);
+        }
+        assert!(!fs::read(&path).unwrap().windows(4).any(|w| w == b"bdk!"));
+
+        let mut db = DB::new(&path).unwrap();
+        assert!(db.is_encrypted().unwrap());
+        match db.unlock(&DbSecret::Key([8u8; 32])) {
+            Err(Error::WrongPassphrase) => {}
+            other => panic!("expected wrong passphrase, got {:?}", other)
+        }
+        db.unlock(&DbSecret::Key([7u8; 32])).unwrap();
+        assert_eq!(db.transaction().unwrap().read_unconfirmed().unwrap()[0].0, tx);
+
+        db.change_secret(&DbSecret::Key([7u8; 32]), &DbSecret::Passphrase("new passphrase")).unwrap();
+        drop(db);
+        let mut db = DB::new(&path).unwrap();
+        assert!(db.unlock(&DbSecret::Key([7u8; 32])).is_err());
+        db.unlock(&DbSecret::Passphrase("new passphrase")).unwrap();
+        let mut dbtx = db.transaction().unwrap();
+        assert_eq!(dbtx.read_unconfirmed().unwrap()[0].0, tx);
+        // lookups by block hash work on keyed hashes
+        let block = sha256d::Hash::from_hex("00".repeat(32).as_str()).unwrap();
+        assert_eq!(dbtx.unconfirm(&block).unwrap(), 0);
+        assert_eq!(dbtx.read_labels().unwrap(), vec!(label.clone()));
+        assert_eq!(dbtx.delete_label(label.kind, label.reference.as_str()).unwrap(), 1);
+        drop(dbtx);
+        // a sealed row moved to an other key does not open
+        db.connection.execute_batch("update txout set txid = 'moved'").unwrap();
+        assert!(db.transaction().unwrap().read_unconfirmed().is_err());
+        drop(db);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
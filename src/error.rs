@@ -20,6 +20,7 @@ use std::fmt;
 use std::io;
 use bitcoin_wallet;
 use bitcoin::blockdata::script;
+use bitcoin::network::constants::Network;
 use rusqlite;
 
 /// An error class to offer a unified error interface upstream
@@ -38,6 +39,72 @@ pub enum Error {
     Script(script::Error),
     /// TOML decode error
     TomlDe(toml::de::Error),
+    /// stored data can not be read
+    Corrupted(&'static str),
+    /// the wallet is not started
+    NotStarted,
+    /// p2p network error
+    P2P(murmel::error::Error),
+    /// available coins do not cover the amount
+    InsufficientFunds { needed: u64, available: u64 },
+    /// what remains of the amount after fees would be dust
+    BelowDust { amount: u64, fee: u64 },
+    /// fee rate is above the maximum of the fee policy
+    FeeAboveMaximum { fee_per_vbyte: u64, max_fee_per_vbyte: u64 },
+    /// passphrase does not decrypt the wallet
+    WrongPassphrase,
+    /// the database is encrypted and was opened without its secret
+    DatabaseLocked,
+    /// passphrase is too short for a new wallet
+    PassphraseTooShort,
+    /// not all inputs of a transaction could be signed
+    SigningFailed,
+    /// address can not be parsed
+    InvalidAddress(bitcoin::util::address::Error),
+    /// address or backup is for an other network than the wallet
+    NetworkMismatch { expected: Network, found: Network },
+    /// socket address can not be parsed
+    AddrParse(std::net::AddrParseError),
+    /// CBOR encode or decode error
+    Cbor(serde_cbor::error::Error),
+    /// hash of wrong length
+    Hash(bitcoin_hashes::Error),
+    /// hex decode error
+    Hex(bitcoin_hashes::hex::Error),
+    /// bitcoin consensus encode or decode error
+    Encode(bitcoin::consensus::encode::Error),
+}
+
+impl Error {
+    /// name of the variant, for clients that dispatch on the kind of error
+    pub fn kind(&self) -> &'static str {
+        match *self {
+            Error::Unsupported(_) => "Unsupported",
+            Error::Lock(_) => "Lock",
+            Error::Wallet(_) => "Wallet",
+            Error::IO(_) => "IO",
+            Error::DB(_) => "DB",
+            Error::Script(_) => "Script",
+            Error::TomlDe(_) => "TomlDe",
+            Error::Corrupted(_) => "Corrupted",
+            Error::NotStarted => "NotStarted",
+            Error::P2P(_) => "P2P",
+            Error::InsufficientFunds { .. } => "InsufficientFunds",
+            Error::BelowDust { .. } => "BelowDust",
+            Error::FeeAboveMaximum { .. } => "FeeAboveMaximum",
+            Error::WrongPassphrase => "WrongPassphrase",
+            Error::DatabaseLocked => "DatabaseLocked",
+            Error::PassphraseTooShort => "PassphraseTooShort",
+            Error::SigningFailed => "SigningFailed",
+            Error::InvalidAddress(_) => "InvalidAddress",
+            Error::NetworkMismatch { .. } => "NetworkMismatch",
+            Error::AddrParse(_) => "AddrParse",
+            Error::Cbor(_) => "Cbor",
+            Error::Hash(_) => "Hash",
+            Error::Hex(_) => "Hex",
+            Error::Encode(_) => "Encode",
+        }
+    }
 }
 
 impl std::error::Error for Error {
@@ -50,6 +117,23 @@ impl std::error::Error for Error {
             Error::DB(ref err) => err.description(),
             Error::Script(ref err) => err.description(),
             Error::TomlDe(ref err) => err.description(),
+            Error::Corrupted(ref s) => s,
+            Error::NotStarted => "not started",
+            Error::P2P(ref err) => err.description(),
+            Error::InsufficientFunds { .. } => "insufficient funds",
+            Error::BelowDust { .. } => "amount is less than the fees needed (+DUST limit)",
+            Error::FeeAboveMaximum { .. } => "fee rate is above the configured maximum",
+            Error::WrongPassphrase => "wrong passphrase",
+            Error::DatabaseLocked => "database is encrypted",
+            Error::PassphraseTooShort => "passphrase should have at least 8 characters",
+            Error::SigningFailed => "could not sign for all inputs",
+            Error::InvalidAddress(ref err) => err.description(),
+            Error::NetworkMismatch { .. } => "address or backup is for an other network",
+            Error::AddrParse(ref err) => err.description(),
+            Error::Cbor(ref err) => err.description(),
+            Error::Hash(ref err) => err.description(),
+            Error::Hex(ref err) => err.description(),
+            Error::Encode(ref err) => err.description(),
         }
     }
 
@@ -62,6 +146,23 @@ impl std::error::Error for Error {
             Error::DB(ref err) => Some(err),
             Error::Script(ref err) => Some(err),
             Error::TomlDe(ref err) => Some(err),
+            Error::Corrupted(_) => None,
+            Error::NotStarted => None,
+            Error::P2P(ref err) => Some(err),
+            Error::InsufficientFunds { .. } => None,
+            Error::BelowDust { .. } => None,
+            Error::FeeAboveMaximum { .. } => None,
+            Error::WrongPassphrase => None,
+            Error::DatabaseLocked => None,
+            Error::PassphraseTooShort => None,
+            Error::SigningFailed => None,
+            Error::InvalidAddress(ref err) => Some(err),
+            Error::NetworkMismatch { .. } => None,
+            Error::AddrParse(ref err) => Some(err),
+            Error::Cbor(ref err) => Some(err),
+            Error::Hash(ref err) => Some(err),
+            Error::Hex(ref err) => Some(err),
+            Error::Encode(ref err) => Some(err),
         }
     }
 }
@@ -77,6 +178,24 @@ impl fmt::Display for Error {
             Error::DB(ref s) => write!(f, "{}", s),
             Error::Script(ref s) => write!(f, "{}", s),
             Error::TomlDe(ref s) => write!(f, "{}", s),
+            Error::Corrupted(ref s) => write!(f, "Corrupted: {}", s),
+            Error::NotStarted => write!(f, "wallet is not started"),
+            Error::P2P(ref s) => write!(f, "{}", s),
+            Error::InsufficientFunds { needed, available } => write!(f, "insufficient funds: needed {} available {} satoshis", needed, available),
+            Error::BelowDust { amount, fee } => write!(f, "amount {} is less than the fee {} (+DUST limit)", amount, fee),
+            Error::FeeAboveMaximum { fee_per_vbyte, max_fee_per_vbyte } =>
+                write!(f, "fee rate {} sat/vbyte is above the configured maximum {}", fee_per_vbyte, max_fee_per_vbyte),
+            Error::WrongPassphrase => write!(f, "wrong passphrase"),
+            Error::DatabaseLocked => write!(f, "database is encrypted, start it with its secret"),
+            Error::PassphraseTooShort => write!(f, "passphrase should have at least 8 characters"),
+            Error::SigningFailed => write!(f, "could not sign for all inputs"),
+            Error::InvalidAddress(ref s) => write!(f, "invalid address: {}", s),
+            Error::NetworkMismatch { expected, found } => write!(f, "address or backup is for {} but the wallet for {}", found, expected),
+            Error::AddrParse(ref s) => write!(f, "{}", s),
+            Error::Cbor(ref s) => write!(f, "{}", s),
+            Error::Hash(ref s) => write!(f, "{}", s),
+            Error::Hex(ref s) => write!(f, "{}", s),
+            Error::Encode(ref s) => write!(f, "{}", s),
         }
     }
 }
@@ -89,7 +208,10 @@ impl fmt::Debug for Error {
 
 impl convert::From<bitcoin_wallet::error::Error> for Error {
     fn from(err: bitcoin_wallet::error::Error) -> Error {
-        Error::Wallet(err)
+        match err {
+            bitcoin_wallet::error::Error::Passphrase => Error::WrongPassphrase,
+            err => Error::Wallet(err)
+        }
     }
 }
 
@@ -106,26 +228,26 @@ impl convert::From<rusqlite::Error> for Error {
 }
 
 impl convert::From<std::net::AddrParseError> for Error {
-    fn from(_: std::net::AddrParseError) -> Error {
-        Error::IO(io::Error::from(io::ErrorKind::InvalidInput))
+    fn from(err: std::net::AddrParseError) -> Error {
+        Error::AddrParse(err)
     }
 }
 
 impl convert::From<serde_cbor::error::Error> for Error {
-    fn from(_: serde_cbor::error::Error) -> Error {
-        Error::IO(io::Error::from(io::ErrorKind::InvalidInput))
+    fn from(err: serde_cbor::error::Error) -> Error {
+        Error::Cbor(err)
     }
 }
 
 impl convert::From<bitcoin_hashes::Error> for Error {
-    fn from(_: bitcoin_hashes::Error) -> Error {
-        Error::IO(io::Error::from(io::ErrorKind::InvalidInput))
+    fn from(err: bitcoin_hashes::Error) -> Error {
+        Error::Hash(err)
     }
 }
 
 impl convert::From<bitcoin_hashes::hex::Error> for Error {
-    fn from(_: bitcoin_hashes::hex::Error) -> Error {
-        Error::IO(io::Error::from(io::ErrorKind::InvalidInput))
+    fn from(err: bitcoin_hashes::hex::Error) -> Error {
+        Error::Hex(err)
     }
 }
 
@@ -139,4 +261,28 @@ impl convert::From<toml::de::Error> for Error {
     fn from(err: toml::de::Error) -> Error {
         Error::TomlDe(err)
     }
-}
\ No newline at end of file
+}
+
+impl convert::From<bitcoin::consensus::encode::Error> for Error {
+    fn from(err: bitcoin::consensus::encode::Error) -> Error {
+        Error::Encode(err)
+    }
+}
+
+impl convert::From<bitcoin::util::address::Error> for Error {
+    fn from(err: bitcoin::util::address::Error) -> Error {
+        Error::InvalidAddress(err)
+    }
+}
+
+impl convert::From<murmel::error::Error> for Error {
+    fn from(err: murmel::error::Error) -> Error {
+        Error::P2P(err)
+    }
+}
+
+impl convert::From<futures::task::SpawnError> for Error {
+    fn from(_: futures::task::SpawnError) -> Error {
+        Error::Unsupported("executor is shut down")
+    }
+}
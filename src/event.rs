@@ -0,0 +1,84 @@
+/*
+ * Copyright 2020 BDK Team
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! events of the wallet and the chain delivered to subscribers
+
+use std::net::SocketAddr;
+use std::sync::mpsc;
+
+use bitcoin_hashes::sha256d;
+
+/// a change of the wallet or the chain
+#[derive(Clone, Debug, PartialEq)]
+pub enum WalletEvent {
+    /// the header chain has a new tip
+    NewTip { height: u32, block_hash: sha256d::Hash },
+    /// a block was processed by the wallet
+    BlockConnected { height: u32, block_hash: sha256d::Hash },
+    /// a processed block is no longer on the trunk
+    BlockDisconnected { block_hash: sha256d::Hash },
+    /// a transaction paying to the wallet was confirmed
+    IncomingTx { txid: sha256d::Hash, amount: u64 },
+    /// a transaction created by the wallet was confirmed
+    OutgoingTxConfirmed { txid: sha256d::Hash, height: u32 },
+    /// balance or available balance changed
+    BalanceChanged { balance: u64, confirmed: u64 },
+    /// connected to a bitcoin peer
+    PeerConnected { address: Option<SocketAddr> },
+    /// a bitcoin peer disconnected
+    PeerDisconnected { address: Option<SocketAddr> },
+    /// processed all blocks up to the tip of the header chain
+    SyncFinished { height: u32 },
+}
+
+/// fan out events to subscribers
+#[derive(Default)]
+pub struct Subscribers {
+    senders: Vec<mpsc::Sender<WalletEvent>>
+}
+
+impl Subscribers {
+    pub fn subscribe(&mut self) -> mpsc::Receiver<WalletEvent> {
+        let (sender, receiver) = mpsc::channel();
+        self.senders.push(sender);
+        receiver
+    }
+
+    /// send event to all subscribers, forget those that dropped their receiver
+    pub fn emit(&mut self, event: WalletEvent) {
+        self.senders.retain(|s| s.send(event.clone()).is_ok());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin_hashes::sha256d;
+
+    use super::{Subscribers, WalletEvent};
+
+    #[test]
+    fn emit_to_live_subscribers() {
+        let mut subscribers = Subscribers::default();
+        let first = subscribers.subscribe();
+        let second = subscribers.subscribe();
+        drop(second);
+
+        let event = WalletEvent::BlockDisconnected { block_hash: sha256d::Hash::default() };
+        subscribers.emit(event.clone());
+        assert_eq!(first.try_recv().unwrap(), event);
+        assert_eq!(subscribers.senders.len(), 1);
+    }
+}
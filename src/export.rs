@@ -0,0 +1,237 @@
+/*
+ * Copyright 2020 BDK Team
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Export of the transaction history for accounting, as CSV or JSON.
+
+use std::io::Write;
+use std::str::FromStr;
+
+use bitcoin_hashes::sha256d;
+
+use crate::error::Error;
+use crate::store::HistoryEntry;
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExportFormat {
+    Csv,
+    Json,
+}
+
+impl FromStr for ExportFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<ExportFormat, Error> {
+        match s {
+            "csv" => Ok(ExportFormat::Csv),
+            "json" => Ok(ExportFormat::Json),
+            _ => Err(Error::Unsupported("export format should be csv or json"))
+        }
+    }
+}
+
+/// a transaction of the export
+#[derive(Clone, Debug, Serialize, PartialEq)]
+pub struct ExportRow {
+    /// UTC time of the block, YYYY-MM-DD hh:mm:ss
+    pub date: Option<String>,
+    pub txid: sha256d::Hash,
+    pub height: Option<u32>,
+    pub amount_in: u64,
+    /// including the fee
+    pub amount_out: u64,
+    pub fee: Option<u64>,
+    pub counterparty: Option<String>,
+    pub label: Option<String>,
+    /// balance after this transaction
+    pub balance: i64,
+}
+
+/// rows of the history with block time in [from, until), unix seconds. Unconfirmed transactions are
+/// exported unless until is given. The running balance counts all of the history.
+pub fn rows(history: &[HistoryEntry], from: Option<u64>, until: Option<u64>) -> Vec<ExportRow> {
+    let mut balance = 0i64;
+    let mut rows = Vec::new();
+    for entry in history {
+        balance += entry.received as i64 - entry.sent as i64;
+        let time = entry.time.map(|t| t as u64);
+        let in_range = match time {
+            Some(time) => from.map_or(true, |from| time >= from) && until.map_or(true, |until| time < until),
+            None => until.is_none()
+        };
+        if in_range {
+            rows.push(ExportRow {
+                date: time.map(format_date),
+                txid: entry.txid,
+                height: entry.height,
+                amount_in: entry.received,
+                amount_out: entry.sent,
+                fee: entry.fee,
+                counterparty: entry.counterparty.as_ref().map(|a| a.to_string()),
+                label: entry.label.clone(),
+                balance,
+            });
+        }
+    }
+    rows
+}
+
+pub fn write<W: Write>(writer: &mut W, format: ExportFormat, rows: &[ExportRow]) -> Result<(), Error> {
+    match format {
+        ExportFormat::Json => {
+            serde_json::to_writer_pretty(&mut *writer, rows).map_err(|_| Error::Unsupported("can not format history"))?;
+            writeln!(writer)?;
+        }
+        ExportFormat::Csv => {
+            writeln!(writer, "date,txid,height,amount_in,amount_out,fee,counterparty,label,balance")?;
+            for row in rows {
+                writeln!(writer, "{},{},{},{},{},{},{},{},{}",
+                         row.date.as_ref().map_or("", |d| d.as_str()),
+                         row.txid,
+                         row.height.map_or(String::new(), |h| h.to_string()),
+                         row.amount_in,
+                         row.amount_out,
+                         row.fee.map_or(String::new(), |f| f.to_string()),
+                         row.counterparty.as_ref().map_or("", |c| c.as_str()),
+                         csv_field(row.label.as_ref().map_or("", |l| l.as_str())),
+                         row.balance)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+// quoted if it contains a separator, quote or line break,
+// prefixed with ' if a spreadsheet would read it as a formula
+fn csv_field(field: &str) -> String {
+    let field = if field.starts_with(|c| c == '=' || c == '+' || c == '-' || c == '@') {
+        format!("'{}", field)
+    } else {
+        field.to_string()
+    };
+    if field.contains(|c| c == ',' || c == '"' || c == '\n' || c == '\r') {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field
+    }
+}
+
+/// unix seconds of the start of a day given as YYYY-MM-DD, UTC
+pub fn parse_date(date: &str) -> Result<u64, Error> {
+    let parts = date.split('-').map(|p| p.parse::<i64>()).collect::<Result<Vec<_>, _>>()
+        .map_err(|_| Error::Unsupported("date should be YYYY-MM-DD"))?;
+    match parts.as_slice() {
+        [year, month, day] if *year >= 1970 && (1..=12).contains(month) && (1..=31).contains(day) =>
+            Ok(days_from_civil(*year, *month, *day) as u64 * SECONDS_PER_DAY),
+        _ => Err(Error::Unsupported("date should be YYYY-MM-DD"))
+    }
+}
+
+/// YYYY-MM-DD hh:mm:ss of unix seconds, UTC
+pub fn format_date(time: u64) -> String {
+    let (year, month, day) = civil_from_days((time / SECONDS_PER_DAY) as i64);
+    let seconds = time % SECONDS_PER_DAY;
+    format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02}", year, month, day, seconds / 3600, seconds / 60 % 60, seconds % 60)
+}
+
+// days since 1970-01-01 of a date of the proleptic Gregorian calendar
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = (if year >= 0 { year } else { year - 399 }) / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+// inverse of days_from_civil
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719468;
+    let era = (if days >= 0 { days } else { days - 146096 }) / 146097;
+    let day_of_era = days - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    (year_of_era + era * 400 + if month <= 2 { 1 } else { 0 }, month, day)
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin_hashes::sha256d;
+    use bitcoin_hashes::hex::FromHex;
+
+    use crate::store::HistoryEntry;
+
+    use super::{ExportFormat, csv_field, format_date, parse_date, rows, write};
+
+    fn entry(n: u8, time: Option<u32>, received: u64, sent: u64) -> HistoryEntry {
+        HistoryEntry {
+            txid: sha256d::Hash::from_hex(format!("{:02x}", n).repeat(32).as_str()).unwrap(),
+            block_hash: None,
+            height: time.map(|_| n as u32),
+            time,
+            received,
+            sent,
+            fee: None,
+            counterparty: None,
+            label: None,
+        }
+    }
+
+    #[test]
+    fn dates() {
+        // time of the genesis block
+        assert_eq!(format_date(1231006505), "2009-01-03 18:15:05");
+        assert_eq!(parse_date("2009-01-03").unwrap(), 1230940800);
+        assert_eq!(parse_date("2020-02-29").unwrap(), 1582934400);
+        assert!(parse_date("2020-13-01").is_err());
+    }
+
+    #[test]
+    fn range_and_balance() {
+        let mut history = vec!(
+            entry(1, Some(1582934400), 1000, 0),
+            entry(2, Some(1583020800), 0, 300),
+            entry(3, None, 50, 0));
+        history[1].label = Some("rent, march".to_string());
+
+        let all = rows(&history, None, None);
+        assert_eq!(all.iter().map(|r| r.balance).collect::<Vec<_>>(), vec!(1000, 700, 750));
+
+        let march = rows(&history, Some(parse_date("2020-03-01").unwrap()), Some(parse_date("2020-04-01").unwrap()));
+        assert_eq!(march.len(), 1);
+        assert_eq!(march[0].balance, 700);
+        assert_eq!(march[0].date, Some("2020-03-01 00:00:00".to_string()));
+
+        let mut csv = Vec::new();
+        write(&mut csv, ExportFormat::Csv, &march).unwrap();
+        assert_eq!(String::from_utf8(csv).unwrap(), format!(
+            "date,txid,height,amount_in,amount_out,fee,counterparty,label,balance\n\
+             2020-03-01 00:00:00,{},2,0,300,,,\"rent, march\",700\n", history[1].txid));
+    }
+
+    #[test]
+    fn no_formulas_in_csv() {
+        assert_eq!(csv_field("=HYPERLINK(\"x\")"), "\"'=HYPERLINK(\"\"x\"\")\"");
+        assert_eq!(csv_field("+1"), "'+1");
+        assert_eq!(csv_field("-1"), "'-1");
+        assert_eq!(csv_field("@SUM(A1)"), "'@SUM(A1)");
+        assert_eq!(csv_field("rent"), "rent");
+    }
+}
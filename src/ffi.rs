@@ -0,0 +1,303 @@
+/*
+ * Copyright 2020 BDK Team
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! C ABI for iOS and other native hosts, the header is generated into include/bdk.h with cbindgen.
+//!
+//! Ownership rules:
+//! - strings passed in are borrowed for the duration of the call, they must be NUL terminated UTF-8
+//! - strings returned, also inside structs, are owned by the caller and freed with bdk_string_free
+//!   or the free function of the struct
+//! - the wallet returned by bdk_start is owned by the caller until it is passed to bdk_stop
+//! - every function returns a BdkErrorCode, on error bdk_last_error returns the message
+
+use std::cell::RefCell;
+use std::ffi::{CStr, CString};
+use std::os::raw::c_char;
+use std::panic::{AssertUnwindSafe, catch_unwind};
+use std::path::PathBuf;
+use std::ptr;
+use std::str::FromStr;
+
+use bitcoin::{Address, Network};
+
+use crate::api::{init_config, start, WalletHandle};
+use crate::error::Error;
+
+/// result of every bdk function
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BdkErrorCode {
+    Ok = 0,
+    /// error without a more specific code
+    Error = 1,
+    /// a required pointer argument was null
+    NullPointer = 2,
+    /// a string argument is not UTF-8
+    InvalidUtf8 = 3,
+    /// the wallet is not started
+    NotStarted = 4,
+    /// a wallet is already configured in the work directory
+    AlreadyExists = 5,
+    InsufficientFunds = 6,
+    BelowDust = 7,
+    WrongPassphrase = 8,
+    PassphraseTooShort = 9,
+    InvalidAddress = 10,
+    NetworkMismatch = 11,
+    Corrupted = 12,
+    /// the library panicked, the wallet should not be used any further
+    Panic = 13,
+    FeeAboveMaximum = 14,
+    /// the database is encrypted, it can not be started without its secret
+    DatabaseLocked = 15,
+}
+
+/// a running wallet
+pub struct BdkWallet {
+    handle: WalletHandle
+}
+
+/// filled by bdk_init_config, free with bdk_init_result_free
+#[repr(C)]
+pub struct BdkInitResult {
+    pub mnemonic_words: *mut c_char,
+    pub deposit_address: *mut c_char,
+}
+
+/// balances in satoshis
+#[repr(C)]
+pub struct BdkBalance {
+    pub balance: u64,
+    pub confirmed: u64,
+}
+
+/// filled by bdk_withdraw, free with bdk_withdraw_tx_free
+#[repr(C)]
+pub struct BdkWithdrawTx {
+    pub txid: *mut c_char,
+    pub fee: u64,
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = RefCell::new(None);
+}
+
+/// message of the last error on this thread, null if there was none.
+/// The string is owned by the library and valid until the next call on this thread.
+#[no_mangle]
+pub extern "C" fn bdk_last_error() -> *const c_char {
+    LAST_ERROR.with(|e| e.borrow().as_ref().map_or(ptr::null(), |e| e.as_ptr()))
+}
+
+/// create a new wallet in work_dir, pd_passphrase may be null.
+/// Network is 0 for bitcoin, 1 for testnet and 2 for regtest.
+#[no_mangle]
+pub unsafe extern "C" fn bdk_init_config(work_dir: *const c_char, network: i32, passphrase: *const c_char,
+                                         pd_passphrase: *const c_char, out: *mut BdkInitResult) -> BdkErrorCode {
+    guard(|| {
+        let out = out_arg(out)?;
+        let work_dir = PathBuf::from(str_from_c(work_dir)?);
+        let network = network_from_i32(network)?;
+        let passphrase = str_from_c(passphrase)?;
+        let pd_passphrase = if pd_passphrase.is_null() { None } else { Some(str_from_c(pd_passphrase)?) };
+        match init_config(work_dir, network, passphrase, pd_passphrase)? {
+            Some(init) => {
+                out.mnemonic_words = c_string(init.mnemonic_words);
+                out.deposit_address = c_string(init.deposit_address.to_string());
+                Ok(())
+            }
+            None => Err(set_error(BdkErrorCode::AlreadyExists, "a wallet is already configured".to_string()))
+        }
+    })
+}
+
+/// start the wallet configured in work_dir, on success *out is the wallet to pass to bdk_stop
+#[no_mangle]
+pub unsafe extern "C" fn bdk_start(work_dir: *const c_char, network: i32, rescan: bool, out: *mut *mut BdkWallet) -> BdkErrorCode {
+    guard(|| {
+        let out = out_arg(out)?;
+        let work_dir = PathBuf::from(str_from_c(work_dir)?);
+        let handle = start(work_dir, network_from_i32(network)?, rescan)?;
+        *out = Box::into_raw(Box::new(BdkWallet { handle }));
+        Ok(())
+    })
+}
+
+/// stop and free the wallet, it must not be used afterwards. Null is ignored.
+#[no_mangle]
+pub unsafe extern "C" fn bdk_stop(wallet: *mut BdkWallet) -> BdkErrorCode {
+    guard(|| {
+        if !wallet.is_null() {
+            Box::from_raw(wallet).handle.stop();
+        }
+        Ok(())
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn bdk_balance(wallet: *const BdkWallet, out: *mut BdkBalance) -> BdkErrorCode {
+    guard(|| {
+        let out = out_arg(out)?;
+        let balance = wallet_from_c(wallet)?.balance()?;
+        out.balance = balance.balance;
+        out.confirmed = balance.confirmed;
+        Ok(())
+    })
+}
+
+/// on success *out is a new deposit address, free with bdk_string_free
+#[no_mangle]
+pub unsafe extern "C" fn bdk_deposit_addr(wallet: *const BdkWallet, out: *mut *mut c_char) -> BdkErrorCode {
+    guard(|| {
+        let out = out_arg(out)?;
+        *out = c_string(wallet_from_c(wallet)?.deposit_addr()?.to_string());
+        Ok(())
+    })
+}
+
+/// send amount satoshis to address, amount 0 sends everything
+#[no_mangle]
+pub unsafe extern "C" fn bdk_withdraw(wallet: *const BdkWallet, passphrase: *const c_char, address: *const c_char,
+                                      fee_per_vbyte: u64, amount: u64, out: *mut BdkWithdrawTx) -> BdkErrorCode {
+    guard(|| {
+        let out = out_arg(out)?;
+        let passphrase = str_from_c(passphrase)?.to_string();
+        let address = Address::from_str(str_from_c(address)?).map_err(Error::from)?;
+        let amount = if amount == 0 { None } else { Some(amount) };
+        let withdraw_tx = wallet_from_c(wallet)?.withdraw(passphrase, address, fee_per_vbyte, amount)?;
+        out.txid = c_string(withdraw_tx.txid.to_string());
+        out.fee = withdraw_tx.fee;
+        Ok(())
+    })
+}
+
+/// free a string returned by the library. Null is ignored.
+#[no_mangle]
+pub unsafe extern "C" fn bdk_string_free(string: *mut c_char) {
+    if !string.is_null() {
+        drop(CString::from_raw(string));
+    }
+}
+
+/// free the strings of an init result, the struct itself is owned by the caller
+#[no_mangle]
+pub unsafe extern "C" fn bdk_init_result_free(init_result: *mut BdkInitResult) {
+    if let Some(init_result) = init_result.as_mut() {
+        bdk_string_free(init_result.mnemonic_words);
+        bdk_string_free(init_result.deposit_address);
+        init_result.mnemonic_words = ptr::null_mut();
+        init_result.deposit_address = ptr::null_mut();
+    }
+}
+
+/// free the strings of a withdraw result, the struct itself is owned by the caller
+#[no_mangle]
+pub unsafe extern "C" fn bdk_withdraw_tx_free(withdraw_tx: *mut BdkWithdrawTx) {
+    if let Some(withdraw_tx) = withdraw_tx.as_mut() {
+        bdk_string_free(withdraw_tx.txid);
+        withdraw_tx.txid = ptr::null_mut();
+    }
+}
+
+// private functions
+
+// run f, turn its error or panic into an error code and remember the message.
+// The caller is told not to use the wallet after a panic, so its state needs no unwind safety.
+fn guard<F>(f: F) -> BdkErrorCode where F: FnOnce() -> Result<(), BdkErrorCode> {
+    LAST_ERROR.with(|e| *e.borrow_mut() = None);
+    match catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(())) => BdkErrorCode::Ok,
+        Ok(Err(code)) => code,
+        Err(_) => set_error(BdkErrorCode::Panic, "bdk panicked".to_string())
+    }
+}
+
+fn set_error(code: BdkErrorCode, message: String) -> BdkErrorCode {
+    let message = CString::new(message.replace('\0', "")).unwrap_or_default();
+    LAST_ERROR.with(|e| *e.borrow_mut() = Some(message));
+    code
+}
+
+impl From<Error> for BdkErrorCode {
+    fn from(error: Error) -> BdkErrorCode {
+        let code = match error {
+            Error::NotStarted => BdkErrorCode::NotStarted,
+            Error::InsufficientFunds { .. } => BdkErrorCode::InsufficientFunds,
+            Error::BelowDust { .. } => BdkErrorCode::BelowDust,
+            Error::FeeAboveMaximum { .. } => BdkErrorCode::FeeAboveMaximum,
+            Error::WrongPassphrase => BdkErrorCode::WrongPassphrase,
+            Error::DatabaseLocked => BdkErrorCode::DatabaseLocked,
+            Error::PassphraseTooShort => BdkErrorCode::PassphraseTooShort,
+            Error::InvalidAddress(_) => BdkErrorCode::InvalidAddress,
+            Error::NetworkMismatch { .. } => BdkErrorCode::NetworkMismatch,
+            Error::Corrupted(_) => BdkErrorCode::Corrupted,
+            _ => BdkErrorCode::Error
+        };
+        set_error(code, error.to_string())
+    }
+}
+
+unsafe fn str_from_c<'a>(string: *const c_char) -> Result<&'a str, BdkErrorCode> {
+    if string.is_null() {
+        return Err(set_error(BdkErrorCode::NullPointer, "null string argument".to_string()));
+    }
+    CStr::from_ptr(string).to_str().map_err(|e| set_error(BdkErrorCode::InvalidUtf8, e.to_string()))
+}
+
+unsafe fn out_arg<'a, T>(out: *mut T) -> Result<&'a mut T, BdkErrorCode> {
+    out.as_mut().ok_or_else(|| set_error(BdkErrorCode::NullPointer, "null out argument".to_string()))
+}
+
+fn c_string(string: String) -> *mut c_char {
+    CString::new(string).unwrap_or_default().into_raw()
+}
+
+// the wallet returned by bdk_start, valid until bdk_stop
+unsafe fn wallet_from_c<'a>(wallet: *const BdkWallet) -> Result<&'a WalletHandle, Error> {
+    wallet.as_ref().map(|w| &w.handle).ok_or(Error::NotStarted)
+}
+
+fn network_from_i32(network: i32) -> Result<Network, Error> {
+    match network {
+        0 => Ok(Network::Bitcoin),
+        1 => Ok(Network::Testnet),
+        2 => Ok(Network::Regtest),
+        _ => Err(Error::Unsupported("invalid network"))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::ffi::{CStr, CString};
+    use std::ptr;
+
+    use super::{bdk_balance, bdk_init_config, bdk_last_error, BdkBalance, BdkErrorCode, BdkInitResult};
+
+    #[test]
+    fn errors_are_codes_with_message() {
+        let mut balance = BdkBalance { balance: 0, confirmed: 0 };
+        assert_eq!(unsafe { bdk_balance(ptr::null(), &mut balance) }, BdkErrorCode::NotStarted);
+        assert!(!bdk_last_error().is_null());
+
+        let work_dir = CString::new("./test_ffi").unwrap();
+        let passphrase = CString::new("short").unwrap();
+        let mut init = BdkInitResult { mnemonic_words: ptr::null_mut(), deposit_address: ptr::null_mut() };
+        assert_eq!(unsafe { bdk_init_config(work_dir.as_ptr(), 2, passphrase.as_ptr(), ptr::null(), &mut init) }, BdkErrorCode::PassphraseTooShort);
+        let message = unsafe { CStr::from_ptr(bdk_last_error()) };
+        assert_eq!(message.to_str().unwrap(), "passphrase should have at least 8 characters");
+        std::fs::remove_dir_all("./test_ffi").unwrap();
+    }
+}
@@ -15,21 +15,34 @@
  * limitations under the License.
  */
 
+use std::any::Any;
+use std::collections::HashMap;
 use std::convert::TryFrom;
-use std::fs;
 use std::net::SocketAddr;
-use std::path::{Path, PathBuf};
+use std::panic::{AssertUnwindSafe, catch_unwind};
+use std::path::PathBuf;
 use std::str::FromStr;
-use std::sync::{Arc, Mutex, RwLock};
+use std::sync::{Arc, mpsc, Mutex};
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::thread;
+use std::time::Duration;
 
 use bitcoin::{Address, Network};
-use jni::JNIEnv;
-use jni::objects::{JObject, JString, JValue};
+use jni::{JavaVM, JNIEnv};
+use jni::objects::{GlobalRef, JObject, JString, JThrowable, JValue};
 use jni::sys::{jboolean, jint, jlong, jobject, jobjectArray};
 use log::{error, info};
+use once_cell::sync::Lazy;
 
-use crate::api::{balance, BalanceAmt, deposit_addr, init_config, InitResult, load_config, remove_config, start, stop, update_config, withdraw, WithdrawTx};
-use crate::config::Config;
+use crate::api::{BalanceAmt, init_config, InitResult, load_config, remove_config, restore_config, start, SyncStatus, update_config, WalletHandle, WithdrawTx};
+use crate::config::{Config, ConfigUpdate};
+use crate::error::Error;
+use crate::event::WalletEvent;
+use crate::store::HistoryEntry;
+
+// started wallets by the id returned to Java, an id is never reused
+static WALLETS: Lazy<Mutex<HashMap<jlong, Arc<WalletHandle>>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+static NEXT_WALLET: AtomicI64 = AtomicI64::new(1);
 
 // public API
 
@@ -37,16 +50,22 @@ use crate::config::Config;
 
 #[no_mangle]
 #[cfg(feature = "android")]
-pub unsafe extern fn Java_org_bdk_jni_BdkLib_initLogger(_: JNIEnv, _: JObject) {
-    android_log::init("BDK").unwrap();
-    info!("android logger initialized");
+pub unsafe extern fn Java_org_bdk_jni_BdkLib_initLogger(env: JNIEnv, _: JObject) {
+    guard(&env, (), || {
+        android_log::init("BDK").map_err(|_| Error::Unsupported("logger is already set"))?;
+        info!("android logger initialized");
+        Ok(())
+    })
 }
 
 #[no_mangle]
 #[cfg(feature = "java")]
-pub unsafe extern fn Java_org_bdk_jni_BdkLib_initLogger(_: JNIEnv, _: JObject) {
-    env_logger::init();
-    info!("java logger initialized");
+pub unsafe extern fn Java_org_bdk_jni_BdkLib_initLogger(env: JNIEnv, _: JObject) {
+    guard(&env, (), || {
+        env_logger::try_init().map_err(|_| Error::Unsupported("logger is already set"))?;
+        info!("java logger initialized");
+        Ok(())
+    })
 }
 
 // Optional<Config> org.bdk.jni.BdkLib.loadConfig(String workDir, int network)
@@ -54,14 +73,15 @@ pub unsafe extern fn Java_org_bdk_jni_BdkLib_initLogger(_: JNIEnv, _: JObject) {
 pub unsafe extern fn Java_org_bdk_jni_BdkLib_loadConfig(env: JNIEnv, _: JObject,
                                                             j_work_dir: JString,
                                                             j_network: jint) -> jobject {
-    let work_dir = string_from_jstring(&env, j_work_dir);
-    let work_dir = PathBuf::from(work_dir);
-    let network = network_from_jint(j_network);
+    guard(&env, null(), || {
+        let work_dir = PathBuf::from(string_from_jstring(&env, j_work_dir)?);
+        let network = network_from_jint(j_network)?;
 
-    match load_config(work_dir, network) {
-        Ok(config) => j_optional_config(&env, &config),
-        Err(_err) => j_optional_empty(&env)
-    }
+        match load_config(work_dir, network) {
+            Ok(config) => j_optional_config(&env, &config),
+            Err(_err) => j_optional_empty(&env)
+        }
+    })
 }
 
 // Optional<Config> org.bdk.jni.BdkLib.removeConfig(String workDir, int network)
@@ -69,14 +89,15 @@ pub unsafe extern fn Java_org_bdk_jni_BdkLib_loadConfig(env: JNIEnv, _: JObject,
 pub unsafe extern fn Java_org_bdk_jni_BdkLib_removeConfig(env: JNIEnv, _: JObject,
                                                               j_work_dir: JString,
                                                               j_network: jint) -> jobject {
-    let work_dir = string_from_jstring(&env, j_work_dir);
-    let work_dir = PathBuf::from(work_dir);
-    let network = network_from_jint(j_network);
+    guard(&env, null(), || {
+        let work_dir = PathBuf::from(string_from_jstring(&env, j_work_dir)?);
+        let network = network_from_jint(j_network)?;
 
-    match remove_config(work_dir, network) {
-        Ok(config) => j_optional_config(&env, &config),
-        Err(_err) => j_optional_empty(&env)
-    }
+        match remove_config(work_dir, network) {
+            Ok(config) => j_optional_config(&env, &config),
+            Err(_err) => j_optional_empty(&env)
+        }
+    })
 }
 
 // Optional<Config> org.bdk.jni.BdkLib.updateConfig(String workDir, int network, String[] bitcoinPeers, int bitcoinConnections, boolean bitcoinDiscovery)
@@ -87,38 +108,46 @@ pub unsafe extern fn Java_org_bdk_jni_BdkLib_updateConfig(env: JNIEnv, _: JObjec
                                                               j_bitcoin_peers: jobjectArray,
                                                               j_bitcoin_connections: jint,
                                                               j_bitcoin_discovery: jboolean) -> jobject {
-    let work_dir = string_from_jstring(&env, j_work_dir);
-    let work_dir = PathBuf::from(work_dir);
-    let network = network_from_jint(j_network);
-
-    let bitcoin_peers_length = env.get_array_length(j_bitcoin_peers)
-        .expect("error get_array_length j_bitcoin_peers");
-
-    let mut bitcoin_peers: Vec<SocketAddr> = Vec::new();
-
-    for i in 0..(bitcoin_peers_length) {
-        let bitcoin_peer = env.get_object_array_element(j_bitcoin_peers, i)
-            .expect("error get_object_array_element j_bitcoin_peers");
-        let bitcoin_peer = JString::try_from(bitcoin_peer)
-            .expect("error JString::try_from j_bitcoin_peers element");
-        let bitcoin_peer = env.get_string(bitcoin_peer)
-            .expect("error env.get_string bitcoin_peer");
-        let bitcoin_peer = bitcoin_peer.to_str()
-            .expect("error bitcoin_peer.toStr()");
-
-        let bitcoin_peer_addr = SocketAddr::from_str(bitcoin_peer)
-            .expect("error SocketAddr::from_str(bitcoin_peer)");
-
-        bitcoin_peers.push(bitcoin_peer_addr);
-    }
+    guard(&env, null(), || {
+        let work_dir = PathBuf::from(string_from_jstring(&env, j_work_dir)?);
+        let network = network_from_jint(j_network)?;
+
+        let mut bitcoin_peers: Vec<SocketAddr> = Vec::new();
+        for i in 0..env.get_array_length(j_bitcoin_peers)? {
+            let bitcoin_peer = JString::from(env.get_object_array_element(j_bitcoin_peers, i)?);
+            let bitcoin_peer = string_from_jstring(&env, bitcoin_peer)?;
+            bitcoin_peers.push(SocketAddr::from_str(bitcoin_peer.as_str()).map_err(Error::from)?);
+        }
 
-    let bitcoin_connections = usize::try_from(j_bitcoin_connections).expect("usize::try_from(j_bitcoin_connections");
-    let bitcoin_discovery = j_bitcoin_discovery == 1;
+        let bitcoin_connections = usize::try_from(j_bitcoin_connections)
+            .map_err(|_| Error::Unsupported("negative bitcoin connections"))?;
+        let bitcoin_discovery = j_bitcoin_discovery == 1;
+
+        let update = ConfigUpdate {
+            bitcoin_peers: Some(bitcoin_peers),
+            bitcoin_connections: Some(bitcoin_connections),
+            bitcoin_discovery: Some(bitcoin_discovery),
+            ..Default::default()
+        };
+        j_optional_config(&env, &update_config(work_dir, network, update)?)
+    })
+}
 
-    match update_config(work_dir, network, bitcoin_peers, bitcoin_connections, bitcoin_discovery) {
-        Ok(updated_config) => j_optional_config(&env, &updated_config),
-        Err(_err) => j_optional_empty(&env)
-    }
+// fields missing from the JSON object are left as they are, e.g. {"sync_mode": "full", "gap_limit": 100}
+// Optional<Config> org.bdk.jni.BdkLib.updateConfigJson(String workDir, int network, String update)
+#[no_mangle]
+pub unsafe extern fn Java_org_bdk_jni_BdkLib_updateConfigJson(env: JNIEnv, _: JObject,
+                                                                  j_work_dir: JString,
+                                                                  j_network: jint,
+                                                                  j_update: JString) -> jobject {
+    guard(&env, null(), || {
+        let work_dir = PathBuf::from(string_from_jstring(&env, j_work_dir)?);
+        let network = network_from_jint(j_network)?;
+        let update: ConfigUpdate = serde_json::from_str(string_from_jstring(&env, j_update)?.as_str())
+            .map_err(|_| Error::Unsupported("malformed config update"))?;
+
+        j_optional_config(&env, &update_config(work_dir, network, update)?)
+    })
 }
 
 // Optional<InitResult> org.bdk.jni.BdkLib.initConfig(String workDir, int network, String passphrase, String pdPassphrase)
@@ -128,129 +157,448 @@ pub unsafe extern fn Java_org_bdk_jni_BdkLib_initConfig(env: JNIEnv, _: JObject,
                                                             j_network: jint,
                                                             j_passphrase: JString,
                                                             j_pd_passphrase: JString) -> jobject {
-    let work_dir = string_from_jstring(&env, j_work_dir);
-    let work_dir = PathBuf::from(work_dir);
-    let network = network_from_jint(j_network);
-
-    let passphrase = string_from_jstring(&env, j_passphrase);
-    let passphrase = passphrase.as_str();
-    let pd_passphrase = env.get_string(j_pd_passphrase).ok();
-    let pd_passphrase = pd_passphrase.iter()
-        .map(|pd| pd.to_str().expect("error j_pd_passphrase JavaStr.to_str()"))
-        .next();
-
-    match init_config(work_dir, network, passphrase, pd_passphrase) {
-        Ok(None) => {
+    guard(&env, null(), || {
+        let work_dir = PathBuf::from(string_from_jstring(&env, j_work_dir)?);
+        let network = network_from_jint(j_network)?;
+
+        let passphrase = string_from_jstring(&env, j_passphrase)?;
+        let pd_passphrase = if j_pd_passphrase.is_null() {
+            None
+        } else {
+            Some(string_from_jstring(&env, j_pd_passphrase)?)
+        };
+
+        match init_config(work_dir, network, passphrase.as_str(), pd_passphrase.as_deref())? {
             // do not init if a config already exists, return empty
-            j_optional_empty(&env)
-        }
-        Ok(Some(init_result)) => {
-            // return config
-            j_optional_init_result(&env, init_result)
+            None => j_optional_empty(&env),
+            Some(init_result) => j_optional_init_result(&env, init_result)
         }
-        Err(_err) => {
-            // TODO throw java exception
-            j_optional_empty(&env)
+    })
+}
+
+// Optional<Address> org.bdk.jni.BdkLib.restoreConfig(String workDir, int network, String mnemonicWords, long birth, String passphrase, String pdPassphrase)
+#[no_mangle]
+pub unsafe extern fn Java_org_bdk_jni_BdkLib_restoreConfig(env: JNIEnv, _: JObject,
+                                                               j_work_dir: JString,
+                                                               j_network: jint,
+                                                               j_mnemonic_words: JString,
+                                                               j_birth: jlong,
+                                                               j_passphrase: JString,
+                                                               j_pd_passphrase: JString) -> jobject {
+    guard(&env, null(), || {
+        let work_dir = PathBuf::from(string_from_jstring(&env, j_work_dir)?);
+        let network = network_from_jint(j_network)?;
+        let mnemonic_words = string_from_jstring(&env, j_mnemonic_words)?;
+        let birth = u64::try_from(j_birth).map_err(|_| Error::Unsupported("negative birth"))?;
+
+        let passphrase = string_from_jstring(&env, j_passphrase)?;
+        let pd_passphrase = if j_pd_passphrase.is_null() {
+            None
+        } else {
+            Some(string_from_jstring(&env, j_pd_passphrase)?)
+        };
+
+        match restore_config(work_dir, network, mnemonic_words.as_str(), birth, passphrase.as_str(), pd_passphrase.as_deref())? {
+            // do not restore if a config already exists, return empty
+            None => j_optional_empty(&env),
+            Some(deposit_address) => {
+                let deposit_address = j_address(&env, &deposit_address)?;
+                j_optional_of(&env, JObject::from(deposit_address))
+            }
         }
-    }
+    })
 }
 
-// void org.bdk.jni.BdkLib.start(String workDir, int network, boolean rescan)
+// long org.bdk.jni.BdkLib.start(String workDir, int network, boolean rescan)
 #[no_mangle]
-pub unsafe extern fn Java_org_bdk_jni_BdkLib_start(env: JNIEnv, _: JObject, j_work_dir: JString, j_network: jint, j_rescan: jboolean) {
-    let work_dir = string_from_jstring(&env, j_work_dir);
-    let work_dir = PathBuf::from(work_dir);
-    let network = network_from_jint(j_network);
-    let rescan = j_rescan == 1;
-
-    match start(work_dir, network, rescan) {
-        Ok(_) => (),
-        Err(_e) => {
-            // TODO throw java exception
-            error!("Could not start wallet.");
-            ()
+pub unsafe extern fn Java_org_bdk_jni_BdkLib_start(env: JNIEnv, _: JObject, j_work_dir: JString, j_network: jint, j_rescan: jboolean) -> jlong {
+    guard(&env, 0, || {
+        let work_dir = PathBuf::from(string_from_jstring(&env, j_work_dir)?);
+        let rescan = j_rescan == 1;
+
+        match network_from_jint(j_network).and_then(|network| start(work_dir, network, rescan)) {
+            Ok(wallet) => {
+                let id = NEXT_WALLET.fetch_add(1, Ordering::SeqCst);
+                WALLETS.lock().unwrap().insert(id, Arc::new(wallet));
+                Ok(id)
+            }
+            Err(e) => {
+                error!("Could not start wallet: {}", e);
+                Err(e.into())
+            }
         }
-    }
+    })
 }
 
-// void org.bdk.jni.BdkLib.stop()
+// void org.bdk.jni.BdkLib.stop(long wallet)
 #[no_mangle]
-pub unsafe extern fn Java_org_bdk_jni_BdkLib_stop(_: JNIEnv, _: JObject) {
-    stop()
+pub unsafe extern fn Java_org_bdk_jni_BdkLib_stop(env: JNIEnv, _: JObject, j_wallet: jlong) {
+    guard(&env, (), || {
+        let mut wallet = WALLETS.lock().unwrap().remove(&j_wallet).ok_or(Error::NotStarted)?;
+        // calls still running on other threads hold the wallet until they return
+        loop {
+            match Arc::try_unwrap(wallet) {
+                Ok(wallet) => break wallet.stop(),
+                Err(in_use) => {
+                    wallet = in_use;
+                    thread::sleep(Duration::from_millis(10));
+                }
+            }
+        }
+        Ok(())
+    })
 }
 
-// Option<BalanceAmt> org.bdk.jni.BdkLib.balance()
+// Option<BalanceAmt> org.bdk.jni.BdkLib.balance(long wallet)
 #[no_mangle]
-pub unsafe extern fn Java_org_bdk_jni_BdkLib_balance(env: JNIEnv, _: JObject) -> jobject {
-    match balance() {
-        Ok(balance_amt) => {
+pub unsafe extern fn Java_org_bdk_jni_BdkLib_balance(env: JNIEnv, _: JObject, j_wallet: jlong) -> jobject {
+    guard(&env, null(), || {
+        match wallet_from_jlong(j_wallet).and_then(|wallet| wallet.balance()) {
             // return wallet balance amt
-            j_optional_balance_amt_result(&env, balance_amt)
-        },
-        Err(_e) => {
-            // TODO throw java exception
-            error!("Could not get wallet balance amt.");
-            j_optional_empty(&env)
+            Ok(balance_amt) => j_optional_balance_amt_result(&env, balance_amt),
+            Err(e) => {
+                error!("Could not get wallet balance amt: {}", e);
+                Err(e.into())
+            }
         }
-    }
+    })
 }
 
 // new Address(String address, int network, Optional<String> type)
-// Address org.bdk.jni.BdkLib.depositAddress()
+// Address org.bdk.jni.BdkLib.depositAddress(long wallet)
 #[no_mangle]
-pub unsafe extern fn Java_org_bdk_jni_BdkLib_depositAddress(env: JNIEnv, _: JObject) -> jobject {
-    let address = deposit_addr();
-    j_address(&env, &address)
+pub unsafe extern fn Java_org_bdk_jni_BdkLib_depositAddress(env: JNIEnv, _: JObject, j_wallet: jlong) -> jobject {
+    guard(&env, null(), || {
+        let address = wallet_from_jlong(j_wallet)?.deposit_addr()?;
+        j_address(&env, &address)
+    })
 }
 
 // new WithdrawTx(String txid, long fee)
-// WithdrawTx org.bdk.jni.BdkLib.withdraw(String passphrase, String address, long feePerVbyte, long amount)
+// WithdrawTx org.bdk.jni.BdkLib.withdraw(long wallet, String passphrase, String address, long feePerVbyte, long amount)
 #[no_mangle]
 pub unsafe extern fn Java_org_bdk_jni_BdkLib_withdraw(env: JNIEnv, _: JObject,
+                                                          j_wallet: jlong,
                                                           j_passphrase: JString,
                                                           j_address: JString,
                                                           j_fee_per_vbyte: jlong,
                                                           j_amount: jlong) -> jobject {
+    guard(&env, null(), || {
+        let passphrase = string_from_jstring(&env, j_passphrase)?;
+        let address = string_from_jstring(&env, j_address)?;
+        let address = Address::from_str(address.as_str()).map_err(Error::from)?;
+
+        let (fee_per_vbyte, amount) = match (u64::try_from(j_fee_per_vbyte), u64::try_from(j_amount)) {
+            (Ok(fee_per_vbyte), Ok(amount)) => (fee_per_vbyte, amount),
+            _ => return Err(Error::Unsupported("negative fee or amount").into())
+        };
+
+        let withdraw_tx = wallet_from_jlong(j_wallet)?.withdraw(passphrase, address, fee_per_vbyte, Some(amount))?;
+        j_withdraw_tx(&env, &withdraw_tx)
+    })
+}
+
+// long org.bdk.jni.BdkLib.estimateFee(long wallet, String address, long feePerVbyte, long amount)
+#[no_mangle]
+pub unsafe extern fn Java_org_bdk_jni_BdkLib_estimateFee(env: JNIEnv, _: JObject,
+                                                             j_wallet: jlong,
+                                                             j_address: JString,
+                                                             j_fee_per_vbyte: jlong,
+                                                             j_amount: jlong) -> jlong {
+    guard(&env, 0, || {
+        let address = string_from_jstring(&env, j_address)?;
+        let address = Address::from_str(address.as_str()).map_err(Error::from)?;
+
+        let (fee_per_vbyte, amount) = match (u64::try_from(j_fee_per_vbyte), u64::try_from(j_amount)) {
+            (Ok(fee_per_vbyte), Ok(amount)) => (fee_per_vbyte, amount),
+            _ => return Err(Error::Unsupported("negative fee or amount").into())
+        };
+
+        let fee = wallet_from_jlong(j_wallet)?.estimate_fee(&address, fee_per_vbyte, Some(amount))?;
+        jlong_from_u64(fee)
+    })
+}
 
-    let passphrase = string_from_jstring(&env, j_passphrase);
-    let address = string_from_jstring(&env, j_address);
-    let address = Address::from_str(address.as_str()).unwrap();
+// HistoryEntry[] org.bdk.jni.BdkLib.history(long wallet)
+#[no_mangle]
+pub unsafe extern fn Java_org_bdk_jni_BdkLib_history(env: JNIEnv, _: JObject, j_wallet: jlong) -> jobjectArray {
+    guard(&env, null(), || {
+        let history = wallet_from_jlong(j_wallet)?.history()?;
+        let j_history = env.new_object_array(history.len() as jint,
+                                             env.find_class("org/bdk/jni/HistoryEntry")?,
+                                             JObject::null())?;
+        for (i, entry) in history.iter().enumerate() {
+            env.set_object_array_element(j_history, i as jint, JObject::from(j_history_entry(&env, entry)?))?;
+        }
+        Ok(j_history)
+    })
+}
 
-    let fee_per_vbyte = u64::try_from(j_fee_per_vbyte).unwrap();
-    let amount = u64::try_from(j_amount).unwrap();
+// SyncStatus org.bdk.jni.BdkLib.syncStatus(long wallet)
+#[no_mangle]
+pub unsafe extern fn Java_org_bdk_jni_BdkLib_syncStatus(env: JNIEnv, _: JObject, j_wallet: jlong) -> jobject {
+    guard(&env, null(), || {
+        let sync_status = wallet_from_jlong(j_wallet)?.sync_status()?;
+        j_sync_status(&env, &sync_status)
+    })
+}
 
-    let withdraw_tx = withdraw(passphrase, address, fee_per_vbyte, Some(amount)).unwrap();
-    j_withdraw_tx(&env, &withdraw_tx)
+// Peer[] org.bdk.jni.BdkLib.peers(long wallet)
+#[no_mangle]
+pub unsafe extern fn Java_org_bdk_jni_BdkLib_peers(env: JNIEnv, _: JObject, j_wallet: jlong) -> jobjectArray {
+    guard(&env, null(), || {
+        let peers = wallet_from_jlong(j_wallet)?.peers();
+        let j_peers = env.new_object_array(peers.len() as jint,
+                                           env.find_class("org/bdk/jni/Peer")?,
+                                           JObject::null())?;
+        for (i, peer) in peers.iter().enumerate() {
+            env.set_object_array_element(j_peers, i as jint, JObject::from(j_peer(&env, peer)?))?;
+        }
+        Ok(j_peers)
+    })
 }
 
+// interface org.bdk.jni.WalletListener {
+//     void onNewTip(String blockHash, long height);
+//     void onBlockConnected(String blockHash, long height);
+//     void onBlockDisconnected(String blockHash);
+//     void onIncomingTx(String txid, long amount);
+//     void onOutgoingTxConfirmed(String txid, long height);
+//     void onBalanceChanged(long balance, long confirmed);
+//     void onPeerConnected(String address);
+//     void onPeerDisconnected(String address);
+//     void onSyncFinished(long height);
+// }
+// void org.bdk.jni.BdkLib.subscribe(long wallet, WalletListener listener)
+// listener is called on a background thread until the wallet is stopped
+#[no_mangle]
+pub unsafe extern fn Java_org_bdk_jni_BdkLib_subscribe(env: JNIEnv, _: JObject, j_wallet: jlong, j_listener: JObject) {
+    guard(&env, (), || {
+        let events = wallet_from_jlong(j_wallet)?.subscribe();
+        let vm = env.get_java_vm()?;
+        let listener = env.new_global_ref(j_listener)?;
+
+        thread::Builder::new().name("java listener".to_string()).spawn(move || j_listen(vm, listener, events)).map_err(Error::from)?;
+        Ok(())
+    })
+}
+
+// test entry point, shows that a panic is thrown as BdkException of kind Panic instead of aborting the JVM
+// void org.bdk.jni.PanicTest.panic(String message)
+#[cfg(feature = "jni-test")]
+#[no_mangle]
+pub unsafe extern fn Java_org_bdk_jni_PanicTest_panic(env: JNIEnv, _: JObject, j_message: JString) {
+    guard(&env, (), || {
+        let message = string_from_jstring(&env, j_message)?;
+        panic!("{}", message)
+    })
+}
+
+// test entry point, calls the listener with count new tip events as subscribe would and returns once all are delivered
+// void org.bdk.jni.ListenerTest.replay(WalletListener listener, int count)
+#[cfg(feature = "jni-test")]
+#[no_mangle]
+pub unsafe extern fn Java_org_bdk_jni_ListenerTest_replay(env: JNIEnv, _: JObject, j_listener: JObject, j_count: jint) {
+    guard(&env, (), || {
+        let count = u32::try_from(j_count).map_err(|_| Error::Unsupported("negative count"))?;
+        let (sender, events) = mpsc::channel();
+        for height in 0..count {
+            sender.send(WalletEvent::NewTip { height, block_hash: Default::default() }).map_err(|_| Error::Unsupported("can not queue event"))?;
+        }
+        drop(sender);
+        let vm = env.get_java_vm()?;
+        let listener = env.new_global_ref(j_listener)?;
+        thread::Builder::new().name("java listener".to_string()).spawn(move || j_listen(vm, listener, events)).map_err(Error::from)?
+            .join().map_err(|_| Error::Unsupported("java listener thread panicked"))?;
+        Ok(())
+    })
+}
 
 // private functions
 
-fn string_from_jstring(env: &JNIEnv, j_string: JString) -> String {
-    let java_str = env.get_string(j_string).expect("error get_string j_string");
-    let str = java_str.to_str().expect("error java_str.to_str");
-    String::from(str)
+// what can go wrong in a JNI function
+enum Failure {
+    // throw as exception of the error's kind
+    Bdk(Error),
+    // a JNI call failed, its exception might already be pending
+    Jni(jni::errors::Error),
+}
+
+impl From<Error> for Failure {
+    fn from(error: Error) -> Failure {
+        Failure::Bdk(error)
+    }
+}
+
+impl From<jni::errors::Error> for Failure {
+    fn from(error: jni::errors::Error) -> Failure {
+        Failure::Jni(error)
+    }
+}
+
+// run f, throw its error or panic as java exception and return null instead.
+// A panic must not unwind into the JVM, that would abort the process.
+fn guard<T, F>(env: &JNIEnv, null: T, f: F) -> T where F: FnOnce() -> Result<T, Failure> {
+    match catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(result)) => result,
+        Ok(Err(Failure::Bdk(e))) => {
+            throw(env, j_exception_class(&e), e.kind(), e.to_string());
+            null
+        }
+        Ok(Err(Failure::Jni(e))) => {
+            // an exception thrown by java code we called is already pending
+            if !env.exception_check().unwrap_or(false) {
+                throw(env, "org/bdk/jni/BdkException", "Jni", e.to_string());
+            }
+            null
+        }
+        Err(panic) => {
+            let message = panic_message(&*panic);
+            error!("panic in JNI call: {}", message);
+            throw(env, "org/bdk/jni/BdkException", "Panic", message);
+            null
+        }
+    }
+}
+
+fn panic_message(panic: &(dyn Any + Send)) -> String {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "panic".to_string()
+    }
+}
+
+// throw a java exception, java ignores the value returned with it.
+// All exception classes extend org.bdk.jni.BdkException(String kind, String message), which extends RuntimeException
+fn throw(env: &JNIEnv, class: &str, kind: &str, message: String) {
+    let thrown = env.new_string(kind).and_then(|kind| {
+        let message = env.new_string(message.as_str())?;
+        env.new_object(class, "(Ljava/lang/String;Ljava/lang/String;)V",
+                       &[JValue::Object(kind.into()), JValue::Object(message.into())])
+    }).and_then(|exception| env.throw(JThrowable::from(exception)));
+    if let Err(e) = thrown {
+        error!("can not throw java exception for {}: {}", message, e);
+    }
+}
+
+fn null() -> jobject {
+    JObject::null().into_inner()
+}
+
+// call the listener with each event until the sender is gone, on a thread attached to the JVM
+fn j_listen(vm: JavaVM, listener: GlobalRef, events: mpsc::Receiver<WalletEvent>) {
+    let env = match vm.attach_current_thread() {
+        Ok(env) => env,
+        Err(e) => {
+            error!("can not attach java listener thread: {}", e);
+            return;
+        }
+    };
+    while let Ok(event) = events.recv() {
+        // the thread stays attached, local references of a call are freed with its frame
+        let called = env.with_local_frame(8, || {
+            j_call_listener(&env, listener.as_obj(), event)?;
+            Ok(JObject::null())
+        });
+        // an exception thrown by the listener must not stay pending for the next call
+        if env.exception_check().unwrap_or(false) {
+            env.exception_clear().ok();
+        }
+        if let Err(e) = called {
+            error!("error calling wallet listener {}", e);
+        }
+    }
+}
+
+fn j_call_listener(env: &JNIEnv, listener: JObject, event: WalletEvent) -> jni::errors::Result<()> {
+    let (name, sig, args) = match event {
+        WalletEvent::NewTip { height, block_hash } =>
+            ("onNewTip", "(Ljava/lang/String;J)V", vec!(j_string_value(env, block_hash.to_string())?, JValue::Long(height as jlong))),
+        WalletEvent::BlockConnected { height, block_hash } =>
+            ("onBlockConnected", "(Ljava/lang/String;J)V", vec!(j_string_value(env, block_hash.to_string())?, JValue::Long(height as jlong))),
+        WalletEvent::BlockDisconnected { block_hash } =>
+            ("onBlockDisconnected", "(Ljava/lang/String;)V", vec!(j_string_value(env, block_hash.to_string())?)),
+        WalletEvent::IncomingTx { txid, amount } =>
+            ("onIncomingTx", "(Ljava/lang/String;J)V", vec!(j_string_value(env, txid.to_string())?, JValue::Long(amount as jlong))),
+        WalletEvent::OutgoingTxConfirmed { txid, height } =>
+            ("onOutgoingTxConfirmed", "(Ljava/lang/String;J)V", vec!(j_string_value(env, txid.to_string())?, JValue::Long(height as jlong))),
+        WalletEvent::BalanceChanged { balance, confirmed } =>
+            ("onBalanceChanged", "(JJ)V", vec!(JValue::Long(balance as jlong), JValue::Long(confirmed as jlong))),
+        WalletEvent::PeerConnected { address } =>
+            ("onPeerConnected", "(Ljava/lang/String;)V", vec!(j_string_value(env, address.map(|a| a.to_string()).unwrap_or_default())?)),
+        WalletEvent::PeerDisconnected { address } =>
+            ("onPeerDisconnected", "(Ljava/lang/String;)V", vec!(j_string_value(env, address.map(|a| a.to_string()).unwrap_or_default())?)),
+        WalletEvent::SyncFinished { height } =>
+            ("onSyncFinished", "(J)V", vec!(JValue::Long(height as jlong))),
+    };
+    env.call_method(listener, name, sig, args.as_slice())?;
+    Ok(())
+}
+
+fn j_string_value<'a>(env: &JNIEnv<'a>, string: String) -> jni::errors::Result<JValue<'a>> {
+    Ok(JValue::Object(env.new_string(string)?.into()))
+}
+
+// the wallet of the id returned by start, until stop
+fn wallet_from_jlong(j_wallet: jlong) -> Result<Arc<WalletHandle>, Error> {
+    WALLETS.lock().unwrap().get(&j_wallet).cloned().ok_or(Error::NotStarted)
+}
+
+fn j_exception_class(error: &Error) -> &'static str {
+    match error {
+        Error::InsufficientFunds { .. } => "org/bdk/jni/InsufficientFundsException",
+        Error::BelowDust { .. } => "org/bdk/jni/BelowDustException",
+        Error::FeeAboveMaximum { .. } => "org/bdk/jni/FeeAboveMaximumException",
+        Error::WrongPassphrase => "org/bdk/jni/WrongPassphraseException",
+        Error::DatabaseLocked => "org/bdk/jni/DatabaseLockedException",
+        Error::PassphraseTooShort => "org/bdk/jni/PassphraseTooShortException",
+        Error::NotStarted => "org/bdk/jni/NotStartedException",
+        Error::InvalidAddress(_) => "org/bdk/jni/InvalidAddressException",
+        Error::NetworkMismatch { .. } => "org/bdk/jni/NetworkMismatchException",
+        Error::Corrupted(_) => "org/bdk/jni/CorruptedException",
+        _ => "org/bdk/jni/BdkException"
+    }
+}
+
+fn string_from_jstring(env: &JNIEnv, j_string: JString) -> Result<String, Failure> {
+    if j_string.is_null() {
+        return Err(Error::Unsupported("null string argument").into());
+    }
+    Ok(String::from(env.get_string(j_string)?))
+}
+
+fn j_optional_of(env: &JNIEnv, object: JObject) -> Result<jobject, Failure> {
+    // Optional.of(object)
+    let j_result = env.call_static_method(
+        "java/util/Optional",
+        "of",
+        "(Ljava/lang/Object;)Ljava/util/Optional;",
+        &[JValue::Object(object)])?.l()?;
+
+    Ok(j_result.into_inner())
 }
 
-fn j_optional_empty(env: &JNIEnv) -> jobject {
+fn j_optional_empty(env: &JNIEnv) -> Result<jobject, Failure> {
     // Optional.empty())
     let j_result = env.call_static_method(
         "java/util/Optional",
         "empty",
         "()Ljava/util/Optional;",
-        &[]).expect("error Optional.empty()")
-        .l().expect("error converting Optional.empty() jvalue to jobject");
+        &[])?.l()?;
 
-    j_result.into_inner()
+    Ok(j_result.into_inner())
 }
 
-fn network_from_jint(network_enum_ordinal: jint) -> Network {
+fn network_from_jint(network_enum_ordinal: jint) -> Result<Network, Error> {
     match network_enum_ordinal {
-        0 => Some(Network::Bitcoin),
-        1 => Some(Network::Testnet),
-        2 => Some(Network::Regtest),
-        _ => None
-    }.expect("invalid network enum ordinal")
+        0 => Ok(Network::Bitcoin),
+        1 => Ok(Network::Testnet),
+        2 => Ok(Network::Regtest),
+        _ => Err(Error::Unsupported("invalid network enum ordinal"))
+    }
 }
 
 fn jint_from_network(network: Network) -> jint {
@@ -261,11 +609,39 @@ fn jint_from_network(network: Network) -> jint {
     }
 }
 
+fn j_string_array(env: &JNIEnv, strings: Vec<String>) -> Result<jobjectArray, Failure> {
+    let j_result = env.new_object_array(strings.len() as jint,
+                                        env.find_class("java/lang/String")?,
+                                        JObject::null())?;
+    for (i, string) in strings.into_iter().enumerate() {
+        env.set_object_array_element(j_result, i as jint, env.new_string(string)?.into())?;
+    }
+    Ok(j_result)
+}
+
+fn j_optional_long(env: &JNIEnv, value: Option<u64>) -> Result<jobject, Failure> {
+    match value {
+        Some(value) => {
+            // Optional.of(Long.valueOf(value))
+            let j_long = env.call_static_method(
+                "java/lang/Long",
+                "valueOf",
+                "(J)Ljava/lang/Long;",
+                &[JValue::Long(jlong_from_u64(value)?)])?.l()?;
+            j_optional_of(env, j_long)
+        }
+        None => j_optional_empty(env)
+    }
+}
+
+fn jlong_from_u64(value: u64) -> Result<jlong, Failure> {
+    Ok(jlong::try_from(value).map_err(|_| Error::Unsupported("value does not fit a java long"))?)
+}
+
 // InitResult(String mnemonicWords, Address depositAddress)
-fn j_optional_init_result(env: &JNIEnv, init_result: InitResult) -> jobject {
-    let mnemonic_words = env.new_string(init_result.mnemonic_words)
-        .expect("error new_string mnemonic_words");
-    let deposit_address: jobject = j_address(&env, &init_result.deposit_address);
+fn j_optional_init_result(env: &JNIEnv, init_result: InitResult) -> Result<jobject, Failure> {
+    let mnemonic_words = env.new_string(init_result.mnemonic_words)?;
+    let deposit_address: jobject = j_address(&env, &init_result.deposit_address)?;
 
     // org.bdk.jni.InitResult
     // Optional.of(InitResult(String mnemonicWords, String depositAddress))
@@ -273,64 +649,35 @@ fn j_optional_init_result(env: &JNIEnv, init_result: InitResult) -> jobject {
         "org/bdk/jni/InitResult",
         "(Ljava/lang/String;Lorg/bdk/jni/Address;)V",
         &[JValue::Object(mnemonic_words.into()), JValue::Object(deposit_address.into())],
-    ).expect("error new_object InitResult");
+    )?;
 
-    let j_result = env.call_static_method(
-        "java/util/Optional",
-        "of",
-        "(Ljava/lang/Object;)Ljava/util/Optional;",
-        &[JValue::Object(j_result)]).expect("error Optional.of(InitResult)")
-        .l().expect("error converting Optional.of() jvalue to jobject");
-
-    j_result.into_inner()
+    j_optional_of(env, j_result)
 }
 
 // new BalanceAmt(long,long)
-fn j_optional_balance_amt_result(env: &JNIEnv, balance_amt: BalanceAmt) -> jobject {
-    let bal = JValue::Long(jlong::try_from(balance_amt.balance).unwrap());
-    let conf = JValue::Long(jlong::try_from(balance_amt.confirmed).unwrap());
+fn j_optional_balance_amt_result(env: &JNIEnv, balance_amt: BalanceAmt) -> Result<jobject, Failure> {
+    let bal = JValue::Long(jlong_from_u64(balance_amt.balance)?);
+    let conf = JValue::Long(jlong_from_u64(balance_amt.confirmed)?);
     let j_result = env.new_object(
         "org/bdk/jni/BalanceAmt",
         "(JJ)V",
         &[bal, conf],
-    ).expect("error new_object BalanceAmt");
+    )?;
 
-    let j_result = env.call_static_method(
-        "java/util/Optional",
-        "of",
-        "(Ljava/lang/Object;)Ljava/util/Optional;",
-        &[JValue::Object(j_result)]).expect("error Optional.of(BalanceAmt)")
-        .l().expect("error converting Optional.of() jvalue to jobject");
-
-    j_result.into_inner()
+    j_optional_of(env, j_result)
 }
 
 // Config(int networkEnumOrdinal, String[] bitcoinPeers, int bitcoinConnections, boolean bitcoinDiscovery)
-fn j_optional_config(env: &JNIEnv, config: &Config) -> jobject {
+fn j_optional_config(env: &JNIEnv, config: &Config) -> Result<jobject, Failure> {
     let j_network_enum_ordinal: JValue = jint_from_network(config.network).into();
 
-    // return peer addresses as JString vector
-    let j_bitcoin_peer_vec: Vec<JString> = config.bitcoin_peers.iter()
-        .map(|s| s.to_string())
-        .map(|a| env.new_string(a).expect("error env.new_string(a)"))
-        .collect();
-
-    let j_bitcoin_peer_arr: jobjectArray = env.new_object_array(i32::try_from(j_bitcoin_peer_vec.len()).unwrap(),
-                                                                env.find_class("java/lang/String").expect("error env.find_class(String)"),
-                                                                env.new_string("").expect("error env.new_string()").into())
-        .expect("error env.new_object_array()");
-
-
-    for i in 0..(j_bitcoin_peer_vec.len()) {
-        env.set_object_array_element(j_bitcoin_peer_arr, i32::try_from(i).unwrap(),
-                                     j_bitcoin_peer_vec[i].into()).expect("error set_object_array_element");
-    }
+    // return peer addresses as String array
+    let j_bitcoin_peer_arr = j_string_array(env, config.bitcoin_peers.iter().map(|p| p.to_string()).collect())?;
 
     let j_bitcoin_connections: JValue = jint::try_from(config.bitcoin_connections)
-        .expect("error converting bitcoin_connections to jint").into();
+        .map_err(|_| Error::Unsupported("bitcoin connections do not fit a java int"))?.into();
 
-    let j_bitcoin_discover: JValue = jboolean::try_from(config.bitcoin_discovery)
-        .expect("error converting bitcoin_discovery to jboolean").into();
+    let j_bitcoin_discover: JValue = (config.bitcoin_discovery as jboolean).into();
 
     // org.bdk.jni.Config
     // Optional.of(Config())
@@ -339,44 +686,19 @@ fn j_optional_config(env: &JNIEnv, config: &Config) -> jobject {
         "(I[Ljava/lang/String;IZ)V",
         &[j_network_enum_ordinal, JValue::Object(j_bitcoin_peer_arr.into()),
             j_bitcoin_connections, j_bitcoin_discover],
-    ).expect("error new_object Config");
-
-    let j_result = env.call_static_method(
-        "java/util/Optional",
-        "of",
-        "(Ljava/lang/Object;)Ljava/util/Optional;",
-        &[JValue::Object(j_result)]).expect("error Optional.of(InitResult)")
-        .l().expect("error converting Optional.of() jvalue to jobject");
+    )?;
 
-    j_result.into_inner()
-}
-
-fn j_optional_string(env: &JNIEnv, string: &String) -> jobject {
-    let j_string = env.new_string(string).unwrap();
-
-    // java.lang.String
-    // Optional.of(String)
-    let j_result = env.call_static_method(
-        "java/util/Optional",
-        "of",
-        "(Ljava/lang/Object;)Ljava/util/Optional;",
-        &[JValue::Object(j_string.into())]).expect("error Optional.of(String)")
-        .l().expect("error converting Optional.of() jvalue to jobject");
-
-    j_result.into_inner()
+    j_optional_of(env, j_result)
 }
 
 // org.bdk.jni.Address(String address, int networkEnumOrdinal, Optional<String> type)
-fn j_address(env: &JNIEnv, address: &Address) -> jobject {
-    let addr = address.to_string();
-    let addr = env.new_string(addr).unwrap();
+fn j_address(env: &JNIEnv, address: &Address) -> Result<jobject, Failure> {
+    let addr = env.new_string(address.to_string())?;
     let addr = JValue::Object(addr.into());
-    let addr_network = jint_from_network(address.network);
-    let addr_network = JValue::Int(addr_network);
-    let addr_type = address.address_type().map(|t| t.to_string());
-    let addr_type: jobject = match addr_type {
-        Some(at) => j_optional_string(&env, &at),
-        None => j_optional_empty(&env)
+    let addr_network = JValue::Int(jint_from_network(address.network));
+    let addr_type: jobject = match address.address_type() {
+        Some(at) => j_optional_of(env, env.new_string(at.to_string())?.into())?,
+        None => j_optional_empty(&env)?
     };
     let addr_type = JValue::Object(addr_type.into());
 
@@ -384,22 +706,70 @@ fn j_address(env: &JNIEnv, address: &Address) -> jobject {
         "org/bdk/jni/Address",
         "(Ljava/lang/String;ILjava/util/Optional;)V",
         &[addr, addr_network, addr_type],
-    ).expect("error new_object Address");
+    )?;
 
-    j_result.into_inner()
+    Ok(j_result.into_inner())
 }
 
 // org.bdk.jni.WithdrawTx(String txid, long fee)
-fn j_withdraw_tx(env: &JNIEnv, withdraw_tx: &WithdrawTx) -> jobject {
-    let txid = withdraw_tx.txid.to_string();
-    let txid = env.new_string(txid).unwrap();
-    let fee = i64::try_from(withdraw_tx.fee).unwrap();
+fn j_withdraw_tx(env: &JNIEnv, withdraw_tx: &WithdrawTx) -> Result<jobject, Failure> {
+    let txid = env.new_string(withdraw_tx.txid.to_string())?;
+    let fee = jlong_from_u64(withdraw_tx.fee)?;
 
     let j_result = env.new_object(
         "org/bdk/jni/WithdrawTx",
         "(Ljava/lang/String;J)V",
         &[JValue::Object(txid.into()), JValue::Long(fee)],
-    ).expect("error new_object WithdrawTx");
+    )?;
+
+    Ok(j_result.into_inner())
+}
+
+// org.bdk.jni.HistoryEntry(String txid, Optional<String> blockHash, Optional<Long> height, Optional<Long> time, long received, long sent, Optional<Long> fee)
+fn j_history_entry(env: &JNIEnv, entry: &HistoryEntry) -> Result<jobject, Failure> {
+    let txid = env.new_string(entry.txid.to_string())?;
+    let block_hash = match entry.block_hash {
+        Some(block_hash) => j_optional_of(env, env.new_string(block_hash.to_string())?.into())?,
+        None => j_optional_empty(env)?
+    };
+    let height = j_optional_long(env, entry.height.map(|h| h as u64))?;
+    let time = j_optional_long(env, entry.time.map(|t| t as u64))?;
+    let fee = j_optional_long(env, entry.fee)?;
+
+    let j_result = env.new_object(
+        "org/bdk/jni/HistoryEntry",
+        "(Ljava/lang/String;Ljava/util/Optional;Ljava/util/Optional;Ljava/util/Optional;JJLjava/util/Optional;)V",
+        &[JValue::Object(txid.into()), JValue::Object(block_hash.into()), JValue::Object(height.into()),
+            JValue::Object(time.into()), JValue::Long(jlong_from_u64(entry.received)?), JValue::Long(jlong_from_u64(entry.sent)?),
+            JValue::Object(fee.into())],
+    )?;
+
+    Ok(j_result.into_inner())
+}
+
+// org.bdk.jni.Peer(String address, String ip, int port)
+fn j_peer(env: &JNIEnv, peer: &SocketAddr) -> Result<jobject, Failure> {
+    let address = env.new_string(peer.to_string())?;
+    let ip = env.new_string(peer.ip().to_string())?;
+
+    let j_result = env.new_object(
+        "org/bdk/jni/Peer",
+        "(Ljava/lang/String;Ljava/lang/String;I)V",
+        &[JValue::Object(address.into()), JValue::Object(ip.into()), JValue::Int(peer.port() as jint)],
+    )?;
+
+    Ok(j_result.into_inner())
+}
+
+// org.bdk.jni.SyncStatus(long tipHeight, Optional<Long> processedHeight)
+fn j_sync_status(env: &JNIEnv, sync_status: &SyncStatus) -> Result<jobject, Failure> {
+    let processed_height = j_optional_long(env, sync_status.processed_height.map(|h| h as u64))?;
+
+    let j_result = env.new_object(
+        "org/bdk/jni/SyncStatus",
+        "(JLjava/util/Optional;)V",
+        &[JValue::Long(sync_status.tip_height as jlong), JValue::Object(processed_height.into())],
+    )?;
 
-    j_result.into_inner()
+    Ok(j_result.into_inner())
 }
@@ -0,0 +1,174 @@
+/*
+ * Copyright 2020 BDK Team
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Labels of transactions, addresses, outputs and extended public keys.
+//!
+//! Import and export use the BIP329 format, one JSON record per line:
+//! {"type": "tx", "ref": "<txid>", "label": "rent"}
+
+use std::io::{BufRead, Write};
+use std::str::FromStr;
+
+use bitcoin::{Address, OutPoint};
+use bitcoin::util::bip32::ExtendedPubKey;
+use bitcoin_hashes::sha256d;
+use bitcoin_hashes::hex::FromHex;
+
+use crate::error::Error;
+
+/// what a label is attached to, BIP329 record types
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LabelKind {
+    Tx,
+    Addr,
+    Output,
+    Xpub,
+}
+
+impl LabelKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LabelKind::Tx => "tx",
+            LabelKind::Addr => "addr",
+            LabelKind::Output => "output",
+            LabelKind::Xpub => "xpub",
+        }
+    }
+
+    /// the reference in its canonical form, fails if it is not a txid, address, txid:vout or xpub as the kind requires
+    pub fn reference(&self, reference: &str) -> Result<String, Error> {
+        Ok(match self {
+            LabelKind::Tx => sha256d::Hash::from_hex(reference)?.to_string(),
+            LabelKind::Addr => Address::from_str(reference)?.to_string(),
+            LabelKind::Output => {
+                let outpoint = parse_outpoint(reference)?;
+                format!("{}:{}", outpoint.txid, outpoint.vout)
+            }
+            LabelKind::Xpub => ExtendedPubKey::from_str(reference).map_err(|_| Error::Unsupported("malformed xpub"))?.to_string(),
+        })
+    }
+}
+
+impl FromStr for LabelKind {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<LabelKind, Error> {
+        match s {
+            "tx" => Ok(LabelKind::Tx),
+            "addr" => Ok(LabelKind::Addr),
+            "output" => Ok(LabelKind::Output),
+            "xpub" => Ok(LabelKind::Xpub),
+            _ => Err(Error::Unsupported("label type should be tx, addr, output or xpub"))
+        }
+    }
+}
+
+/// a BIP329 record
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Label {
+    #[serde(rename = "type")]
+    pub kind: LabelKind,
+    #[serde(rename = "ref")]
+    pub reference: String,
+    pub label: String,
+    /// descriptor of the wallet the label was made in, kept as imported
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub origin: Option<String>,
+}
+
+impl Label {
+    /// a label with the reference in canonical form
+    pub fn new(kind: LabelKind, reference: &str, label: String) -> Result<Label, Error> {
+        Ok(Label { kind, reference: kind.reference(reference)?, label, origin: None })
+    }
+}
+
+// a record as other wallets may write it, with types we do not keep
+#[derive(Deserialize)]
+struct Record {
+    #[serde(rename = "type")]
+    kind: String,
+    #[serde(rename = "ref")]
+    reference: String,
+    label: Option<String>,
+    origin: Option<String>,
+}
+
+/// "txid:vout"
+pub fn parse_outpoint(s: &str) -> Result<OutPoint, Error> {
+    let mut parts = s.splitn(2, ':');
+    match (parts.next(), parts.next()) {
+        (Some(txid), Some(vout)) => Ok(OutPoint {
+            txid: sha256d::Hash::from_hex(txid)?,
+            vout: vout.parse().map_err(|_| Error::Unsupported("output should be txid:vout"))?,
+        }),
+        _ => Err(Error::Unsupported("output should be txid:vout"))
+    }
+}
+
+/// read BIP329 records. Records of types other than tx, addr, output and xpub, or without label, are skipped
+pub fn read<R: BufRead>(reader: R) -> Result<Vec<Label>, Error> {
+    let mut labels = Vec::new();
+    for line in reader.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record: Record = serde_json::from_str(line.as_str()).map_err(|_| Error::Unsupported("malformed BIP329 record"))?;
+        if let (Ok(kind), Some(label)) = (LabelKind::from_str(record.kind.as_str()), record.label) {
+            labels.push(Label { kind, reference: kind.reference(record.reference.as_str())?, label, origin: record.origin });
+        }
+    }
+    Ok(labels)
+}
+
+/// write BIP329 records
+pub fn write<W: Write>(writer: &mut W, labels: &[Label]) -> Result<(), Error> {
+    for label in labels {
+        serde_json::to_writer(&mut *writer, label).map_err(|_| Error::Unsupported("can not format label"))?;
+        writeln!(writer)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Label, LabelKind, read, write};
+
+    const TXID: &str = "f91d0a8a78462bc59398f2c5d7a84fcff491c26ba54c4833478b202796c8aafd";
+
+    #[test]
+    fn bip329_round_trip() {
+        let records = format!(
+            "{{\"type\":\"tx\",\"ref\":\"{}\",\"label\":\"rent, march\",\"origin\":\"wpkh([d34db33f/84'/0'/0'])\"}}\n\
+             {{\"type\":\"output\",\"ref\":\"{}:1\",\"label\":\"change\",\"spendable\":true}}\n\
+             \n\
+             {{\"type\":\"pubkey\",\"ref\":\"0283409659355b6d1cc3c32decd5d561abaac86c37a353b52895a5e6c196d6f448\",\"label\":\"skipped\"}}\n\
+             {{\"type\":\"addr\",\"ref\":\"bc1q34aq5drpuwy3wgl9lhup9892qp6svr8ldzyy7c\"}}\n", TXID, TXID);
+        let labels = read(records.as_bytes()).unwrap();
+        assert_eq!(labels, vec!(
+            Label { kind: LabelKind::Tx, reference: TXID.to_string(), label: "rent, march".to_string(), origin: Some("wpkh([d34db33f/84'/0'/0'])".to_string()) },
+            Label::new(LabelKind::Output, format!("{}:1", TXID).as_str(), "change".to_string()).unwrap()));
+
+        let mut exported = Vec::new();
+        write(&mut exported, &labels).unwrap();
+        assert_eq!(read(exported.as_slice()).unwrap(), labels);
+
+        assert!(read(format!("{{\"type\":\"output\",\"ref\":\"{}\",\"label\":\"no vout\"}}", TXID).as_bytes()).is_err());
+        assert!(read("not json".as_bytes()).is_err());
+    }
+}
@@ -21,15 +21,29 @@
 extern crate serde_derive;
 
 pub mod api;
+pub mod backup;
 pub mod blockdownload;
+pub mod component;
 pub mod config;
+pub mod crypt;
 pub mod db;
 pub mod error;
+pub mod event;
+pub mod export;
+pub mod labels;
 pub mod p2p_bitcoin;
+pub mod ping;
 pub mod sendtx;
+pub mod storage;
 pub mod store;
 pub mod trunk;
 pub mod wallet;
 
+#[cfg(test)]
+pub mod mocknode;
+
 #[cfg(any(feature = "java", feature = "android"))]
-pub mod jni;
\ No newline at end of file
+pub mod jni;
+
+#[cfg(feature = "ffi")]
+pub mod ffi;
\ No newline at end of file
@@ -0,0 +1,306 @@
+/*
+ * Copyright 2020 BDK Team
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! an in-process bitcoin peer serving a scripted chain, for tests
+
+use std::io;
+use std::io::{Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bitcoin::{Address, BitcoinHash, Block, BlockHeader, OutPoint, Transaction, TxIn, TxOut};
+use bitcoin::blockdata::constants::genesis_block;
+use bitcoin::blockdata::script::Builder;
+use bitcoin::consensus::encode::{deserialize, serialize};
+use bitcoin::network::address;
+use bitcoin::network::constants::Network;
+use bitcoin::network::message::{NetworkMessage, RawNetworkMessage};
+use bitcoin::network::message_blockdata::{Inventory, InvType};
+use bitcoin::network::message_network::VersionMessage;
+use bitcoin::util::hash::MerkleRoot;
+use bitcoin_hashes::sha256d;
+use byteorder::{ByteOrder, LittleEndian};
+use log::debug;
+use rand::{RngCore, thread_rng};
+
+const PROTOCOL_VERSION: u32 = 70012;
+const NODE_NETWORK: u64 = 1;
+const MAX_HEADERS: usize = 2000;
+const NEW_COINS: u64 = 5000000000;
+
+/// a bitcoin node listening on localhost that serves a chain built by the test
+pub struct MockNode {
+    network: Network,
+    address: SocketAddr,
+    chain: Arc<Mutex<Vec<Block>>>,
+    mempool: Arc<Mutex<Vec<Transaction>>>,
+    received: Arc<Mutex<Vec<Transaction>>>,
+    peers: Arc<Mutex<Vec<MockPeer>>>,
+    get_headers: Arc<Mutex<usize>>,
+}
+
+struct MockPeer {
+    stream: TcpStream,
+    send_headers: bool,
+}
+
+impl MockNode {
+    /// start listening on a random localhost port with a chain of only the genesis block
+    pub fn start(network: Network) -> MockNode {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("can not bind mock node");
+        let node = MockNode {
+            network,
+            address: listener.local_addr().unwrap(),
+            chain: Arc::new(Mutex::new(vec!(genesis_block(network)))),
+            mempool: Arc::new(Mutex::new(Vec::new())),
+            received: Arc::new(Mutex::new(Vec::new())),
+            peers: Arc::new(Mutex::new(Vec::new())),
+            get_headers: Arc::new(Mutex::new(0)),
+        };
+        let connection = node.connection();
+        thread::Builder::new().name("mock node".to_string()).spawn(move || {
+            for stream in listener.incoming() {
+                if let Ok(stream) = stream {
+                    let mut connection = connection.clone();
+                    thread::spawn(move || {
+                        if let Err(e) = connection.serve(stream) {
+                            debug!("mock node connection closed {}", e);
+                        }
+                    });
+                }
+            }
+        }).unwrap();
+        node
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    pub fn height(&self) -> u32 {
+        self.chain.lock().unwrap().len() as u32 - 1
+    }
+
+    pub fn tip(&self) -> Block {
+        self.chain.lock().unwrap().last().unwrap().clone()
+    }
+
+    /// transactions peers broadcast to this node
+    pub fn received(&self) -> Vec<Transaction> {
+        self.received.lock().unwrap().clone()
+    }
+
+    /// mine a block paying to miner that confirms all transactions received, and announce it
+    pub fn mine(&self, miner: &Address) -> Block {
+        let block = self.extend(miner);
+        self.announce(&[block.header]);
+        block
+    }
+
+    /// replace the last depth blocks with depth + 1 new ones paying to miner, and announce the new trunk.
+    /// Transactions of the replaced blocks are not confirmed again.
+    pub fn reorg(&self, depth: usize, miner: &Address) -> Vec<Block> {
+        {
+            let mut chain = self.chain.lock().unwrap();
+            let len = chain.len();
+            chain.truncate(len - depth);
+        }
+        let blocks = (0..depth + 1).map(|_| self.extend(miner)).collect::<Vec<_>>();
+        self.announce(blocks.iter().map(|b| b.header).collect::<Vec<_>>().as_slice());
+        blocks
+    }
+
+    /// true if all connected peers asked for headers announcements with sendheaders
+    pub fn peers_send_headers(&self) -> bool {
+        let peers = self.peers.lock().unwrap();
+        !peers.is_empty() && peers.iter().all(|p| p.send_headers)
+    }
+
+    /// number of getheaders messages received
+    pub fn get_headers_received(&self) -> usize {
+        *self.get_headers.lock().unwrap()
+    }
+
+    /// disconnect all peers
+    pub fn disconnect(&self) {
+        for peer in self.peers.lock().unwrap().drain(..) {
+            peer.stream.shutdown(Shutdown::Both).ok();
+        }
+    }
+
+    fn extend(&self, miner: &Address) -> Block {
+        let mut chain = self.chain.lock().unwrap();
+        let height = chain.len() as u32;
+        let prev = chain.last().unwrap().header;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as u32;
+        let mut block = Block {
+            header: BlockHeader {
+                version: 1,
+                prev_blockhash: prev.bitcoin_hash(),
+                merkle_root: sha256d::Hash::default(),
+                // a wallet is only interested in blocks later than its birth
+                time: std::cmp::max(prev.time, now) + 1,
+                bits: prev.bits,
+                nonce: 0,
+            },
+            txdata: vec!(Transaction {
+                version: 2,
+                lock_time: 0,
+                input: vec!(TxIn {
+                    previous_output: OutPoint { txid: sha256d::Hash::default(), vout: 0xffffffff },
+                    script_sig: Builder::new().push_int(height as i64).push_int(0).into_script(),
+                    sequence: 0xffffffff,
+                    witness: Vec::new(),
+                }),
+                output: vec!(TxOut { value: NEW_COINS, script_pubkey: miner.script_pubkey() }),
+            }),
+        };
+        block.txdata.extend(self.mempool.lock().unwrap().drain(..));
+        block.header.merkle_root = block.merkle_root();
+        while block.header.validate_pow(&block.header.target()).is_err() {
+            block.header.nonce += 1;
+        }
+        chain.push(block.clone());
+        block
+    }
+
+    fn announce(&self, headers: &[BlockHeader]) {
+        let mut peers = self.peers.lock().unwrap();
+        peers.retain(|peer| {
+            let mut stream = &peer.stream;
+            let message = if peer.send_headers {
+                NetworkMessage::Headers(headers.to_vec())
+            } else {
+                NetworkMessage::Inv(vec!(Inventory { inv_type: InvType::Block, hash: headers.last().unwrap().bitcoin_hash() }))
+            };
+            send(&mut stream, self.network, message).is_ok()
+        });
+    }
+
+    fn connection(&self) -> MockConnection {
+        MockConnection {
+            network: self.network,
+            chain: self.chain.clone(),
+            mempool: self.mempool.clone(),
+            received: self.received.clone(),
+            peers: self.peers.clone(),
+            get_headers: self.get_headers.clone(),
+        }
+    }
+}
+
+impl Drop for MockNode {
+    fn drop(&mut self) {
+        self.disconnect();
+    }
+}
+
+#[derive(Clone)]
+struct MockConnection {
+    network: Network,
+    chain: Arc<Mutex<Vec<Block>>>,
+    mempool: Arc<Mutex<Vec<Transaction>>>,
+    received: Arc<Mutex<Vec<Transaction>>>,
+    peers: Arc<Mutex<Vec<MockPeer>>>,
+    get_headers: Arc<Mutex<usize>>,
+}
+
+impl MockConnection {
+    fn serve(&mut self, mut stream: TcpStream) -> Result<(), io::Error> {
+        let remote = stream.peer_addr()?;
+        loop {
+            let message = receive(&mut stream)?;
+            debug!("mock node received {} from {}", message.command(), remote);
+            match message.payload {
+                NetworkMessage::Version(_) => {
+                    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+                    let mut version = VersionMessage::new(NODE_NETWORK, now,
+                                                          address::Address::new(&remote, 0),
+                                                          address::Address::new(&stream.local_addr()?, NODE_NETWORK),
+                                                          thread_rng().next_u64(), "/mocknode/".to_string(),
+                                                          (self.chain.lock().unwrap().len() - 1) as i32);
+                    version.version = PROTOCOL_VERSION;
+                    send(&mut stream, self.network, NetworkMessage::Version(version))?;
+                    send(&mut stream, self.network, NetworkMessage::Verack)?;
+                    self.peers.lock().unwrap().push(MockPeer { stream: stream.try_clone()?, send_headers: false });
+                }
+                NetworkMessage::SendHeaders => {
+                    for peer in self.peers.lock().unwrap().iter_mut() {
+                        if peer.stream.peer_addr().ok() == Some(remote) {
+                            peer.send_headers = true;
+                        }
+                    }
+                }
+                NetworkMessage::Ping(nonce) => {
+                    send(&mut stream, self.network, NetworkMessage::Pong(nonce))?;
+                }
+                NetworkMessage::GetHeaders(ref get) => {
+                    *self.get_headers.lock().unwrap() += 1;
+                    let headers = {
+                        let chain = self.chain.lock().unwrap();
+                        let start = get.locator_hashes.iter()
+                            .find_map(|l| chain.iter().position(|b| b.bitcoin_hash() == *l))
+                            .map(|p| p + 1).unwrap_or(0);
+                        chain.iter().skip(start).take(MAX_HEADERS)
+                            .take_while(|b| b.bitcoin_hash() != get.stop_hash)
+                            .map(|b| b.header).collect::<Vec<_>>()
+                    };
+                    send(&mut stream, self.network, NetworkMessage::Headers(headers))?;
+                }
+                NetworkMessage::GetData(ref inv) => {
+                    for i in inv {
+                        if i.inv_type == InvType::Block || i.inv_type == InvType::WitnessBlock {
+                            let block = self.chain.lock().unwrap().iter().find(|b| b.bitcoin_hash() == i.hash).cloned();
+                            if let Some(block) = block {
+                                send(&mut stream, self.network, NetworkMessage::Block(block))?;
+                            }
+                        }
+                    }
+                }
+                NetworkMessage::Inv(ref inv) => {
+                    let wanted = inv.iter().filter(|i| i.inv_type == InvType::Transaction).cloned().collect::<Vec<_>>();
+                    if !wanted.is_empty() {
+                        send(&mut stream, self.network, NetworkMessage::GetData(wanted))?;
+                    }
+                }
+                NetworkMessage::Tx(ref tx) => {
+                    let mut received = self.received.lock().unwrap();
+                    if !received.iter().any(|t| t.txid() == tx.txid()) {
+                        received.push(tx.clone());
+                        self.mempool.lock().unwrap().push(tx.clone());
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+fn send(stream: &mut dyn Write, network: Network, payload: NetworkMessage) -> Result<(), io::Error> {
+    stream.write_all(serialize(&RawNetworkMessage { magic: network.magic(), payload }).as_slice())
+}
+
+fn receive(stream: &mut dyn Read) -> Result<RawNetworkMessage, io::Error> {
+    // magic, command, length, checksum
+    let mut message = vec!(0u8; 24);
+    stream.read_exact(message.as_mut_slice())?;
+    let length = LittleEndian::read_u32(&message[16..20]) as usize;
+    message.resize(24 + length, 0);
+    stream.read_exact(&mut message[24..])?;
+    deserialize(message.as_slice()).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))
+}
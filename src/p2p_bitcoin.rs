@@ -38,6 +38,7 @@ use bitcoin::{
 };
 use bitcoin_hashes::sha256d;
 use futures::{
+    channel::oneshot,
     executor::ThreadPool,
     future,
     Future,
@@ -46,47 +47,74 @@ use futures::{
     task::{Context, SpawnExt}
 };
 use futures_timer::Interval;
-use log::debug;
+use log::{debug, error, info, warn};
 use murmel::{
     chaindb::SharedChainDB,
     dispatcher::Dispatcher,
     dns::dns_seed,
     downstream::Downstream,
     p2p::{
-        BitcoinP2PConfig, P2PControlSender, PeerMessage, PeerMessageReceiver, PeerMessageSender,
+        BitcoinP2PConfig, P2PControl, P2PControlSender, PeerMessage, PeerMessageReceiver, PeerMessageSender,
         PeerSource
     },
     p2p::P2P,
-    ping::Ping,
     timeout::Timeout
 };
 use murmel::p2p::PeerId;
 use rand::{RngCore, thread_rng};
 
 use crate::blockdownload::BlockDownload;
-use crate::db::SharedDB;
+use crate::component::{Component, next};
+use crate::config::DEFAULT_USER_AGENT;
+use crate::error::Error;
+use crate::event::WalletEvent;
+use crate::ping::Ping;
 use crate::sendtx::SendTx;
+use crate::storage::SharedStorage;
 use crate::store::SharedContentStore;
 use crate::trunk::Trunk;
 
-const MAX_PROTOCOL_VERSION: u32 = 70001;
+// 70012 enables sendheaders (BIP130). feefilter (BIP133, 70013) and wtxidrelay (BIP339, 70016)
+// messages are not known to the bitcoin crate's NetworkMessage, peers would send us messages
+// we can not decode if we announced a higher version.
+const MAX_PROTOCOL_VERSION: u32 = 70012;
 
 pub struct P2PBitcoin {
     connections: usize,
     peers: Vec<SocketAddr>,
     chain_db: SharedChainDB,
     network: Network,
-    db: SharedDB,
+    db: SharedStorage,
     content_store: SharedContentStore,
     discovery: bool,
-    birth: u64
+    birth: u64,
+    user_agent: String,
+    running: Option<Running>
 }
 
+/// what start spawned, so stop can end it
+struct Running {
+    p2p_control: P2PControlSender<NetworkMessage>,
+    connected: SharedPeers,
+    keep_connected: oneshot::Sender<()>,
+    components: Vec<Component>,
+    events: thread::JoinHandle<()>
+}
+
+type SharedPeers = Arc<Mutex<HashMap<PeerId, Option<SocketAddr>>>>;
+
 impl P2PBitcoin {
-    pub fn new (network: Network, connections: usize, peers: Vec<SocketAddr>, discovery: bool, chain_db: SharedChainDB, db: SharedDB, content_store: SharedContentStore, birth: u64) -> P2PBitcoin {
-        P2PBitcoin {connections, peers, chain_db, network, db, content_store, discovery, birth}
+    pub fn new (network: Network, connections: usize, peers: Vec<SocketAddr>, discovery: bool, chain_db: SharedChainDB, db: SharedStorage, content_store: SharedContentStore, birth: u64) -> P2PBitcoin {
+        P2PBitcoin {connections, peers, chain_db, network, db, content_store, discovery, birth, user_agent: DEFAULT_USER_AGENT.to_string(), running: None}
     }
-    pub fn start(&self, executor: &mut ThreadPool) {
+
+    /// user agent announced to peers, set before start
+    pub fn set_user_agent(&mut self, user_agent: String) {
+        self.user_agent = user_agent;
+    }
+
+    /// spawn connections and components, returns immediately
+    pub fn start(&mut self, executor: &mut ThreadPool) -> Result<(), Error> {
         let (sender, receiver) = mpsc::sync_channel(100);
 
         let mut dispatcher = Dispatcher::new(receiver);
@@ -103,7 +131,7 @@ impl P2PBitcoin {
             nonce: thread_rng().next_u64(),
             network: self.network,
             max_protocol_version: MAX_PROTOCOL_VERSION,
-            user_agent: "bdk 0.1.0".to_string(),
+            user_agent: self.user_agent.clone(),
             server: false,
             height
         };
@@ -115,62 +143,63 @@ impl P2PBitcoin {
 
         let downstream = Arc::new(Mutex::new(BitcoinDriver{store: self.content_store.clone()}));
 
-        let processed_block;
-        {
-            let mut db = self.db.lock().unwrap();
-            let mut tx = db.transaction();
-            processed_block = tx.read_processed().expect("can not read processed block");
-        }
-        if let Some(mut processed_block) = processed_block {
-            let mut disconnected = Vec::new();
-            {
-                // re-org might have happened while this node was down
-                let chain_db = self.chain_db.read().unwrap();
-                if let Some(mut header) = chain_db.get_header(&processed_block) {
-                    while chain_db.pos_on_trunk(&processed_block).is_none() {
-                        disconnected.push(header.stored.header.clone());
-                        processed_block = header.stored.header.prev_blockhash;
-                        header = chain_db.get_header(&processed_block).expect("inconsistent header cache");
-                    }
-                } else {
-                    panic!("can not find header for last processed block");
+        let mut processed_block = self.read_processed()?;
+        if let Some(processed) = processed_block {
+            // re-org might have happened while this node was down
+            let (fork_point, depth) = self.fork_point(&processed);
+            if fork_point != Some(processed) {
+                match fork_point {
+                    Some(fork_point) => warn!("re-org of depth {} below processed block {}, fork at {}", depth, processed, fork_point),
+                    None => warn!("processed block {} is not in the header chain, depth of re-org is unknown", processed)
                 }
-            }
-            if !disconnected.is_empty() {
-                let mut downstream = downstream.lock().unwrap();
-                for h in &disconnected {
-                    downstream.block_disconnected(h);
+                match self.content_store.write().unwrap().reorg(fork_point) {
+                    Ok(unwound) => info!("unwound {} blocks with wallet transactions", unwound),
+                    Err(e) => error!("can not unwind re-org: {}", e)
                 }
+                processed_block = self.read_processed()?;
             }
         }
 
         let timeout = Arc::new(Mutex::new(Timeout::new(p2p_control.clone())));
 
+        let mut components = Vec::new();
         if self.discovery {
-            dispatcher.add_listener(AddressPoolMaintainer::new(p2p_control.clone(), self.db.clone(), murmel::p2p::SERVICE_BLOCKS));
+            let (listener, component) = AddressPoolMaintainer::new(p2p_control.clone(), self.db.clone(), murmel::p2p::SERVICE_BLOCKS)?;
+            dispatcher.add_listener(listener);
+            components.push(component);
         }
-        dispatcher.add_listener(BlockDownload::new(self.chain_db.clone(), p2p_control.clone(), timeout.clone(), downstream, processed_block, self.birth));
-        dispatcher.add_listener(Ping::new(p2p_control.clone(), timeout.clone()));
-
-        let sendtx = SendTx::new(p2p_control.clone(), self.db.clone());
+        let (listener, component) = BlockDownload::new(self.chain_db.clone(), self.db.clone(), p2p_control.clone(), timeout.clone(), downstream, processed_block, self.birth)?;
+        dispatcher.add_listener(listener);
+        components.push(component);
+        let (listener, component) = Ping::new(p2p_control.clone())?;
+        dispatcher.add_listener(listener);
+        components.push(component);
+
+        let connected = Arc::new(Mutex::new(HashMap::new()));
+        let (listener, component) = PeerObserver::new(p2p_control.clone(), self.content_store.clone(), connected.clone())?;
+        dispatcher.add_listener(listener);
+        components.push(component);
+
+        let (sendtx, component) = SendTx::new(p2p_control.clone(), self.db.clone())?;
         dispatcher.add_listener(sendtx.clone());
+        components.push(component);
         self.content_store.write().unwrap().set_tx_sender(sendtx);
 
         let mut earlier = HashSet::new();
         let p2p = p2p.clone();
         for addr in &self.peers {
             earlier.insert(addr.clone());
-            executor.spawn(p2p.add_peer("bitcoin", PeerSource::Outgoing(addr.clone())).map(|_|())).expect("can not spawn task for peers");
+            executor.spawn(p2p.add_peer("bitcoin", PeerSource::Outgoing(addr.clone())).map(|_|()))?;
         }
 
         let dns = dns_seed(self.network);
         {
             let mut db = self.db.lock().unwrap();
-            let mut tx = db.transaction();
+            let mut tx = db.transaction()?;
             for a in &dns {
-                tx.store_address("bitcoin", a, 0, 0, 0).expect("can not store addresses in db");
+                tx.store_address("bitcoin", a, 0, 0, 0)?;
             }
-            tx.commit();
+            tx.commit()?;
         }
 
         let keep_connected = KeepConnected {
@@ -181,27 +210,77 @@ impl P2PBitcoin {
             dns,
             cex: executor.clone()
         };
-        executor.spawn(Interval::new(Duration::new(10, 0)).for_each(move |_| keep_connected.clone())).expect("can not keep connected");
+        let (keep_connected_stop, mut stop) = oneshot::channel();
+        executor.spawn(Interval::new(Duration::new(10, 0))
+            .take_while(move |_| future::ready(match stop.try_recv() { Ok(None) => true, _ => false }))
+            .for_each(move |_| keep_connected.clone()))?;
 
         let p2p = p2p.clone();
         let mut cex = executor.clone();
-        executor.spawn(future::poll_fn(move |_| {
+        let events = thread::Builder::new().name("p2p events".to_string()).spawn(move || {
             let needed_services = 0;
             p2p.poll_events("bitcoin", needed_services, &mut cex);
-            Async::Ready(())
-        })).expect("can not spawn bitcoin event loop");
+        })?;
+
+        self.running = Some(Running { p2p_control, connected, keep_connected: keep_connected_stop, components, events });
+        Ok(())
     }
 
-    pub fn shutdown(&self) {
-        self.chain_db.write().unwrap().shutdown()
+    /// stop connecting, disconnect peers, stop and join all components, then flush the header chain.
+    /// murmel's event loop has no exit yet, its thread is left idle without peers.
+    pub fn stop(&mut self) {
+        if let Some(running) = self.running.take() {
+            running.keep_connected.send(()).ok();
+            for pid in running.connected.lock().unwrap().keys() {
+                running.p2p_control.send(P2PControl::Disconnect(*pid));
+            }
+            for component in running.components {
+                component.stop();
+            }
+            warn!("murmel's event loop can not be stopped, thread {:?} stays idle", running.events.thread().name());
+        }
+        self.chain_db.write().unwrap().shutdown();
     }
+
+    /// addresses of connected peers, None for those murmel does not know the address of
+    pub fn connected_peers(&self) -> Vec<Option<SocketAddr>> {
+        match self.running {
+            Some(ref running) => running.connected.lock().unwrap().values().cloned().collect(),
+            None => Vec::new()
+        }
+    }
+
+    fn read_processed(&self) -> Result<Option<sha256d::Hash>, Error> {
+        let mut db = self.db.lock().unwrap();
+        let mut tx = db.transaction()?;
+        tx.read_processed()
+    }
+
+    /// walk back from the processed block to the trunk.
+    /// Returns the block where it forks from the trunk, or None if the processed block is not known to
+    /// the header chain, and the number of blocks walked back.
+    fn fork_point(&self, processed: &sha256d::Hash) -> (Option<sha256d::Hash>, u32) {
+        let chain_db = self.chain_db.read().unwrap();
+        let mut block_hash = *processed;
+        let mut depth = 0;
+        while chain_db.pos_on_trunk(&block_hash).is_none() {
+            if let Some(header) = chain_db.get_header(&block_hash) {
+                block_hash = header.stored.header.prev_blockhash;
+                depth += 1;
+            } else {
+                return (None, depth);
+            }
+        }
+        (Some(block_hash), depth)
+    }
+
 }
 
 #[derive(Clone)]
 struct KeepConnected {
     cex: ThreadPool,
     dns: Vec<SocketAddr>,
-    db: SharedDB,
+    db: SharedStorage,
     earlier: Arc<Mutex<HashSet<SocketAddr>>>,
     p2p: Arc<P2P<NetworkMessage, RawNetworkMessage, BitcoinP2PConfig>>,
     min_connections: usize
@@ -215,12 +294,21 @@ impl Future for KeepConnected {
             let choice;
             {
                 self.p2p.connected_peers().iter().for_each(|a| {self.earlier.lock().unwrap().insert(a.clone());} );
-                choice = self.db.lock().unwrap().transaction().get_an_address("bitcoin", self.earlier.clone()).expect("can not read addresses from db")
+                let mut db = self.db.lock().unwrap();
+                choice = match db.transaction().and_then(|tx| tx.get_an_address("bitcoin", self.earlier.clone())) {
+                    Ok(choice) => choice,
+                    Err(e) => {
+                        error!("can not read addresses from db: {}", e);
+                        None
+                    }
+                };
             }
             if let Some(choice) = choice {
                 self.earlier.lock().unwrap().insert(choice);
                 let add = self.p2p.add_peer("bitcoin", PeerSource::Outgoing(choice)).map(|_| ());
-                self.cex.spawn(add).expect("can not add peer for outgoing connection");
+                if let Err(e) = self.cex.spawn(add) {
+                    error!("can not add peer for outgoing connection: {:?}", e);
+                }
             }
             else {
                 let eligible = self.dns.iter().cloned().filter(|a| !self.earlier.lock().unwrap().contains(&a)).collect::<Vec<_>>();
@@ -229,7 +317,9 @@ impl Future for KeepConnected {
                     let choice = eligible[(rng.next_u32() as usize) % eligible.len()];
                     self.earlier.lock().unwrap().insert(choice);
                     let add = self.p2p.add_peer("bitcoin", PeerSource::Outgoing(choice)).map(|_| ());
-                    self.cex.spawn(add).expect("can not add peer for outgoing connection");
+                    if let Err(e) = self.cex.spawn(add) {
+                        error!("can not add peer for outgoing connection: {:?}", e);
+                    }
                 }
             }
         }
@@ -238,64 +328,54 @@ impl Future for KeepConnected {
 }
 
 struct AddressPoolMaintainer {
-    db: SharedDB,
+    db: SharedStorage,
     addresses: HashMap<PeerId, SocketAddr>,
     needed_services: u64
 }
 
 impl AddressPoolMaintainer {
-    pub fn new(p2p: P2PControlSender<NetworkMessage>, db: SharedDB, needed_services: u64) -> PeerMessageSender<NetworkMessage>  {
+    pub fn new(p2p: P2PControlSender<NetworkMessage>, db: SharedStorage, needed_services: u64) -> Result<(PeerMessageSender<NetworkMessage>, Component), Error>  {
         let (sender, receiver) = mpsc::sync_channel(p2p.back_pressure);
         let mut m = AddressPoolMaintainer { db, addresses: HashMap::new(), needed_services };
 
-        thread::Builder::new().name("address pool".to_string()).spawn(move || { m.run(receiver) }).unwrap();
+        let component = Component::spawn("address pool", move |stop| { m.run(receiver, stop) })?;
 
-        PeerMessageSender::new(sender)
+        Ok((PeerMessageSender::new(sender), component))
     }
 
-    fn run(&mut self, receiver: PeerMessageReceiver<NetworkMessage>) {
-        while let Ok(msg) = receiver.recv () {
+    fn run(&mut self, receiver: PeerMessageReceiver<NetworkMessage>, stop: mpsc::Receiver<()>) {
+        while let Some(msg) = next(&receiver, &stop) {
+            let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
             match msg {
                 PeerMessage::Connected(pid, addr) => {
                     if let Some(address) = addr {
                         self.addresses.insert(pid, address);
-                        let mut db = self.db.lock().unwrap();
-                        let mut tx = db.transaction();
                         debug!("store successful connection to {} peer={}", &address, pid);
-                        let now = SystemTime::now().duration_since(
-                            SystemTime::UNIX_EPOCH).unwrap().as_secs();
-                        tx.store_address("bitcoin", &address, now, now, 0).unwrap();
-                        tx.commit();
+                        self.store(&[(address, now, now, 0)]);
                     }
                 }
                 PeerMessage::Disconnected(pid, banned) => {
                     if banned {
                         if let Some(address) = self.addresses.remove(&pid) {
-                            let mut db = self.db.lock().unwrap();
-                            let mut tx = db.transaction();
-                            let now = SystemTime::now().duration_since(
-                                SystemTime::UNIX_EPOCH).unwrap().as_secs();
                             debug!("store ban of {} peer={}", &address, pid);
-                            tx.store_address("bitcoin", &address, 0, 0, now).unwrap();
-                            tx.commit();
+                            self.store(&[(address, 0, 0, now)]);
                         }
                     }
                 }
                 PeerMessage::Incoming(pid, msg) => {
                     match msg {
                         NetworkMessage::Addr(av) => {
-                            let mut db = self.db.lock().unwrap();
-                            let mut tx = db.transaction();
+                            let mut addresses = Vec::new();
                             for (last_seen, a) in &av {
-                                if (*last_seen as u64) < (SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs()) &&
+                                if (*last_seen as u64) < now &&
                                     a.services & self.needed_services == self.needed_services {
                                     if let Ok(addr) = a.socket_addr() {
                                         debug!("received and stored address {} peer={}", &addr, pid);
-                                        tx.store_address("bitcoin", &addr, 0, *last_seen as u64, 0).unwrap();
+                                        addresses.push((addr, 0, *last_seen as u64, 0));
                                     }
                                 }
                             }
-                            tx.commit();
+                            self.store(addresses.as_slice());
                         }
                         _ => { }
                     }
@@ -304,6 +384,54 @@ impl AddressPoolMaintainer {
             }
         }
     }
+
+    /// store addresses with their connected, last seen and banned times
+    fn store(&self, addresses: &[(SocketAddr, u64, u64, u64)]) {
+        let mut db = self.db.lock().unwrap();
+        let result = db.transaction().and_then(|mut tx| {
+            for (address, connected, last_seen, banned) in addresses {
+                tx.store_address("bitcoin", address, *connected, *last_seen, *banned)?;
+            }
+            tx.commit()
+        });
+        if let Err(e) = result {
+            error!("can not store peer addresses: {}", e);
+        }
+    }
+}
+
+/// tell subscribers of the content store about peers coming and going
+/// and keep track of connected peers
+struct PeerObserver {
+    store: SharedContentStore,
+    connected: SharedPeers
+}
+
+impl PeerObserver {
+    pub fn new(p2p: P2PControlSender<NetworkMessage>, store: SharedContentStore, connected: SharedPeers) -> Result<(PeerMessageSender<NetworkMessage>, Component), Error> {
+        let (sender, receiver) = mpsc::sync_channel(p2p.back_pressure);
+        let mut observer = PeerObserver { store, connected };
+
+        let component = Component::spawn("peer observer", move |stop| { observer.run(receiver, stop) })?;
+
+        Ok((PeerMessageSender::new(sender), component))
+    }
+
+    fn run(&mut self, receiver: PeerMessageReceiver<NetworkMessage>, stop: mpsc::Receiver<()>) {
+        while let Some(msg) = next(&receiver, &stop) {
+            match msg {
+                PeerMessage::Connected(pid, address) => {
+                    self.connected.lock().unwrap().insert(pid, address);
+                    self.store.write().unwrap().emit(WalletEvent::PeerConnected { address });
+                }
+                PeerMessage::Disconnected(pid, _) => {
+                    let address = self.connected.lock().unwrap().remove(&pid).unwrap_or(None);
+                    self.store.write().unwrap().emit(WalletEvent::PeerDisconnected { address });
+                }
+                _ => {}
+            }
+        }
+    }
 }
 
 struct BitcoinDriver {
@@ -312,15 +440,21 @@ struct BitcoinDriver {
 
 impl Downstream for BitcoinDriver {
     fn block_connected(&mut self, block: &Block, height: u32) {
-        self.store.write().unwrap().block_connected(block, height).expect("can not add block");
+        if let Err(e) = self.store.write().unwrap().block_connected(block, height) {
+            error!("can not add block {}: {}", height, e);
+        }
     }
 
     fn header_connected(&mut self, block: &BlockHeader, height: u32) {
-        self.store.write().unwrap().add_header(height, block).expect("can not add header");
+        if let Err(e) = self.store.write().unwrap().add_header(height, block) {
+            error!("can not add header {}: {}", height, e);
+        }
     }
 
     fn block_disconnected(&mut self, header: &BlockHeader) {
-        self.store.write().unwrap().unwind_tip(header).expect("can not unwind tip");
+        if let Err(e) = self.store.write().unwrap().unwind_tip(header) {
+            error!("can not unwind tip: {}", e);
+        }
     }
 }
 
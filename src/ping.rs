@@ -0,0 +1,110 @@
+/*
+ * Copyright 2020 BDK Team
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! ping connected peers and disconnect those that do not answer
+
+use std::{
+    collections::HashMap,
+    sync::mpsc,
+    time::{Duration, SystemTime}
+};
+
+use bitcoin::network::message::NetworkMessage;
+use log::debug;
+use murmel::p2p::{P2PControl, P2PControlSender, PeerId, PeerMessage, PeerMessageReceiver, PeerMessageSender};
+use rand::{RngCore, thread_rng};
+
+use crate::component::{Component, stopped};
+use crate::error::Error;
+
+// how often a peer is pinged
+const PING_INTERVAL: Duration = Duration::from_secs(60);
+// how long a peer has to answer
+const PONG_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub struct Ping {
+    p2p: P2PControlSender<NetworkMessage>,
+    // nonce of the unanswered ping and when the peer was last pinged
+    peers: HashMap<PeerId, (Option<u64>, SystemTime)>
+}
+
+impl Ping {
+    pub fn new(p2p: P2PControlSender<NetworkMessage>) -> Result<(PeerMessageSender<NetworkMessage>, Component), Error> {
+        let (sender, receiver) = mpsc::sync_channel(p2p.back_pressure);
+        let mut ping = Ping { p2p, peers: HashMap::new() };
+
+        let component = Component::spawn("ping", move |stop| { ping.run(receiver, stop) })?;
+
+        Ok((PeerMessageSender::new(sender), component))
+    }
+
+    fn run(&mut self, receiver: PeerMessageReceiver<NetworkMessage>, stop: mpsc::Receiver<()>) {
+        while !stopped(&stop) {
+            match receiver.recv_timeout(Duration::from_millis(1000)) {
+                Ok(msg) => self.process(msg),
+                Err(mpsc::RecvTimeoutError::Timeout) => {}
+                Err(mpsc::RecvTimeoutError::Disconnected) => break
+            }
+            self.ping(SystemTime::now());
+        }
+    }
+
+    fn process(&mut self, msg: PeerMessage<NetworkMessage>) {
+        match msg {
+            PeerMessage::Connected(pid, _) => {
+                self.peers.insert(pid, (None, SystemTime::now()));
+            }
+            PeerMessage::Disconnected(pid, _) => {
+                self.peers.remove(&pid);
+            }
+            PeerMessage::Incoming(pid, NetworkMessage::Ping(nonce)) => {
+                self.p2p.send_network(pid, NetworkMessage::Pong(nonce));
+            }
+            PeerMessage::Incoming(pid, NetworkMessage::Pong(nonce)) => {
+                if let Some((asked, _)) = self.peers.get_mut(&pid) {
+                    if *asked == Some(nonce) {
+                        *asked = None;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // disconnect peers that did not answer in time, ping those not pinged for a while
+    fn ping(&mut self, now: SystemTime) {
+        let mut rng = thread_rng();
+        let mut silent = Vec::new();
+        for (pid, (asked, since)) in self.peers.iter_mut() {
+            let elapsed = now.duration_since(*since).unwrap_or_default();
+            if asked.is_some() {
+                if elapsed > PONG_TIMEOUT {
+                    silent.push(*pid);
+                }
+            } else if elapsed > PING_INTERVAL {
+                let nonce = rng.next_u64();
+                self.p2p.send_network(*pid, NetworkMessage::Ping(nonce));
+                *asked = Some(nonce);
+                *since = now;
+            }
+        }
+        for pid in silent {
+            debug!("no pong in time, disconnect peer={}", pid);
+            self.peers.remove(&pid);
+            self.p2p.send(P2PControl::Disconnect(pid));
+        }
+    }
+}
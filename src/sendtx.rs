@@ -17,7 +17,6 @@
 use std::{
     collections::HashMap,
     sync::mpsc,
-    thread,
     time::SystemTime
 };
 
@@ -25,43 +24,45 @@ use bitcoin::network::message::NetworkMessage;
 use bitcoin::network::message_blockdata::{Inventory, InvType};
 use bitcoin::Transaction;
 use bitcoin_hashes::sha256d;
-use log::debug;
+use log::{debug, error};
 use lru_cache::LruCache;
 use murmel::p2p::{P2PControlSender, PeerMessage, PeerMessageReceiver, PeerMessageSender};
 
-use crate::db::SharedDB;
+use crate::component::{Component, next};
+use crate::error::Error;
+use crate::storage::SharedStorage;
 
 pub struct SendTx {
     p2p: P2PControlSender<NetworkMessage>,
-    db: SharedDB,
+    db: SharedStorage,
     cache: LruCache<sha256d::Hash, Transaction>
 }
 
 const CACHE_SIZE: usize=1000;
 
 impl SendTx {
-    pub fn new(p2p: P2PControlSender<NetworkMessage>, db: SharedDB) -> PeerMessageSender<NetworkMessage> {
+    pub fn new(p2p: P2PControlSender<NetworkMessage>, db: SharedStorage) -> Result<(PeerMessageSender<NetworkMessage>, Component), Error> {
         let (sender, receiver) = mpsc::sync_channel(p2p.back_pressure);
 
         let mut own_unconfirmed = HashMap::new();
         {
             let mut db = db.lock().unwrap();
-            let tx = db.transaction();
-            for (t, _) in tx.read_unconfirmed().expect("can not read unconfirmed transactions") {
+            let tx = db.transaction()?;
+            for (t, _) in tx.read_unconfirmed()? {
                 own_unconfirmed.insert(t.txid(), t);
             }
         }
 
         let mut txsender = SendTx { p2p, db, cache: LruCache::new(CACHE_SIZE) };
 
-        thread::Builder::new().name("sendtx".to_string()).spawn(move || { txsender.run(receiver) }).unwrap();
+        let component = Component::spawn("sendtx", move |stop| { txsender.run(receiver, stop) })?;
 
-        PeerMessageSender::new(sender)
+        Ok((PeerMessageSender::new(sender), component))
     }
 
-    fn run(&mut self, receiver: PeerMessageReceiver<NetworkMessage>) {
+    fn run(&mut self, receiver: PeerMessageReceiver<NetworkMessage>, stop: mpsc::Receiver<()>) {
         let mut last_announcement = SystemTime::now();
-        while let Ok(msg) = receiver.recv() {
+        while let Some(msg) = next(&receiver, &stop) {
             match msg {
                 PeerMessage::Incoming(pid, msg) => {
                     match msg {
@@ -78,9 +79,7 @@ impl SendTx {
                                 }).collect::<Vec<_>>();
 
                                 if !txs.is_empty() {
-                                    let mut db = self.db.lock().unwrap();
-                                    let tx = db.transaction();
-                                    for (t, _) in tx.read_unconfirmed().expect("can not read unconfirmed transactions").iter().filter(|(t, _)| txs.contains(&t.txid())) {
+                                    for t in self.read_unconfirmed().iter().filter(|t| txs.contains(&t.txid())) {
                                         self.p2p.send_network(pid, NetworkMessage::Tx(t.clone()));
                                         debug!("sent our transaction {} at request of peer={}", t.txid(), pid);
                                     }
@@ -113,9 +112,7 @@ impl SendTx {
                 _ => {}
             }
             if SystemTime::now().duration_since(last_announcement).unwrap().as_secs() > 60 {
-                let mut db = self.db.lock().unwrap();
-                let tx = db.transaction();
-                for (transaction, _) in tx.read_unconfirmed().expect("can not read unconfirmed transactions") {
+                for transaction in self.read_unconfirmed() {
                     if !self.cache.contains_key(&transaction.txid()) {
                         if let Some(peer) = self.p2p.send_random_network(NetworkMessage::Inv(vec!(Inventory { hash: transaction.txid(), inv_type: InvType::Transaction }))) {
                             debug!("announced our transaction {} to peer={}", transaction.txid(), peer);
@@ -126,4 +123,16 @@ impl SendTx {
             }
         }
     }
-}
\ No newline at end of file
+
+    /// our unconfirmed transactions, none if they can not be read
+    fn read_unconfirmed(&self) -> Vec<Transaction> {
+        let mut db = self.db.lock().unwrap();
+        match db.transaction().and_then(|tx| tx.read_unconfirmed()) {
+            Ok(unconfirmed) => unconfirmed.into_iter().map(|(t, _)| t).collect(),
+            Err(e) => {
+                error!("can not read unconfirmed transactions: {}", e);
+                Vec::new()
+            }
+        }
+    }
+}
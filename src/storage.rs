@@ -0,0 +1,1026 @@
+/*
+ * Copyright 2020 BDK Team
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Persistence of the wallet.
+//!
+//! Components read and write through Storage, implemented on SQLite by db::DB and in memory by
+//! MemoryStorage. Embedders may plug in their own and start the wallet with api::start_with_storage.
+
+use std::collections::{HashMap, HashSet};
+use std::hash::Hash;
+use std::mem;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use bitcoin::{Address, Network, OutPoint, PublicKey, Script, Transaction, TxOut};
+use bitcoin::consensus::{deserialize, serialize};
+use bitcoin::util::bip32::ExtendedPubKey;
+use bitcoin_hashes::{sha256, sha256d};
+use bitcoin_hashes::hex::FromHex;
+use bitcoin_wallet::account::{Account, AccountAddressType, KeyDerivation, MasterAccount};
+use bitcoin_wallet::coins::{Coin, Coins};
+use bitcoin_wallet::proved::ProvedTransaction;
+
+use crate::error::Error;
+use crate::labels::{Label, LabelKind};
+
+pub type SharedStorage = Arc<Mutex<dyn Storage>>;
+
+/// a transaction not yet confirmed on the trunk, with the funding it was published for
+pub type Unconfirmed = (Transaction, Option<(PublicKey, sha256::Hash, u16)>);
+
+// banned peers are not connected for a day
+pub(crate) const BAN_TIME: u64 = 60 * 60 * 24;
+
+/// the wallet persistence
+pub trait Storage: Send {
+    /// begin a transaction, nothing is written unless it is committed
+    fn transaction<'a>(&'a mut self) -> Result<Box<dyn StorageTransaction + 'a>, Error>;
+
+    /// make committed transactions durable, called at shutdown
+    fn flush(&mut self) -> Result<(), Error>;
+}
+
+/// reads and writes of a storage transaction
+pub trait StorageTransaction {
+    fn commit(self: Box<Self>) -> Result<(), Error>;
+
+    fn rollback(self: Box<Self>) -> Result<(), Error>;
+
+    /// forget coins, transactions, download progress and which issued addresses were funded, scan again after the block
+    fn rescan(&mut self, after: &sha256d::Hash) -> Result<(), Error>;
+
+    fn store_txout(&mut self, tx: &Transaction, funding: Option<(&PublicKey, &sha256::Hash, u16)>) -> Result<(), Error>;
+
+    fn read_unconfirmed(&self) -> Result<Vec<Unconfirmed>, Error>;
+
+    /// forget confirmations of our transactions in a block no longer on the trunk
+    fn unconfirm(&mut self, block_id: &sha256d::Hash) -> Result<usize, Error>;
+
+    /// set or clear the confirming block of one of our transactions
+    fn confirm(&mut self, txid: &sha256d::Hash, block_id: Option<&sha256d::Hash>) -> Result<usize, Error>;
+
+    /// insert or replace a transaction of the wallet, keeps the earliest first seen time
+    fn store_transaction(&mut self, transaction: &WalletTransaction) -> Result<(), Error>;
+
+    fn read_transactions(&self) -> Result<Vec<WalletTransaction>, Error>;
+
+    /// insert or replace an address handed out to receive payments
+    fn store_issued_address(&mut self, address: &IssuedAddress) -> Result<(), Error>;
+
+    /// issued addresses in the order they were handed out
+    fn read_issued_addresses(&self) -> Result<Vec<IssuedAddress>, Error>;
+
+    /// insert or replace the label of a transaction, address, output or xpub
+    fn store_label(&mut self, label: &Label) -> Result<(), Error>;
+
+    fn delete_label(&mut self, kind: LabelKind, reference: &str) -> Result<usize, Error>;
+
+    fn read_labels(&self) -> Result<Vec<Label>, Error>;
+
+    /// replace the confirmed coins and confirm stored transactions that have a proof
+    fn store_coins(&mut self, coins: &Coins) -> Result<(), Error>;
+
+    /// write only the changes of the confirmed coins since they were stored,
+    /// confirm stored transactions with a new proof
+    fn update_coins(&mut self, coins: &Coins, changes: &CoinChanges) -> Result<(), Error>;
+
+    fn read_coins(&mut self, master_account: &mut MasterAccount) -> Result<Coins, Error>;
+
+    /// replace all accounts
+    fn store_master(&mut self, master: &MasterAccount) -> Result<usize, Error>;
+
+    fn store_account(&mut self, account: &Account) -> Result<usize, Error>;
+
+    fn read_account(&mut self, account_number: u32, sub: u32, network: Network, look_ahead: u32) -> Result<Account, Error>;
+
+    fn read_processed(&mut self) -> Result<Option<sha256d::Hash>, Error>;
+
+    fn store_processed(&mut self, block_id: &sha256d::Hash) -> Result<(), Error>;
+
+    fn delete_processed(&mut self) -> Result<(), Error>;
+
+    fn read_download_progress(&mut self) -> Result<Option<DownloadProgress>, Error>;
+
+    fn store_download_progress(&mut self, progress: &DownloadProgress) -> Result<(), Error>;
+
+    /// remember a peer address, times are unix seconds and never decrease
+    fn store_address(&mut self, network: &str, address: &SocketAddr, connected: u64, last_seen: u64, banned: u64) -> Result<usize, Error>;
+
+    /// an address not banned during the last day, preferring those seen recently
+    fn get_an_address(&self, network: &str, other_than: Arc<Mutex<HashSet<SocketAddr>>>) -> Result<Option<SocketAddr>, Error>;
+
+    /// everything stored but peers
+    fn read_snapshot(&mut self) -> Result<Snapshot, Error>;
+
+    /// replace everything stored but peers
+    fn store_snapshot(&mut self, snapshot: &Snapshot) -> Result<(), Error>;
+}
+
+/// store the coins and accounts of a new wallet
+pub fn init(storage: &mut dyn Storage, coins: &Coins, master: &MasterAccount) -> Result<(), Error> {
+    let mut tx = storage.transaction()?;
+    tx.store_coins(coins)?;
+    tx.store_master(master)?;
+    // the first key of the receiving account is handed out as deposit address on init
+    if let Some(key) = master.get((0, 0)).and_then(|a| a.get_key(0)) {
+        tx.store_issued_address(&IssuedAddress {
+            address: key.address.clone(),
+            account: 0,
+            sub: 0,
+            kix: 0,
+            issued: SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs(),
+            funded: None,
+            label: None,
+        })?;
+    }
+    tx.commit()
+}
+
+/// the confirmed coins and the blocks proving their transactions, taken before the coins change
+pub struct StoredCoins {
+    coins: HashSet<OutPoint>,
+    // block hash by txid
+    proofs: HashMap<sha256d::Hash, sha256d::Hash>,
+}
+
+impl StoredCoins {
+    pub fn new(coins: &Coins) -> StoredCoins {
+        StoredCoins {
+            coins: coins.confirmed().iter().map(|(o, _)| *o).collect(),
+            proofs: coins.proofs().iter().map(|(txid, proof)| (*txid, proof.get_block_hash().clone())).collect(),
+        }
+    }
+
+    pub fn contains(&self, outpoint: &OutPoint) -> bool {
+        self.coins.contains(outpoint)
+    }
+
+    /// what changed to get to coins
+    pub fn changes(&self, coins: &Coins) -> CoinChanges {
+        let proved = coins.proofs().iter()
+            .filter(|(txid, proof)| self.proofs.get(txid) != Some(&proof.get_block_hash().clone()))
+            .map(|(txid, _)| *txid)
+            .collect::<HashSet<_>>();
+        let added = coins.confirmed().iter()
+            .map(|(o, _)| *o)
+            .filter(|o| !self.coins.contains(o) || proved.contains(&o.txid))
+            .collect();
+        let spent = self.coins.iter()
+            .filter(|o| coins.confirmed().get(o).is_none())
+            .cloned()
+            .collect();
+        CoinChanges { added, spent, proved: proved.into_iter().collect() }
+    }
+}
+
+/// changes of the confirmed coins
+#[derive(Default, Debug)]
+pub struct CoinChanges {
+    /// new coins and those of transactions with a new proof
+    pub added: Vec<OutPoint>,
+    /// coins no longer confirmed or spent
+    pub spent: Vec<OutPoint>,
+    /// transactions with a new proof
+    pub proved: Vec<sha256d::Hash>,
+}
+
+/// a transaction paying to or spending from the wallet
+#[derive(Clone, Debug, PartialEq)]
+pub struct WalletTransaction {
+    pub transaction: Transaction,
+    /// block, height and position in the block if confirmed
+    pub block_hash: Option<sha256d::Hash>,
+    pub height: Option<u32>,
+    pub position: Option<u32>,
+    /// unix seconds
+    pub first_seen: u64,
+}
+
+impl WalletTransaction {
+    pub fn unconfirmed(transaction: Transaction) -> WalletTransaction {
+        WalletTransaction {
+            transaction,
+            block_hash: None,
+            height: None,
+            position: None,
+            first_seen: SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs(),
+        }
+    }
+}
+
+/// an address handed out to receive payments
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct IssuedAddress {
+    pub address: Address,
+    /// derivation of the key, account, sub account and key index
+    pub account: u32,
+    pub sub: u32,
+    pub kix: u32,
+    /// unix seconds
+    pub issued: u64,
+    /// height of the first block paying to the address
+    pub funded: Option<u32>,
+    pub label: Option<String>,
+}
+
+impl IssuedAddress {
+    /// derivation path relative to the master key
+    pub fn path(&self) -> String {
+        format!("m/{}/{}/{}", self.account, self.sub, self.kix)
+    }
+}
+
+/// coins, transactions, accounts and progress of a wallet, see backup
+#[derive(Serialize, Deserialize, Default)]
+pub struct Snapshot {
+    coins: Vec<CoinRow>,
+    // with true if confirmed
+    txouts: Vec<(TxoutRow, bool)>,
+    accounts: Vec<AccountRow>,
+    processed: Option<sha256d::Hash>,
+    download: Option<DownloadProgress>,
+    #[serde(default)]
+    transactions: Vec<TransactionRow>,
+    #[serde(default)]
+    issued: Vec<IssuedAddressRow>,
+    #[serde(default)]
+    labels: Vec<Label>,
+}
+
+impl Snapshot {
+    /// txouts are our transactions with true if confirmed
+    pub fn new(coins: Vec<CoinRow>, txouts: Vec<(TxoutRow, bool)>, accounts: Vec<AccountRow>,
+               processed: Option<sha256d::Hash>, download: Option<DownloadProgress>, transactions: Vec<TransactionRow>,
+               issued: Vec<IssuedAddressRow>, labels: Vec<Label>) -> Snapshot {
+        Snapshot { coins, txouts, accounts, processed, download, transactions, issued, labels }
+    }
+
+    /// our transactions with true if confirmed
+    pub fn txout_rows(&self) -> &[(TxoutRow, bool)] {
+        self.txouts.as_slice()
+    }
+
+    /// txid of our transactions with true if confirmed
+    pub fn txout_confirmations(&self) -> Vec<(String, bool)> {
+        self.txouts.iter().map(|(row, confirmed)| (row.txid.clone(), *confirmed)).collect()
+    }
+
+    pub fn transactions(&self) -> &[TransactionRow] {
+        self.transactions.as_slice()
+    }
+
+    pub fn issued(&self) -> &[IssuedAddressRow] {
+        self.issued.as_slice()
+    }
+
+    pub fn labels(&self) -> &[Label] {
+        self.labels.as_slice()
+    }
+
+    pub fn coins(&self) -> &[CoinRow] {
+        self.coins.as_slice()
+    }
+
+    pub fn accounts(&self) -> &[AccountRow] {
+        self.accounts.as_slice()
+    }
+
+    pub fn processed(&self) -> Option<sha256d::Hash> {
+        self.processed
+    }
+
+    pub fn download(&self) -> Option<DownloadProgress> {
+        self.download
+    }
+
+    /// transactions with the block confirming them, taken from the stored transactions, else from the proofs of coins.
+    /// Confirmed transactions found in neither are left without block.
+    pub fn txouts(&self) -> Result<Vec<(TxoutRow, Option<sha256d::Hash>)>, Error> {
+        let mut blocks = HashMap::new();
+        for row in &self.coins {
+            let proof: ProvedTransaction = serde_cbor::from_slice(row.proof.as_slice())?;
+            blocks.insert(row.txid.clone(), proof.get_block_hash().clone());
+        }
+        for row in &self.transactions {
+            if let Some(block) = &row.block {
+                blocks.insert(row.txid.clone(), sha256d::Hash::from_hex(block.as_str())?);
+            }
+        }
+        Ok(self.txouts.iter().map(|(row, confirmed)| {
+            let block = if *confirmed {
+                blocks.get(&row.txid).cloned()
+            } else {
+                None
+            };
+            (row.clone(), block)
+        }).collect())
+    }
+}
+
+/// progress of block download, to resume where it left off after a restart
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Serialize, Deserialize)]
+pub struct DownloadProgress {
+    /// height and hash of the last block passed to the wallet
+    pub verified: (u32, sha256d::Hash),
+    /// height and hash of the last block queued for download
+    pub queued: (u32, sha256d::Hash),
+}
+
+// Stored forms of coins, transactions and accounts, shared by the implementations.
+// They are public so storages outside of this crate can read and write snapshots.
+
+/// a confirmed coin, with its CBOR encoded proof
+#[derive(Serialize, Deserialize, Clone)]
+pub struct CoinRow {
+    pub txid: String,
+    pub vout: u32,
+    pub value: i64,
+    pub script: Vec<u8>,
+    pub account: u32,
+    pub sub: u32,
+    pub kix: u32,
+    pub tweak: Option<String>,
+    pub csv: Option<i64>,
+    pub proof: Vec<u8>,
+}
+
+impl CoinRow {
+    fn new(outpoint: &OutPoint, coin: &Coin, coins: &Coins) -> Result<CoinRow, Error> {
+        let proof = coins.proofs().get(&outpoint.txid).ok_or(Error::Corrupted("inconsistent wallet, missing proof"))?;
+        Ok(CoinRow {
+            txid: outpoint.txid.to_string(),
+            vout: outpoint.vout,
+            value: coin.output.value as i64,
+            script: coin.output.script_pubkey.to_bytes(),
+            account: coin.derivation.account,
+            sub: coin.derivation.sub,
+            kix: coin.derivation.kix,
+            tweak: coin.derivation.tweak.as_ref().map(hex::encode),
+            csv: coin.derivation.csv.map(|csv| csv as i64),
+            proof: serde_cbor::ser::to_vec(&proof)?,
+        })
+    }
+
+    /// rows of the confirmed coins
+    pub fn from_coins(coins: &Coins) -> Result<Vec<CoinRow>, Error> {
+        coins.confirmed().iter().map(|(outpoint, coin)| CoinRow::new(outpoint, coin, coins)).collect()
+    }
+
+    /// rows of the added coins
+    pub fn added(coins: &Coins, changes: &CoinChanges) -> Result<Vec<CoinRow>, Error> {
+        changes.added.iter().map(|outpoint| {
+            let coin = coins.confirmed().get(outpoint).ok_or(Error::Corrupted("inconsistent wallet, missing added coin"))?;
+            CoinRow::new(outpoint, coin, coins)
+        }).collect()
+    }
+
+    /// coins of the rows, less those spent by the unconfirmed transactions
+    pub fn to_coins(rows: Vec<CoinRow>, unconfirmed: Vec<Unconfirmed>, master_account: &mut MasterAccount) -> Result<Coins, Error> {
+        let mut coins = Coins::new();
+        for row in rows {
+            let point = OutPoint { txid: sha256d::Hash::from_hex(row.txid.as_str())?, vout: row.vout };
+            let coin = Coin {
+                output: TxOut { script_pubkey: Script::from(row.script), value: row.value as u64 },
+                derivation: KeyDerivation {
+                    account: row.account,
+                    sub: row.sub,
+                    kix: row.kix,
+                    tweak: match row.tweak {
+                        Some(tweak) => Some(hex::decode(tweak).map_err(|_| Error::Corrupted("tweak is not hex"))?),
+                        None => None
+                    },
+                    csv: row.csv.map(|c| c as u16),
+                },
+            };
+            let proof: ProvedTransaction = serde_cbor::from_slice(row.proof.as_slice())?;
+            coins.add_confirmed(point, coin, proof);
+        }
+
+        // remove unconfirmed spend
+        for (tx, _) in unconfirmed {
+            coins.process_unconfirmed_transaction(master_account, &tx);
+        }
+        Ok(coins)
+    }
+}
+
+/// one of our transactions, with its funding if it funds a contract
+#[derive(Serialize, Deserialize, Clone)]
+pub struct TxoutRow {
+    pub txid: String,
+    pub tx: Vec<u8>,
+    pub publisher: Option<Vec<u8>>,
+    pub id: Option<String>,
+    pub term: Option<i64>,
+}
+
+impl TxoutRow {
+    pub fn new(tx: &Transaction, funding: Option<(&PublicKey, &sha256::Hash, u16)>) -> TxoutRow {
+        TxoutRow {
+            txid: tx.txid().to_string(),
+            tx: serialize(tx),
+            publisher: funding.map(|(publisher, _, _)| publisher.to_bytes()),
+            id: funding.map(|(_, id, _)| id.to_string()),
+            term: funding.map(|(_, _, term)| term as i64),
+        }
+    }
+
+    pub fn unconfirmed(self) -> Result<Unconfirmed, Error> {
+        let funding = match (self.publisher, self.id, self.term) {
+            (Some(publisher), Some(id), Some(term)) =>
+                Some((PublicKey::from_slice(publisher.as_slice()).map_err(|_| Error::Corrupted("publisher in txout is not a public key"))?,
+                      sha256::Hash::from_hex(id.as_str())?,
+                      term as u16)),
+            (None, None, None) => None,
+            _ => return Err(Error::Corrupted("incomplete funding in txout"))
+        };
+        Ok((deserialize::<Transaction>(self.tx.as_slice())?, funding))
+    }
+}
+
+/// an account with its instantiated keys CBOR encoded
+#[derive(Serialize, Deserialize, Clone)]
+pub struct AccountRow {
+    pub account: u32,
+    pub sub: u32,
+    pub address_type: u32,
+    pub master: String,
+    pub instantiated: Vec<u8>,
+}
+
+impl AccountRow {
+    pub fn new(account: &Account) -> Result<AccountRow, Error> {
+        Ok(AccountRow {
+            account: account.account_number(),
+            sub: account.sub_account_number(),
+            address_type: account.address_type().as_u32(),
+            master: account.master_public().to_string(),
+            instantiated: serde_cbor::ser::to_vec(&account.instantiated())?,
+        })
+    }
+
+    pub fn to_account(&self, network: Network, look_ahead: u32) -> Result<Account, Error> {
+        Ok(Account::new_from_storage(
+            AccountAddressType::from_u32(self.address_type),
+            self.account,
+            self.sub,
+            ExtendedPubKey::from_str(self.master.as_str()).map_err(|_| Error::Corrupted("malformed master public stored"))?,
+            serde_cbor::from_slice(self.instantiated.as_slice())?,
+            0,
+            look_ahead,
+            network,
+        ))
+    }
+}
+
+/// a transaction of the wallet, the block is its hash in hex
+#[derive(Serialize, Deserialize, Clone)]
+pub struct TransactionRow {
+    pub txid: String,
+    pub tx: Vec<u8>,
+    pub block: Option<String>,
+    pub height: Option<u32>,
+    pub position: Option<u32>,
+    pub first_seen: i64,
+}
+
+impl TransactionRow {
+    pub fn new(transaction: &WalletTransaction) -> TransactionRow {
+        TransactionRow {
+            txid: transaction.transaction.txid().to_string(),
+            tx: serialize(&transaction.transaction),
+            block: transaction.block_hash.map(|b| b.to_string()),
+            height: transaction.height,
+            position: transaction.position,
+            first_seen: transaction.first_seen as i64,
+        }
+    }
+
+    pub fn to_transaction(&self) -> Result<WalletTransaction, Error> {
+        Ok(WalletTransaction {
+            transaction: deserialize(self.tx.as_slice())?,
+            block_hash: self.block.as_ref().map(|b| sha256d::Hash::from_hex(b.as_str())).transpose()?,
+            height: self.height,
+            position: self.position,
+            first_seen: self.first_seen as u64,
+        })
+    }
+
+    /// forget the block
+    pub fn unconfirm(&mut self) {
+        self.block = None;
+        self.height = None;
+        self.position = None;
+    }
+}
+
+/// an issued address
+#[derive(Serialize, Deserialize, Clone)]
+pub struct IssuedAddressRow {
+    pub address: String,
+    pub account: u32,
+    pub sub: u32,
+    pub kix: u32,
+    pub issued: i64,
+    pub funded: Option<u32>,
+    pub label: Option<String>,
+}
+
+impl IssuedAddressRow {
+    pub fn new(address: &IssuedAddress) -> IssuedAddressRow {
+        IssuedAddressRow {
+            address: address.address.to_string(),
+            account: address.account,
+            sub: address.sub,
+            kix: address.kix,
+            issued: address.issued as i64,
+            funded: address.funded,
+            label: address.label.clone(),
+        }
+    }
+
+    /// in the order they were issued
+    pub fn to_issued_addresses(rows: Vec<IssuedAddressRow>) -> Result<Vec<IssuedAddress>, Error> {
+        let mut addresses = rows.into_iter().map(|row| Ok(IssuedAddress {
+            address: Address::from_str(row.address.as_str())?,
+            account: row.account,
+            sub: row.sub,
+            kix: row.kix,
+            issued: row.issued as u64,
+            funded: row.funded,
+            label: row.label,
+        })).collect::<Result<Vec<_>, Error>>()?;
+        addresses.sort_by_key(|a| (a.issued, a.account, a.sub, a.kix));
+        Ok(addresses)
+    }
+}
+
+/// keeps everything in memory, for tests and embedders that persist elsewhere
+#[derive(Default)]
+pub struct MemoryStorage {
+    state: MemoryState,
+}
+
+#[derive(Default)]
+struct MemoryState {
+    // by txid and vout
+    coins: HashMap<(String, u32), CoinRow>,
+    // by txid, with the hash of the confirming block
+    txout: HashMap<String, (TxoutRow, Option<sha256d::Hash>)>,
+    accounts: HashMap<(u32, u32), AccountRow>,
+    processed: Option<sha256d::Hash>,
+    download: Option<DownloadProgress>,
+    // by txid
+    transactions: HashMap<String, TransactionRow>,
+    // by address
+    issued: HashMap<String, IssuedAddressRow>,
+    // by kind and reference
+    labels: HashMap<(LabelKind, String), Label>,
+    // connected, last seen and banned by network and address
+    addresses: HashMap<(String, SocketAddr), (u64, u64, u64)>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> MemoryStorage {
+        MemoryStorage::default()
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn transaction<'a>(&'a mut self) -> Result<Box<dyn StorageTransaction + 'a>, Error> {
+        Ok(Box::new(MemoryTransaction { state: &mut self.state, undo: UndoLog::default() }))
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+// the previous value of an entry or of a whole table
+enum Undo<K, V> {
+    Entry(K, Option<V>),
+    Table(HashMap<K, V>),
+}
+
+// previous values of what a transaction changed, newest last
+#[derive(Default)]
+struct UndoLog {
+    coins: Vec<Undo<(String, u32), CoinRow>>,
+    txout: Vec<Undo<String, (TxoutRow, Option<sha256d::Hash>)>>,
+    accounts: Vec<Undo<(u32, u32), AccountRow>>,
+    processed: Option<Option<sha256d::Hash>>,
+    download: Option<Option<DownloadProgress>>,
+    transactions: Vec<Undo<String, TransactionRow>>,
+    issued: Vec<Undo<String, IssuedAddressRow>>,
+    labels: Vec<Undo<(LabelKind, String), Label>>,
+    addresses: Vec<Undo<(String, SocketAddr), (u64, u64, u64)>>,
+}
+
+// log the value of an entry before it changes
+fn touch<K: Clone + Eq + Hash, V: Clone>(log: &mut Vec<Undo<K, V>>, table: &HashMap<K, V>, key: &K) {
+    log.push(Undo::Entry(key.clone(), table.get(key).cloned()));
+}
+
+// log a whole table while replacing it
+fn replace<K: Eq + Hash, V>(log: &mut Vec<Undo<K, V>>, table: &mut HashMap<K, V>, with: HashMap<K, V>) {
+    log.push(Undo::Table(mem::replace(table, with)));
+}
+
+fn undo<K: Eq + Hash, V>(log: Vec<Undo<K, V>>, table: &mut HashMap<K, V>) {
+    for entry in log.into_iter().rev() {
+        match entry {
+            Undo::Entry(key, Some(value)) => { table.insert(key, value); }
+            Undo::Entry(key, None) => { table.remove(&key); }
+            Undo::Table(previous) => *table = previous,
+        }
+    }
+}
+
+// changes the state in place and logs previous values, a transaction dropped without commit restores them
+struct MemoryTransaction<'a> {
+    state: &'a mut MemoryState,
+    undo: UndoLog,
+}
+
+impl<'a> Drop for MemoryTransaction<'a> {
+    fn drop(&mut self) {
+        let log = mem::replace(&mut self.undo, UndoLog::default());
+        undo(log.coins, &mut self.state.coins);
+        undo(log.txout, &mut self.state.txout);
+        undo(log.accounts, &mut self.state.accounts);
+        undo(log.transactions, &mut self.state.transactions);
+        undo(log.issued, &mut self.state.issued);
+        undo(log.labels, &mut self.state.labels);
+        undo(log.addresses, &mut self.state.addresses);
+        if let Some(processed) = log.processed {
+            self.state.processed = processed;
+        }
+        if let Some(download) = log.download {
+            self.state.download = download;
+        }
+    }
+}
+
+impl<'a> StorageTransaction for MemoryTransaction<'a> {
+    fn commit(mut self: Box<Self>) -> Result<(), Error> {
+        self.undo = UndoLog::default();
+        Ok(())
+    }
+
+    fn rollback(self: Box<Self>) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn rescan(&mut self, after: &sha256d::Hash) -> Result<(), Error> {
+        if self.state.processed.is_some() {
+            self.undo.processed.get_or_insert(self.state.processed);
+            self.state.processed = Some(*after);
+        }
+        replace(&mut self.undo.txout, &mut self.state.txout, HashMap::new());
+        replace(&mut self.undo.coins, &mut self.state.coins, HashMap::new());
+        replace(&mut self.undo.transactions, &mut self.state.transactions, HashMap::new());
+        self.undo.download.get_or_insert(self.state.download);
+        self.state.download = None;
+        let funded = self.state.issued.iter().filter(|(_, row)| row.funded.is_some()).map(|(a, _)| a.clone()).collect::<Vec<_>>();
+        for address in funded {
+            touch(&mut self.undo.issued, &self.state.issued, &address);
+            if let Some(row) = self.state.issued.get_mut(&address) {
+                row.funded = None;
+            }
+        }
+        Ok(())
+    }
+
+    fn store_txout(&mut self, tx: &Transaction, funding: Option<(&PublicKey, &sha256::Hash, u16)>) -> Result<(), Error> {
+        let txid = tx.txid().to_string();
+        touch(&mut self.undo.txout, &self.state.txout, &txid);
+        self.state.txout.insert(txid, (TxoutRow::new(tx, funding), None));
+        Ok(())
+    }
+
+    fn read_unconfirmed(&self) -> Result<Vec<Unconfirmed>, Error> {
+        self.state.txout.values().filter(|(_, confirmed)| confirmed.is_none())
+            .map(|(row, _)| row.clone().unconfirmed()).collect()
+    }
+
+    fn unconfirm(&mut self, block_id: &sha256d::Hash) -> Result<usize, Error> {
+        let block = block_id.to_string();
+        let transactions = self.state.transactions.iter()
+            .filter(|(_, row)| row.block.as_ref() == Some(&block)).map(|(txid, _)| txid.clone()).collect::<Vec<_>>();
+        for txid in transactions {
+            touch(&mut self.undo.transactions, &self.state.transactions, &txid);
+            if let Some(row) = self.state.transactions.get_mut(&txid) {
+                row.unconfirm();
+            }
+        }
+        let txouts = self.state.txout.iter()
+            .filter(|(_, (_, confirmed))| *confirmed == Some(*block_id)).map(|(txid, _)| txid.clone()).collect::<Vec<_>>();
+        for txid in &txouts {
+            touch(&mut self.undo.txout, &self.state.txout, txid);
+            if let Some((_, confirmed)) = self.state.txout.get_mut(txid) {
+                *confirmed = None;
+            }
+        }
+        Ok(txouts.len())
+    }
+
+    fn confirm(&mut self, txid: &sha256d::Hash, block_id: Option<&sha256d::Hash>) -> Result<usize, Error> {
+        let txid = txid.to_string();
+        touch(&mut self.undo.txout, &self.state.txout, &txid);
+        match self.state.txout.get_mut(&txid) {
+            Some((_, confirmed)) => {
+                *confirmed = block_id.cloned();
+                Ok(1)
+            }
+            None => Ok(0)
+        }
+    }
+
+    fn store_transaction(&mut self, transaction: &WalletTransaction) -> Result<(), Error> {
+        let mut row = TransactionRow::new(transaction);
+        if let Some(stored) = self.state.transactions.get(&row.txid) {
+            row.first_seen = row.first_seen.min(stored.first_seen);
+        }
+        touch(&mut self.undo.transactions, &self.state.transactions, &row.txid);
+        self.state.transactions.insert(row.txid.clone(), row);
+        Ok(())
+    }
+
+    fn read_transactions(&self) -> Result<Vec<WalletTransaction>, Error> {
+        self.state.transactions.values().map(|row| row.to_transaction()).collect()
+    }
+
+    fn store_issued_address(&mut self, address: &IssuedAddress) -> Result<(), Error> {
+        let row = IssuedAddressRow::new(address);
+        touch(&mut self.undo.issued, &self.state.issued, &row.address);
+        self.state.issued.insert(row.address.clone(), row);
+        Ok(())
+    }
+
+    fn read_issued_addresses(&self) -> Result<Vec<IssuedAddress>, Error> {
+        IssuedAddressRow::to_issued_addresses(self.state.issued.values().cloned().collect())
+    }
+
+    fn store_label(&mut self, label: &Label) -> Result<(), Error> {
+        let key = (label.kind, label.reference.clone());
+        touch(&mut self.undo.labels, &self.state.labels, &key);
+        self.state.labels.insert(key, label.clone());
+        Ok(())
+    }
+
+    fn delete_label(&mut self, kind: LabelKind, reference: &str) -> Result<usize, Error> {
+        let key = (kind, reference.to_string());
+        touch(&mut self.undo.labels, &self.state.labels, &key);
+        Ok(self.state.labels.remove(&key).map_or(0, |_| 1))
+    }
+
+    fn read_labels(&self) -> Result<Vec<Label>, Error> {
+        Ok(self.state.labels.values().cloned().collect())
+    }
+
+    fn store_coins(&mut self, coins: &Coins) -> Result<(), Error> {
+        let rows = CoinRow::from_coins(coins)?.into_iter().map(|row| ((row.txid.clone(), row.vout), row)).collect();
+        replace(&mut self.undo.coins, &mut self.state.coins, rows);
+        let proofs = coins.proofs();
+        let mut proved = Vec::new();
+        for (txid, (_, confirmed)) in self.state.txout.iter() {
+            if confirmed.is_none() {
+                if let Some(proof) = proofs.get(&sha256d::Hash::from_hex(txid.as_str())?) {
+                    proved.push((txid.clone(), proof.get_block_hash().clone()));
+                }
+            }
+        }
+        for (txid, block_hash) in proved {
+            touch(&mut self.undo.txout, &self.state.txout, &txid);
+            if let Some((_, confirmed)) = self.state.txout.get_mut(&txid) {
+                *confirmed = Some(block_hash);
+            }
+        }
+        Ok(())
+    }
+
+    fn update_coins(&mut self, coins: &Coins, changes: &CoinChanges) -> Result<(), Error> {
+        for outpoint in &changes.spent {
+            let key = (outpoint.txid.to_string(), outpoint.vout);
+            touch(&mut self.undo.coins, &self.state.coins, &key);
+            self.state.coins.remove(&key);
+        }
+        for row in CoinRow::added(coins, changes)? {
+            let key = (row.txid.clone(), row.vout);
+            touch(&mut self.undo.coins, &self.state.coins, &key);
+            self.state.coins.insert(key, row);
+        }
+        for txid in &changes.proved {
+            let key = txid.to_string();
+            if let Some(proof) = coins.proofs().get(txid) {
+                touch(&mut self.undo.txout, &self.state.txout, &key);
+                if let Some((_, confirmed)) = self.state.txout.get_mut(&key) {
+                    *confirmed = Some(proof.get_block_hash().clone());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn read_coins(&mut self, master_account: &mut MasterAccount) -> Result<Coins, Error> {
+        CoinRow::to_coins(self.state.coins.values().cloned().collect(), self.read_unconfirmed()?, master_account)
+    }
+
+    fn store_master(&mut self, master: &MasterAccount) -> Result<usize, Error> {
+        replace(&mut self.undo.accounts, &mut self.state.accounts, HashMap::new());
+        let mut inserted = 0;
+        for (_, account) in master.accounts().iter() {
+            inserted += self.store_account(account)?;
+        }
+        Ok(inserted)
+    }
+
+    fn store_account(&mut self, account: &Account) -> Result<usize, Error> {
+        let row = AccountRow::new(account)?;
+        let key = (row.account, row.sub);
+        touch(&mut self.undo.accounts, &self.state.accounts, &key);
+        self.state.accounts.insert(key, row);
+        Ok(1)
+    }
+
+    fn read_account(&mut self, account_number: u32, sub: u32, network: Network, look_ahead: u32) -> Result<Account, Error> {
+        self.state.accounts.get(&(account_number, sub)).ok_or(Error::Corrupted("missing account"))?
+            .to_account(network, look_ahead)
+    }
+
+    fn read_processed(&mut self) -> Result<Option<sha256d::Hash>, Error> {
+        Ok(self.state.processed)
+    }
+
+    fn store_processed(&mut self, block_id: &sha256d::Hash) -> Result<(), Error> {
+        self.undo.processed.get_or_insert(self.state.processed);
+        self.state.processed = Some(*block_id);
+        Ok(())
+    }
+
+    fn delete_processed(&mut self) -> Result<(), Error> {
+        self.undo.processed.get_or_insert(self.state.processed);
+        self.state.processed = None;
+        Ok(())
+    }
+
+    fn read_download_progress(&mut self) -> Result<Option<DownloadProgress>, Error> {
+        Ok(self.state.download)
+    }
+
+    fn store_download_progress(&mut self, progress: &DownloadProgress) -> Result<(), Error> {
+        self.undo.download.get_or_insert(self.state.download);
+        self.state.download = Some(*progress);
+        Ok(())
+    }
+
+    fn store_address(&mut self, network: &str, address: &SocketAddr, connected: u64, last_seen: u64, banned: u64) -> Result<usize, Error> {
+        let key = (network.to_string(), *address);
+        touch(&mut self.undo.addresses, &self.state.addresses, &key);
+        let entry = self.state.addresses.entry(key).or_insert((0, 0, 0));
+        *entry = (entry.0.max(connected), entry.1.max(last_seen), entry.2.max(banned));
+        Ok(1)
+    }
+
+    fn get_an_address(&self, network: &str, other_than: Arc<Mutex<HashSet<SocketAddr>>>) -> Result<Option<SocketAddr>, Error> {
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+        let other_than = other_than.lock().unwrap();
+        Ok(self.state.addresses.iter()
+            .filter(|((n, a), (_, _, banned))| n == network && *banned < now - BAN_TIME && !other_than.contains(a))
+            .max_by_key(|(_, (_, last_seen, _))| *last_seen)
+            .map(|((_, a), _)| *a))
+    }
+
+    fn read_snapshot(&mut self) -> Result<Snapshot, Error> {
+        Ok(Snapshot::new(
+            self.state.coins.values().cloned().collect(),
+            self.state.txout.values().map(|(row, confirmed)| (row.clone(), confirmed.is_some())).collect(),
+            self.state.accounts.values().cloned().collect(),
+            self.state.processed,
+            self.state.download,
+            self.state.transactions.values().cloned().collect(),
+            self.state.issued.values().cloned().collect(),
+            self.state.labels.values().cloned().collect()))
+    }
+
+    fn store_snapshot(&mut self, snapshot: &Snapshot) -> Result<(), Error> {
+        let txout = snapshot.txouts()?.into_iter().map(|(row, confirmed)| (row.txid.clone(), (row, confirmed))).collect();
+        replace(&mut self.undo.coins, &mut self.state.coins,
+                snapshot.coins().iter().map(|row| ((row.txid.clone(), row.vout), row.clone())).collect());
+        replace(&mut self.undo.txout, &mut self.state.txout, txout);
+        replace(&mut self.undo.accounts, &mut self.state.accounts,
+                snapshot.accounts().iter().map(|row| ((row.account, row.sub), row.clone())).collect());
+        self.undo.processed.get_or_insert(self.state.processed);
+        self.state.processed = snapshot.processed();
+        self.undo.download.get_or_insert(self.state.download);
+        self.state.download = snapshot.download();
+        replace(&mut self.undo.transactions, &mut self.state.transactions,
+                snapshot.transactions().iter().map(|row| (row.txid.clone(), row.clone())).collect());
+        replace(&mut self.undo.issued, &mut self.state.issued,
+                snapshot.issued().iter().map(|row| (row.address.clone(), row.clone())).collect());
+        replace(&mut self.undo.labels, &mut self.state.labels,
+                snapshot.labels().iter().map(|label| ((label.kind, label.reference.clone()), label.clone())).collect());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+    use std::net::SocketAddr;
+    use std::str::FromStr;
+    use std::sync::{Arc, Mutex};
+
+    use bitcoin::{OutPoint, Script, Transaction, TxIn, TxOut};
+    use bitcoin_hashes::sha256d;
+    use bitcoin_hashes::hex::FromHex;
+
+    use super::{DownloadProgress, MemoryStorage, Storage};
+
+    fn transaction() -> Transaction {
+        Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec!(TxIn { previous_output: OutPoint::default(), script_sig: Script::new(), sequence: 0xffffffff, witness: vec!() }),
+            output: vec!(TxOut { value: 4242, script_pubkey: Script::new() }),
+        }
+    }
+
+    #[test]
+    fn memory_round_trip() {
+        let mut storage = MemoryStorage::new();
+        let genesis = sha256d::Hash::from_hex("0f9188f13cb7b2c71f2a335e3a4fc328bf5beb436012afca590b1a11466e2206").unwrap();
+        let tx = transaction();
+        let progress = DownloadProgress { verified: (0, genesis), queued: (1, genesis) };
+        let peer = SocketAddr::from_str("127.0.0.1:18444").unwrap();
+        {
+            let mut stx = storage.transaction().unwrap();
+            stx.store_txout(&tx, None).unwrap();
+            stx.store_processed(&genesis).unwrap();
+            stx.store_download_progress(&progress).unwrap();
+            stx.store_address("regtest", &peer, 1, 2, 0).unwrap();
+            stx.commit().unwrap();
+        }
+        let mut stx = storage.transaction().unwrap();
+        assert_eq!(stx.read_unconfirmed().unwrap()[0].0, tx);
+        assert_eq!(stx.read_processed().unwrap(), Some(genesis));
+        assert_eq!(stx.read_download_progress().unwrap(), Some(progress));
+        assert_eq!(stx.get_an_address("regtest", Arc::new(Mutex::new(HashSet::new()))).unwrap(), Some(peer));
+        assert_eq!(stx.get_an_address("bitcoin", Arc::new(Mutex::new(HashSet::new()))).unwrap(), None);
+
+        stx.rescan(&genesis).unwrap();
+        assert!(stx.read_unconfirmed().unwrap().is_empty());
+        assert_eq!(stx.read_download_progress().unwrap(), None);
+    }
+
+    #[test]
+    fn memory_rollback() {
+        let mut storage = MemoryStorage::new();
+        {
+            let mut stx = storage.transaction().unwrap();
+            stx.store_txout(&transaction(), None).unwrap();
+            stx.rollback().unwrap();
+        }
+        {
+            // dropped without commit
+            let mut stx = storage.transaction().unwrap();
+            stx.store_txout(&transaction(), None).unwrap();
+        }
+        assert!(storage.transaction().unwrap().read_unconfirmed().unwrap().is_empty());
+
+        // changed entries and replaced tables are restored
+        let genesis = sha256d::Hash::from_hex("0f9188f13cb7b2c71f2a335e3a4fc328bf5beb436012afca590b1a11466e2206").unwrap();
+        {
+            let mut stx = storage.transaction().unwrap();
+            stx.store_txout(&transaction(), None).unwrap();
+            stx.store_processed(&genesis).unwrap();
+            stx.commit().unwrap();
+        }
+        {
+            let mut stx = storage.transaction().unwrap();
+            stx.confirm(&transaction().txid(), Some(&genesis)).unwrap();
+            stx.rescan(&sha256d::Hash::default()).unwrap();
+            stx.delete_processed().unwrap();
+            assert!(stx.read_unconfirmed().unwrap().is_empty());
+        }
+        let mut stx = storage.transaction().unwrap();
+        assert_eq!(stx.read_unconfirmed().unwrap()[0].0, transaction());
+        assert_eq!(stx.read_processed().unwrap(), Some(genesis));
+    }
+}
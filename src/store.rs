@@ -17,9 +17,11 @@
 
 //! store
 
-use std::sync::{Arc, RwLock};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, mpsc, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
 
-use bitcoin::{Address, BitcoinHash, Block, BlockHeader, PublicKey, Script, Transaction};
+use bitcoin::{Address, BitcoinHash, Block, BlockHeader, OutPoint, PublicKey, Script, Transaction};
 use bitcoin::{
     blockdata::{
         opcodes::all,
@@ -29,43 +31,111 @@ use bitcoin::{
 };
 use bitcoin::network::message::NetworkMessage;
 use bitcoin_hashes::{sha256, sha256d};
+use bitcoin_hashes::hex::FromHex;
+use bitcoin_wallet::proved::ProvedTransaction;
 use log::{debug, info};
 use murmel::p2p::{PeerMessage, PeerMessageSender};
 
-use crate::db::SharedDB;
 use crate::error::Error;
+use crate::event::{Subscribers, WalletEvent};
+use crate::labels::{Label, LabelKind};
+use crate::storage::{CoinRow, IssuedAddress, SharedStorage, StorageTransaction, StoredCoins, WalletTransaction};
 use crate::trunk::Trunk;
 use crate::wallet::Wallet;
 
 pub type SharedContentStore = Arc<RwLock<ContentStore>>;
 
+/// a transaction of the wallet
+#[derive(Clone, Debug, Serialize)]
+pub struct HistoryEntry {
+    pub txid: sha256d::Hash,
+    /// block and its height and time if confirmed on the trunk
+    pub block_hash: Option<sha256d::Hash>,
+    pub height: Option<u32>,
+    pub time: Option<u32>,
+    /// paid to the wallet
+    pub received: u64,
+    /// spent from the wallet
+    pub sent: u64,
+    /// known only if the wallet spent in this transaction and knows all its inputs
+    pub fee: Option<u64>,
+    /// the first output not paying to the wallet if the wallet spent in this transaction
+    pub counterparty: Option<Address>,
+    pub label: Option<String>,
+}
+
+/// a confirmed coin of the wallet
+#[derive(Clone, Debug, Serialize)]
+pub struct CoinEntry {
+    pub outpoint: OutPoint,
+    pub value: u64,
+    pub address: Option<Address>,
+    /// label of the output, else of its address
+    pub label: Option<String>,
+}
+
+/// differences found by verify
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct VerifyReport {
+    /// transactions with a merkle proof not matching the header of their block
+    pub invalid_proofs: Vec<sha256d::Hash>,
+    /// blocks of proofs no longer on the trunk
+    pub off_trunk: Vec<sha256d::Hash>,
+    /// unspent outputs of confirmed transactions missing in the coins table or the wallet
+    pub missing_coins: Vec<OutPoint>,
+    /// coins in the coins table or the wallet that are not unspent outputs of confirmed transactions
+    pub extra_coins: Vec<OutPoint>,
+    /// our transactions stored as confirmed without a valid proof on the trunk or the other way around
+    pub wrong_confirmations: Vec<sha256d::Hash>,
+    /// the differences were repaired
+    pub repaired: bool,
+    /// coins without a proof in the wallet or the coins table can not be repaired, only a rescan restores them
+    pub rescan_needed: bool,
+}
+
+impl VerifyReport {
+    /// nothing to repair
+    pub fn is_consistent(&self) -> bool {
+        self.invalid_proofs.is_empty() && self.off_trunk.is_empty() && self.missing_coins.is_empty() &&
+            self.extra_coins.is_empty() && self.wrong_confirmations.is_empty()
+    }
+}
+
 /// the distributed content storage
 pub struct ContentStore {
     trunk: Arc<dyn Trunk + Send + Sync>,
-    db: SharedDB,
+    db: SharedStorage,
     wallet: Wallet,
     txout: Option<PeerMessageSender<NetworkMessage>>,
-    stopped: bool
+    subscribers: Subscribers
 }
 
 impl ContentStore {
     /// new content store
-    pub fn new(db: SharedDB, trunk: Arc<dyn Trunk + Send + Sync>, wallet: Wallet) -> Result<ContentStore, Error> {
+    pub fn new(db: SharedStorage, trunk: Arc<dyn Trunk + Send + Sync>, wallet: Wallet) -> Result<ContentStore, Error> {
         Ok(ContentStore {
             trunk,
             db,
             wallet,
             txout: None,
-            stopped: false
+            subscribers: Subscribers::default()
         })
     }
 
-    pub fn set_stopped(&mut self, stopped: bool) {
-        self.stopped = stopped;
+    /// receive events of wallet and chain changes until the receiver is dropped
+    pub fn subscribe(&mut self) -> mpsc::Receiver<WalletEvent> {
+        self.subscribers.subscribe()
     }
 
-    pub fn get_stopped(& self) -> bool {
-        self.stopped
+    pub fn emit(&mut self, event: WalletEvent) {
+        self.subscribers.emit(event);
+    }
+
+    fn emit_balance_change(&mut self, previous: Vec<u64>) {
+        let balance = self.balance();
+        if balance != previous {
+            self.emit(WalletEvent::BalanceChanged { balance: balance[0], confirmed: balance[1] });
+        }
     }
 
     pub fn set_tx_sender(&mut self, txout: PeerMessageSender<NetworkMessage>) {
@@ -76,23 +146,79 @@ impl ContentStore {
         vec!(self.wallet.balance(), self.wallet.available_balance(self.trunk.len(), |h| self.trunk.get_height(h)))
     }
 
-    pub fn deposit_address(&mut self) -> Address {
-        self.wallet.master.get_mut((0, 0)).expect("can not find 0/0 account")
-            .next_key().expect("can not generate receiver address in 0/0").address.clone()
+    /// the last issued address until it receives funds, then a new one
+    pub fn deposit_address(&mut self) -> Result<Address, Error> {
+        let current = {
+            let mut db = self.db.lock().unwrap();
+            let tx = db.transaction()?;
+            let issued = tx.read_issued_addresses()?;
+            tx.rollback()?;
+            issued.into_iter().filter(|a| (a.account, a.sub) == (0, 0)).last()
+        };
+        match current {
+            Some(current) if current.funded.is_none() => Ok(current.address),
+            _ => self.new_address()
+        }
+    }
+
+    /// issue a new address to receive payments
+    pub fn new_address(&mut self) -> Result<Address, Error> {
+        let mut db = self.db.lock().unwrap();
+        let mut tx = db.transaction()?;
+        let issued = tx.read_issued_addresses()?.into_iter().map(|a| a.address).collect::<HashSet<_>>();
+        // keys used before they were recorded as issued, e.g. by a wallet restored from its mnemonic
+        let used = tx.read_transactions()?.iter()
+            .flat_map(|t| t.transaction.output.iter().map(|o| o.script_pubkey.clone()))
+            .chain(self.wallet.coins().confirmed().values().map(|c| c.output.script_pubkey.clone()))
+            .collect::<HashSet<_>>();
+        let account = self.wallet.master.get_mut((0, 0)).ok_or(Error::Corrupted("missing account 0/0"))?;
+        // the next key index is not stored with the account, skip keys issued or used before
+        let address = loop {
+            let address = account.next_key()?.address.clone();
+            if !issued.contains(&address) && !used.contains(&address.script_pubkey()) {
+                break address;
+            }
+        };
+        let kix = account.instantiated().iter().position(|k| k.address == address)
+            .ok_or(Error::Corrupted("missing issued key"))? as u32;
+        tx.store_issued_address(&IssuedAddress {
+            address: address.clone(),
+            account: 0,
+            sub: 0,
+            kix,
+            issued: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(),
+            funded: None,
+            label: None,
+        })?;
+        tx.store_account(account)?;
+        tx.commit()?;
+        Ok(address)
+    }
+
+    /// addresses handed out to receive payments, in the order they were issued
+    pub fn issued_addresses(&self) -> Result<Vec<IssuedAddress>, Error> {
+        let mut db = self.db.lock().unwrap();
+        let tx = db.transaction()?;
+        let issued = tx.read_issued_addresses()?;
+        tx.rollback()?;
+        Ok(issued)
     }
 
     pub fn fund(&mut self, id: &sha256::Hash, term: u16, amount: u64, fee_per_vbyte: u64, passpharse: String) -> Result<(Transaction, PublicKey, u64), Error> {
+        let balance = self.balance();
         let (transaction, funder, fee) = self.wallet.fund(id, term, passpharse, fee_per_vbyte, amount, self.trunk.clone(),
                                                           |pk, term| Self::funding_script(pk, term.unwrap()))?;
         let mut db = self.db.lock().unwrap();
-        let mut tx = db.transaction();
-        tx.store_account(&self.wallet.master.get((1, 0)).unwrap())?;
-        tx.store_txout(&transaction, Some((&funder, id, term))).expect("can not store outgoing transaction");
-        tx.commit();
+        let mut tx = db.transaction()?;
+        tx.store_account(&self.wallet.master.get((1, 0)).ok_or(Error::Corrupted("missing account 1/0"))?)?;
+        tx.store_txout(&transaction, Some((&funder, id, term)))?;
+        tx.store_transaction(&WalletTransaction::unconfirmed(transaction.clone()))?;
+        tx.commit()?;
         if let Some(ref txout) = self.txout {
             txout.send(PeerMessage::Outgoing(NetworkMessage::Tx(transaction.clone())));
         }
         info!("Wallet balance: {} satoshis {} available", self.wallet.balance(), self.wallet.available_balance(self.trunk.len(), |h| self.trunk.get_height(h)));
+        self.emit_balance_change(balance);
         Ok((transaction, funder, fee))
     }
 
@@ -111,19 +237,175 @@ impl ContentStore {
     }
 
     pub fn withdraw(&mut self, passphrase: String, address: Address, fee_per_vbyte: u64, amount: Option<u64>) -> Result<(Transaction, u64), Error> {
+        let balance = self.balance();
         let (transaction, fee) = self.wallet.withdraw(passphrase, address, fee_per_vbyte, amount, self.trunk.clone())?;
         let mut db = self.db.lock().unwrap();
-        let mut tx = db.transaction();
-        tx.store_account(&self.wallet.master.get((0, 1)).unwrap())?;
-        tx.store_txout(&transaction, None).expect("can not store outgoing transaction");
-        tx.commit();
+        let mut tx = db.transaction()?;
+        tx.store_account(&self.wallet.master.get((0, 1)).ok_or(Error::Corrupted("missing account 0/1"))?)?;
+        tx.store_txout(&transaction, None)?;
+        tx.store_transaction(&WalletTransaction::unconfirmed(transaction.clone()))?;
+        tx.commit()?;
         if let Some(ref txout) = self.txout {
             txout.send(PeerMessage::Outgoing(NetworkMessage::Tx(transaction.clone())));
         }
         info!("Wallet balance: {} satoshis {} available", self.wallet.balance(), self.wallet.available_balance(self.trunk.len(), |h| self.trunk.get_height(h)));
+        self.emit_balance_change(balance);
         Ok((transaction, fee))
     }
 
+    /// fee a withdrawal would pay
+    pub fn estimate_fee(&self, address: &Address, fee_per_vbyte: u64, amount: Option<u64>) -> Result<u64, Error> {
+        self.wallet.estimate_fee(address, fee_per_vbyte, amount, self.trunk.clone())
+    }
+
+    /// height of the header chain tip
+    pub fn tip_height(&self) -> u32 {
+        self.trunk.len()
+    }
+
+    /// height of the last block processed by the wallet
+    pub fn processed_height(&self) -> Result<Option<u32>, Error> {
+        let mut db = self.db.lock().unwrap();
+        let processed = db.transaction()?.read_processed()?;
+        Ok(processed.and_then(|block_hash| self.trunk.get_height(&block_hash)))
+    }
+
+    fn network(&self) -> Network {
+        self.wallet.master.accounts().values()
+            .flat_map(|account| account.instantiated().iter().map(|key| key.address.network))
+            .next().unwrap_or(Network::Bitcoin)
+    }
+
+    // scripts of all keys of the wallet
+    fn scripts(&self) -> HashSet<Script> {
+        self.wallet.master.accounts().values()
+            .flat_map(|account| account.instantiated().iter().map(|key| key.address.script_pubkey()))
+            .collect()
+    }
+
+    /// transactions paying to or spending from the wallet, confirmed in order of height, then unconfirmed
+    pub fn history(&self) -> Result<Vec<HistoryEntry>, Error> {
+        let scripts = self.scripts();
+        let network = self.network();
+
+        // stored transactions, then those of the wallet stored before they were
+        let mut transactions = Vec::new();
+        let mut positions = HashMap::new();
+        let labels;
+        {
+            let mut db = self.db.lock().unwrap();
+            let tx = db.transaction()?;
+            labels = labels_of(tx.read_labels()?, LabelKind::Tx);
+            for stored in tx.read_transactions()? {
+                if let Some(position) = stored.position {
+                    positions.insert(stored.transaction.txid(), position);
+                }
+                transactions.push((stored.block_hash, stored.transaction));
+            }
+            for proof in self.wallet.coins().proofs().values() {
+                transactions.push((Some(proof.get_block_hash().clone()), proof.get_transaction().clone()));
+            }
+            for (transaction, _) in tx.read_unconfirmed()? {
+                transactions.push((None, transaction));
+            }
+        }
+        let mut seen = HashSet::new();
+        transactions.retain(|(_, t)| seen.insert(t.txid()));
+        let known = transactions.iter().map(|(_, t)| (t.txid(), t)).collect::<HashMap<_, _>>();
+
+        let mut history = transactions.iter().map(|(block_hash, transaction)| {
+            let received = transaction.output.iter().filter(|o| scripts.contains(&o.script_pubkey)).map(|o| o.value).sum::<u64>();
+            let spent = transaction.input.iter().map(|i|
+                known.get(&i.previous_output.txid).and_then(|t| t.output.get(i.previous_output.vout as usize))).collect::<Vec<_>>();
+            let sent = spent.iter().filter_map(|o| *o).filter(|o| scripts.contains(&o.script_pubkey)).map(|o| o.value).sum::<u64>();
+            let fee = if sent > 0 && spent.iter().all(|o| o.is_some()) {
+                let input = spent.iter().filter_map(|o| *o).map(|o| o.value).sum::<u64>();
+                input.checked_sub(transaction.output.iter().map(|o| o.value).sum::<u64>())
+            } else {
+                None
+            };
+            let counterparty = if sent > 0 {
+                transaction.output.iter().find(|o| !scripts.contains(&o.script_pubkey))
+                    .and_then(|o| Address::from_script(&o.script_pubkey, network))
+            } else {
+                None
+            };
+            let header = block_hash.and_then(|h| if self.trunk.is_on_trunk(&h) { self.trunk.get_header(&h) } else { None });
+            HistoryEntry {
+                txid: transaction.txid(),
+                block_hash: *block_hash,
+                height: block_hash.and_then(|h| self.trunk.get_height(&h)),
+                time: header.map(|h| h.time),
+                received,
+                sent,
+                fee,
+                counterparty,
+                label: labels.get(&transaction.txid().to_string()).cloned(),
+            }
+        }).collect::<Vec<_>>();
+        history.sort_by_key(|e| (e.height.is_none(), e.height, positions.get(&e.txid).cloned()));
+        Ok(history)
+    }
+
+    /// confirmed coins with their labels
+    pub fn coins(&self) -> Result<Vec<CoinEntry>, Error> {
+        let network = self.network();
+        let labels = {
+            let mut db = self.db.lock().unwrap();
+            let tx = db.transaction()?;
+            let labels = tx.read_labels()?;
+            tx.rollback()?;
+            labels
+        };
+        let outputs = labels_of(labels.clone(), LabelKind::Output);
+        let addresses = labels_of(labels, LabelKind::Addr);
+        Ok(self.wallet.coins().confirmed().iter().map(|(outpoint, coin)| {
+            let address = Address::from_script(&coin.output.script_pubkey, network);
+            CoinEntry {
+                outpoint: *outpoint,
+                value: coin.output.value,
+                label: outputs.get(&format!("{}:{}", outpoint.txid, outpoint.vout)).cloned()
+                    .or_else(|| address.as_ref().and_then(|a| addresses.get(&a.to_string()).cloned())),
+                address,
+            }
+        }).collect())
+    }
+
+    /// label a transaction, address, output or xpub, an empty label removes it
+    pub fn set_label(&mut self, kind: LabelKind, reference: &str, label: &str) -> Result<(), Error> {
+        let reference = kind.reference(reference)?;
+        let mut db = self.db.lock().unwrap();
+        let mut tx = db.transaction()?;
+        if label.is_empty() {
+            tx.delete_label(kind, reference.as_str())?;
+            if kind == LabelKind::Addr {
+                label_issued(tx.as_mut(), reference.as_str(), None)?;
+            }
+        } else {
+            store_label(tx.as_mut(), &Label { kind, reference, label: label.to_string(), origin: None })?;
+        }
+        tx.commit()
+    }
+
+    /// store labels, replacing those of the same references. Returns the number stored.
+    pub fn import_labels(&mut self, labels: &[Label]) -> Result<usize, Error> {
+        let mut db = self.db.lock().unwrap();
+        let mut tx = db.transaction()?;
+        for label in labels {
+            store_label(tx.as_mut(), label)?;
+        }
+        tx.commit()?;
+        Ok(labels.len())
+    }
+
+    pub fn labels(&self) -> Result<Vec<Label>, Error> {
+        let mut db = self.db.lock().unwrap();
+        let tx = db.transaction()?;
+        let labels = tx.read_labels()?;
+        tx.rollback()?;
+        Ok(labels)
+    }
+
     pub fn get_tip(&self) -> Option<sha256d::Hash> {
         if let Some(header) = self.trunk.get_tip() {
             return Some(header.bitcoin_hash());
@@ -132,39 +414,319 @@ impl ContentStore {
     }
 
     pub fn block_connected(&mut self, block: &Block, height: u32) -> Result<(), Error> {
-        debug!("processing block {} {}", height, block.header.bitcoin_hash());
-        // let newly_confirmed_publication;
+        let block_hash = block.header.bitcoin_hash();
+        debug!("processing block {} {}", height, block_hash);
+        let balance = self.balance();
+        let mut events = vec!(WalletEvent::BlockConnected { height, block_hash });
         {
             let mut db = self.db.lock().unwrap();
-            let mut tx = db.transaction();
+            let mut tx = db.transaction()?;
 
+            let before = StoredCoins::new(self.wallet.coins());
             if self.wallet.process(block) {
-                tx.store_coins(&self.wallet.coins())?;
+                // our transactions are no longer unconfirmed once coins are stored
+                let own = tx.read_unconfirmed()?.iter().map(|(t, _)| t.txid()).collect::<HashSet<_>>();
+                let changes = before.changes(self.wallet.coins());
+                tx.update_coins(self.wallet.coins(), &changes)?;
+                if !changes.added.is_empty() {
+                    let funded = self.wallet.coins().confirmed().iter()
+                        .filter(|(o, _)| changes.added.contains(o))
+                        .map(|(_, c)| c.output.script_pubkey.clone())
+                        .collect::<HashSet<_>>();
+                    for mut issued in tx.read_issued_addresses()? {
+                        if issued.funded.is_none() && funded.contains(&issued.address.script_pubkey()) {
+                            issued.funded = Some(height);
+                            tx.store_issued_address(&issued)?;
+                        }
+                    }
+                }
+                for (position, transaction) in block.txdata.iter().enumerate() {
+                    let txid = transaction.txid();
+                    if self.wallet.coins().proofs().contains_key(&txid) || own.contains(&txid) ||
+                        transaction.input.iter().any(|i| before.contains(&i.previous_output)) {
+                        tx.store_transaction(&WalletTransaction {
+                            block_hash: Some(block_hash),
+                            height: Some(height),
+                            position: Some(position as u32),
+                            ..WalletTransaction::unconfirmed(transaction.clone())
+                        })?;
+                    }
+                    if own.contains(&txid) {
+                        events.push(WalletEvent::OutgoingTxConfirmed { txid, height });
+                    } else {
+                        let amount = self.wallet.coins().confirmed().iter()
+                            .filter(|(o, _)| o.txid == txid && !before.contains(o))
+                            .map(|(_, c)| c.output.value).sum::<u64>();
+                        if amount > 0 {
+                            events.push(WalletEvent::IncomingTx { txid, amount });
+                        }
+                    }
+                }
                 info!("New wallet balance {} satoshis {} available", self.wallet.balance(), self.wallet.available_balance(self.trunk.len(), |h| self.trunk.get_height(h)));
             }
-            tx.store_processed(&block.header.bitcoin_hash())?;
-            tx.commit();
+            tx.store_processed(&block_hash)?;
+            tx.commit()?;
+        }
+        for event in events {
+            self.emit(event);
+        }
+        self.emit_balance_change(balance);
+        if height == self.trunk.len() {
+            self.emit(WalletEvent::SyncFinished { height });
         }
         Ok(())
     }
 
     /// add a header to the tip of the chain
     pub fn add_header(&mut self, height: u32, header: &BlockHeader) -> Result<(), Error> {
-        info!("new chain tip at height {} {}", height, header.bitcoin_hash());
+        let block_hash = header.bitcoin_hash();
+        info!("new chain tip at height {} {}", height, block_hash);
+        if self.get_tip() == Some(block_hash) {
+            self.emit(WalletEvent::NewTip { height, block_hash });
+        }
         Ok(())
     }
 
     /// unwind the tip
     pub fn unwind_tip(&mut self, header: &BlockHeader) -> Result<(), Error> {
-        info!("unwind tip {}", header.bitcoin_hash());
-        // let mut deleted_some = false;
+        let block_hash = header.bitcoin_hash();
+        info!("unwind tip {}", block_hash);
+        let balance = self.balance();
         let mut db = self.db.lock().unwrap();
-        let mut tx = db.transaction();
-        tx.store_processed(&header.prev_blockhash)?;
-        tx.commit();
-        self.wallet.unwind_tip(&header.bitcoin_hash());
+        let mut tx = db.transaction()?;
+        // only move back if processing already went past this block
+        let processed = tx.read_processed()?;
+        if processed == Some(block_hash) || processed.map_or(false, |p| !self.trunk.is_on_trunk(&p)) {
+            tx.store_processed(&header.prev_blockhash)?;
+        }
+        tx.unconfirm(&block_hash)?;
+        let before = StoredCoins::new(self.wallet.coins());
+        self.wallet.unwind_tip(&block_hash);
+        tx.update_coins(self.wallet.coins(), &before.changes(self.wallet.coins()))?;
+        unfund_issued(&mut *tx)?;
+        tx.commit()?;
+        drop(db);
+        self.emit(WalletEvent::BlockDisconnected { block_hash });
+        self.emit_balance_change(balance);
         return Ok(());
     }
+
+    /// unwind all blocks of the wallet that are no longer on the trunk and continue processing after fork_point.
+    /// If the fork point is unknown processing continues after the last block with a wallet transaction still on the trunk.
+    /// Returns the number of blocks unwound.
+    pub fn reorg(&mut self, fork_point: Option<sha256d::Hash>) -> Result<usize, Error> {
+        let mut off_trunk = Vec::new();
+        let mut last_on_trunk: Option<(u32, sha256d::Hash)> = None;
+        for proof in self.wallet.coins().proofs().values() {
+            let block_hash = proof.get_block_hash().clone();
+            if let Some(height) = self.trunk.get_height(&block_hash) {
+                if last_on_trunk.map_or(true, |(h, _)| height > h) {
+                    last_on_trunk = Some((height, block_hash));
+                }
+            } else if !off_trunk.contains(&block_hash) {
+                off_trunk.push(block_hash);
+            }
+        }
+        let balance = self.balance();
+        let mut db = self.db.lock().unwrap();
+        let mut tx = db.transaction()?;
+        let before = StoredCoins::new(self.wallet.coins());
+        for block_hash in &off_trunk {
+            info!("unwind block {} no longer on trunk", block_hash);
+            tx.unconfirm(block_hash)?;
+            self.wallet.unwind_tip(block_hash);
+        }
+        tx.update_coins(self.wallet.coins(), &before.changes(self.wallet.coins()))?;
+        unfund_issued(&mut *tx)?;
+        match fork_point.or(last_on_trunk.map(|(_, h)| h)) {
+            Some(fork_point) => tx.store_processed(&fork_point)?,
+            None => tx.delete_processed()?
+        }
+        tx.commit()?;
+        drop(db);
+        for block_hash in &off_trunk {
+            self.emit(WalletEvent::BlockDisconnected { block_hash: *block_hash });
+        }
+        self.emit_balance_change(balance);
+        Ok(off_trunk.len())
+    }
+
+    /// check proofs against the trunk, recompute unspent outputs from stored transactions and compare them with the
+    /// stored coins and confirmations. Repair unwinds blocks with invalid proofs or off the trunk, rewrites the coins
+    /// of the wallet and fixes confirmations.
+    pub fn verify(&mut self, repair: bool) -> Result<VerifyReport, Error> {
+        let mut report = VerifyReport::default();
+        let mut unwind = Vec::new();
+        for proof in self.wallet.coins().proofs().values() {
+            let block_hash = proof.get_block_hash().clone();
+            match self.trunk.get_header(&block_hash) {
+                Some(header) if self.trunk.is_on_trunk(&block_hash) => {
+                    if proof.merkle_root() != header.merkle_root {
+                        report.invalid_proofs.push(proof.get_transaction().txid());
+                        if !unwind.contains(&block_hash) {
+                            unwind.push(block_hash);
+                        }
+                    }
+                }
+                _ => if !report.off_trunk.contains(&block_hash) {
+                    report.off_trunk.push(block_hash);
+                    unwind.push(block_hash);
+                }
+            }
+        }
+
+        let balance = self.balance();
+        let scripts = self.scripts();
+        let mut db = self.db.lock().unwrap();
+        let mut tx = db.transaction()?;
+
+        // confirmed transactions with their block
+        let mut confirmed = HashMap::new();
+        for stored in tx.read_transactions()? {
+            if let Some(block_hash) = stored.block_hash {
+                if !unwind.contains(&block_hash) && self.trunk.is_on_trunk(&block_hash) {
+                    confirmed.insert(stored.transaction.txid(), (block_hash, stored.transaction));
+                }
+            }
+        }
+        for proof in self.wallet.coins().proofs().values() {
+            let block_hash = proof.get_block_hash().clone();
+            if !unwind.contains(&block_hash) {
+                confirmed.entry(proof.get_transaction().txid()).or_insert((block_hash, proof.get_transaction().clone()));
+            }
+        }
+        let spent = confirmed.values()
+            .flat_map(|(_, t)| t.input.iter().map(|i| i.previous_output))
+            .collect::<HashSet<_>>();
+        let unspent = confirmed.iter()
+            .flat_map(|(txid, (_, t))| t.output.iter().enumerate()
+                .filter(|(_, o)| scripts.contains(&o.script_pubkey))
+                .map(move |(vout, _)| OutPoint { txid: *txid, vout: vout as u32 }))
+            .filter(|o| !spent.contains(o))
+            .collect::<HashSet<_>>();
+
+        // coins are kept less those spent by unconfirmed transactions
+        let unconfirmed = tx.read_unconfirmed()?;
+        let pending = unconfirmed.iter()
+            .flat_map(|(t, _)| t.input.iter().map(|i| i.previous_output))
+            .collect::<HashSet<_>>();
+        let expected = unspent.difference(&pending).cloned().collect::<HashSet<_>>();
+
+        let snapshot = tx.read_snapshot()?;
+        let mut stored = HashSet::new();
+        for row in snapshot.coins() {
+            let outpoint = OutPoint { txid: sha256d::Hash::from_hex(row.txid.as_str())?, vout: row.vout };
+            let proof: ProvedTransaction = serde_cbor::from_slice(row.proof.as_slice())?;
+            let block_hash = proof.get_block_hash();
+            if !unwind.contains(block_hash) && self.trunk.is_on_trunk(block_hash) && !self.proves(&outpoint.txid, &proof)
+                && !report.invalid_proofs.contains(&outpoint.txid) {
+                report.invalid_proofs.push(outpoint.txid);
+            }
+            stored.insert(outpoint);
+        }
+        let held = self.wallet.coins().confirmed().iter().map(|(o, _)| *o).collect::<HashSet<_>>();
+        report.missing_coins = expected.iter().filter(|o| !stored.contains(o) || !held.contains(o)).cloned().collect();
+        report.extra_coins = stored.union(&held).filter(|o| !expected.contains(o)).cloned().collect();
+        let mut confirmations = Vec::new();
+        for (txid, is_confirmed) in snapshot.txout_confirmations() {
+            let txid = sha256d::Hash::from_hex(txid.as_str())?;
+            let block_hash = confirmed.get(&txid).map(|(b, _)| *b);
+            if is_confirmed != block_hash.is_some() {
+                report.wrong_confirmations.push(txid);
+                confirmations.push((txid, block_hash));
+            }
+        }
+
+        // the recomputed unspent coins, proved by the wallet or by the stored coins
+        let mut rows = HashMap::new();
+        for row in snapshot.coins().iter().cloned().chain(CoinRow::from_coins(self.wallet.coins())?) {
+            let outpoint = OutPoint { txid: sha256d::Hash::from_hex(row.txid.as_str())?, vout: row.vout };
+            let proof: ProvedTransaction = serde_cbor::from_slice(row.proof.as_slice())?;
+            if unspent.contains(&outpoint) && !unwind.contains(proof.get_block_hash()) && self.proves(&outpoint.txid, &proof) {
+                rows.insert(outpoint, row);
+            }
+        }
+        report.rescan_needed = expected.iter().any(|o| !rows.contains_key(o));
+
+        if !repair || report.is_consistent() {
+            tx.rollback()?;
+            return Ok(report);
+        }
+        for block_hash in &unwind {
+            info!("verify unwinds block {}", block_hash);
+            tx.unconfirm(block_hash)?;
+            self.wallet.unwind_tip(block_hash);
+        }
+        let coins = CoinRow::to_coins(rows.into_iter().map(|(_, row)| row).collect(), unconfirmed, &mut self.wallet.master)?;
+        self.wallet.replace_coins(coins)?;
+        tx.store_coins(self.wallet.coins())?;
+        // continue processing after the last block with a wallet transaction if processing went off the trunk
+        if tx.read_processed()?.map_or(false, |p| !self.trunk.is_on_trunk(&p)) {
+            let last_on_trunk = self.wallet.coins().proofs().values()
+                .filter_map(|proof| self.trunk.get_height(proof.get_block_hash()).map(|h| (h, proof.get_block_hash().clone())))
+                .max_by_key(|(h, _)| *h);
+            match last_on_trunk {
+                Some((_, block_hash)) => tx.store_processed(&block_hash)?,
+                None => tx.delete_processed()?
+            }
+        }
+        for (txid, block_hash) in &confirmations {
+            tx.confirm(txid, block_hash.as_ref())?;
+        }
+        tx.commit()?;
+        drop(db);
+        report.repaired = true;
+        for block_hash in &unwind {
+            self.emit(WalletEvent::BlockDisconnected { block_hash: *block_hash });
+        }
+        self.emit_balance_change(balance);
+        Ok(report)
+    }
+
+    // the proof is of the transaction and matches the header of its block on the trunk
+    fn proves(&self, txid: &sha256d::Hash, proof: &ProvedTransaction) -> bool {
+        proof.get_transaction().txid() == *txid && self.trunk.is_on_trunk(proof.get_block_hash()) &&
+            self.trunk.get_header(proof.get_block_hash()).map_or(false, |header| proof.merkle_root() == header.merkle_root)
+    }
+}
+
+// labels of a kind by reference
+fn labels_of(labels: Vec<Label>, kind: LabelKind) -> HashMap<String, String> {
+    labels.into_iter().filter(|l| l.kind == kind).map(|l| (l.reference, l.label)).collect()
+}
+
+// an issued address keeps the label of its address too
+fn store_label(tx: &mut dyn StorageTransaction, label: &Label) -> Result<(), Error> {
+    tx.store_label(label)?;
+    if label.kind == LabelKind::Addr {
+        label_issued(tx, label.reference.as_str(), Some(&label.label))?;
+    }
+    Ok(())
+}
+
+fn label_issued(tx: &mut dyn StorageTransaction, address: &str, label: Option<&String>) -> Result<(), Error> {
+    for mut issued in tx.read_issued_addresses()? {
+        if issued.address.to_string() == address {
+            issued.label = label.cloned();
+            tx.store_issued_address(&issued)?;
+        }
+    }
+    Ok(())
+}
+
+// issued addresses are no longer funded if no confirmed transaction pays to them after blocks were unwound
+fn unfund_issued(tx: &mut dyn StorageTransaction) -> Result<(), Error> {
+    let paid = tx.read_transactions()?.iter()
+        .filter(|t| t.block_hash.is_some())
+        .flat_map(|t| t.transaction.output.iter().map(|o| o.script_pubkey.clone()))
+        .collect::<HashSet<_>>();
+    for mut issued in tx.read_issued_addresses()? {
+        if issued.funded.is_some() && !paid.contains(&issued.address.script_pubkey()) {
+            issued.funded = None;
+            tx.store_issued_address(&issued)?;
+        }
+    }
+    Ok(())
 }
 
 #[cfg(test)]
@@ -181,8 +743,13 @@ mod test {
     use bitcoin::util::hash::MerkleRoot;
     use bitcoin_hashes::sha256d;
     use bitcoin_wallet::account::{Account, AccountAddressType, Unlocker};
+    use bitcoin_wallet::coins::Coins;
 
     use crate::db::DB;
+    use crate::event::WalletEvent;
+    use crate::labels::LabelKind;
+    use crate::storage;
+    use crate::storage::Snapshot;
     use crate::trunk::Trunk;
     use crate::wallet::Wallet;
 
@@ -234,11 +801,7 @@ mod test {
 
     fn new_store(trunk: Arc<TestTrunk>) -> ContentStore {
         let mut memdb = DB::memory().unwrap();
-        {
-            let mut tx = memdb.transaction();
-            tx.create_tables();
-            tx.commit();
-        }
+        memdb.migrate().unwrap();
         let mut wallet = Wallet::from_encrypted(
             hex::decode("0e05ba48bb0fdc7285dc9498202aeee5e1777ac4f55072b30f15f6a8632ad0f3fde1c41d9e162dbe5d3153282eaebd081cf3b3312336fc56f5dd18a2df6ea48c1cdd11a1ed11281cd2e0f864f02e5bed5ab03326ed24e43b8a184acff9cb4e730db484e33f2b24295a97b2ca87871a69384eb64d4160ce8b3e8b4d90234040970e531d4333a8979dbe533c2b2668bf43b6607b2d24c5b42765ebfdd075fd173c").unwrap().as_slice(),
             ExtendedPubKey::from_str("tpubD6NzVbkrYhZ4XKz4vgwBmnnVmA7EgWhnXvimQ4krq94yUgcSSbroi4uC1xbZ3UGMxG9M2utmaPjdpMrWW2uKRY9Mj4DZWrrY8M4pry8shsK").unwrap(),
@@ -292,4 +855,277 @@ mod test {
         add_tx(&mut block, coin_base(miner, height));
         block
     }
-}
\ No newline at end of file
+
+    // the event a balance change of the store emits
+    fn balance_changed(store: &ContentStore) -> WalletEvent {
+        let balance = store.balance();
+        WalletEvent::BalanceChanged { balance: balance[0], confirmed: balance[1] }
+    }
+
+    // coins written incrementally read back as the wallet has them
+    fn assert_stored(store: &mut ContentStore) {
+        let mut db = store.db.lock().unwrap();
+        let coins = db.transaction().unwrap().read_coins(&mut store.wallet.master).unwrap();
+        assert_eq!(coins.confirmed().len(), store.wallet.coins().confirmed().len());
+        assert_eq!(coins.confirmed_balance(), store.wallet.confirmed_balance());
+    }
+
+    #[test]
+    fn coins_are_stored_incrementally() {
+        let trunk = Arc::new(
+            TestTrunk { trunk: Arc::new(Mutex::new(Vec::new())) });
+        let mut store = new_store(trunk.clone());
+        let genesis = genesis_block(Network::Testnet);
+        trunk.extend(&genesis.header);
+        store.block_connected(&genesis, 0).unwrap();
+        let events = store.subscribe();
+
+        let miner = store.deposit_address().unwrap();
+        let first = mine(&store, 1, &miner);
+        trunk.extend(&first.header);
+        store.block_connected(&first, 1).unwrap();
+        assert_stored(&mut store);
+        assert_eq!(events.try_iter().collect::<Vec<_>>(), vec!(
+            WalletEvent::BlockConnected { height: 1, block_hash: first.bitcoin_hash() },
+            WalletEvent::IncomingTx { txid: first.txdata[0].txid(), amount: NEW_COINS },
+            balance_changed(&store)));
+
+        let burn = Address::p2shwsh(&Builder::new().push_opcode(all::OP_VERIFY).into_script(), Network::Testnet);
+        let (burn_half, _) = store.withdraw(PASSPHRASE.to_string(), burn, 1, Some(NEW_COINS / 2)).unwrap();
+        let burn_txid = burn_half.txid();
+        assert_eq!(events.try_iter().collect::<Vec<_>>(), vec!(balance_changed(&store)));
+        let mut second = mine(&store, 2, &miner);
+        add_tx(&mut second, burn_half);
+        trunk.extend(&second.header);
+        store.block_connected(&second, 2).unwrap();
+        assert_stored(&mut store);
+        assert_eq!(events.try_iter().collect::<Vec<_>>(), vec!(
+            WalletEvent::BlockConnected { height: 2, block_hash: second.bitcoin_hash() },
+            WalletEvent::IncomingTx { txid: second.txdata[0].txid(), amount: NEW_COINS },
+            WalletEvent::OutgoingTxConfirmed { txid: burn_txid, height: 2 },
+            balance_changed(&store)));
+        assert!(store.db.lock().unwrap().transaction().unwrap().read_unconfirmed().unwrap().is_empty());
+
+        // coin bases paying the wallet and the withdrawal
+        let transactions = store.db.lock().unwrap().transaction().unwrap().read_transactions().unwrap();
+        assert_eq!(transactions.len(), 3);
+        let burn = transactions.iter().find(|t| t.transaction.txid() == burn_txid).unwrap();
+        assert_eq!((burn.block_hash, burn.height, burn.position), (Some(second.bitcoin_hash()), Some(2), Some(1)));
+
+        trunk.trunk.lock().unwrap().pop();
+        store.unwind_tip(&second.header).unwrap();
+        assert_eq!(events.try_iter().collect::<Vec<_>>(), vec!(
+            WalletEvent::BlockDisconnected { block_hash: second.bitcoin_hash() },
+            balance_changed(&store)));
+        assert_eq!(store.db.lock().unwrap().transaction().unwrap().read_unconfirmed().unwrap().len(), 1);
+        let transactions = store.db.lock().unwrap().transaction().unwrap().read_transactions().unwrap();
+        assert_eq!(transactions.iter().find(|t| t.transaction.txid() == burn_txid).unwrap().block_hash, None);
+    }
+
+    #[test]
+    fn reorg_unwinds_blocks_off_trunk() {
+        let trunk = Arc::new(
+            TestTrunk { trunk: Arc::new(Mutex::new(Vec::new())) });
+        let mut store = new_store(trunk.clone());
+        let genesis = genesis_block(Network::Testnet);
+        trunk.extend(&genesis.header);
+        store.block_connected(&genesis, 0).unwrap();
+
+        let miner = store.deposit_address().unwrap();
+        let first = mine(&store, 1, &miner);
+        trunk.extend(&first.header);
+        store.block_connected(&first, 1).unwrap();
+        let second = mine(&store, 2, &miner);
+        trunk.extend(&second.header);
+        store.block_connected(&second, 2).unwrap();
+        assert_eq!(store.balance()[0], 2 * NEW_COINS);
+
+        // replace the tip with a block paying someone else, as if it happened while we were down
+        trunk.trunk.lock().unwrap().pop();
+        let burn = Address::p2shwsh(&Builder::new().push_opcode(all::OP_VERIFY).into_script(), Network::Testnet);
+        let fork = mine(&store, 2, &burn);
+        trunk.extend(&fork.header);
+
+        let events = store.subscribe();
+        assert_eq!(store.reorg(None).unwrap(), 1);
+        assert_eq!(store.balance()[0], NEW_COINS);
+        assert_eq!(events.try_iter().collect::<Vec<_>>(), vec!(
+            WalletEvent::BlockDisconnected { block_hash: second.bitcoin_hash() },
+            balance_changed(&store)));
+        assert_stored(&mut store);
+        assert_eq!(store.db.lock().unwrap().transaction().unwrap().read_processed().unwrap(), Some(first.bitcoin_hash()));
+    }
+
+    #[test]
+    fn verify_and_repair() {
+        let trunk = Arc::new(
+            TestTrunk { trunk: Arc::new(Mutex::new(Vec::new())) });
+        let mut store = new_store(trunk.clone());
+        let genesis = genesis_block(Network::Testnet);
+        trunk.extend(&genesis.header);
+        store.block_connected(&genesis, 0).unwrap();
+
+        let miner = store.deposit_address().unwrap();
+        let first = mine(&store, 1, &miner);
+        trunk.extend(&first.header);
+        store.block_connected(&first, 1).unwrap();
+        let second = mine(&store, 2, &miner);
+        trunk.extend(&second.header);
+        store.block_connected(&second, 2).unwrap();
+        assert!(store.verify(false).unwrap().is_consistent());
+
+        // stored coins with the proof of the other coin
+        {
+            let mut db = store.db.lock().unwrap();
+            let mut tx = db.transaction().unwrap();
+            let snapshot = tx.read_snapshot().unwrap();
+            let mut coins = snapshot.coins().to_vec();
+            let proof = coins[0].proof.clone();
+            coins[0].proof = coins[1].proof.clone();
+            coins[1].proof = proof;
+            tx.store_snapshot(&Snapshot::new(coins, snapshot.txout_rows().to_vec(), snapshot.accounts().to_vec(),
+                                             snapshot.processed(), snapshot.download(), snapshot.transactions().to_vec(),
+                                             snapshot.issued().to_vec(), snapshot.labels().to_vec())).unwrap();
+            tx.commit().unwrap();
+        }
+        let report = store.verify(false).unwrap();
+        assert_eq!(report.invalid_proofs.len(), 2);
+        assert!(!report.rescan_needed);
+        assert!(store.verify(true).unwrap().repaired);
+        assert_stored(&mut store);
+        assert!(store.verify(false).unwrap().is_consistent());
+
+        // the tip was replaced but reorg was never called
+        trunk.trunk.lock().unwrap().pop();
+        let burn = Address::p2shwsh(&Builder::new().push_opcode(all::OP_VERIFY).into_script(), Network::Testnet);
+        let fork = mine(&store, 2, &burn);
+        trunk.extend(&fork.header);
+
+        let report = store.verify(false).unwrap();
+        assert_eq!(report.off_trunk, vec!(second.bitcoin_hash()));
+        assert_eq!(report.extra_coins.len(), 1);
+        assert!(!report.repaired);
+        assert_eq!(store.balance()[0], 2 * NEW_COINS);
+
+        let report = store.verify(true).unwrap();
+        assert!(report.repaired && !report.rescan_needed);
+        assert_eq!(store.balance()[0], NEW_COINS);
+        assert_stored(&mut store);
+        assert_eq!(store.db.lock().unwrap().transaction().unwrap().read_processed().unwrap(), Some(first.bitcoin_hash()));
+        assert!(store.verify(false).unwrap().is_consistent());
+
+        // lost coins are restored from the wallet
+        {
+            let mut db = store.db.lock().unwrap();
+            let mut tx = db.transaction().unwrap();
+            tx.store_coins(&Coins::new()).unwrap();
+            tx.commit().unwrap();
+        }
+        let report = store.verify(false).unwrap();
+        assert_eq!(report.missing_coins, vec!(OutPoint { txid: first.txdata[0].txid(), vout: 0 }));
+        assert!(store.verify(true).unwrap().repaired);
+        assert_stored(&mut store);
+        assert!(store.verify(false).unwrap().is_consistent());
+
+        // lost coins of the wallet are restored from the coins table
+        store.wallet.rescan();
+        assert_eq!(store.balance()[0], 0);
+        let report = store.verify(false).unwrap();
+        assert_eq!(report.missing_coins, vec!(OutPoint { txid: first.txdata[0].txid(), vout: 0 }));
+        let report = store.verify(true).unwrap();
+        assert!(report.repaired && !report.rescan_needed);
+        assert_eq!(store.balance()[0], NEW_COINS);
+        assert_stored(&mut store);
+        assert!(store.verify(false).unwrap().is_consistent());
+
+        // lost in both only a rescan restores
+        store.wallet.rescan();
+        {
+            let mut db = store.db.lock().unwrap();
+            let mut tx = db.transaction().unwrap();
+            tx.store_coins(&Coins::new()).unwrap();
+            tx.commit().unwrap();
+        }
+        assert!(store.verify(false).unwrap().rescan_needed);
+        let report = store.verify(true).unwrap();
+        assert!(report.repaired && report.rescan_needed);
+        assert_eq!(store.balance()[0], 0);
+    }
+
+    #[test]
+    fn deposit_address_until_funded() {
+        let trunk = Arc::new(
+            TestTrunk { trunk: Arc::new(Mutex::new(Vec::new())) });
+        let mut store = new_store(trunk.clone());
+        let genesis = genesis_block(Network::Testnet);
+        trunk.extend(&genesis.header);
+        store.block_connected(&genesis, 0).unwrap();
+        // as api::init_config does, returning the first receiving key as deposit address
+        storage::init(&mut *store.db.lock().unwrap(), store.wallet.coins(), &store.wallet.master).unwrap();
+        let initial = store.wallet.master.get((0, 0)).and_then(|a| a.get_key(0)).unwrap().address.clone();
+        assert_eq!(store.issued_addresses().unwrap()[0].address, initial);
+
+        let deposit = store.deposit_address().unwrap();
+        assert_eq!(deposit, initial);
+        assert_eq!(store.deposit_address().unwrap(), deposit);
+        let fresh = store.new_address().unwrap();
+        assert_ne!(fresh, deposit);
+        assert_eq!(store.deposit_address().unwrap(), fresh);
+
+        let first = mine(&store, 1, &fresh);
+        trunk.extend(&first.header);
+        store.block_connected(&first, 1).unwrap();
+        let next = store.deposit_address().unwrap();
+        assert!(next != deposit && next != fresh);
+
+        let issued = store.issued_addresses().unwrap();
+        assert_eq!(issued.iter().map(|a| a.address.clone()).collect::<Vec<_>>(), vec!(deposit, fresh, next));
+        assert_eq!(issued.iter().map(|a| a.funded).collect::<Vec<_>>(), vec!(None, Some(1), None));
+        assert_eq!(issued.iter().map(|a| a.kix).collect::<Vec<_>>(), vec!(0, 1, 2));
+
+        // a key paid before it was issued is skipped
+        let paid = store.wallet.master.get((0, 0)).and_then(|a| a.get_key(3)).unwrap().address.clone();
+        let second = mine(&store, 2, &paid);
+        trunk.extend(&second.header);
+        store.block_connected(&second, 2).unwrap();
+        let fresh = store.new_address().unwrap();
+        assert_eq!(store.issued_addresses().unwrap().last().unwrap().kix, 4);
+
+        // no longer funded once the paying block is unwound
+        store.unwind_tip(&second.header).unwrap();
+        store.unwind_tip(&first.header).unwrap();
+        assert!(store.issued_addresses().unwrap().iter().all(|a| a.funded.is_none()));
+        assert_eq!(store.deposit_address().unwrap(), fresh);
+    }
+
+    #[test]
+    fn labels_in_history_and_coins() {
+        let trunk = Arc::new(
+            TestTrunk { trunk: Arc::new(Mutex::new(Vec::new())) });
+        let mut store = new_store(trunk.clone());
+        let genesis = genesis_block(Network::Testnet);
+        trunk.extend(&genesis.header);
+        store.block_connected(&genesis, 0).unwrap();
+
+        let miner = store.deposit_address().unwrap();
+        let first = mine(&store, 1, &miner);
+        trunk.extend(&first.header);
+        store.block_connected(&first, 1).unwrap();
+        let txid = first.txdata[0].txid();
+
+        store.set_label(LabelKind::Tx, txid.to_string().as_str(), "block reward").unwrap();
+        store.set_label(LabelKind::Addr, miner.to_string().as_str(), "mining").unwrap();
+        store.set_label(LabelKind::Output, format!("{}:0", txid).as_str(), "first coin").unwrap();
+        assert!(store.set_label(LabelKind::Output, txid.to_string().as_str(), "no vout").is_err());
+
+        let history = store.history().unwrap();
+        assert_eq!(history.iter().find(|e| e.txid == txid).unwrap().label, Some("block reward".to_string()));
+        assert_eq!(store.coins().unwrap()[0].label, Some("first coin".to_string()));
+        assert_eq!(store.issued_addresses().unwrap()[0].label, Some("mining".to_string()));
+
+        // the coin falls back to the label of its address
+        store.set_label(LabelKind::Output, format!("{}:0", txid).as_str(), "").unwrap();
+        assert_eq!(store.coins().unwrap()[0].label, Some("mining".to_string()));
+        assert_eq!(store.labels().unwrap().len(), 2);
+    }
+}
@@ -40,6 +40,10 @@ const MIN_FEE_PER_VBYTE: u64 = 1;
 const MAX_TERM: u16 = 6 * 24 * 30;
 // approx. one month.
 const RBF: u32 = 0xffffffff - 2;
+// upper bound of the weight signing adds to an input, a P2SH-P2WPKH script_sig and witness
+const MAX_SIGNATURE_WEIGHT: u64 = 4 * 23 + 108;
+// segwit marker and flag
+const SEGWIT_WEIGHT: u64 = 2;
 
 pub struct Wallet {
     pub coins: Coins,
@@ -105,20 +109,20 @@ impl Wallet {
         fee_per_vbyte = std::cmp::min(MAX_FEE_PER_VBYTE, std::cmp::max(MIN_FEE_PER_VBYTE, fee_per_vbyte));
         term = std::cmp::min(MAX_TERM, term);
         let mut fee = 0;
-        let change_address = self.master.get_mut((0, 1)).unwrap().next_key().unwrap().address.clone();
+        let change_address = self.master.get_mut((0, 1)).ok_or(Error::Corrupted("missing account 0/1"))?.next_key()?.address.clone();
         let height = trunk.len();
         let coins = self.coins.choose_inputs(amount, height, |h| trunk.get_height(h));
         let total_input = coins.iter().map(|(_, c, _)| c.output.value).sum::<u64>();
         let contract_address;
         let funder;
         {
-            let commit_account = self.master.get_mut((1, 0)).unwrap();
-            let kix = commit_account.add_script_key(scripter, Some(&id[..]), Some(term)).expect("can not commit to ad");
-            contract_address = commit_account.get_key(kix).unwrap().address.clone();
-            funder = commit_account.compute_base_public_key(kix).expect("can not compute base public key");
+            let commit_account = self.master.get_mut((1, 0)).ok_or(Error::Corrupted("missing account 1/0"))?;
+            let kix = commit_account.add_script_key(scripter, Some(&id[..]), Some(term))?;
+            contract_address = commit_account.get_key(kix).ok_or(Error::Corrupted("missing committed key"))?.address.clone();
+            funder = commit_account.compute_base_public_key(kix)?;
         }
         if amount > total_input {
-            return Err(Error::Unsupported("insufficient funds"));
+            return Err(Error::InsufficientFunds { needed: amount, available: self.available_balance(height, |h| trunk.get_height(h)) });
         }
         let mut tx = Transaction {
             input: coins.iter().map(|(point, coin, h)|
@@ -136,13 +140,13 @@ impl Wallet {
         };
         loop {
             tx.output.clear();
-            if amount - fee > DUST {
+            if amount > fee + DUST {
                 tx.output.push(TxOut {
                     value: amount - fee,
                     script_pubkey: contract_address.script_pubkey(),
                 });
             } else {
-                return Err(Error::Unsupported("withdraw amount is less than the fees needed (+DUST limit)"));
+                return Err(Error::BelowDust { amount, fee });
             }
             if total_input > amount && (total_input - amount) > DUST {
                 tx.output.insert((thread_rng().next_u32() % 2) as usize, TxOut {
@@ -156,7 +160,7 @@ impl Wallet {
                                 }, &mut unlocker)?
                 != tx.input.len() {
                 error!("could not sign all inputs of our transaction {:?} {}", tx, hex::encode(serialize(&tx)));
-                return Err(Error::Unsupported("could not sign for all inputs"));
+                return Err(Error::SigningFailed);
             }
             if fee == 0 {
                 fee = (tx.get_weight() as u64 * fee_per_vbyte + 3) / 4;
@@ -181,6 +185,10 @@ impl Wallet {
 
     pub fn withdraw(&mut self, passphrase: String, address: Address, mut fee_per_vbyte: u64, amount: Option<u64>, trunk: Arc<dyn Trunk>) -> Result<(Transaction, u64), Error> {
         let network = self.master.master_public().network;
+        // testnet and regtest share address formats
+        if (address.network == Network::Bitcoin) != (network == Network::Bitcoin) {
+            return Err(Error::NetworkMismatch { expected: network, found: address.network });
+        }
         let mut unlocker = Unlocker::new(
             self.master.encrypted(), passphrase.as_str(),
             network, Some(self.master.master_public()))?;
@@ -189,11 +197,11 @@ impl Wallet {
         let amount = amount.unwrap_or(balance);
         fee_per_vbyte = std::cmp::min(MAX_FEE_PER_VBYTE, std::cmp::max(MIN_FEE_PER_VBYTE, fee_per_vbyte));
         let mut fee = 0;
-        let change_address = self.master.get_mut((0, 1)).unwrap().next_key().unwrap().address.clone();
+        let change_address = self.master.get_mut((0, 1)).ok_or(Error::Corrupted("missing account 0/1"))?.next_key()?.address.clone();
         let coins = self.coins.choose_inputs(amount, height, |h| trunk.get_height(h));
         let total_input = coins.iter().map(|(_, c, _)| c.output.value).sum::<u64>();
         if amount > total_input {
-            return Err(Error::Unsupported("insufficient funds"));
+            return Err(Error::InsufficientFunds { needed: amount, available: self.available_balance(height, |h| trunk.get_height(h)) });
         }
         let mut tx = Transaction {
             input: coins.iter().map(|(point, coin, h)|
@@ -211,13 +219,13 @@ impl Wallet {
         };
         loop {
             tx.output.clear();
-            if amount - fee > DUST {
+            if amount > fee + DUST {
                 tx.output.push(TxOut {
                     value: amount - fee,
                     script_pubkey: address.script_pubkey(),
                 });
             } else {
-                return Err(Error::Unsupported("withdraw amount is less than the fees needed (+DUST limit)"));
+                return Err(Error::BelowDust { amount, fee });
             }
             if total_input > amount && (total_input - amount) > DUST {
                 tx.output.insert((thread_rng().next_u32() % 2) as usize, TxOut {
@@ -231,7 +239,7 @@ impl Wallet {
                                 }, &mut unlocker)?
                 != tx.input.len() {
                 error!("could not sign all inputs of our transaction {:?} {}", tx, hex::encode(serialize(&tx)));
-                return Err(Error::Unsupported("could not sign for all inputs"));
+                return Err(Error::SigningFailed);
             }
             if fee == 0 {
                 fee = (tx.get_weight() as u64 * fee_per_vbyte + 3) / 4;
@@ -254,16 +262,60 @@ impl Wallet {
         Ok((tx, fee))
     }
 
-    pub fn from_storage(coins: Coins, mut master: MasterAccount) -> Wallet {
-        for (_, coin) in coins.confirmed() {
-            let ref d = coin.derivation;
-            master.get_mut((d.account, d.sub)).unwrap().do_look_ahead(Some(d.kix)).expect("can not look ahead of storage");
+    /// fee withdraw would pay, estimated without signing so no passphrase is needed
+    pub fn estimate_fee(&self, address: &Address, mut fee_per_vbyte: u64, amount: Option<u64>, trunk: Arc<dyn Trunk>) -> Result<u64, Error> {
+        let network = self.master.master_public().network;
+        if (address.network == Network::Bitcoin) != (network == Network::Bitcoin) {
+            return Err(Error::NetworkMismatch { expected: network, found: address.network });
+        }
+        let height = trunk.len();
+        let balance = self.available_balance(height, |h| trunk.get_height(h));
+        let amount = amount.unwrap_or(balance);
+        fee_per_vbyte = std::cmp::min(MAX_FEE_PER_VBYTE, std::cmp::max(MIN_FEE_PER_VBYTE, fee_per_vbyte));
+        let coins = self.coins.choose_inputs(amount, height, |h| trunk.get_height(h));
+        let total_input = coins.iter().map(|(_, c, _)| c.output.value).sum::<u64>();
+        if amount > total_input {
+            return Err(Error::InsufficientFunds { needed: amount, available: balance });
         }
-        for (_, coin) in coins.unconfirmed() {
+        let mut tx = Transaction {
+            input: coins.iter().map(|(point, _, _)|
+                TxIn {
+                    previous_output: point.clone(),
+                    script_sig: Script::new(),
+                    sequence: RBF,
+                    witness: vec![],
+                }).collect(),
+            output: vec!(TxOut { value: amount, script_pubkey: address.script_pubkey() }),
+            version: 2,
+            lock_time: 0,
+        };
+        if total_input - amount > DUST {
+            // change goes to a P2SH-P2WPKH address
+            tx.output.push(TxOut { value: total_input - amount, script_pubkey: Address::p2sh(&Script::new(), network).script_pubkey() });
+        }
+        let weight = tx.get_weight() as u64 + SEGWIT_WEIGHT + tx.input.len() as u64 * MAX_SIGNATURE_WEIGHT;
+        let fee = (weight * fee_per_vbyte + 3) / 4;
+        if amount <= fee + DUST {
+            return Err(Error::BelowDust { amount, fee });
+        }
+        Ok(fee)
+    }
+
+    pub fn from_storage(coins: Coins, master: MasterAccount) -> Result<Wallet, Error> {
+        let mut wallet = Wallet { coins: Coins::new(), master };
+        wallet.replace_coins(coins)?;
+        Ok(wallet)
+    }
+
+    /// replace the coins, looking ahead of their keys
+    pub fn replace_coins(&mut self, coins: Coins) -> Result<(), Error> {
+        for (_, coin) in coins.confirmed().iter().chain(coins.unconfirmed().iter()) {
             let ref d = coin.derivation;
-            master.get_mut((d.account, d.sub)).unwrap().do_look_ahead(Some(d.kix)).expect("can not look ahead of storage");
+            self.master.get_mut((d.account, d.sub)).ok_or(Error::Corrupted("coin of unknown account"))?
+                .do_look_ahead(Some(d.kix))?;
         }
-        Wallet { coins: coins, master }
+        self.coins = coins;
+        Ok(())
     }
 
     pub fn from_encrypted(encrypted: &[u8], public_master_key: ExtendedPubKey, birth: u64) -> Wallet {
@@ -271,31 +323,37 @@ impl Wallet {
         Wallet { coins: Coins::new(), master }
     }
 
-    pub fn new(bitcoin_network: Network, passphrase: &str, pd_passphrase: Option<&str>) -> (Mnemonic, Address, Wallet) {
-        assert!(passphrase.len() >= 8, "Password should have at least 8 characters");
+    pub fn new(bitcoin_network: Network, passphrase: &str, pd_passphrase: Option<&str>) -> Result<(Mnemonic, Address, Wallet), Error> {
         let mut entropy = [0u8; 16];
         thread_rng().fill_bytes(&mut entropy);
-        let mnemonic = Mnemonic::new(&entropy).expect("can not create mnemonic");
-        let mut master = MasterAccount::from_mnemonic(&mnemonic, SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(),
-                                                      bitcoin_network, passphrase, pd_passphrase).expect("can not generate wallet");
+        let mnemonic = Mnemonic::new(&entropy)?;
+        let (deposit_address, wallet) = Self::restore(bitcoin_network, &mnemonic, SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(),
+                                                      passphrase, pd_passphrase)?;
+        Ok((mnemonic, deposit_address, wallet))
+    }
+
+    /// re-create a wallet from its mnemonic, coins are found by scanning blocks after birth
+    pub fn restore(bitcoin_network: Network, mnemonic: &Mnemonic, birth: u64, passphrase: &str, pd_passphrase: Option<&str>) -> Result<(Address, Wallet), Error> {
+        if passphrase.len() < 8 {
+            return Err(Error::PassphraseTooShort);
+        }
+        let mut master = MasterAccount::from_mnemonic(mnemonic, birth, bitcoin_network, passphrase, pd_passphrase)?;
         let mut unlocker = Unlocker::new(master.encrypted().as_slice(),
                                          passphrase, bitcoin_network,
-                                         Some(&master.master_public())).expect("Internal error in wallet generation");
-        let receiver = Account::new(&mut unlocker, AccountAddressType::P2SHWPKH, 0, 0, KEY_LOOK_AHEAD)
-            .expect("can not create receiver account");
+                                         Some(&master.master_public()))?;
+        let receiver = Account::new(&mut unlocker, AccountAddressType::P2SHWPKH, 0, 0, KEY_LOOK_AHEAD)?;
         master.add_account(receiver);
-        let change = Account::new(&mut unlocker, AccountAddressType::P2SHWPKH, 0, 1, KEY_LOOK_AHEAD)
-            .expect("can not create change account");
+        let change = Account::new(&mut unlocker, AccountAddressType::P2SHWPKH, 0, 1, KEY_LOOK_AHEAD)?;
         master.add_account(change);
-        let commitments = Account::new(&mut unlocker, AccountAddressType::P2WSH(KEY_PURPOSE), 1, 0, 0)
-            .expect("can not create commitments account");
+        let commitments = Account::new(&mut unlocker, AccountAddressType::P2WSH(KEY_PURPOSE), 1, 0, 0)?;
         master.add_account(commitments);
-        let deposit_address = master.get((0, 0)).unwrap().get_key(0).unwrap().address.clone();
+        let deposit_address = master.get((0, 0)).and_then(|a| a.get_key(0))
+            .ok_or(Error::Unsupported("can not derive deposit address"))?.address.clone();
 
-        (mnemonic, deposit_address, Wallet {
+        Ok((deposit_address, Wallet {
             master,
             coins: Coins::new(),
-        })
+        }))
     }
 }
 
@@ -314,6 +372,7 @@ mod test {
     use bitcoin_hashes::{sha256, sha256d};
     use bitcoin_wallet::account::{Account, AccountAddressType, Unlocker};
 
+    use crate::error::Error;
     use crate::store::ContentStore;
     use crate::trunk::Trunk;
     use crate::wallet::Wallet;
@@ -367,7 +426,7 @@ mod test {
         //     hex::decode("0e05ba48bb0fdc7285dc9498202aeee5e1777ac4f55072b30f15f6a8632ad0f3fde1c41d9e162dbe5d3153282eaebd081cf3b3312336fc56f5dd18a2df6ea48c1cdd11a1ed11281cd2e0f864f02e5bed5ab03326ed24e43b8a184acff9cb4e730db484e33f2b24295a97b2ca87871a69384eb64d4160ce8b3e8b4d90234040970e531d4333a8979dbe533c2b2668bf43b6607b2d24c5b42765ebfdd075fd173c").unwrap().as_slice(),
         //     ExtendedPubKey::from_str("tpubD6NzVbkrYhZ4XKz4vgwBmnnVmA7EgWhnXvimQ4krq94yUgcSSbroi4uC1xbZ3UGMxG9M2utmaPjdpMrWW2uKRY9Mj4DZWrrY8M4pry8shsK").unwrap(),
         //     1567260002);
-        let (mnemonic, address, mut wallet) = Wallet::new(Network::Testnet, PASSPHRASE, Option::None);
+        let (mnemonic, address, mut wallet) = Wallet::new(Network::Testnet, PASSPHRASE, Option::None).unwrap();
         let mut unlocker = Unlocker::new_for_master(&wallet.master, PASSPHRASE).unwrap();
         wallet.master.add_account(Account::new(&mut unlocker, AccountAddressType::P2WPKH, 0, 0, 10).unwrap());
         wallet.master.add_account(Account::new(&mut unlocker, AccountAddressType::P2WPKH, 0, 1, 10).unwrap());
@@ -462,4 +521,73 @@ mod test {
         assert_eq!(wallet.balance(), 3 * NEW_COINS + NEW_COINS / 2 - fee);
         assert_eq!(wallet.available_balance(4, |h| trunk.get_height(h)), 3 * NEW_COINS + NEW_COINS / 2 - fee);
     }
-}
\ No newline at end of file
+
+    #[test]
+    pub fn withdraw_errors() {
+        let trunk = Arc::new(
+            TestTrunk { trunk: Arc::new(Mutex::new(Vec::new())) });
+        let mut wallet = new_wallet();
+        let genesis = genesis_block(Network::Testnet);
+        let miner = wallet.master.get_mut((0, 0)).unwrap().next_key().unwrap().address.clone();
+        trunk.extend(&genesis.header);
+        wallet.process(&genesis);
+        let next = mine(&genesis.bitcoin_hash(), 1, &miner);
+        trunk.extend(&next.header);
+        wallet.process(&next);
+
+        let burn = Address::p2shwsh(&Builder::new().push_opcode(all::OP_VERIFY).into_script(), Network::Testnet);
+        match wallet.withdraw(PASSPHRASE.to_string(), burn.clone(), 1, Some(2 * NEW_COINS), trunk.clone()) {
+            Err(Error::InsufficientFunds { needed, available }) => {
+                assert_eq!(needed, 2 * NEW_COINS);
+                assert!(available <= NEW_COINS);
+            }
+            other => panic!("expected insufficient funds, got {:?}", other.map(|(_, fee)| fee))
+        }
+        match wallet.withdraw(PASSPHRASE.to_string(), burn.clone(), 1, Some(100), trunk.clone()) {
+            Err(Error::BelowDust { amount, .. }) => assert_eq!(amount, 100),
+            other => panic!("expected below dust, got {:?}", other.map(|(_, fee)| fee))
+        }
+        match wallet.withdraw("wrong passphrase".to_string(), burn, 1, Some(NEW_COINS / 2), trunk.clone()) {
+            Err(Error::WrongPassphrase) => {}
+            other => panic!("expected wrong passphrase, got {:?}", other.map(|(_, fee)| fee))
+        }
+        let mainnet = Address::p2shwsh(&Builder::new().push_opcode(all::OP_VERIFY).into_script(), Network::Bitcoin);
+        match wallet.withdraw(PASSPHRASE.to_string(), mainnet, 1, Some(NEW_COINS / 2), trunk.clone()) {
+            Err(Error::NetworkMismatch { expected, found }) => {
+                assert_eq!(expected, Network::Testnet);
+                assert_eq!(found, Network::Bitcoin);
+            }
+            other => panic!("expected network mismatch, got {:?}", other.map(|(_, fee)| fee))
+        }
+    }
+
+    #[test]
+    pub fn restore_from_mnemonic() {
+        let (mnemonic, address, wallet) = Wallet::new(Network::Testnet, PASSPHRASE, None).unwrap();
+        let (restored_address, restored) = Wallet::restore(Network::Testnet, &mnemonic, 0, PASSPHRASE, None).unwrap();
+        assert_eq!(restored_address, address);
+        assert_eq!(restored.master_public(), wallet.master_public());
+        assert_eq!(restored.birth(), 0);
+    }
+
+    #[test]
+    pub fn estimate_fee_covers_withdraw() {
+        let trunk = Arc::new(
+            TestTrunk { trunk: Arc::new(Mutex::new(Vec::new())) });
+        let (_, _, mut wallet) = Wallet::new(Network::Testnet, PASSPHRASE, None).unwrap();
+        let genesis = genesis_block(Network::Testnet);
+        let miner = wallet.master.get_mut((0, 0)).unwrap().next_key().unwrap().address.clone();
+        trunk.extend(&genesis.header);
+        wallet.process(&genesis);
+
+        let next = mine(&genesis.bitcoin_hash(), 1, &miner);
+        trunk.extend(&next.header);
+        wallet.process(&next);
+
+        let burn = Address::p2shwsh(&Builder::new().push_opcode(all::OP_VERIFY).into_script(), Network::Testnet);
+        let estimate = wallet.estimate_fee(&burn, 5, Some(NEW_COINS / 2), trunk.clone()).unwrap();
+        let (_, fee) = wallet.withdraw(PASSPHRASE.to_string(), burn, 5, Some(NEW_COINS / 2), trunk.clone()).unwrap();
+        assert!(estimate >= fee);
+        assert!(estimate < fee + 5 * 10);
+    }
+}
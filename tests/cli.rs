@@ -0,0 +1,97 @@
+/*
+ * Copyright 2020 BDK Team
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! runs bdk-cli on an offline regtest wallet and checks output and exit codes
+
+use std::fs;
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+
+const DATA_DIR: &str = "./test_cli";
+const PASSPHRASE: &str = "correct horse battery staple";
+
+const EXIT_USAGE: i32 = 2;
+const EXIT_NO_WALLET: i32 = 3;
+const EXIT_WRONG_PASSPHRASE: i32 = 4;
+const EXIT_INSUFFICIENT_FUNDS: i32 = 5;
+
+// run bdk-cli on the regtest wallet of DATA_DIR with stdin as input
+fn bdk_cli(args: &[&str], stdin: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_bdk-cli"))
+        .args(&["-d", DATA_DIR, "-n", "regtest"])
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(stdin.as_bytes()).unwrap();
+    child.wait_with_output().unwrap()
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8(output.stdout.clone()).unwrap()
+}
+
+#[test]
+fn offline_wallet() {
+    fs::remove_dir_all(DATA_DIR).ok();
+
+    assert_eq!(bdk_cli(&["balance"], "").status.code(), Some(EXIT_NO_WALLET));
+    assert_eq!(bdk_cli(&["init"], "short\n").status.code(), Some(EXIT_WRONG_PASSPHRASE));
+
+    // passphrase on stdin
+    let init = bdk_cli(&["init"], format!("{}\n", PASSPHRASE).as_str());
+    assert!(init.status.success());
+    let init = stdout(&init);
+    let lines = init.lines().collect::<Vec<_>>();
+    assert_eq!(lines.len(), 2);
+    assert_eq!(lines[0].split(' ').count(), 12);
+    let deposit = lines[1].to_string();
+
+    // passphrase from a file, the wallet exists already
+    fs::write(format!("{}/passphrase", DATA_DIR), format!("{}\n", PASSPHRASE)).unwrap();
+    let again = bdk_cli(&["init", "--passphrase-file", format!("{}/passphrase", DATA_DIR).as_str()], "");
+    assert_eq!(again.status.code(), Some(EXIT_USAGE));
+
+    assert!(bdk_cli(&["config", "set", "connections", "0"], "").status.success());
+    assert!(bdk_cli(&["config", "set", "discovery", "off"], "").status.success());
+    assert_eq!(bdk_cli(&["config", "set", "connections", "many"], "").status.code(), Some(EXIT_USAGE));
+    assert_eq!(bdk_cli(&["config", "set", "sync-mode", "headers"], "").status.code(), Some(EXIT_USAGE));
+    assert_eq!(bdk_cli(&["config", "set", "nokey", "1"], "").status.code(), Some(EXIT_USAGE));
+
+    let balance = bdk_cli(&["balance"], "");
+    assert!(balance.status.success());
+    assert_eq!(stdout(&balance).trim(), "0 0");
+
+    let receive = bdk_cli(&["receive"], "");
+    assert!(receive.status.success());
+    assert_eq!(stdout(&receive).trim(), deposit);
+    let new = bdk_cli(&["receive", "--new"], "");
+    assert!(new.status.success());
+    assert_ne!(stdout(&new).trim(), deposit);
+
+    let history = bdk_cli(&["history", "--json"], "");
+    assert!(history.status.success());
+    let history: serde_json::Value = serde_json::from_str(stdout(&history).as_str()).unwrap();
+    assert_eq!(history, serde_json::Value::Array(Vec::new()));
+
+    // a withdrawal with the passphrase from a file finds no funds
+    let send = bdk_cli(&["send", "--passphrase-file", format!("{}/passphrase", DATA_DIR).as_str(), deposit.as_str(), "1000"], "");
+    assert_eq!(send.status.code(), Some(EXIT_INSUFFICIENT_FUNDS));
+
+    fs::remove_dir_all(DATA_DIR).unwrap();
+}
@@ -0,0 +1,11 @@
+#!/bin/bash
+# build the library with the C ABI, compile the C test program against include/bdk.h and run it
+
+set -e
+cd "$(dirname "$0")/../.."
+
+cargo build --features "ffi"
+cc -o target/debug/test_ffi tests/ffi/test_ffi.c -Iinclude -Ltarget/debug -lbdk -lpthread -ldl -lm
+rm -rf ./test_ffi_c
+LD_LIBRARY_PATH=target/debug DYLD_LIBRARY_PATH=target/debug target/debug/test_ffi
+rm -rf ./test_ffi_c
@@ -0,0 +1,78 @@
+/*
+ * Copyright 2020 BDK Team
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+/* exercises the C ABI on an offline regtest wallet, run with tests/ffi/run.sh */
+
+#include <stdio.h>
+#include <string.h>
+
+#include "bdk.h"
+
+#define WORK_DIR "./test_ffi_c"
+#define REGTEST 2
+#define PASSPHRASE "correct horse battery staple"
+/* p2wsh of OP_VERIFY */
+#define BURN "bcrt1qme73kus6rcrr9d70qnkl2qev3m8l48u6ppyjz54eymc6tfl8vhtsy3gtc7"
+
+static int failures = 0;
+
+static void expect(const char *what, BdkErrorCode expected, BdkErrorCode actual) {
+    if (expected != actual) {
+        const char *message = bdk_last_error();
+        fprintf(stderr, "FAIL %s: expected %d got %d (%s)\n", what, expected, actual, message ? message : "no message");
+        failures++;
+    } else {
+        printf("ok %s\n", what);
+    }
+}
+
+int main(void) {
+    BdkInitResult init = { NULL, NULL };
+    expect("short passphrase", BDK_ERROR_CODE_PASSPHRASE_TOO_SHORT, bdk_init_config(WORK_DIR, REGTEST, "short", NULL, &init));
+    expect("null out argument", BDK_ERROR_CODE_NULL_POINTER, bdk_init_config(WORK_DIR, REGTEST, PASSPHRASE, NULL, NULL));
+    expect("init", BDK_ERROR_CODE_OK, bdk_init_config(WORK_DIR, REGTEST, PASSPHRASE, NULL, &init));
+    if (init.mnemonic_words == NULL || strlen(init.deposit_address) == 0) {
+        fprintf(stderr, "FAIL init result is empty\n");
+        failures++;
+    }
+    bdk_init_result_free(&init);
+    expect("init again", BDK_ERROR_CODE_ALREADY_EXISTS, bdk_init_config(WORK_DIR, REGTEST, PASSPHRASE, NULL, &init));
+
+    BdkBalance balance = { 1, 1 };
+    expect("balance before start", BDK_ERROR_CODE_NOT_STARTED, bdk_balance(NULL, &balance));
+
+    BdkWallet *wallet = NULL;
+    expect("start", BDK_ERROR_CODE_OK, bdk_start(WORK_DIR, REGTEST, false, &wallet));
+    expect("balance", BDK_ERROR_CODE_OK, bdk_balance(wallet, &balance));
+    if (balance.balance != 0 || balance.confirmed != 0) {
+        fprintf(stderr, "FAIL new wallet has a balance\n");
+        failures++;
+    }
+
+    char *address = NULL;
+    expect("deposit address", BDK_ERROR_CODE_OK, bdk_deposit_addr(wallet, &address));
+    bdk_string_free(address);
+
+    BdkWithdrawTx withdraw_tx = { NULL, 0 };
+    expect("withdraw from empty wallet", BDK_ERROR_CODE_INSUFFICIENT_FUNDS, bdk_withdraw(wallet, PASSPHRASE, BURN, 1, 10000, &withdraw_tx));
+    expect("withdraw to bad address", BDK_ERROR_CODE_INVALID_ADDRESS, bdk_withdraw(wallet, PASSPHRASE, "not an address", 1, 10000, &withdraw_tx));
+    bdk_withdraw_tx_free(&withdraw_tx);
+
+    expect("stop", BDK_ERROR_CODE_OK, bdk_stop(wallet));
+
+    printf("%d failures\n", failures);
+    return failures == 0 ? 0 : 1;
+}
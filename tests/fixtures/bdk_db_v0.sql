@@ -0,0 +1,55 @@
+-- bdk.db as written by the first release, before schema versioning
+create table seed (
+    k0 number,
+    k1 number
+);
+
+create table address (
+    network text,
+    slot number,
+    ip text,
+    connected number,
+    last_seen number,
+    banned number,
+    primary key(network, slot)
+) without rowid;
+
+create table account (
+    account number,
+    sub number,
+    address_type number,
+    master text,
+    instantiated blob,
+    primary key(account, sub)
+) without rowid;
+
+create table coins (
+    txid text,
+    vout number,
+    value number,
+    script blob,
+    account number,
+    sub number,
+    kix number,
+    tweak text,
+    csv number,
+    proof blob,
+    primary key(txid, vout)
+) without rowid;
+
+create table processed (
+    block text
+);
+
+create table txout (
+    txid text primary key,
+    tx blob,
+    confirmed text,
+    publisher blob,
+    id text,
+    term number
+) without rowid;
+
+insert into seed (rowid, k0, k1) values (1, 1, 2);
+insert into processed (rowid, block) values (1, '0f9188f13cb7b2c71f2a335e3a4fc328bf5beb436012afca590b1a11466e2206');
+insert into address (network, slot, ip, connected, last_seen, banned) values ('regtest', 1, '127.0.0.1:18444', 0, 0, 0);
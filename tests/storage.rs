@@ -0,0 +1,206 @@
+/*
+ * Copyright 2020 BDK Team
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! a storage implemented outside of the crate, it keeps its data in a MemoryStorage
+//! but reads and writes snapshots row by row
+
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use bdk::error::Error;
+use bdk::labels::{Label, LabelKind};
+use bdk::storage::{CoinChanges, DownloadProgress, IssuedAddress, MemoryStorage, Snapshot, Storage, StorageTransaction, Unconfirmed, WalletTransaction};
+use bitcoin::{Network, OutPoint, PublicKey, Script, Transaction, TxIn, TxOut};
+use bitcoin_hashes::{sha256, sha256d};
+use bitcoin_wallet::account::{Account, MasterAccount};
+use bitcoin_wallet::coins::Coins;
+
+#[derive(Default)]
+struct RowStorage {
+    inner: MemoryStorage,
+}
+
+impl Storage for RowStorage {
+    fn transaction<'a>(&'a mut self) -> Result<Box<dyn StorageTransaction + 'a>, Error> {
+        Ok(Box::new(RowTransaction { inner: self.inner.transaction()? }))
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        self.inner.flush()
+    }
+}
+
+struct RowTransaction<'a> {
+    inner: Box<dyn StorageTransaction + 'a>,
+}
+
+impl<'a> StorageTransaction for RowTransaction<'a> {
+    fn commit(self: Box<Self>) -> Result<(), Error> {
+        self.inner.commit()
+    }
+
+    fn rollback(self: Box<Self>) -> Result<(), Error> {
+        self.inner.rollback()
+    }
+
+    fn rescan(&mut self, after: &sha256d::Hash) -> Result<(), Error> {
+        self.inner.rescan(after)
+    }
+
+    fn store_txout(&mut self, tx: &Transaction, funding: Option<(&PublicKey, &sha256::Hash, u16)>) -> Result<(), Error> {
+        self.inner.store_txout(tx, funding)
+    }
+
+    fn read_unconfirmed(&self) -> Result<Vec<Unconfirmed>, Error> {
+        self.inner.read_unconfirmed()
+    }
+
+    fn unconfirm(&mut self, block_id: &sha256d::Hash) -> Result<usize, Error> {
+        self.inner.unconfirm(block_id)
+    }
+
+    fn confirm(&mut self, txid: &sha256d::Hash, block_id: Option<&sha256d::Hash>) -> Result<usize, Error> {
+        self.inner.confirm(txid, block_id)
+    }
+
+    fn store_transaction(&mut self, transaction: &WalletTransaction) -> Result<(), Error> {
+        self.inner.store_transaction(transaction)
+    }
+
+    fn read_transactions(&self) -> Result<Vec<WalletTransaction>, Error> {
+        self.inner.read_transactions()
+    }
+
+    fn store_issued_address(&mut self, address: &IssuedAddress) -> Result<(), Error> {
+        self.inner.store_issued_address(address)
+    }
+
+    fn read_issued_addresses(&self) -> Result<Vec<IssuedAddress>, Error> {
+        self.inner.read_issued_addresses()
+    }
+
+    fn store_label(&mut self, label: &Label) -> Result<(), Error> {
+        self.inner.store_label(label)
+    }
+
+    fn delete_label(&mut self, kind: LabelKind, reference: &str) -> Result<usize, Error> {
+        self.inner.delete_label(kind, reference)
+    }
+
+    fn read_labels(&self) -> Result<Vec<Label>, Error> {
+        self.inner.read_labels()
+    }
+
+    fn store_coins(&mut self, coins: &Coins) -> Result<(), Error> {
+        self.inner.store_coins(coins)
+    }
+
+    fn update_coins(&mut self, coins: &Coins, changes: &CoinChanges) -> Result<(), Error> {
+        self.inner.update_coins(coins, changes)
+    }
+
+    fn read_coins(&mut self, master_account: &mut MasterAccount) -> Result<Coins, Error> {
+        self.inner.read_coins(master_account)
+    }
+
+    fn store_master(&mut self, master: &MasterAccount) -> Result<usize, Error> {
+        self.inner.store_master(master)
+    }
+
+    fn store_account(&mut self, account: &Account) -> Result<usize, Error> {
+        self.inner.store_account(account)
+    }
+
+    fn read_account(&mut self, account_number: u32, sub: u32, network: Network, look_ahead: u32) -> Result<Account, Error> {
+        self.inner.read_account(account_number, sub, network, look_ahead)
+    }
+
+    fn read_processed(&mut self) -> Result<Option<sha256d::Hash>, Error> {
+        self.inner.read_processed()
+    }
+
+    fn store_processed(&mut self, block_id: &sha256d::Hash) -> Result<(), Error> {
+        self.inner.store_processed(block_id)
+    }
+
+    fn delete_processed(&mut self) -> Result<(), Error> {
+        self.inner.delete_processed()
+    }
+
+    fn read_download_progress(&mut self) -> Result<Option<DownloadProgress>, Error> {
+        self.inner.read_download_progress()
+    }
+
+    fn store_download_progress(&mut self, progress: &DownloadProgress) -> Result<(), Error> {
+        self.inner.store_download_progress(progress)
+    }
+
+    fn store_address(&mut self, network: &str, address: &SocketAddr, connected: u64, last_seen: u64, banned: u64) -> Result<usize, Error> {
+        self.inner.store_address(network, address, connected, last_seen, banned)
+    }
+
+    fn get_an_address(&self, network: &str, other_than: Arc<Mutex<HashSet<SocketAddr>>>) -> Result<Option<SocketAddr>, Error> {
+        self.inner.get_an_address(network, other_than)
+    }
+
+    // copied row by row, as a storage that keeps rows in its own tables would
+    fn read_snapshot(&mut self) -> Result<Snapshot, Error> {
+        let stored = self.inner.read_snapshot()?;
+        Ok(Snapshot::new(stored.coins().to_vec(), stored.txout_rows().to_vec(), stored.accounts().to_vec(),
+                         stored.processed(), stored.download(), stored.transactions().to_vec(),
+                         stored.issued().to_vec(), stored.labels().to_vec()))
+    }
+
+    fn store_snapshot(&mut self, snapshot: &Snapshot) -> Result<(), Error> {
+        let copy = Snapshot::new(snapshot.coins().to_vec(), snapshot.txout_rows().to_vec(), snapshot.accounts().to_vec(),
+                                 snapshot.processed(), snapshot.download(), snapshot.transactions().to_vec(),
+                                 snapshot.issued().to_vec(), snapshot.labels().to_vec());
+        self.inner.store_snapshot(&copy)
+    }
+}
+
+#[test]
+fn snapshot_of_an_external_storage() {
+    let transaction = Transaction {
+        version: 2,
+        lock_time: 0,
+        input: vec!(TxIn { previous_output: OutPoint::default(), script_sig: Script::new(), sequence: 0xffffffff, witness: vec!() }),
+        output: vec!(TxOut { value: 4242, script_pubkey: Script::new() }),
+    };
+    let label = Label::new(LabelKind::Tx, transaction.txid().to_string().as_str(), "rent".to_string()).unwrap();
+    let block = sha256d::Hash::default();
+
+    let mut source = RowStorage::default();
+    let mut tx = source.transaction().unwrap();
+    tx.store_txout(&transaction, None).unwrap();
+    tx.store_transaction(&WalletTransaction::unconfirmed(transaction.clone())).unwrap();
+    tx.store_label(&label).unwrap();
+    tx.store_processed(&block).unwrap();
+    let snapshot = tx.read_snapshot().unwrap();
+    tx.commit().unwrap();
+
+    let mut target = RowStorage::default();
+    let mut tx = target.transaction().unwrap();
+    tx.store_snapshot(&snapshot).unwrap();
+    tx.commit().unwrap();
+
+    let mut tx = target.transaction().unwrap();
+    assert_eq!(tx.read_unconfirmed().unwrap()[0].0, transaction);
+    assert_eq!(tx.read_transactions().unwrap()[0].transaction, transaction);
+    assert_eq!(tx.read_labels().unwrap(), vec!(label));
+    assert_eq!(tx.read_processed().unwrap(), Some(block));
+}